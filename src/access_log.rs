@@ -0,0 +1,75 @@
+use std::{sync::RwLock, time::Duration};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{HyperRequest, HyperResponse};
+use crate::variable::Template;
+
+lazy_static::lazy_static! {
+    static ref G_ACCESS_LOG: RwLock<Option<Template>> = RwLock::new(None);
+}
+
+/// Set template of access log lines, disabled when `None`.
+pub fn set_access_log(format: Option<&str>) -> Result<(), ConfigError> {
+    let template = format.map(Template::parse).transpose()?;
+    *G_ACCESS_LOG.write().unwrap() = template;
+
+    Ok(())
+}
+
+/// Access log line of a request, rendered while its context is at hand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLine(String);
+
+impl AccessLine {
+    /// `None` when access log disabled.
+    pub fn render(ctx: &GatewayContext, req: &HyperRequest) -> Option<AccessLine> {
+        let template = G_ACCESS_LOG.read().unwrap();
+
+        template
+            .as_ref()
+            .map(|template| AccessLine(template.render(ctx, req)))
+    }
+
+    /// Log with status and latency of the response sent.
+    pub fn log(self, resp: &HyperResponse, latency: Duration) {
+        tracing::info!(
+            target: "access_log",
+            status = resp.status().as_u16(),
+            latency_ms = latency.as_millis() as u64,
+            "{}",
+            self.0
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    #[test]
+    fn render_access_line() {
+        let req = hyper::Request::builder()
+            .uri("/api?page=2")
+            .body(Body::empty())
+            .unwrap();
+        let mut ctx =
+            GatewayContext::new(Some("10.0.0.1:1234".parse().unwrap()), Scheme::HTTP, &req);
+        ctx.set_var("user", "alice");
+
+        assert!(set_access_log(Some("$remote_addr $user")).is_err());
+
+        set_access_log(Some(r#"$remote_addr "$method $request_uri" $var_user"#)).unwrap();
+        assert_eq!(
+            AccessLine::render(&ctx, &req),
+            Some(AccessLine(
+                r#"10.0.0.1 "GET /api?page=2" alice"#.to_string()
+            ))
+        );
+
+        set_access_log(None).unwrap();
+        assert_eq!(AccessLine::render(&ctx, &req), None);
+    }
+}
@@ -0,0 +1,11 @@
+use super::ApiResult;
+use crate::alert::{alert_manager, AlertStatus};
+
+pub struct AlertApi;
+
+impl AlertApi {
+    /// State of every alert rule.
+    pub async fn get_list() -> ApiResult<Vec<AlertStatus>> {
+        Ok(alert_manager().alerts().into())
+    }
+}
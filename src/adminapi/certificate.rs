@@ -0,0 +1,10 @@
+use super::{ApiCtx, ApiResult};
+use crate::tls::{certificate_infos, CertificateInfo};
+
+pub struct CertificateApi;
+
+impl CertificateApi {
+    pub async fn get_list(app_ctx: ApiCtx) -> ApiResult<Vec<CertificateInfo>> {
+        Ok(certificate_infos(&app_ctx.certificates).into())
+    }
+}
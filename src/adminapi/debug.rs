@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use lieweb::{Json, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use super::{status::Status, ApiResult};
+use crate::journal::{journal, CaptureInfo, JournalEntry};
+use crate::tls::TlsStatsSnapshot;
+
+/// max minutes of a request capture
+const MAX_CAPTURE_MINUTES: u64 = 60;
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileQuery {
+    #[serde(default = "default_profile_seconds")]
+    pub seconds: u64,
+    #[serde(default = "default_profile_frequency")]
+    pub frequency: i32,
+}
+
+fn default_profile_seconds() -> u64 {
+    10
+}
+
+fn default_profile_frequency() -> i32 {
+    99
+}
+
+/// Bytes of bodies buffered under the memory budget, not the whole heap.
+#[derive(Debug, Default, Serialize)]
+pub struct MemoryBudgetStats {
+    /// 0 when unlimited
+    pub limit: usize,
+    pub used: usize,
+    pub pressure: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RuntimeStats {
+    pub num_workers: usize,
+    pub active_tasks: usize,
+    pub injection_queue_depth: usize,
+    pub worker_poll_count: Vec<u64>,
+    pub worker_busy_ms: Vec<u128>,
+}
+
+/// Capture requests of a route, or with `x-request-id` matching a pattern.
+#[derive(Debug, Deserialize)]
+pub struct CaptureReq {
+    #[serde(default)]
+    pub route_id: Option<String>,
+    /// regex of request id
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default = "default_capture_minutes")]
+    pub minutes: u64,
+}
+
+fn default_capture_minutes() -> u64 {
+    5
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CapturedRequests {
+    pub captures: Vec<CaptureInfo>,
+    pub requests: Vec<JournalEntry>,
+}
+
+pub struct DebugApi;
+
+impl DebugApi {
+    /// CPU profile in pprof protobuf format.
+    #[cfg(feature = "profiling")]
+    pub async fn cpu_profile(req: Request) -> Result<Response, Status> {
+        use lieweb::LieRequest;
+        use pprof::protos::Message;
+
+        const MAX_PROFILE_SECONDS: u64 = 60;
+
+        let query: ProfileQuery = req.get_query().map_err(Status::bad_request)?;
+        let seconds = query.seconds.clamp(1, MAX_PROFILE_SECONDS);
+
+        let profile = tokio::task::spawn_blocking(move || {
+            let guard = pprof::ProfilerGuardBuilder::default()
+                .frequency(query.frequency)
+                .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+                .build()?;
+
+            std::thread::sleep(Duration::from_secs(seconds));
+
+            guard.report().build()?.pprof()
+        })
+        .await
+        .map_err(Status::internal_error)?
+        .map_err(Status::internal_error)?;
+
+        let mut content = Vec::new();
+        profile
+            .encode(&mut content)
+            .map_err(Status::internal_error)?;
+
+        let resp = hyper::Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+            .body(hyper::Body::from(content))
+            .map_err(Status::internal_error)?;
+
+        Ok(resp)
+    }
+
+    #[cfg(not(feature = "profiling"))]
+    pub async fn cpu_profile(_req: Request) -> Result<Response, Status> {
+        Err(Status::not_found("build without `profiling` feature"))
+    }
+
+    pub async fn memory_budget_stats() -> ApiResult<MemoryBudgetStats> {
+        let budget = crate::budget::memory_budget();
+
+        Ok(MemoryBudgetStats {
+            limit: budget.limit(),
+            used: budget.used(),
+            pressure: budget.pressure(),
+        }
+        .into())
+    }
+
+    pub async fn captured_requests() -> ApiResult<CapturedRequests> {
+        let journal = journal();
+
+        Ok(CapturedRequests {
+            captures: journal.captures(),
+            requests: journal.entries(),
+        }
+        .into())
+    }
+
+    pub async fn start_capture(req: Json<CaptureReq>) -> ApiResult<Vec<CaptureInfo>> {
+        let req = req.take();
+
+        if req.route_id.is_none() && req.request_id.is_none() {
+            return Err(Status::bad_request("route_id or request_id required"));
+        }
+
+        let minutes = req.minutes.clamp(1, MAX_CAPTURE_MINUTES);
+
+        journal()
+            .capture(
+                req.route_id,
+                req.request_id.as_deref(),
+                Duration::from_secs(minutes * 60),
+            )
+            .map_err(Status::bad_request)?;
+
+        Ok(journal().captures().into())
+    }
+
+    pub async fn stop_capture() -> ApiResult<Vec<CaptureInfo>> {
+        journal().stop();
+
+        Ok(journal().captures().into())
+    }
+
+    pub async fn tls_stats() -> ApiResult<TlsStatsSnapshot> {
+        Ok(crate::tls::tls_stats().snapshot().into())
+    }
+
+    /// Tokio runtime metrics, only available when built with `--cfg tokio_unstable`.
+    pub async fn runtime_stats() -> ApiResult<RuntimeStats> {
+        #[cfg(tokio_unstable)]
+        {
+            let metrics = tokio::runtime::Handle::current().metrics();
+            let num_workers = metrics.num_workers();
+
+            Ok(RuntimeStats {
+                num_workers,
+                active_tasks: metrics.active_tasks_count(),
+                injection_queue_depth: metrics.injection_queue_depth(),
+                worker_poll_count: (0..num_workers)
+                    .map(|i| metrics.worker_poll_count(i))
+                    .collect(),
+                worker_busy_ms: (0..num_workers)
+                    .map(|i| metrics.worker_total_busy_duration(i).as_millis())
+                    .collect(),
+            }
+            .into())
+        }
+
+        #[cfg(not(tokio_unstable))]
+        {
+            Err(Status::not_found("build without `tokio_unstable` cfg"))
+        }
+    }
+}
@@ -0,0 +1,15 @@
+use super::{status::Status, ApiCtx, ApiResult};
+use crate::fixture::{run_tests, TestResult};
+
+pub struct FixtureApi;
+
+impl FixtureApi {
+    /// Run `tests` of current registry config.
+    pub async fn run(app_ctx: ApiCtx) -> ApiResult<Vec<TestResult>> {
+        let config = app_ctx.registry_config();
+
+        let results = run_tests(&config).await.map_err(Status::bad_request)?;
+
+        Ok(results.into())
+    }
+}
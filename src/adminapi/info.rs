@@ -0,0 +1,12 @@
+use super::{ApiCtx, ApiResult};
+use crate::info::GatewayInfo;
+
+pub struct InfoApi;
+
+impl InfoApi {
+    pub async fn get(app_ctx: ApiCtx) -> ApiResult<GatewayInfo> {
+        let config = app_ctx.registry_config();
+
+        Ok(GatewayInfo::new(app_ctx.provider, app_ctx.listeners.clone(), &config).into())
+    }
+}
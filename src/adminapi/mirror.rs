@@ -0,0 +1,11 @@
+use super::ApiResult;
+use crate::plugins::mirror::{mirror_stats, MirrorReport};
+
+pub struct MirrorApi;
+
+impl MirrorApi {
+    /// Differences between responses of mirror and primary, by route.
+    pub async fn get_list() -> ApiResult<Vec<MirrorReport>> {
+        Ok(mirror_stats().reports().into())
+    }
+}
@@ -1,38 +1,96 @@
+mod alert;
+mod certificate;
+mod debug;
+mod fixture;
+mod info;
+mod mirror;
 mod route;
 mod session;
+mod slo;
 mod status;
 mod upstream;
+mod user;
 
 use std::{
     net::SocketAddr,
-    sync::{Arc, RwLock, Mutex},
+    sync::{Arc, Mutex},
 };
 
-use lieweb::{response::IntoResponse, AppState, Error, LieResponse, PathParam, Request, Response};
+use lieweb::{
+    extracts::Query, response::IntoResponse, AppState, Error, LieResponse, PathParam, Request,
+    Response,
+};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Notify;
 
-use crate::registry::{Registry, RegistryWriter, RegistryReader};
-use crate::{registry::RegistryConfig, server::ServerContext};
+use crate::info::Listeners;
+use crate::registry::{Registry, RegistryConfig, RegistryReader, RegistryWriter};
+use crate::server::ServerContext;
+use crate::tls::Certificates;
 
 use self::{
+    alert::AlertApi,
+    certificate::CertificateApi,
+    debug::DebugApi,
+    fixture::FixtureApi,
+    info::InfoApi,
+    mirror::MirrorApi,
     route::RouteApi,
     session::{AuthMiddleware, SessionApi},
+    slo::SloApi,
     status::Status,
     upstream::UpstreamApi,
+    user::{UserApi, UserStore},
 };
 
 type ApiCtx = AppState<AppContext>;
 
 type ApiParam = PathParam<Param>;
 
+type ApiDryRun = Query<DryRun>;
+
 type ApiResult<T> = Result<ApiResponse<T>, Status>;
 
 #[derive(Clone)]
 pub struct AppContext {
     registry_writer: Arc<Mutex<RegistryWriter>>,
-    registry_reader: RegistryReader,
+    // read handle is not `Sync`
+    registry_reader: Arc<Mutex<RegistryReader>>,
     registry_notify: Arc<Notify>,
+    certificates: Arc<Certificates>,
+    provider: &'static str,
+    listeners: Listeners,
+}
+
+impl AppContext {
+    fn registry_config(&self) -> RegistryConfig {
+        self.registry_reader.lock().unwrap().get().config.clone()
+    }
+
+    /// Change registry config, publish to gateway only when it builds.
+    /// With `dry_run`, validate only and leave the registry untouched.
+    fn apply_config<F>(&self, dry_run: bool, f: F) -> Result<(), Status>
+    where
+        F: FnOnce(&mut RegistryConfig) -> Result<(), Status>,
+    {
+        let mut writer = self.registry_writer.lock().unwrap();
+
+        let mut config = self.registry_config();
+        f(&mut config)?;
+
+        Registry::validate(&config).map_err(Status::bad_request)?;
+
+        if dry_run {
+            return Ok(());
+        }
+
+        writer.load_config(config);
+        writer.publish();
+
+        self.registry_notify.notify_one();
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,6 +98,13 @@ pub struct Param {
     pub id: String,
 }
 
+/// `?dry_run=true` on mutating endpoints.
+#[derive(Debug, Default, Deserialize)]
+pub struct DryRun {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
 #[derive(Debug, Serialize)]
 pub struct ApiResponse<T: Serialize> {
     pub err_code: i32,
@@ -93,18 +158,29 @@ impl AdminApi {
     }
 
     pub async fn run(self, addr: SocketAddr) -> Result<(), Error> {
+        let listeners = Listeners::new(&self.rtcfg);
+
         let ServerContext {
-            registry,
-            registry_notify: config_notify,
+            registry_writer,
+            registry_reader,
+            registry_notify,
             watch,
+            config,
+            certificates,
             ..
         } = self.rtcfg;
 
         let app_ctx = AppContext {
-            registry_notify: config_notify,
-            registry,
+            registry_writer,
+            registry_reader: Arc::new(Mutex::new(registry_reader)),
+            registry_notify,
+            certificates,
+            provider: config.registry_provider.kind(),
+            listeners,
         };
 
+        UserStore::init(&config.admin);
+
         let mut app = lieweb::App::with_state(app_ctx);
 
         app.middleware(AuthMiddleware::new("/api/session/login"));
@@ -113,6 +189,8 @@ impl AdminApi {
 
         app.post("/api/session/logout", SessionApi::logout);
 
+        app.post("/api/session/password", SessionApi::change_password);
+
         app.get("/api/routes", RouteApi::get_list);
 
         app.post("/api/routes", RouteApi::add);
@@ -121,14 +199,58 @@ impl AdminApi {
 
         app.put("/api/routes/:id", RouteApi::update);
 
+        app.delete("/api/routes/:id", RouteApi::delete);
+
         app.get("/api/upstreams", UpstreamApi::get_list);
 
         app.post("/api/upstreams", UpstreamApi::add);
 
         app.get("/api/upstreams/:id", UpstreamApi::get_detail);
 
+        app.get("/api/upstreams/:id/endpoints/stats", UpstreamApi::endpoint_stats);
+
         app.put("/api/upstreams/:id", UpstreamApi::update);
 
+        app.delete("/api/upstreams/:id", UpstreamApi::delete);
+
+        app.get("/api/certificates", CertificateApi::get_list);
+
+        app.get("/api/slo", SloApi::get_list);
+
+        app.get("/api/alerts", AlertApi::get_list);
+
+        app.get("/api/mirror/comparisons", MirrorApi::get_list);
+
+        app.get("/api/info", InfoApi::get);
+
+        app.post("/api/tests/run", FixtureApi::run);
+
+        app.get("/api/users", UserApi::get_list);
+
+        app.post("/api/users", UserApi::add);
+
+        app.get("/api/users/:id", UserApi::get_detail);
+
+        app.put("/api/users/:id", UserApi::update);
+
+        app.delete("/api/users/:id", UserApi::delete);
+
+        if config.admin.debug_endpoints {
+            app.get("/api/debug/pprof/profile", DebugApi::cpu_profile);
+
+            app.get("/api/debug/memory_budget", DebugApi::memory_budget_stats);
+
+            app.get("/api/debug/runtime", DebugApi::runtime_stats);
+
+            app.get("/api/debug/tls", DebugApi::tls_stats);
+
+            app.get("/api/debug/requests", DebugApi::captured_requests);
+
+            app.post("/api/debug/requests/capture", DebugApi::start_capture);
+
+            app.delete("/api/debug/requests/capture", DebugApi::stop_capture);
+        }
+
         tracing::info!("adminapi run on {:?}", addr);
 
         tokio::select! {
@@ -1,17 +1,56 @@
 use lieweb::Json;
+use serde::Serialize;
 
-use super::{status::Status, ApiCtx, ApiParam, ApiResult};
+use super::{status::Status, ApiCtx, ApiDryRun, ApiParam, ApiResult};
 use crate::config::RouteConfig;
+use crate::error::ConfigError;
+use crate::router::Route;
 
 type RouteCfg = Json<RouteConfig>;
 
 pub struct RouteApi;
 
+#[derive(Debug, Serialize)]
+pub struct RouteDetail {
+    #[serde(flatten)]
+    pub route: RouteConfig,
+    /// enabled plugins in the order they run
+    pub plugin_order: Vec<PluginOrder>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PluginOrder {
+    pub name: String,
+    pub order: i64,
+}
+
+fn plugin_order(route: &Route) -> Vec<PluginOrder> {
+    route
+        .plugins
+        .iter()
+        .map(|p| PluginOrder {
+            name: p.name.clone(),
+            order: p.order,
+        })
+        .collect()
+}
+
+/// Build `route` without applying it, every bad plugin is listed in `details`.
+fn validate(route: &RouteConfig) -> Result<(), Status> {
+    match Route::new(route) {
+        Ok(_) => Ok(()),
+        Err(ConfigError::InvalidPlugins(_, errors)) => {
+            Err(Status::bad_request("invalid plugins").with_details(errors))
+        }
+        Err(err) => Err(Status::bad_request(err)),
+    }
+}
+
 impl RouteApi {
-    pub async fn get_detail(app_ctx: ApiCtx, param: ApiParam) -> ApiResult<RouteConfig> {
+    pub async fn get_detail(app_ctx: ApiCtx, param: ApiParam) -> ApiResult<RouteDetail> {
         let route_id = &param.value().id;
 
-        let config = app_ctx.registry_reader.get().config;
+        let config = app_ctx.registry_config();
 
         let route = config
             .routes
@@ -20,34 +59,49 @@ impl RouteApi {
             .cloned()
             .ok_or_else(|| Status::not_found("Route not exist"))?;
 
-        Ok(route.into())
+        let plugin_order = Route::new(&route)
+            .map(|r| plugin_order(&r))
+            .map_err(Status::internal_error)?;
+
+        Ok(RouteDetail {
+            route,
+            plugin_order,
+        }
+        .into())
     }
 
     pub async fn get_list(app_ctx: ApiCtx) -> ApiResult<Vec<RouteConfig>> {
-        let config = app_ctx.registry_reader.get().config;
+        let config = app_ctx.registry_config();
 
-        Ok(config.routes.clone().into())
+        Ok(config.routes.into())
     }
 
-    pub async fn add(app_ctx: ApiCtx, route: RouteCfg) -> ApiResult<RouteConfig> {
+    pub async fn add(
+        app_ctx: ApiCtx,
+        query: ApiDryRun,
+        route: RouteCfg,
+    ) -> ApiResult<RouteConfig> {
         let route: RouteConfig = route.take();
 
-        let mut config = app_ctx.registry.config.write().unwrap();
-
-        if config.routes.iter().any(|r| r.id == route.id) {
-            return Err(Status::bad_request("Route Id exist"));
-        }
+        validate(&route)?;
 
-        config.routes.push(route.clone());
+        app_ctx.apply_config(query.value().dry_run, |config| {
+            if config.routes.iter().any(|r| r.id == route.id) {
+                return Err(Status::bad_request("Route Id exist"));
+            }
 
-        app_ctx.registry_notify.notify_one();
+            config.routes.push(route.clone());
+            Ok(())
+        })?;
 
         Ok(route.into())
     }
 
+    /// Replace route config, also used to enable or disable the route.
     pub async fn update(
         app_ctx: ApiCtx,
         param: ApiParam,
+        query: ApiDryRun,
         route: RouteCfg,
     ) -> ApiResult<RouteConfig> {
         let mut route = route.take();
@@ -55,19 +109,80 @@ impl RouteApi {
 
         route.id = route_id;
 
-        let mut config = app_ctx.registry.config.write().unwrap();
+        validate(&route)?;
 
-        match config.routes.iter_mut().find(|r| r.id == route.id) {
-            Some(r) => {
-                let _ = std::mem::replace(r, route.clone());
+        app_ctx.apply_config(query.value().dry_run, |config| {
+            match config.routes.iter_mut().find(|r| r.id == route.id) {
+                Some(r) => {
+                    let _ = std::mem::replace(r, route.clone());
+                    Ok(())
+                }
+                None => Err(Status::not_found("Route not exist")),
             }
-            None => {
-                return Err(Status::not_found("Route not exist"));
+        })?;
+
+        Ok(route.into())
+    }
+
+    pub async fn delete(
+        app_ctx: ApiCtx,
+        param: ApiParam,
+        query: ApiDryRun,
+    ) -> ApiResult<RouteConfig> {
+        let route_id = param.take().id;
+        let mut route = None;
+
+        app_ctx.apply_config(query.value().dry_run, |config| {
+            match config.routes.iter().position(|r| r.id == route_id) {
+                Some(index) => {
+                    route = Some(config.routes.remove(index));
+                    Ok(())
+                }
+                None => Err(Status::not_found("Route not exist")),
             }
-        }
+        })?;
 
-        app_ctx.registry_notify.notify_one();
+        Ok(route.unwrap_or_default().into())
+    }
+}
 
-        Ok(route.into())
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn invalid_plugins() {
+        let route: RouteConfig = serde_json::from_value(serde_json::json!({
+            "id": "broken",
+            "name": "broken",
+            "desc": "",
+            "uris": ["/broken"],
+            "upstream_id": "upstream-001",
+            "plugins": {
+                "ua_block": { "enable": true, "patterns": ["(unclosed"] },
+                "script": { "enable": true, "script": "pub fn on_access(ctx, req { }" },
+                "no_such_plugin": { "enable": true },
+                "headers": { "enable": true, "request": { "set": { "x-ok": "1" } } },
+                "mock": { "enable": false, "no_such_field": 1 }
+            }
+        }))
+        .unwrap();
+
+        let status = validate(&route).unwrap_err();
+        assert_eq!(status.status, lieweb::http::StatusCode::BAD_REQUEST);
+
+        let details = status.details.unwrap();
+        let names: Vec<_> = details
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["no_such_plugin", "script", "ua_block"]);
+        assert!(details[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("unknown plugin"));
+        assert!(details[2]["message"].as_str().unwrap().contains("unclosed"));
     }
 }
@@ -5,16 +5,22 @@ use std::{
     time::Duration,
 };
 
-use hyper::StatusCode;
+use hyper::{Method, StatusCode};
 use lieweb::{middleware::Middleware, Cookie, Request, Response};
 use lieweb::{Json, LieRequest, LieResponse};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use super::status::Status;
+use super::user::{self, UserStore};
+use crate::config::{Role, User};
 
-const ALLOWED_ADMIN: (&str, &str) = ("admin", "admin");
 const SESSION_COOKIE_NAME: &str = "sid";
+const PASSWORD_PATH: &str = "/api/session/password";
+const LOGOUT_PATH: &str = "/api/session/logout";
+const USERS_PATH: &str = "/api/users";
+/// captured requests carry headers, admin only
+const DEBUG_REQUESTS_PATH: &str = "/api/debug/requests";
 
 lazy_static::lazy_static! {
     static ref G_SESSION_STORE: Arc<RwLock<SessionStore<String>>> = Arc::new(RwLock::new(SessionStore::new()));
@@ -67,7 +73,11 @@ impl Middleware for AuthMiddleware {
                     session.load(cookie).cloned()
                 };
 
-                if let Some(_session) = session {
+                if let Some(user) = session.and_then(|name| UserStore::get(&name)) {
+                    if !is_permitted(&user, req.method(), req.path()) {
+                        return LieResponse::with_status(StatusCode::FORBIDDEN).into();
+                    }
+
                     let resp = next.run(req).await;
                     return resp;
                 }
@@ -80,14 +90,41 @@ impl Middleware for AuthMiddleware {
     }
 }
 
+/// Check role permission, user with `must_reset_password` only allowed to change password.
+fn is_permitted(user: &User, method: &Method, path: &str) -> bool {
+    if path == PASSWORD_PATH || path == LOGOUT_PATH {
+        return true;
+    }
+
+    if user.must_reset_password {
+        return false;
+    }
+
+    let admin_only = path.starts_with(USERS_PATH) || path.starts_with(DEBUG_REQUESTS_PATH);
+
+    match user.role {
+        Role::Admin => true,
+        Role::Operator => !admin_only,
+        Role::Viewer => !admin_only && method == Method::GET,
+    }
+}
+
+fn session_user(req: &Request) -> Option<String> {
+    let cookie = req.get_cookie(SESSION_COOKIE_NAME).ok()?;
+
+    let session_store = G_SESSION_STORE.clone();
+    let session = session_store.read().unwrap();
+    session.load(&cookie).cloned()
+}
+
 pub struct SessionApi;
 
 impl SessionApi {
     pub async fn login(req: Json<LoginReq>) -> Result<LieResponse, Status> {
         let login_req: LoginReq = req.take();
 
-        if login_req.username == ALLOWED_ADMIN.0 && login_req.password == ALLOWED_ADMIN.1 {
-            let login_name = login_req.username;
+        if let Some(user) = UserStore::authenticate(&login_req.username, &login_req.password) {
+            let login_name = user.username;
 
             let sid = rand::thread_rng().gen::<[u8; 8]>();
             let sid = sid
@@ -105,7 +142,11 @@ impl SessionApi {
             let mut cookie = Cookie::new(SESSION_COOKIE_NAME, sid);
             cookie.set_path("/");
 
-            let data = LoginResp { login_name };
+            let data = LoginResp {
+                login_name,
+                role: user.role,
+                must_reset_password: user.must_reset_password,
+            };
 
             return Ok(LieResponse::with_json(data).append_cookie(cookie));
         }
@@ -126,6 +167,24 @@ impl SessionApi {
 
         Ok(resp)
     }
+
+    pub async fn change_password(req: Request) -> Result<LieResponse, Status> {
+        let username = session_user(&req).ok_or_else(|| Status::unauthorized("not login"))?;
+
+        let mut req = req;
+        let change_req: ChangePasswordReq = req
+            .read_json()
+            .await
+            .map_err(|_| Status::bad_request("invalid request"))?;
+
+        user::change_password(
+            &username,
+            &change_req.old_password,
+            &change_req.new_password,
+        )?;
+
+        Ok(LieResponse::with_status(StatusCode::OK))
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -137,4 +196,58 @@ pub struct LoginReq {
 #[derive(Debug, Serialize)]
 pub struct LoginResp {
     pub login_name: String,
+    pub role: Role,
+    pub must_reset_password: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordReq {
+    pub old_password: String,
+    pub new_password: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn user(role: Role, must_reset_password: bool) -> User {
+        User {
+            username: "bob".to_string(),
+            password: "pw".to_string(),
+            role,
+            must_reset_password,
+        }
+    }
+
+    #[test]
+    fn role_permission() {
+        let admin = user(Role::Admin, false);
+        let operator = user(Role::Operator, false);
+        let viewer = user(Role::Viewer, false);
+
+        for user in [&admin, &operator, &viewer] {
+            assert!(is_permitted(user, &Method::GET, "/api/routes"));
+            assert!(is_permitted(user, &Method::POST, PASSWORD_PATH));
+        }
+
+        assert!(is_permitted(&admin, &Method::DELETE, "/api/users/bob"));
+        assert!(is_permitted(&admin, &Method::GET, DEBUG_REQUESTS_PATH));
+
+        assert!(is_permitted(&operator, &Method::PUT, "/api/routes/hello"));
+        assert!(!is_permitted(&operator, &Method::GET, "/api/users"));
+        assert!(!is_permitted(&operator, &Method::GET, DEBUG_REQUESTS_PATH));
+
+        assert!(!is_permitted(&viewer, &Method::PUT, "/api/routes/hello"));
+        assert!(!is_permitted(&viewer, &Method::GET, "/api/users"));
+    }
+
+    #[test]
+    fn must_reset_password() {
+        let admin = user(Role::Admin, true);
+
+        assert!(is_permitted(&admin, &Method::POST, PASSWORD_PATH));
+        assert!(is_permitted(&admin, &Method::POST, LOGOUT_PATH));
+        assert!(!is_permitted(&admin, &Method::GET, "/api/routes"));
+        assert!(!is_permitted(&admin, &Method::GET, "/api/users"));
+    }
 }
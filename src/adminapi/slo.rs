@@ -0,0 +1,10 @@
+use super::ApiResult;
+use crate::slo::{slo_tracker, SloReport};
+
+pub struct SloApi;
+
+impl SloApi {
+    pub async fn get_list() -> ApiResult<Vec<SloReport>> {
+        Ok(slo_tracker().reports().into())
+    }
+}
@@ -4,6 +4,9 @@ use lieweb::{http::StatusCode, response::IntoResponse, Error, LieResponse};
 pub struct Status {
     pub code: i32,
     pub message: String,
+    /// structured causes, like errors of each plugin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
     #[serde(skip)]
     pub status: StatusCode,
 }
@@ -13,14 +16,21 @@ impl Status {
         Status {
             code,
             message: message.to_string(),
+            details: None,
             status,
         }
     }
 
+    pub fn with_details(mut self, details: impl serde::Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
+    }
+
     pub fn bad_request(message: impl ToString) -> Self {
         Status {
             code: 10400,
             message: message.to_string(),
+            details: None,
             status: StatusCode::BAD_REQUEST,
         }
     }
@@ -29,6 +39,7 @@ impl Status {
         Status {
             code: 10401,
             message: message.to_string(),
+            details: None,
             status: StatusCode::UNAUTHORIZED,
         }
     }
@@ -37,6 +48,7 @@ impl Status {
         Status {
             code: 10404,
             message: message.to_string(),
+            details: None,
             status: StatusCode::NOT_FOUND,
         }
     }
@@ -45,6 +57,7 @@ impl Status {
         Status {
             code: 10500,
             message: message.to_string(),
+            details: None,
             status: StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
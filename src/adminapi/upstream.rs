@@ -1,7 +1,8 @@
 use lieweb::{extracts::JsonRejection, Json};
 
-use super::{status::Status, ApiCtx, ApiParam, ApiResult};
+use super::{status::Status, ApiCtx, ApiDryRun, ApiParam, ApiResult};
 use crate::config::UpstreamConfig;
+use crate::outlier::{outlier_stats, EndpointStats};
 
 type UpstreamCfg = Json<UpstreamConfig>;
 
@@ -11,7 +12,7 @@ impl UpstreamApi {
     pub async fn get_detail(app_ctx: ApiCtx, param: ApiParam) -> ApiResult<UpstreamConfig> {
         let upstream_id = &param.value().id;
 
-        let config = app_ctx.registry_reader.get();
+        let config = app_ctx.registry_config();
 
         let upstream = config
             .upstreams
@@ -23,24 +24,57 @@ impl UpstreamApi {
         Ok(upstream.into())
     }
 
-    pub async fn get_list(app_ctx: ApiCtx) -> ApiResult<Vec<UpstreamConfig>> {
-        let config = app_ctx.registry.config.read().unwrap();
+    /// Rolling statistics of endpoints, to see why an endpoint is avoided.
+    pub async fn endpoint_stats(
+        app_ctx: ApiCtx,
+        param: ApiParam,
+    ) -> ApiResult<Vec<EndpointStats>> {
+        let upstream_id = &param.value().id;
+
+        let upstream = app_ctx
+            .registry_reader
+            .lock()
+            .unwrap()
+            .get()
+            .upstreams
+            .get(upstream_id)
+            .cloned()
+            .ok_or_else(|| Status::not_found("Upstream not exist"))?;
+        let upstream = upstream.read().unwrap();
+
+        let stats: Vec<EndpointStats> = upstream
+            .endpoints
+            .iter()
+            .map(|(ep, healthiness)| {
+                let health = *healthiness.read().unwrap();
+                outlier_stats().stats(upstream_id, &ep.target, health)
+            })
+            .collect();
 
-        Ok(config.upstreams.clone().into())
+        Ok(stats.into())
     }
 
-    pub async fn add(app_ctx: ApiCtx, upstream: UpstreamCfg) -> ApiResult<UpstreamConfig> {
-        let upstream = upstream.take();
+    pub async fn get_list(app_ctx: ApiCtx) -> ApiResult<Vec<UpstreamConfig>> {
+        let config = app_ctx.registry_config();
 
-        let mut config = app_ctx.registry.config.write().unwrap();
+        Ok(config.upstreams.into())
+    }
 
-        if config.upstreams.iter().any(|up| up.id == upstream.id) {
-            return Err(Status::bad_request("Upstream Id exist"));
-        }
+    pub async fn add(
+        app_ctx: ApiCtx,
+        query: ApiDryRun,
+        upstream: UpstreamCfg,
+    ) -> ApiResult<UpstreamConfig> {
+        let upstream = upstream.take();
 
-        config.upstreams.push(upstream.clone());
+        app_ctx.apply_config(query.value().dry_run, |config| {
+            if config.upstreams.iter().any(|up| up.id == upstream.id) {
+                return Err(Status::bad_request("Upstream Id exist"));
+            }
 
-        app_ctx.registry_notify.notify_one();
+            config.upstreams.push(upstream.clone());
+            Ok(())
+        })?;
 
         Ok(upstream.into())
     }
@@ -48,6 +82,7 @@ impl UpstreamApi {
     pub async fn update(
         app_ctx: ApiCtx,
         param: ApiParam,
+        query: ApiDryRun,
         upstream: Result<Json<UpstreamConfig>, JsonRejection>,
     ) -> ApiResult<UpstreamConfig> {
         let mut upstream = upstream.map(|v| v.take()).map_err(Status::bad_request)?;
@@ -55,19 +90,38 @@ impl UpstreamApi {
 
         upstream.id = upstream_id;
 
-        let mut config = app_ctx.registry.config.write().unwrap();
-
-        match config.upstreams.iter_mut().find(|up| up.id == upstream.id) {
-            Some(up) => {
-                let _ = std::mem::replace(up, upstream.clone());
+        app_ctx.apply_config(query.value().dry_run, |config| {
+            match config.upstreams.iter_mut().find(|up| up.id == upstream.id) {
+                Some(up) => {
+                    let _ = std::mem::replace(up, upstream.clone());
+                    Ok(())
+                }
+                None => Err(Status::not_found("Upstream not exist")),
             }
-            None => {
-                return Err(Status::not_found("Upstream not exist"));
-            }
-        }
-
-        app_ctx.registry_notify.notify_one();
+        })?;
 
         Ok(upstream.into())
     }
+
+    /// Fails when the upstream is still referenced by routes.
+    pub async fn delete(
+        app_ctx: ApiCtx,
+        param: ApiParam,
+        query: ApiDryRun,
+    ) -> ApiResult<UpstreamConfig> {
+        let upstream_id = param.take().id;
+        let mut upstream = None;
+
+        app_ctx.apply_config(query.value().dry_run, |config| {
+            match config.upstreams.iter().position(|up| up.id == upstream_id) {
+                Some(index) => {
+                    upstream = Some(config.upstreams.remove(index));
+                    Ok(())
+                }
+                None => Err(Status::not_found("Upstream not exist")),
+            }
+        })?;
+
+        Ok(upstream.unwrap_or_default().into())
+    }
 }
@@ -0,0 +1,399 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use lieweb::Json;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::{status::Status, ApiParam, ApiResult};
+use crate::config::{AdminConfig, Role, User};
+
+const HASH_PREFIX: &str = "$argon2";
+/// unsalted hash of older versions, still verified and rehashed on login
+const LEGACY_HASH_PREFIX: &str = "sha256:";
+
+lazy_static::lazy_static! {
+    static ref G_USER_STORE: Arc<RwLock<UserStore>> = Arc::new(RwLock::new(UserStore::default()));
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct UserFile {
+    users: Vec<User>,
+}
+
+#[derive(Debug, Default)]
+pub struct UserStore {
+    users: Vec<User>,
+    path: Option<PathBuf>,
+}
+
+impl UserStore {
+    /// Init store from admin config, users file takes precedence when exists.
+    pub fn init(cfg: &AdminConfig) {
+        let mut users = cfg.users.clone();
+
+        if let Some(ref path) = cfg.users_path {
+            if path.is_file() {
+                match crate::config::load_file::<UserFile>(path) {
+                    Ok(file) => users = file.users,
+                    Err(err) => tracing::error!(?err, ?path, "load users file failed"),
+                }
+            }
+        }
+
+        let mut store = G_USER_STORE.write().unwrap();
+        store.users = users;
+        store.path = cfg.users_path.clone();
+    }
+
+    pub fn get(username: &str) -> Option<User> {
+        let store = G_USER_STORE.read().unwrap();
+        store.users.iter().find(|u| u.username == username).cloned()
+    }
+
+    /// Check username and password, return the user when matched.
+    /// Plain text or legacy hashed password is replaced by a new hash.
+    pub fn authenticate(username: &str, password: &str) -> Option<User> {
+        let user = Self::get(username).filter(|user| verify_password(&user.password, password))?;
+
+        if !user.password.starts_with(HASH_PREFIX) {
+            let hash = hash_password(password);
+            let rehashed = Self::update(|users| {
+                if let Some(user) = users.iter_mut().find(|u| u.username == username) {
+                    user.password = hash;
+                }
+                Ok(())
+            });
+            if let Err(err) = rehashed {
+                tracing::warn!(?err, username, "rehash password failed");
+            }
+        }
+
+        Some(user)
+    }
+
+    fn update<T>(f: impl FnOnce(&mut Vec<User>) -> Result<T, Status>) -> Result<T, Status> {
+        let mut store = G_USER_STORE.write().unwrap();
+
+        let mut users = store.users.clone();
+        let ret = f(&mut users)?;
+
+        if let Some(ref path) = store.path {
+            let file = UserFile {
+                users: users.clone(),
+            };
+            crate::config::dump_file(&file, path).map_err(|e| {
+                tracing::error!(?e, ?path, "save users file failed");
+                Status::internal_error("save users failed")
+            })?;
+        }
+
+        store.users = users;
+
+        Ok(ret)
+    }
+}
+
+/// Salted argon2id hash in PHC string format.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("hash password with default argon2 params")
+        .to_string()
+}
+
+/// Stored password may be plain text from config file, legacy `sha256:` hex, or hashed by
+/// `hash_password`.
+fn verify_password(stored: &str, password: &str) -> bool {
+    if stored.starts_with(HASH_PREFIX) {
+        PasswordHash::new(stored)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    } else if let Some(hex) = stored.strip_prefix(LEGACY_HASH_PREFIX) {
+        let digest = Sha256::digest(password.as_bytes());
+        let expected = digest
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        constant_time_eq(hex.as_bytes(), expected.as_bytes())
+    } else {
+        constant_time_eq(stored.as_bytes(), password.as_bytes())
+    }
+}
+
+/// Compare without early return, only the length is leaked.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub role: Role,
+    pub must_reset_password: bool,
+}
+
+impl From<&User> for UserInfo {
+    fn from(user: &User) -> Self {
+        UserInfo {
+            username: user.username.clone(),
+            role: user.role,
+            must_reset_password: user.must_reset_password,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddUserReq {
+    pub username: String,
+    pub password: String,
+    /// least privileged when not given
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default = "default_must_reset")]
+    pub must_reset_password: bool,
+}
+
+fn default_must_reset() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateUserReq {
+    pub password: Option<String>,
+    pub role: Option<Role>,
+    pub must_reset_password: Option<bool>,
+}
+
+pub struct UserApi;
+
+impl UserApi {
+    pub async fn get_list() -> ApiResult<Vec<UserInfo>> {
+        let store = G_USER_STORE.read().unwrap();
+
+        Ok(store.users.iter().map(UserInfo::from).collect::<Vec<_>>().into())
+    }
+
+    pub async fn get_detail(param: ApiParam) -> ApiResult<UserInfo> {
+        let username = &param.value().id;
+
+        let user = UserStore::get(username).ok_or_else(|| Status::not_found("User not exist"))?;
+
+        Ok(UserInfo::from(&user).into())
+    }
+
+    pub async fn add(req: Json<AddUserReq>) -> ApiResult<UserInfo> {
+        let req = req.take();
+
+        if req.username.is_empty() || req.password.is_empty() {
+            return Err(Status::bad_request("username and password required"));
+        }
+
+        let user = User {
+            username: req.username,
+            password: hash_password(&req.password),
+            role: req.role,
+            must_reset_password: req.must_reset_password,
+        };
+
+        UserStore::update(|users| add_user(users, user.clone()))?;
+
+        Ok(UserInfo::from(&user).into())
+    }
+
+    pub async fn update(param: ApiParam, req: Json<UpdateUserReq>) -> ApiResult<UserInfo> {
+        let username = param.take().id;
+        let req = req.take();
+
+        let user = UserStore::update(|users| update_user(users, &username, &req))?;
+
+        Ok(UserInfo::from(&user).into())
+    }
+
+    pub async fn delete(param: ApiParam) -> ApiResult<UserInfo> {
+        let username = param.take().id;
+
+        let user = UserStore::update(|users| delete_user(users, &username))?;
+
+        Ok(UserInfo::from(&user).into())
+    }
+}
+
+fn add_user(users: &mut Vec<User>, user: User) -> Result<(), Status> {
+    if users.iter().any(|u| u.username == user.username) {
+        return Err(Status::bad_request("User exist"));
+    }
+
+    users.push(user);
+    Ok(())
+}
+
+/// Modify `users` in place, caller discards them on error.
+fn update_user(users: &mut [User], username: &str, req: &UpdateUserReq) -> Result<User, Status> {
+    let user = users
+        .iter_mut()
+        .find(|u| u.username == username)
+        .ok_or_else(|| Status::not_found("User not exist"))?;
+
+    if let Some(ref password) = req.password {
+        user.password = hash_password(password);
+    }
+    if let Some(role) = req.role {
+        user.role = role;
+    }
+    if let Some(must_reset_password) = req.must_reset_password {
+        user.must_reset_password = must_reset_password;
+    }
+    let user = user.clone();
+
+    if !users.iter().any(|u| u.role == Role::Admin) {
+        return Err(Status::bad_request("At least one admin required"));
+    }
+
+    Ok(user)
+}
+
+fn delete_user(users: &mut Vec<User>, username: &str) -> Result<User, Status> {
+    let index = users
+        .iter()
+        .position(|u| u.username == username)
+        .ok_or_else(|| Status::not_found("User not exist"))?;
+
+    let is_last_admin = users[index].role == Role::Admin
+        && users.iter().filter(|u| u.role == Role::Admin).count() == 1;
+    if is_last_admin {
+        return Err(Status::bad_request("Can not delete the last admin"));
+    }
+
+    Ok(users.remove(index))
+}
+
+/// Change password of the login user, also clears the forced reset flag.
+pub fn change_password(username: &str, old_password: &str, new_password: &str) -> Result<(), Status> {
+    if new_password.is_empty() {
+        return Err(Status::bad_request("new password required"));
+    }
+
+    UserStore::update(|users| {
+        let user = users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or_else(|| Status::not_found("User not exist"))?;
+
+        if !verify_password(&user.password, old_password) {
+            return Err(Status::unauthorized("invalid password"));
+        }
+
+        user.password = hash_password(new_password);
+        user.must_reset_password = false;
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn password_hash() {
+        let hash = hash_password("s3cret");
+        assert!(hash.starts_with("$argon2id$"));
+        // salted
+        assert_ne!(hash, hash_password("s3cret"));
+
+        assert!(verify_password(&hash, "s3cret"));
+        assert!(!verify_password(&hash, "s3cret!"));
+        assert!(!verify_password("$argon2id$broken", "s3cret"));
+
+        let legacy = "sha256:2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+        assert!(verify_password(legacy, "secret"));
+        assert!(!verify_password(legacy, "secret2"));
+
+        assert!(verify_password("admin", "admin"));
+        assert!(!verify_password("admin", "admin1"));
+    }
+
+    #[test]
+    fn default_role() {
+        let req: AddUserReq =
+            serde_json::from_value(serde_json::json!({ "username": "bob", "password": "pw" }))
+                .unwrap();
+        assert_eq!(req.role, Role::Viewer);
+
+        let user: User = serde_yaml::from_str("username: admin\npassword: admin\n").unwrap();
+        assert_eq!(user.role, Role::Admin);
+    }
+
+    fn user(username: &str, role: Role) -> User {
+        User {
+            username: username.to_string(),
+            password: hash_password(username),
+            role,
+            must_reset_password: false,
+        }
+    }
+
+    fn update_req(role: Option<Role>) -> UpdateUserReq {
+        UpdateUserReq {
+            password: None,
+            role,
+            must_reset_password: None,
+        }
+    }
+
+    #[test]
+    fn crud() {
+        let mut users = vec![user("admin", Role::Admin)];
+
+        add_user(&mut users, user("bob", Role::Viewer)).unwrap();
+        let err = add_user(&mut users, user("bob", Role::Operator)).unwrap_err();
+        assert_eq!(err.status, lieweb::http::StatusCode::BAD_REQUEST);
+        assert_eq!(users.len(), 2);
+
+        let req = UpdateUserReq {
+            password: Some("new".to_string()),
+            role: Some(Role::Operator),
+            must_reset_password: Some(true),
+        };
+        let bob = update_user(&mut users, "bob", &req).unwrap();
+        assert_eq!(bob.role, Role::Operator);
+        assert!(bob.must_reset_password);
+        assert!(verify_password(&users[1].password, "new"));
+
+        let err = update_user(&mut users, "alice", &update_req(None)).unwrap_err();
+        assert_eq!(err.status, lieweb::http::StatusCode::NOT_FOUND);
+
+        assert_eq!(delete_user(&mut users, "bob").unwrap().username, "bob");
+        assert!(delete_user(&mut users, "bob").is_err());
+        assert_eq!(users.len(), 1);
+    }
+
+    #[test]
+    fn keep_last_admin() {
+        let mut users = vec![user("admin", Role::Admin), user("bob", Role::Viewer)];
+
+        assert!(delete_user(&mut users, "admin").is_err());
+        let demote = update_req(Some(Role::Operator));
+        assert!(update_user(&mut users.clone(), "admin", &demote).is_err());
+
+        // another admin, either can go
+        update_user(&mut users, "bob", &update_req(Some(Role::Admin))).unwrap();
+        update_user(&mut users, "admin", &update_req(Some(Role::Viewer))).unwrap();
+        assert!(delete_user(&mut users, "bob").is_err());
+        assert!(delete_user(&mut users, "admin").is_ok());
+    }
+}
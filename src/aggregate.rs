@@ -0,0 +1,247 @@
+use std::time::Duration;
+
+use hyper::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING},
+    Body, Method, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{bad_gateway, read_body, HyperRequest, HyperResponse};
+use crate::jsonpath::JsonTemplate;
+use crate::upstream::UpstreamMap;
+use crate::variable::Template;
+
+/// Fan a request out to several upstreams and merge the json responses.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AggregateConfig {
+    pub branches: Vec<BranchConfig>,
+    /// merged shape, strings like `$.user.name` select from `{ branch name: branch json }`,
+    /// absent means the object of all branches
+    #[serde(default)]
+    pub template: Option<Value>,
+    #[serde(default)]
+    pub on_failure: FailurePolicy,
+    /// max body size in bytes of each branch response
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+}
+
+fn default_max_body_size() -> usize {
+    1024 * 1024
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BranchConfig {
+    pub name: String,
+    /// default to the upstream of route
+    #[serde(default)]
+    pub upstream_id: Option<String>,
+    /// path and query sent to upstream, variables and path params rendered,
+    /// absent keeps the request uri
+    #[serde(default)]
+    pub uri: Option<String>,
+    /// timeout in milliseconds, 0 means bounded by route timeout only
+    #[serde(default)]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// any failed branch fails the whole request with 502
+    Fail,
+    /// failed branches are `null`, errors reported in `_errors`
+    Partial,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        FailurePolicy::Fail
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Branch {
+    name: String,
+    upstream_id: String,
+    uri: Option<Template>,
+    timeout: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    branches: Vec<Branch>,
+    template: Option<JsonTemplate>,
+    on_failure: FailurePolicy,
+    max_body_size: usize,
+}
+
+impl Aggregate {
+    pub fn new(cfg: &AggregateConfig, upstream_id: &str) -> Result<Self, ConfigError> {
+        if cfg.branches.is_empty() {
+            return Err(ConfigError::Message("aggregate without branches".to_string()));
+        }
+
+        let mut branches: Vec<Branch> = Vec::with_capacity(cfg.branches.len());
+
+        for b in &cfg.branches {
+            if branches.iter().any(|exist| exist.name == b.name) {
+                return Err(ConfigError::Message(format!(
+                    "duplicate aggregate branch<{}>",
+                    b.name
+                )));
+            }
+
+            let uri = match b.uri {
+                Some(ref uri) => Some(Template::parse_path(uri)?),
+                None => None,
+            };
+
+            branches.push(Branch {
+                name: b.name.clone(),
+                upstream_id: b
+                    .upstream_id
+                    .clone()
+                    .unwrap_or_else(|| upstream_id.to_string()),
+                uri,
+                timeout: Some(Duration::from_millis(b.timeout)).filter(|t| !t.is_zero()),
+            });
+        }
+
+        let template = match cfg.template {
+            Some(ref tpl) => Some(JsonTemplate::parse(tpl)?),
+            None => None,
+        };
+
+        Ok(Aggregate {
+            branches,
+            template,
+            on_failure: cfg.on_failure,
+            max_body_size: cfg.max_body_size,
+        })
+    }
+
+    pub fn upstream_ids(&self) -> impl Iterator<Item = &str> {
+        self.branches.iter().map(|b| b.upstream_id.as_str())
+    }
+
+    /// Call all branches concurrently, branch requests are `GET` with headers of `req`.
+    pub async fn run(
+        &self,
+        ctx: &GatewayContext,
+        upstreams: &UpstreamMap,
+        req: &HyperRequest,
+    ) -> HyperResponse {
+        let results = futures::future::join_all(
+            self.branches
+                .iter()
+                .map(|b| self.call_branch(b, ctx, upstreams, req)),
+        )
+        .await;
+
+        let mut merged = Map::new();
+        let mut errors = Map::new();
+
+        for (branch, ret) in self.branches.iter().zip(results) {
+            match ret {
+                Ok(value) => {
+                    merged.insert(branch.name.clone(), value);
+                }
+                Err(err) => {
+                    tracing::error!(
+                        route_id = ?ctx.route_id,
+                        branch = %branch.name,
+                        %err,
+                        "aggregate branch failed"
+                    );
+
+                    if self.on_failure == FailurePolicy::Fail {
+                        return bad_gateway();
+                    }
+
+                    merged.insert(branch.name.clone(), Value::Null);
+                    errors.insert(branch.name.clone(), Value::String(err));
+                }
+            }
+        }
+
+        let merged = Value::Object(merged);
+        let mut output = match self.template {
+            Some(ref tpl) => tpl.render(&merged),
+            None => merged,
+        };
+
+        if !errors.is_empty() {
+            if let Value::Object(ref mut output) = output {
+                output.insert("_errors".to_string(), Value::Object(errors));
+            }
+        }
+
+        hyper::Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&output).unwrap_or_default()))
+            .unwrap()
+    }
+
+    async fn call_branch(
+        &self,
+        branch: &Branch,
+        ctx: &GatewayContext,
+        upstreams: &UpstreamMap,
+        req: &HyperRequest,
+    ) -> Result<Value, String> {
+        let uri = match branch.uri {
+            Some(ref tpl) => tpl.render(ctx, req),
+            None => req
+                .uri()
+                .path_and_query()
+                .map(|pq| pq.to_string())
+                .unwrap_or_else(|| "/".to_string()),
+        };
+
+        let mut branch_req = hyper::Request::builder()
+            .method(Method::GET)
+            .uri(uri)
+            .body(Body::empty())
+            .map_err(|e| e.to_string())?;
+
+        *branch_req.headers_mut() = req.headers().clone();
+        branch_req.headers_mut().remove(CONTENT_LENGTH);
+        branch_req.headers_mut().remove(CONTENT_TYPE);
+        branch_req.headers_mut().remove(TRANSFER_ENCODING);
+
+        let mut branch_ctx = GatewayContext::new(ctx.remote_addr(), ctx.orig_scheme().clone(), req);
+        branch_ctx.route_id = ctx.route_id.clone();
+        branch_ctx.upstream_id = Some(branch.upstream_id.clone());
+        branch_ctx.path_params = ctx.path_params.clone();
+        branch_ctx.vars = ctx.vars.clone();
+
+        let mut forwarder = match upstreams.get(&branch.upstream_id) {
+            Some(upstream) => upstream.read().unwrap().forwarder(&mut branch_ctx),
+            None => return Err(format!("upstream<{}> not found", branch.upstream_id)),
+        };
+
+        let forward = forwarder.forward(&mut branch_ctx, branch_req);
+        let resp = match branch.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, forward)
+                .await
+                .map_err(|_| "timeout".to_string())?,
+            None => forward.await,
+        }
+        .map_err(|e| e.to_string())?;
+
+        if !resp.status().is_success() {
+            return Err(format!("status {}", resp.status()));
+        }
+
+        let body = read_body(resp.into_body(), self.max_body_size)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        serde_json::from_slice(&body).map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,543 @@
+//! Alert rules on latency, size and error rate of routes and upstreams.
+//!
+//! Only targets with rules are recorded. Rules are evaluated periodically, alerts
+//! firing and resolving are logged and posted to webhooks.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use hyper::{StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+
+use crate::error::ConfigError;
+use crate::slo::spawn_webhook;
+
+/// seconds of a bucket of series
+const BUCKET_SECS: u64 = 10;
+/// histogram buckets per doubling of value, about 19% apart
+const HISTOGRAM_PRECISION: f64 = 4.0;
+
+lazy_static::lazy_static! {
+    static ref G_ALERT_MANAGER: AlertManager = AlertManager::default();
+}
+
+pub fn alert_manager() -> &'static AlertManager {
+    &G_ALERT_MANAGER
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AlertConfig {
+    #[serde(default)]
+    pub rules: Vec<AlertRule>,
+    /// urls posted with alerts when they fire or resolve
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    /// seconds between evaluations of rules
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        AlertConfig {
+            rules: Vec::new(),
+            webhooks: Vec::new(),
+            interval: default_interval(),
+        }
+    }
+}
+
+fn default_interval() -> u64 {
+    10
+}
+
+/// Alert when `metric` of `target` stays above `threshold` for `duration`.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    pub target: AlertTarget,
+    pub metric: AlertMetric,
+    /// milliseconds for latency, bytes for sizes, ratio for error rate
+    pub threshold: f64,
+    /// seconds above threshold before firing, 0 fires at once
+    #[serde(default)]
+    pub duration: u64,
+    /// seconds of requests the metric is computed over
+    #[serde(default = "default_window")]
+    pub window: u64,
+    /// never fire on fewer requests in window
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u64,
+}
+
+fn default_window() -> u64 {
+    60
+}
+
+fn default_min_requests() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertTarget {
+    Route(String),
+    Upstream(String),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    LatencyP50,
+    LatencyP90,
+    LatencyP99,
+    /// ratio of 5xx responses
+    ErrorRate,
+    RequestSizeP99,
+    ResponseSizeP99,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    Inactive,
+    /// above threshold, not for `duration` yet
+    Pending,
+    Firing,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertStatus {
+    pub rule: AlertRule,
+    pub state: AlertState,
+    /// unix time in seconds the state began
+    pub since: u64,
+    /// metric at last evaluation, `None` when too few requests
+    pub value: Option<f64>,
+}
+
+/// Posted to webhooks.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertNotification {
+    /// `firing` or `resolved`
+    pub event: &'static str,
+    #[serde(flatten)]
+    pub alert: AlertStatus,
+}
+
+/// What is recorded of a forwarded request.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub status: StatusCode,
+    pub latency: Duration,
+    /// content-length of request, if any
+    pub request_size: Option<u64>,
+    pub response_size: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct AlertManager {
+    webhooks: RwLock<Vec<String>>,
+    interval: RwLock<Duration>,
+    routes: RwLock<HashMap<String, Arc<Mutex<Series>>>>,
+    upstreams: RwLock<HashMap<String, Arc<Mutex<Series>>>>,
+    alerts: Mutex<Vec<AlertStatus>>,
+}
+
+impl AlertManager {
+    /// Replace rules, series and states of alerts are reset.
+    pub fn set_config(&self, cfg: &AlertConfig) -> Result<(), ConfigError> {
+        for rule in &cfg.rules {
+            if rule.window == 0 || !rule.threshold.is_finite() {
+                return Err(ConfigError::Message(format!(
+                    "invalid alert rule<{}>, window and threshold required",
+                    rule.name
+                )));
+            }
+        }
+        for url in &cfg.webhooks {
+            url.parse::<Uri>()?;
+        }
+
+        let mut routes = HashMap::new();
+        let mut upstreams = HashMap::new();
+        for rule in &cfg.rules {
+            let series = match rule.target {
+                AlertTarget::Route(ref id) => routes.entry(id.clone()),
+                AlertTarget::Upstream(ref id) => upstreams.entry(id.clone()),
+            }
+            .or_insert_with(|| Arc::new(Mutex::new(Series::default())));
+
+            let mut series = series.lock().unwrap();
+            series.window = series.window.max(rule.window);
+        }
+
+        let now = unix_secs();
+        let alerts = cfg
+            .rules
+            .iter()
+            .map(|rule| AlertStatus {
+                rule: rule.clone(),
+                state: AlertState::Inactive,
+                since: now,
+                value: None,
+            })
+            .collect();
+
+        *self.webhooks.write().unwrap() = cfg.webhooks.clone();
+        *self.interval.write().unwrap() = Duration::from_secs(cfg.interval);
+        *self.routes.write().unwrap() = routes;
+        *self.upstreams.write().unwrap() = upstreams;
+        *self.alerts.lock().unwrap() = alerts;
+
+        Ok(())
+    }
+
+    pub fn record(&self, route_id: &str, upstream_id: &str, sample: Sample) {
+        self.record_at(route_id, upstream_id, sample, unix_secs());
+    }
+
+    fn record_at(&self, route_id: &str, upstream_id: &str, sample: Sample, now: u64) {
+        let route = self.routes.read().unwrap().get(route_id).cloned();
+        let upstream = self.upstreams.read().unwrap().get(upstream_id).cloned();
+
+        for series in route.iter().chain(upstream.iter()) {
+            series.lock().unwrap().record(&sample, now);
+        }
+    }
+
+    /// Current state of every rule.
+    pub fn alerts(&self) -> Vec<AlertStatus> {
+        self.alerts.lock().unwrap().clone()
+    }
+
+    /// Evaluate rules, notify alerts changed between firing and resolved.
+    pub fn evaluate(&self) {
+        let notifications = self.evaluate_at(unix_secs());
+        let webhooks = self.webhooks.read().unwrap();
+
+        for notification in notifications {
+            let alert = &notification.alert;
+            if notification.event == "firing" {
+                tracing::warn!(rule = %alert.rule.name, value = ?alert.value, "alert firing");
+            } else {
+                tracing::info!(rule = %alert.rule.name, value = ?alert.value, "alert resolved");
+            }
+
+            for url in webhooks.iter() {
+                spawn_webhook(url.clone(), &notification);
+            }
+        }
+    }
+
+    fn evaluate_at(&self, now: u64) -> Vec<AlertNotification> {
+        let mut alerts = self.alerts.lock().unwrap();
+        let mut notifications = Vec::new();
+
+        for alert in alerts.iter_mut() {
+            let series = match alert.rule.target {
+                AlertTarget::Route(ref id) => self.routes.read().unwrap().get(id).cloned(),
+                AlertTarget::Upstream(ref id) => self.upstreams.read().unwrap().get(id).cloned(),
+            };
+
+            alert.value = series.and_then(|s| s.lock().unwrap().value(&alert.rule, now));
+            let above = matches!(alert.value, Some(v) if v > alert.rule.threshold);
+
+            let next = match (alert.state, above) {
+                (AlertState::Inactive, true) => AlertState::Pending,
+                (state, true) => state,
+                (_, false) => AlertState::Inactive,
+            };
+            if next != alert.state {
+                alert.since = now;
+            }
+
+            let next = if next == AlertState::Pending
+                && now.saturating_sub(alert.since) >= alert.rule.duration
+            {
+                alert.since = now;
+                AlertState::Firing
+            } else {
+                next
+            };
+
+            let event = match (alert.state, next) {
+                (AlertState::Firing, AlertState::Inactive) => Some("resolved"),
+                (state, AlertState::Firing) if state != AlertState::Firing => Some("firing"),
+                _ => None,
+            };
+            alert.state = next;
+
+            if let Some(event) = event {
+                notifications.push(AlertNotification {
+                    event,
+                    alert: alert.clone(),
+                });
+            }
+        }
+
+        notifications
+    }
+
+    pub fn interval(&self) -> Duration {
+        (*self.interval.read().unwrap()).max(Duration::from_secs(1))
+    }
+}
+
+/// Evaluate alert rules periodically.
+pub fn spawn_evaluator() {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(alert_manager().interval()).await;
+            alert_manager().evaluate();
+        }
+    });
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    /// bucket index since unix epoch
+    index: u64,
+    total: u64,
+    errors: u64,
+    latency: Histogram,
+    request_size: Histogram,
+    response_size: Histogram,
+}
+
+/// Buckets of the widest window of rules on a target.
+#[derive(Debug, Default)]
+struct Series {
+    /// seconds
+    window: u64,
+    buckets: VecDeque<Bucket>,
+}
+
+impl Series {
+    fn record(&mut self, sample: &Sample, now: u64) {
+        let index = now / BUCKET_SECS;
+        let kept = self.window / BUCKET_SECS + 1;
+        while matches!(self.buckets.front(), Some(b) if b.index + kept <= index) {
+            self.buckets.pop_front();
+        }
+
+        if !matches!(self.buckets.back(), Some(b) if b.index == index) {
+            self.buckets.push_back(Bucket {
+                index,
+                ..Default::default()
+            });
+        }
+        let bucket = self.buckets.back_mut().unwrap();
+
+        bucket.total += 1;
+        bucket.errors += sample.status.is_server_error() as u64;
+        bucket.latency.add(sample.latency.as_millis() as u64);
+        if let Some(size) = sample.request_size {
+            bucket.request_size.add(size);
+        }
+        if let Some(size) = sample.response_size {
+            bucket.response_size.add(size);
+        }
+    }
+
+    /// Metric of requests in `rule.window` till `now`, `None` when too few.
+    fn value(&self, rule: &AlertRule, now: u64) -> Option<f64> {
+        let first = (now / BUCKET_SECS + 1).saturating_sub(rule.window / BUCKET_SECS + 1);
+        let buckets = self.buckets.iter().filter(|b| b.index >= first);
+
+        let merged = buckets.fold(Bucket::default(), |mut m, b| {
+            m.total += b.total;
+            m.errors += b.errors;
+            m.latency.merge(&b.latency);
+            m.request_size.merge(&b.request_size);
+            m.response_size.merge(&b.response_size);
+            m
+        });
+
+        let (histogram, quantile) = match rule.metric {
+            AlertMetric::ErrorRate if merged.total >= rule.min_requests.max(1) => {
+                return Some(merged.errors as f64 / merged.total as f64);
+            }
+            AlertMetric::ErrorRate => return None,
+            AlertMetric::LatencyP50 => (&merged.latency, 0.5),
+            AlertMetric::LatencyP90 => (&merged.latency, 0.9),
+            AlertMetric::LatencyP99 => (&merged.latency, 0.99),
+            AlertMetric::RequestSizeP99 => (&merged.request_size, 0.99),
+            AlertMetric::ResponseSizeP99 => (&merged.response_size, 0.99),
+        };
+
+        if histogram.count < rule.min_requests.max(1) {
+            return None;
+        }
+        histogram.quantile(quantile)
+    }
+}
+
+/// Log-scaled histogram, quantiles are upper bounds of buckets.
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    count: u64,
+    buckets: BTreeMap<u32, u64>,
+}
+
+impl Histogram {
+    fn index(value: u64) -> u32 {
+        if value == 0 {
+            0
+        } else {
+            ((value as f64).log2() * HISTOGRAM_PRECISION).floor() as u32 + 1
+        }
+    }
+
+    fn upper_bound(index: u32) -> f64 {
+        if index == 0 {
+            0.0
+        } else {
+            2f64.powf(index as f64 / HISTOGRAM_PRECISION)
+        }
+    }
+
+    fn add(&mut self, value: u64) {
+        self.count += 1;
+        *self.buckets.entry(Self::index(value)).or_default() += 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        self.count += other.count;
+        for (index, count) in &other.buckets {
+            *self.buckets.entry(*index).or_default() += count;
+        }
+    }
+
+    fn quantile(&self, q: f64) -> Option<f64> {
+        let rank = (self.count as f64 * q).ceil().max(1.0) as u64;
+
+        let mut seen = 0;
+        for (index, count) in &self.buckets {
+            seen += count;
+            if seen >= rank {
+                return Some(Self::upper_bound(*index));
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(status: u16, latency: u64) -> Sample {
+        Sample {
+            status: StatusCode::from_u16(status).unwrap(),
+            latency: Duration::from_millis(latency),
+            request_size: Some(100),
+            response_size: None,
+        }
+    }
+
+    fn states(manager: &AlertManager) -> Vec<AlertState> {
+        manager.alerts().iter().map(|a| a.state).collect()
+    }
+
+    #[test]
+    fn alert_rules() {
+        let cfg: AlertConfig = serde_json::from_value(serde_json::json!({
+            "rules": [{
+                "name": "users slow",
+                "target": { "route": "users" },
+                "metric": "latency_p99",
+                "threshold": 500.0,
+                "duration": 30
+            }, {
+                "name": "backend errors",
+                "target": { "upstream": "backend" },
+                "metric": "error_rate",
+                "threshold": 0.05
+            }]
+        }))
+        .unwrap();
+
+        let manager = AlertManager::default();
+        manager.set_config(&cfg).unwrap();
+
+        let now = 1_000_000;
+        for _ in 0..98 {
+            manager.record_at("users", "backend", sample(200, 20), now);
+        }
+        manager.record_at("users", "backend", sample(502, 900), now);
+        manager.record_at("users", "backend", sample(502, 900), now);
+        // no rules on them, not recorded
+        manager.record_at("other", "other", sample(500, 900), now);
+        assert!(manager.routes.read().unwrap().get("other").is_none());
+
+        // p99 is 900ms, pending for its duration; 2% errors are fine
+        assert!(manager.evaluate_at(now).is_empty());
+        assert_eq!(
+            states(&manager),
+            vec![AlertState::Pending, AlertState::Inactive]
+        );
+        let p99 = manager.alerts()[0].value.unwrap();
+        assert!((900.0..900.0 * 1.2).contains(&p99), "{}", p99);
+
+        for _ in 0..10 {
+            manager.record_at("users", "backend", sample(503, 10), now + 10);
+        }
+        let notifications = manager.evaluate_at(now + 30);
+        let fired: Vec<_> = notifications
+            .iter()
+            .map(|n| (n.event, n.alert.rule.name.as_str()))
+            .collect();
+        assert_eq!(
+            fired,
+            vec![("firing", "users slow"), ("firing", "backend errors")]
+        );
+        assert_eq!(
+            states(&manager),
+            vec![AlertState::Firing, AlertState::Firing]
+        );
+
+        // requests out of window, too few to judge
+        let notifications = manager.evaluate_at(now + 200);
+        assert_eq!(notifications.len(), 2);
+        assert!(notifications.iter().all(|n| n.event == "resolved"));
+        assert_eq!(
+            states(&manager),
+            vec![AlertState::Inactive, AlertState::Inactive]
+        );
+        assert!(manager.evaluate_at(now + 210).is_empty());
+    }
+
+    #[test]
+    fn histogram_quantile() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+
+        for value in 1..=1000 {
+            histogram.add(value);
+        }
+        for (q, expected) in [(0.5, 500.0), (0.9, 900.0), (0.99, 990.0)] {
+            let value = histogram.quantile(q).unwrap();
+            assert!(
+                value >= expected && value < expected * 1.2,
+                "{} {}",
+                q,
+                value
+            );
+        }
+
+        histogram.add(0);
+        assert_eq!(Histogram::upper_bound(Histogram::index(0)), 0.0);
+    }
+}
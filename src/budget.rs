@@ -0,0 +1,131 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+lazy_static::lazy_static! {
+    static ref G_MEMORY_BUDGET: MemoryBudget = MemoryBudget::new(0);
+}
+
+/// Global memory budget shared by buffering plugins.
+pub fn memory_budget() -> &'static MemoryBudget {
+    &G_MEMORY_BUDGET
+}
+
+/// Byte budget for buffered bodies, a limit of 0 means unlimited.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: AtomicUsize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit: AtomicUsize::new(limit),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Used bytes relative to the limit, 0.0 when unlimited.
+    pub fn pressure(&self) -> f64 {
+        match self.limit() {
+            0 => 0.0,
+            limit => self.used() as f64 / limit as f64,
+        }
+    }
+
+    /// Try to reserve `size` bytes, the reservation is released when the permit dropped.
+    pub fn try_reserve(&self, size: usize) -> Option<BudgetPermit<'_>> {
+        let limit = self.limit();
+
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let next = used.checked_add(size)?;
+            if limit != 0 && next > limit {
+                tracing::warn!(used, size, limit, "memory budget exhausted");
+                crate::statsd::record_budget_rejection();
+                return None;
+            }
+
+            match self
+                .used
+                .compare_exchange_weak(used, next, Ordering::AcqRel, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(actual) => used = actual,
+            }
+        }
+
+        Some(BudgetPermit { budget: self, size })
+    }
+
+    fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::AcqRel);
+    }
+}
+
+#[derive(Debug)]
+pub struct BudgetPermit<'a> {
+    budget: &'a MemoryBudget,
+    size: usize,
+}
+
+impl<'a> BudgetPermit<'a> {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Give back bytes reserved more than `size`, like when the body is smaller than estimated.
+    pub fn shrink(&mut self, size: usize) {
+        if size < self.size {
+            self.budget.release(self.size - size);
+            self.size = size;
+        }
+    }
+}
+
+impl<'a> Drop for BudgetPermit<'a> {
+    fn drop(&mut self) {
+        self.budget.release(self.size);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(100);
+
+        let p1 = budget.try_reserve(60).unwrap();
+        assert!(budget.try_reserve(50).is_none());
+        assert_eq!(budget.used(), 60);
+        assert!((budget.pressure() - 0.6).abs() < f64::EPSILON);
+
+        drop(p1);
+        assert_eq!(budget.used(), 0);
+
+        let mut p2 = budget.try_reserve(100).unwrap();
+        p2.shrink(30);
+        assert_eq!((p2.size(), budget.used()), (30, 30));
+        p2.shrink(50);
+        assert_eq!(budget.used(), 30);
+        drop(p2);
+        assert_eq!(budget.used(), 0);
+
+        let unlimited = MemoryBudget::new(0);
+        let _p = unlimited.try_reserve(usize::MAX / 2).unwrap();
+        assert_eq!(unlimited.pressure(), 0.0);
+    }
+}
@@ -0,0 +1,69 @@
+//! Faults injected into registry loads and publishes, for testing that the data plane keeps
+//! serving the last good config. Only built with the `chaos` feature.
+//!
+//! Faults apply to the current thread, so tests running in parallel do not interfere.
+
+use std::{cell::RefCell, time::Duration};
+
+use crate::error::ConfigError;
+
+#[derive(Debug, Default)]
+struct Faults {
+    publish_delay: Option<Duration>,
+    failing_publishes: u32,
+    failing_loads: u32,
+}
+
+thread_local! {
+    static FAULTS: RefCell<Faults> = RefCell::new(Faults::default());
+}
+
+/// Delay every publish, readers keep the previous registry meanwhile.
+pub fn delay_publishes(delay: Duration) {
+    FAULTS.with(|f| f.borrow_mut().publish_delay = Some(delay));
+}
+
+/// Fail the next `n` publishes, pending changes stay until a publish succeeds.
+pub fn fail_publishes(n: u32) {
+    FAULTS.with(|f| f.borrow_mut().failing_publishes = n);
+}
+
+/// Fail the next `n` loads from provider, like a partial etcd outage.
+pub fn fail_loads(n: u32) {
+    FAULTS.with(|f| f.borrow_mut().failing_loads = n);
+}
+
+pub fn reset() {
+    FAULTS.with(|f| *f.borrow_mut() = Faults::default());
+}
+
+pub(crate) fn before_publish() {
+    if let Some(delay) = FAULTS.with(|f| f.borrow().publish_delay) {
+        std::thread::sleep(delay);
+    }
+}
+
+pub(crate) fn check_publish() -> Result<(), ConfigError> {
+    take_failure(|f| &mut f.failing_publishes, "publish")
+}
+
+pub(crate) fn check_load() -> Result<(), ConfigError> {
+    take_failure(|f| &mut f.failing_loads, "load")
+}
+
+fn take_failure(counter: fn(&mut Faults) -> &mut u32, what: &str) -> Result<(), ConfigError> {
+    FAULTS.with(|f| {
+        let mut faults = f.borrow_mut();
+        let remaining = counter(&mut faults);
+
+        if *remaining == 0 {
+            return Ok(());
+        }
+
+        *remaining -= 1;
+        Err(ConfigError::Message(format!(
+            "chaos: registry {} failed",
+            what
+        )))
+    })
+}
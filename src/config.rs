@@ -1,13 +1,23 @@
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
+    sync::RwLock,
 };
 
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
+use crate::aggregate::AggregateConfig;
+use crate::alert::AlertConfig;
+use crate::daemon::DaemonConfig;
 use crate::error::{unsupport_file, ConfigError};
-use crate::health::HealthConfig;
+use crate::health::{HealthConfig, WarmupConfig};
+use crate::limiter::PriorityClass;
+use crate::protocol::ProtocolConfig;
+use crate::slo::SloConfig;
+use crate::statsd::StatsdConfig;
+use crate::store::StoreConfig;
+use crate::telemetry::TelemetryConfig;
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct Config {
@@ -19,22 +29,69 @@ pub struct Config {
 }
 
 impl Config {
+    /// Load main config, its directory is where relative paths resolve, see `resolve_path`.
     pub fn load_file(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
-        load_file(path)
+        let path = path.as_ref();
+        let cfg: Config = load_file(path)?;
+        cfg.server.buffer.validate()?;
+        cfg.server.tls_session.validate()?;
+        cfg.server.tls_provider.validate(&cfg.server.tls_config)?;
+
+        set_config_dir(path.parent().unwrap_or_else(|| Path::new("")));
+
+        Ok(cfg)
     }
 }
 
+lazy_static::lazy_static! {
+    static ref G_CONFIG_DIR: RwLock<PathBuf> = RwLock::new(PathBuf::new());
+}
+
+pub fn set_config_dir(dir: &Path) {
+    *G_CONFIG_DIR.write().unwrap() = dir.to_path_buf();
+}
+
+/// Relative `path` resolved against directory of main config file, absolute ones kept.
+pub fn resolve_path(path: &Path) -> PathBuf {
+    G_CONFIG_DIR.read().unwrap().join(path)
+}
+
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct AdminConfig {
     pub enable: bool,
     pub adminapi_addr: String,
     pub users: Vec<User>,
+    /// enable debug endpoints, like profiling and runtime stats
+    #[serde(default)]
+    pub debug_endpoints: bool,
+    /// file to persist users managed by admin api
+    #[serde(default)]
+    pub users_path: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct User {
     pub username: String,
     pub password: String,
+    /// users of config files written before roles existed are admins
+    #[serde(default = "legacy_role")]
+    pub role: Role,
+    /// user must change password before other operations
+    #[serde(default)]
+    pub must_reset_password: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Operator,
+    #[default]
+    Viewer,
+}
+
+fn legacy_role() -> Role {
+    Role::Admin
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -43,12 +100,219 @@ pub struct ServerConfig {
     pub http_addr: String,
     pub https_addr: String,
     pub tls_config: HashMap<String, TlsConfig>,
+    #[serde(default)]
+    pub tls_session: TlsSessionConfig,
+    /// TLS implementation of https listener and upstream clients
+    #[serde(default)]
+    pub tls_provider: TlsProvider,
+    /// warn when a certificate expires within days
+    #[serde(default = "default_cert_expiry_warn_days")]
+    pub cert_expiry_warn_days: u64,
+    #[serde(default)]
+    pub buffer: BufferConfig,
+    /// memory budget in bytes for buffering plugins, 0 means unlimited
+    #[serde(default)]
+    pub memory_budget: usize,
+    /// interval in seconds to log runtime worker utilization, 0 means disabled
+    #[serde(default)]
+    pub runtime_metrics_interval: u64,
+    /// number of SO_REUSEPORT acceptor sockets, 0 or 1 means single accept loop
+    #[serde(default)]
+    pub acceptors: usize,
+    /// storage backend shared by stateful plugins
+    #[serde(default)]
+    pub store: StoreConfig,
+    #[serde(default)]
+    pub match_trace: MatchTraceConfig,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    /// push request metrics to StatsD, disabled when not set
+    #[serde(default)]
+    pub statsd: Option<StatsdConfig>,
+    /// export request spans by OpenTelemetry, disabled when not set
+    #[serde(default)]
+    pub telemetry: Option<TelemetryConfig>,
+    #[serde(default)]
+    pub alerts: AlertConfig,
+    #[serde(default)]
+    pub plugin_load_mode: PluginLoadMode,
+    #[serde(default)]
+    pub plugin_metrics: PluginMetricsConfig,
+    /// template of access log lines, like `$remote_addr "$method $request_uri" $route_id`,
+    /// logged with status and latency, disabled when not set
+    #[serde(default)]
+    pub access_log: Option<String>,
+}
+
+/// How routes treat plugins unknown to this gateway build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PluginLoadMode {
+    /// the whole registry config fails to load
+    #[default]
+    Strict,
+    /// plugin is skipped with a warning, like when rolling configs across versions
+    Lenient,
+}
+
+/// Timing of plugins in dispatch, kept in `PluginTimings` and sent to statsd.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PluginMetricsConfig {
+    #[serde(default)]
+    pub enable: bool,
+    /// warn about plugins slower than this in milliseconds, 0 means disabled
+    #[serde(default)]
+    pub slow_threshold: u64,
+}
+
+/// Debug log of requests which hit a route uri but failed its matcher.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MatchTraceConfig {
+    /// trace all routes, otherwise only routes with `trace_match`
+    #[serde(default)]
+    pub enable: bool,
+    /// ratio of near misses logged
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+impl Default for MatchTraceConfig {
+    fn default() -> Self {
+        MatchTraceConfig {
+            enable: false,
+            sample_rate: default_sample_rate(),
+        }
+    }
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BufferConfig {
+    /// max http1 read buffer size in bytes
+    #[serde(default)]
+    pub http1_max_buf_size: Option<usize>,
+    /// http2 stream-level flow control window in bytes
+    #[serde(default)]
+    pub http2_stream_window_size: Option<u32>,
+    /// http2 connection-level flow control window in bytes
+    #[serde(default)]
+    pub http2_connection_window_size: Option<u32>,
+}
+
+/// hyper panics on smaller http1 read buffer
+const MIN_HTTP1_BUF_SIZE: usize = 8192;
+
+impl BufferConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match self.http1_max_buf_size {
+            Some(size) if size < MIN_HTTP1_BUF_SIZE => Err(ConfigError::Message(format!(
+                "http1_max_buf_size<{}> should be at least {}",
+                size, MIN_HTTP1_BUF_SIZE
+            ))),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct TlsConfig {
     pub cert_path: PathBuf,
     pub key_path: PathBuf,
+    /// DER encoded OCSP response to staple
+    #[serde(default)]
+    pub ocsp_path: Option<PathBuf>,
+}
+
+/// TLS implementation, for deployments with compliance requirements on crypto libraries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsProvider {
+    #[default]
+    Rustls,
+    /// library of the system like OpenSSL or Schannel, needs the `native-tls` feature;
+    /// serves a single certificate without OCSP stapling, session tickets or HTTP/2,
+    /// upstream clients speak HTTP/1 without `tls_pins` or `upstream_host` as SNI
+    NativeTls,
+}
+
+impl TlsProvider {
+    pub fn validate(&self, tls_config: &HashMap<String, TlsConfig>) -> Result<(), ConfigError> {
+        if *self == TlsProvider::Rustls {
+            return Ok(());
+        }
+
+        if !cfg!(feature = "native-tls") {
+            return Err(ConfigError::Message(
+                "build without `native-tls` feature".to_string(),
+            ));
+        }
+        if tls_config.len() > 1 {
+            return Err(ConfigError::Message(
+                "native_tls provider serves a single certificate".to_string(),
+            ));
+        }
+        if tls_config.values().any(|tls| tls.ocsp_path.is_some()) {
+            return Err(ConfigError::Message(
+                "OCSP stapling needs rustls provider".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TlsSessionConfig {
+    /// max sessions cached for session id resumption
+    #[serde(default = "default_session_cache_size")]
+    pub cache_size: usize,
+    /// enable session ticket resumption
+    #[serde(default = "default_true")]
+    pub tickets: bool,
+    /// ticket key rotation interval in seconds
+    #[serde(default = "default_ticket_rotation")]
+    pub ticket_rotation: u64,
+}
+
+impl Default for TlsSessionConfig {
+    fn default() -> Self {
+        TlsSessionConfig {
+            cache_size: default_session_cache_size(),
+            tickets: true,
+            ticket_rotation: default_ticket_rotation(),
+        }
+    }
+}
+
+impl TlsSessionConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.tickets && self.ticket_rotation == 0 {
+            return Err(ConfigError::Message(
+                "ticket_rotation should be positive".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn default_session_cache_size() -> usize {
+    256
+}
+
+fn default_ticket_rotation() -> u64 {
+    6 * 60 * 60
+}
+
+fn default_cert_expiry_warn_days() -> u64 {
+    30
+}
+
+fn default_true() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -59,6 +323,15 @@ pub enum RegistryProvider {
     File(FileProvider),
 }
 
+impl RegistryProvider {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RegistryProvider::Etcd(_) => "etcd",
+            RegistryProvider::File(_) => "file",
+        }
+    }
+}
+
 impl Default for RegistryProvider {
     fn default() -> Self {
         RegistryProvider::File(FileProvider {
@@ -79,27 +352,101 @@ pub struct FileProvider {
     pub path: PathBuf,
 }
 
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct RouteConfig {
     #[serde(default)]
     pub id: String,
     pub name: String,
     pub desc: String,
+    /// disabled route is kept in config but never matched
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// hosts like `a.example.com` or `*.example.com`, empty for any host
+    #[serde(default)]
+    pub hosts: Vec<String>,
     pub uris: Vec<String>,
     pub upstream_id: String,
     #[serde(default)]
     pub overwrite_host: bool,
+    /// host header sent to upstream regardless of endpoint, takes precedence over `overwrite_host`,
+    /// also SNI of https endpoints
+    #[serde(default)]
+    pub upstream_host: Option<String>,
     #[serde(default)]
     pub matcher: String,
     #[serde(default)]
     pub priority: u32,
     #[serde(default)]
     pub plugins: HashMap<String, PluginConfig>,
+    /// time budget in milliseconds of the whole request, 0 means no timeout
+    #[serde(default)]
+    pub timeout: u64,
+    /// header carrying remaining budget on timeout responses, like `x-timeout-remaining`
+    #[serde(default)]
+    pub timeout_header: Option<String>,
+    /// max size in bytes of upstream response body, 0 means unlimited
+    #[serde(default)]
+    pub max_response_size: u64,
+    #[serde(default)]
+    pub slo: Option<SloConfig>,
+    /// log why requests hitting the uris failed the matcher, see `MatchTraceConfig`
+    #[serde(default)]
+    pub trace_match: bool,
+    /// fan out to upstreams and merge json responses instead of forwarding
+    #[serde(default)]
+    pub aggregate: Option<AggregateConfig>,
+    /// shed order when upstream concurrency limit reached
+    #[serde(default)]
+    pub priority_class: PriorityClass,
+    /// rune script filtering or reordering endpoints before load balance, see `EndpointSelector`
+    #[serde(default)]
+    pub endpoint_selector: Option<String>,
+    /// answer `OPTIONS` by gateway with methods allowed by matcher, and 405 to other methods
+    #[serde(default)]
+    pub auto_options: bool,
+    /// translation between client and upstream protocols, like passing websocket upgrades
+    #[serde(default)]
+    pub protocol: ProtocolConfig,
+}
+
+impl Default for RouteConfig {
+    fn default() -> Self {
+        RouteConfig {
+            id: String::new(),
+            name: String::new(),
+            desc: String::new(),
+            enabled: true,
+            hosts: Vec::new(),
+            uris: Vec::new(),
+            upstream_id: String::new(),
+            overwrite_host: false,
+            upstream_host: None,
+            matcher: String::new(),
+            priority: 0,
+            plugins: HashMap::new(),
+            timeout: 0,
+            timeout_header: None,
+            max_response_size: 0,
+            slo: None,
+            trace_match: false,
+            aggregate: None,
+            priority_class: PriorityClass::Normal,
+            endpoint_selector: None,
+            auto_options: false,
+            protocol: ProtocolConfig::default(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PluginConfig {
     pub enable: bool,
+    /// matcher expression, plugin only runs for matched requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+    /// overrides `priority()` of plugin on this route, higher runs first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<i32>,
     #[serde(flatten)]
     pub config: Value,
 }
@@ -113,6 +460,18 @@ pub struct UpstreamConfig {
     pub endpoints: Vec<EndpointConfig>,
     pub strategy: String,
     pub health_check: HealthConfig,
+    #[serde(default)]
+    pub buffer: BufferConfig,
+    /// warm up new joined endpoints before load balancing to them
+    #[serde(default)]
+    pub warmup: Option<WarmupConfig>,
+    /// max in-flight requests, 0 means unlimited, see `PriorityClass`
+    #[serde(default)]
+    pub max_concurrency: usize,
+    /// sha256 pins of SubjectPublicKeyInfo in base64, like `sha256/<base64>`,
+    /// a certificate in chain of https endpoints must match one
+    #[serde(default)]
+    pub tls_pins: Vec<String>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -179,6 +538,54 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn buffer_config() {
+        let buffer = |size| BufferConfig {
+            http1_max_buf_size: size,
+            ..Default::default()
+        };
+
+        assert!(buffer(None).validate().is_ok());
+        assert!(buffer(Some(8192)).validate().is_ok());
+        assert!(buffer(Some(4096)).validate().is_err());
+    }
+
+    #[test]
+    fn tls_session_config() {
+        let session = |tickets, ticket_rotation| TlsSessionConfig {
+            tickets,
+            ticket_rotation,
+            ..Default::default()
+        };
+
+        assert!(TlsSessionConfig::default().validate().is_ok());
+        assert!(session(true, 0).validate().is_err());
+        assert!(session(false, 0).validate().is_ok());
+    }
+
+    #[test]
+    fn tls_provider() {
+        let tls = |count: usize, ocsp: bool| {
+            (0..count)
+                .map(|i| {
+                    let tls = TlsConfig {
+                        ocsp_path: ocsp.then(|| PathBuf::from("ocsp.der")),
+                        ..Default::default()
+                    };
+                    (format!("{}.example.com", i), tls)
+                })
+                .collect::<HashMap<_, _>>()
+        };
+
+        assert!(TlsProvider::Rustls.validate(&tls(2, true)).is_ok());
+        assert!(TlsProvider::NativeTls.validate(&tls(2, false)).is_err());
+        assert!(TlsProvider::NativeTls.validate(&tls(1, true)).is_err());
+        assert_eq!(
+            TlsProvider::NativeTls.validate(&tls(1, false)).is_ok(),
+            cfg!(feature = "native-tls")
+        );
+    }
+
     #[test]
     fn plugin_config() {
         #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -236,13 +643,17 @@ mod test {
             rules: vec![TrafficSplitRule {
                 matcher: r#"PathRegexp('/hello/world/\(.*\)')"#.to_string(),
                 upstream_id: "hello-to-tom".to_string(),
+                weight: None,
             }],
+            sticky: None,
         };
 
         plugins.insert(
             "path_rewrite".to_string(),
             PluginConfig {
                 enable: true,
+                when: None,
+                order: None,
                 config: serde_json::to_value(path_rewrite).unwrap(),
             },
         );
@@ -251,6 +662,8 @@ mod test {
             "traffic_split".to_string(),
             PluginConfig {
                 enable: true,
+                when: Some("Header('x-debug', '1')".to_string()),
+                order: None,
                 config: serde_json::to_value(traffic_split).unwrap(),
             },
         );
@@ -265,11 +678,13 @@ mod test {
                     TlsConfig {
                         cert_path: PathBuf::from("example.cert"),
                         key_path: PathBuf::from("example.key"),
+                        ocsp_path: None,
                     },
                 )]
                 .iter()
                 .cloned()
                 .collect(),
+                ..Default::default()
             },
             admin: AdminConfig {
                 enable: true,
@@ -277,7 +692,11 @@ mod test {
                 users: vec![User {
                     username: "admin".to_string(),
                     password: "admin".to_string(),
+                    role: Role::Admin,
+                    must_reset_password: false,
                 }],
+                debug_endpoints: false,
+                users_path: None,
             },
             registry_provider: RegistryProvider::default(),
         };
@@ -319,6 +738,9 @@ mod test {
                     strategy: "random".to_string(),
 
                     health_check: HealthConfig::default(),
+                    buffer: BufferConfig::default(),
+                    warmup: None,
+                    max_concurrency: 0,
                 },
                 UpstreamConfig {
                     id: "upstream-002".to_string(),
@@ -330,8 +752,12 @@ mod test {
                     }],
                     strategy: "weighted".to_string(),
                     health_check: HealthConfig::default(),
+                    buffer: BufferConfig::default(),
+                    warmup: None,
+                    max_concurrency: 0,
                 },
             ],
+            ..Default::default()
         };
 
         dump_file(&registry, "config2/apireception.yaml").unwrap();
@@ -1,23 +1,99 @@
-use std::{net::SocketAddr, time::SystemTime};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime},
+};
 
-use hyper::http::{uri::Scheme, Extensions};
-use hyper::Uri;
+use hyper::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::http::{uri::Scheme, Extensions, HeaderValue};
+use hyper::{body::Bytes, Body, Uri};
 
+use crate::budget::{memory_budget, BudgetPermit, MemoryBudget};
+use crate::error::BufferError;
 use crate::http::*;
+use crate::protocol::ProtocolConfig;
 use crate::registry::Endpoint;
+use crate::upstream::UpstreamResolver;
 
+/// Identity of the authenticated consumer, set by auth plugins.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Consumer(pub String);
+
+/// Id of request, set by `request_id` plugin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+/// Bucket state admitting the request, set by `rate_limit` plugin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitDecision {
+    pub limit: u64,
+    pub remaining: u64,
+}
+
+/// Endpoint the request was forwarded to, set by forwarder for `after_forward`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectedEndpoint(pub Uri);
+
+/// Time spent by plugins, recorded by dispatch when plugin metrics are enabled.
+#[derive(Debug, Clone, Default)]
+pub struct PluginTimings(pub Vec<PluginTiming>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginTiming {
+    pub plugin: String,
+    /// `on_access` or `after_forward`
+    pub phase: &'static str,
+    pub elapsed: Duration,
+    /// plugin answered the request itself
+    pub short_circuited: bool,
+}
+
+/// Request body read by `GatewayContext::buffer_body`.
+///
+/// Plugins replacing the request body should remove it.
+#[derive(Debug, Clone)]
+pub struct BufferedRequestBody(pub Bytes);
+
+/// Memory budget of bodies buffered for the request, released with the context.
+#[derive(Debug, Default)]
+struct BudgetPermits(Vec<BudgetPermit<'static>>);
+
+/// State of a request shared by dispatch and plugins.
+///
+/// What the client sent is only readable by methods. Public fields are set by dispatch
+/// from route, those plugins may change are noted. Plugins pass values to each other by
+/// type with `set` and `get`.
 #[derive(Debug)]
 pub struct GatewayContext {
-    pub remote_addr: Option<SocketAddr>,
-    pub start_time: SystemTime,
-    pub orig_scheme: Scheme,
-    pub orig_host: Option<String>,
-    pub orig_uri: Uri,
+    remote_addr: Option<SocketAddr>,
+    start_time: SystemTime,
+    orig_scheme: Scheme,
+    orig_host: Option<String>,
+    orig_uri: Uri,
     pub route_id: Option<String>,
+    /// upstream forwarded to, plugins may change it
     pub upstream_id: Option<String>,
+    /// params captured from route uri, like `:id` in `/users/:id`
+    pub path_params: HashMap<String, String>,
     pub overwrite_host: bool,
+    /// host header sent to upstream
+    pub upstream_host: Option<HeaderValue>,
+    pub protocol: ProtocolConfig,
+    /// endpoints of upstream, endpoint selector may narrow them
     pub available_endpoints: Vec<Endpoint>,
-    pub extensions: Extensions,
+    /// resolving upstreams other than the forwarded one
+    pub upstreams: Option<UpstreamResolver>,
+    /// variables set by plugins, see `crate::variable`
+    pub vars: HashMap<String, String>,
+    /// set by plugins to route the rewritten request again instead of forwarding
+    pub internal_redirect: bool,
+    /// internal redirects happened
+    pub redirects: u32,
+    /// set by `timeout` plugin, forward is cancelled when reached
+    pub deadline: Option<Instant>,
+    /// forward cancelled by deadline or route timeout
+    pub timed_out: bool,
+    extensions: Extensions,
 }
 
 impl GatewayContext {
@@ -30,9 +106,231 @@ impl GatewayContext {
             orig_uri: req.uri().clone(),
             route_id: None,
             upstream_id: None,
+            path_params: HashMap::new(),
             overwrite_host: false,
+            upstream_host: None,
+            protocol: ProtocolConfig::default(),
             available_endpoints: Vec::new(),
+            upstreams: None,
+            vars: HashMap::new(),
+            internal_redirect: false,
+            redirects: 0,
+            deadline: None,
+            timed_out: false,
             extensions: Extensions::new(),
         }
     }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn start_time(&self) -> SystemTime {
+        self.start_time
+    }
+
+    pub fn orig_scheme(&self) -> &Scheme {
+        &self.orig_scheme
+    }
+
+    pub fn orig_host(&self) -> Option<&str> {
+        self.orig_host.as_deref()
+    }
+
+    /// Uri as sent by client, before rewrites and redirects.
+    pub fn orig_uri(&self) -> &Uri {
+        &self.orig_uri
+    }
+
+    /// Share `value` with later plugins and `after_forward`, the previous one of `T` is returned.
+    pub fn set<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.extensions.insert(value)
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.extensions.get::<T>()
+    }
+
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.extensions.get_mut::<T>()
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.extensions.remove::<T>()
+    }
+
+    pub fn set_var(&mut self, name: impl ToString, value: impl ToString) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    /// Keep `permit` until the request is done, for memory held by buffers of the request.
+    pub fn keep_permit(&mut self, permit: BudgetPermit<'static>) {
+        match self.get_mut::<BudgetPermits>() {
+            Some(permits) => permits.0.push(permit),
+            None => {
+                self.set(BudgetPermits(vec![permit]));
+            }
+        }
+    }
+
+    /// Read request body no larger than `limit` bytes, and put it back for forwarding.
+    /// The body is read once, later plugins get the same bytes. Its size is reserved from
+    /// memory budget until the context dropped.
+    ///
+    /// `None` when larger than `limit`, the body is then forwarded unbuffered. When memory
+    /// budget exhausted, the body is left unread. On other errors, the body is lost and the
+    /// request should be rejected.
+    pub async fn buffer_body(
+        &mut self,
+        req: &mut HyperRequest,
+        limit: usize,
+    ) -> Result<Option<Bytes>, BufferError> {
+        self.buffer_body_in(memory_budget(), req, limit).await
+    }
+
+    async fn buffer_body_in(
+        &mut self,
+        budget: &'static MemoryBudget,
+        req: &mut HyperRequest,
+        limit: usize,
+    ) -> Result<Option<Bytes>, BufferError> {
+        if let Some(BufferedRequestBody(bytes)) = self.get::<BufferedRequestBody>() {
+            return Ok(Some(bytes.clone()).filter(|bytes| bytes.len() <= limit));
+        }
+
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+        let mut permit = budget
+            .try_reserve(content_length.map_or(limit, |len| len.min(limit)))
+            .ok_or(BufferError::BudgetExhausted)?;
+
+        match buffer_body(std::mem::take(req.body_mut()), limit).await? {
+            BufferedBody::Full(bytes) => {
+                permit.shrink(bytes.len());
+                self.keep_permit(permit);
+
+                // chunked or not, length is known now
+                let headers = req.headers_mut();
+                headers.remove(TRANSFER_ENCODING);
+                if !bytes.is_empty() || headers.contains_key(CONTENT_LENGTH) {
+                    headers.insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+                }
+
+                *req.body_mut() = Body::from(bytes.clone());
+                self.set(BufferedRequestBody(bytes.clone()));
+
+                Ok(Some(bytes))
+            }
+            BufferedBody::Partial(body) => {
+                *req.body_mut() = body;
+
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn buffer_body_once() {
+        let mut req = hyper::Request::builder()
+            .method("POST")
+            .header(TRANSFER_ENCODING, "chunked")
+            .body(Body::wrap_stream(futures::stream::iter(vec![
+                Ok::<_, std::io::Error>("hello, "),
+                Ok("world"),
+            ])))
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let first = ctx.buffer_body(&mut req, 1024).await.unwrap();
+        let second = ctx.buffer_body(&mut req, 64).await.unwrap();
+        assert_eq!(first.as_deref(), Some(&b"hello, world"[..]));
+        assert_eq!(first, second);
+
+        // cached body larger than limit of a later reader
+        assert_eq!(ctx.buffer_body(&mut req, 4).await.unwrap(), None);
+
+        assert_eq!(req.headers()[CONTENT_LENGTH], "12");
+        assert!(!req.headers().contains_key(TRANSFER_ENCODING));
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello, world");
+    }
+
+    #[tokio::test]
+    async fn buffer_body_too_large() {
+        let mut req = hyper::Request::builder()
+            .method("POST")
+            .body(Body::wrap_stream(futures::stream::iter(vec![
+                Ok::<_, std::io::Error>("hello, "),
+                Ok("world"),
+            ])))
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        assert_eq!(ctx.buffer_body(&mut req, 4).await.unwrap(), None);
+        assert!(ctx.get::<BufferedRequestBody>().is_none());
+
+        // chunks read are put back, a larger limit still gets all
+        let body = ctx.buffer_body(&mut req, 1024).await.unwrap();
+        assert_eq!(body.as_deref(), Some(&b"hello, world"[..]));
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello, world");
+    }
+
+    #[tokio::test]
+    async fn buffer_body_budget() {
+        let budget: &'static MemoryBudget = Box::leak(Box::new(MemoryBudget::new(16)));
+        let request = |body: &'static str| {
+            hyper::Request::builder()
+                .method("POST")
+                .header(CONTENT_LENGTH, body.len())
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let mut req = request("hello, world");
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        let body = ctx.buffer_body_in(budget, &mut req, 16).await.unwrap();
+        assert_eq!(body.as_deref(), Some(&b"hello, world"[..]));
+        // held by the context as long as the buffered body
+        assert_eq!(budget.used(), 12);
+
+        // fits in what is left
+        let mut small = request("hey");
+        let mut small_ctx = GatewayContext::new(None, Scheme::HTTP, &small);
+        let body = small_ctx
+            .buffer_body_in(budget, &mut small, 16)
+            .await
+            .unwrap();
+        assert_eq!(body.as_deref(), Some(&b"hey"[..]));
+        assert_eq!(budget.used(), 15);
+        drop(small_ctx);
+
+        // under the limit, but more than left
+        let mut other = request("hello");
+        let mut other_ctx = GatewayContext::new(None, Scheme::HTTP, &other);
+        assert!(matches!(
+            other_ctx.buffer_body_in(budget, &mut other, 16).await,
+            Err(BufferError::BudgetExhausted)
+        ));
+        // left unread
+        let body = hyper::body::to_bytes(other.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+
+        drop(ctx);
+        assert_eq!(budget.used(), 0);
+
+        let mut other = request("hello");
+        let body = other_ctx
+            .buffer_body_in(budget, &mut other, 16)
+            .await
+            .unwrap();
+        assert_eq!(body.as_deref(), Some(&b"hello"[..]));
+    }
 }
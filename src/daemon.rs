@@ -0,0 +1,273 @@
+//! Run as a managed OS service, detached with a pid file on unix, or as a windows service.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct DaemonConfig {
+    /// detach from terminal and run in background, unix only
+    #[serde(default)]
+    pub detach: bool,
+    /// file to write pid, removed on exit
+    #[serde(default)]
+    pub pid_file: Option<PathBuf>,
+}
+
+/// Pid file, removed when dropped.
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Fail when the pid file belongs to a running process.
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        if let Ok(content) = std::fs::read_to_string(path) {
+            if let Ok(pid) = content.trim().parse::<u32>() {
+                if pid != std::process::id() && process_alive(pid) {
+                    return Err(Error::Message(format!(
+                        "already running, pid<{}> in {:?}",
+                        pid, path
+                    )));
+                }
+            }
+        }
+
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+
+        Ok(PidFile(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            tracing::warn!(path = ?self.0, %err, "remove pid file failed");
+        }
+    }
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks existence of process
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    false
+}
+
+/// Detach and write pid file as configured, must be called before starting any thread.
+pub fn daemonize(cfg: &DaemonConfig) -> Result<Option<PidFile>, Error> {
+    if cfg.detach {
+        detach()?;
+    }
+
+    cfg.pid_file.as_deref().map(PidFile::create).transpose()
+}
+
+/// Fork twice into a new session, with stdio redirected to `/dev/null`.
+#[cfg(unix)]
+fn detach() -> Result<(), Error> {
+    use std::io::Error as IoError;
+
+    // SAFETY: no other thread is running, so forked child is in a consistent state
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(IoError::last_os_error().into()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+
+        if libc::setsid() == -1 {
+            return Err(IoError::last_os_error().into());
+        }
+
+        // not a session leader, never acquire a controlling terminal again
+        match libc::fork() {
+            -1 => return Err(IoError::last_os_error().into()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+
+        let null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+        if null == -1 {
+            return Err(IoError::last_os_error().into());
+        }
+        for fd in 0..3 {
+            libc::dup2(null, fd);
+        }
+        if null > 2 {
+            libc::close(null);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn detach() -> Result<(), Error> {
+    Err(Error::Message(
+        "detach not support on this platform, install as service instead".to_string(),
+    ))
+}
+
+/// Windows service, registered with `install-service` and started by service control manager.
+#[cfg(windows)]
+pub mod service {
+    use std::{ffi::OsString, sync::Arc, time::Duration};
+
+    use tokio::sync::Notify;
+    use windows_service::{
+        define_windows_service,
+        service::{
+            ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+            ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus,
+            ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+        service_dispatcher,
+        service_manager::{ServiceManager, ServiceManagerAccess},
+    };
+
+    use crate::error::Error;
+
+    pub const SERVICE_NAME: &str = "apireception";
+
+    /// Argument passed by service control manager.
+    pub const SERVICE_ARG: &str = "--service";
+
+    fn service_error(err: windows_service::Error) -> Error {
+        Error::Message(format!("windows service error, {}", err))
+    }
+
+    pub fn install() -> Result<(), Error> {
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )
+        .map_err(service_error)?;
+
+        let info = ServiceInfo {
+            name: OsString::from(SERVICE_NAME),
+            display_name: OsString::from("apireception api gateway"),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()?,
+            launch_arguments: vec![OsString::from(SERVICE_ARG)],
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+
+        manager
+            .create_service(&info, ServiceAccess::QUERY_STATUS)
+            .map_err(service_error)?;
+
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<(), Error> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .map_err(service_error)?;
+
+        manager
+            .open_service(SERVICE_NAME, ServiceAccess::DELETE)
+            .and_then(|service| service.delete())
+            .map_err(service_error)?;
+
+        Ok(())
+    }
+
+    /// Block until the service stopped.
+    pub fn run() -> Result<(), Error> {
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(service_error)
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    fn service_main(_args: Vec<OsString>) {
+        if let Err(err) = run_service() {
+            tracing::error!(?err, "service run error");
+        }
+    }
+
+    fn run_service() -> Result<(), Error> {
+        // started in system directory, config paths are relative to executable
+        if let Some(dir) = std::env::current_exe()?.parent() {
+            std::env::set_current_dir(dir)?;
+        }
+
+        let shutdown = Arc::new(Notify::new());
+        let notify = shutdown.clone();
+
+        let status_handle =
+            service_control_handler::register(SERVICE_NAME, move |event| match event {
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    notify.notify_one();
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            })
+            .map_err(service_error)?;
+
+        let set_state = |state, controls_accepted, exit_code| {
+            status_handle.set_service_status(ServiceStatus {
+                service_type: ServiceType::OWN_PROCESS,
+                current_state: state,
+                controls_accepted,
+                exit_code: ServiceExitCode::Win32(exit_code),
+                checkpoint: 0,
+                wait_hint: Duration::from_secs(30),
+                process_id: None,
+            })
+        };
+
+        set_state(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            0,
+        )
+        .map_err(service_error)?;
+
+        // stop signal drains connections like ctrl_c
+        let ret = crate::start(async move { shutdown.notified().await });
+
+        let exit_code = if ret.is_ok() { 0 } else { 1 };
+        set_state(
+            ServiceState::Stopped,
+            ServiceControlAccept::empty(),
+            exit_code,
+        )
+        .map_err(service_error)?;
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pid_file() {
+        let path = std::env::temp_dir().join(format!("apireception-{}.pid", std::process::id()));
+
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // stale pid file is replaced
+        std::fs::write(&path, "4294967\n").unwrap();
+        let _pid_file = PidFile::create(&path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use crate::config::{MatchTraceConfig, PluginMetricsConfig};
+use crate::context::{GatewayContext, PluginTiming, PluginTimings};
+use crate::error::ConfigError;
+use crate::telemetry::TelemetryConfig;
+
+lazy_static::lazy_static! {
+    static ref G_MATCH_TRACE: RwLock<MatchTraceConfig> = RwLock::new(MatchTraceConfig::default());
+}
+
+pub fn set_match_trace(cfg: &MatchTraceConfig) {
+    *G_MATCH_TRACE.write().unwrap() = cfg.clone();
+}
+
+/// Whether to trace a near miss of route, sampled.
+pub fn match_trace_sampled(route_trace: bool) -> bool {
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return false;
+    }
+
+    let cfg = G_MATCH_TRACE.read().unwrap();
+
+    (cfg.enable || route_trace) && rand::random::<f64>() < cfg.sample_rate
+}
+
+static PLUGIN_METRICS: AtomicBool = AtomicBool::new(false);
+/// milliseconds
+static SLOW_PLUGIN_THRESHOLD: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_plugin_metrics(cfg: &PluginMetricsConfig) {
+    SLOW_PLUGIN_THRESHOLD.store(cfg.slow_threshold, Ordering::Relaxed);
+    PLUGIN_METRICS.store(cfg.enable, Ordering::Relaxed);
+}
+
+/// Checked by dispatch before timing plugins, cheap enough for every call.
+pub fn plugin_metrics_enabled() -> bool {
+    PLUGIN_METRICS.load(Ordering::Relaxed)
+}
+
+/// Keep time spent by `plugin` in `ctx` and statsd, warn when slow.
+pub fn record_plugin(
+    ctx: &mut GatewayContext,
+    plugin: &str,
+    phase: &'static str,
+    elapsed: Duration,
+    short_circuited: bool,
+) {
+    let route_id = ctx.route_id.as_deref().unwrap_or_default();
+
+    let threshold = SLOW_PLUGIN_THRESHOLD.load(Ordering::Relaxed);
+    if threshold > 0 && elapsed >= Duration::from_millis(threshold) {
+        tracing::warn!(route_id, plugin, phase, ?elapsed, "slow plugin");
+    }
+
+    crate::statsd::record_plugin(route_id, plugin, phase, elapsed, short_circuited);
+
+    let timing = PluginTiming {
+        plugin: plugin.to_string(),
+        phase,
+        elapsed,
+        short_circuited,
+    };
+    match ctx.get_mut::<PluginTimings>() {
+        Some(timings) => timings.0.push(timing),
+        None => {
+            ctx.set(PluginTimings(vec![timing]));
+        }
+    }
+}
+
+/// Init tracing subscriber, with tokio-console layer when `console` feature enabled,
+/// and OpenTelemetry layer when `telemetry` set.
+pub fn init_tracing(telemetry: Option<&TelemetryConfig>) -> Result<(), ConfigError> {
+    use tracing_subscriber::{filter::LevelFilter, prelude::*};
+
+    let otel = telemetry.map(crate::telemetry::layer).transpose()?;
+
+    #[cfg(feature = "console")]
+    tracing_subscriber::registry()
+        .with(console_subscriber::spawn())
+        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO))
+        .with(otel)
+        .init();
+
+    #[cfg(not(feature = "console"))]
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO))
+        .with(otel)
+        .init();
+
+    Ok(())
+}
+
+/// Periodically log worker utilization of current runtime.
+///
+/// Runtime metrics need `--cfg tokio_unstable`, otherwise do nothing.
+pub fn spawn_runtime_monitor(interval: Duration) {
+    if interval.is_zero() {
+        return;
+    }
+
+    #[cfg(tokio_unstable)]
+    tokio::spawn(async move {
+        let metrics = tokio::runtime::Handle::current().metrics();
+        let num_workers = metrics.num_workers();
+
+        let busy = |i| metrics.worker_total_busy_duration(i);
+
+        let mut last: Vec<Duration> = (0..num_workers).map(busy).collect();
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let curr: Vec<Duration> = (0..num_workers).map(busy).collect();
+            let utilization: Vec<f64> = curr
+                .iter()
+                .zip(last.iter())
+                .map(|(c, l)| (*c - *l).as_secs_f64() / interval.as_secs_f64())
+                .collect();
+            last = curr;
+
+            tracing::debug!(
+                ?utilization,
+                active_tasks = metrics.active_tasks_count(),
+                injection_queue_depth = metrics.injection_queue_depth(),
+                "runtime worker utilization"
+            );
+
+            if utilization.iter().all(|u| *u > 0.9) {
+                tracing::warn!(?utilization, "all runtime workers saturated");
+            }
+        }
+    });
+}
@@ -12,6 +12,15 @@ pub enum Error {
     Message(String),
 }
 
+/// Why a body was not buffered, see `GatewayContext::buffer_body`.
+#[derive(Debug, thiserror::Error)]
+pub enum BufferError {
+    #[error("memory budget exhausted")]
+    BudgetExhausted,
+    #[error("read body error: {0}")]
+    Body(#[from] hyper::Error),
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ConfigError {
     #[error("yaml config error")]
@@ -28,7 +37,7 @@ pub enum ConfigError {
     AddrParse(#[from] std::net::AddrParseError),
     #[error("parse uri error")]
     UriParse(#[from] hyper::http::uri::InvalidUri),
-    #[error("parse match error")]
+    #[error("parse matcher error: {0}")]
     MatcherParse(#[from] MatcherParseError),
     #[error("{0}")]
     Message(String),
@@ -36,28 +45,77 @@ pub enum ConfigError {
     UpstreamNotFound(String),
     #[error("unknown strategy<{0}>")]
     UnknownLBStrategy(String),
+    #[error("duplicate route id<{0}>")]
+    DuplicateRouteId(String),
+    #[error("duplicate plugin<{0}>")]
+    DuplicatePlugin(String),
+    #[error("route<{}> has invalid plugins: {}", .0, join_errors(.1))]
+    InvalidPlugins(String, Vec<PluginError>),
+    #[error("route<{0}> conflicts with route<{1}> on uri<{2}>")]
+    RouteConflict(String, String, String),
+    #[error("store error: {0}")]
+    Store(#[from] StoreError),
+}
+
+fn join_errors(errors: &[PluginError]) -> String {
+    let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+    errors.join("; ")
 }
 
-#[derive(Debug, PartialEq)]
-pub struct MatcherParseError(String);
+/// Why a plugin of route failed to build.
+#[derive(Debug, Clone, thiserror::Error, serde::Serialize)]
+#[error("plugin<{name}>: {message}")]
+pub struct PluginError {
+    pub name: String,
+    /// error with its sources, like `json config error: missing field ...`
+    pub message: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StoreError {
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("json error")]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "redis-store")]
+    #[error("redis error")]
+    Redis(#[from] redis::RedisError),
+    #[error("{0}")]
+    Message(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatcherParseError {
+    pub message: String,
+    /// byte offset of the failure
+    pub offset: usize,
+    /// 1-based column of the failure
+    pub column: usize,
+}
 
 impl MatcherParseError {
-    pub fn new(e: String) -> Self {
-        MatcherParseError(e)
+    pub fn new(message: impl ToString, input: &str, offset: usize) -> Self {
+        let column = input
+            .get(..offset)
+            .map(|s| s.chars().count())
+            .unwrap_or(offset)
+            + 1;
+
+        MatcherParseError {
+            message: message.to_string(),
+            offset,
+            column,
+        }
     }
 }
 
 impl std::fmt::Display for MatcherParseError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        fmt.write_str(&self.0)
+        write!(fmt, "{} at column {}", self.message, self.column)
     }
 }
 
-impl std::error::Error for MatcherParseError {
-    fn description(&self) -> &str {
-        &self.0
-    }
-}
+impl std::error::Error for MatcherParseError {}
 
 pub fn upstream_not_found(upstream: impl ToString) -> ConfigError {
     ConfigError::UpstreamNotFound(upstream.to_string())
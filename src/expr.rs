@@ -0,0 +1,337 @@
+//! Comparison expressions over request attributes, like
+//! `header.x-api-version >= 2 && (query.beta = 'true' || path =~ '^/beta/')`.
+
+use std::cmp::Ordering;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{char, digit1},
+    combinator::{cut, eof, map, map_res, opt, recognize},
+    error::context,
+    sequence::{delimited, pair, terminated, tuple},
+};
+
+use crate::context::GatewayContext;
+use crate::error::MatcherParseError;
+use crate::http::HyperRequest;
+use crate::matcher::{expect, parse_quoted, sp, ComparableRegex, PResult};
+use crate::variable::Variable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// `=~`
+    Match,
+    /// `!~`
+    NotMatch,
+}
+
+impl Op {
+    fn test(self, ord: Option<Ordering>) -> bool {
+        match (self, ord) {
+            (Op::Eq, Some(ord)) => ord == Ordering::Equal,
+            (Op::Ne, Some(ord)) => ord != Ordering::Equal,
+            (Op::Lt, Some(ord)) => ord == Ordering::Less,
+            (Op::Le, Some(ord)) => ord != Ordering::Greater,
+            (Op::Gt, Some(ord)) => ord == Ordering::Greater,
+            (Op::Ge, Some(ord)) => ord != Ordering::Less,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// attribute is coerced to number before comparing
+    Number(f64),
+    Str(String),
+    Regex(ComparableRegex),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Compare(Variable, Op, Value),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(i: &str) -> Result<Expr, MatcherParseError> {
+        let (_i, expr) = top_level(i).map_err(|e| match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e.into_error(i),
+            nom::Err::Incomplete(_) => MatcherParseError::new("unexpected end", i, i.len()),
+        })?;
+
+        Ok(expr)
+    }
+
+    /// Missing attributes never match, whatever the operator is.
+    pub fn evaluate(&self, ctx: &GatewayContext, req: &HyperRequest) -> bool {
+        match self {
+            Expr::Compare(var, op, value) => var
+                .resolve(ctx, req)
+                .map(|attr| compare(&attr, *op, value))
+                .unwrap_or(false),
+            Expr::And(lhs, rhs) => lhs.evaluate(ctx, req) && rhs.evaluate(ctx, req),
+            Expr::Or(lhs, rhs) => lhs.evaluate(ctx, req) || rhs.evaluate(ctx, req),
+        }
+    }
+}
+
+/// Attribute not being a number only satisfies `!=` against a number.
+fn compare(attr: &str, op: Op, value: &Value) -> bool {
+    match (op, value) {
+        (Op::Match, Value::Regex(re)) => re.is_match(attr),
+        (Op::NotMatch, Value::Regex(re)) => !re.is_match(attr),
+        (_, Value::Regex(_)) => false,
+        (op, Value::Number(n)) => match attr.trim().parse::<f64>() {
+            Ok(attr) => op.test(attr.partial_cmp(n)),
+            Err(_) => op == Op::Ne,
+        },
+        (op, Value::Str(s)) => op.test(Some(attr.cmp(s.as_str()))),
+    }
+}
+
+fn selector(s: &str) -> Result<Variable, String> {
+    match s {
+        "path" => Ok(Variable::Uri),
+        "method" => Ok(Variable::Method),
+        "remote_ip" => Ok(Variable::RemoteAddr),
+        _ => match s.split_once('.') {
+            Some(("header", name)) if !name.is_empty() => {
+                Ok(Variable::Http(name.to_ascii_lowercase()))
+            }
+            Some(("query", name)) if !name.is_empty() => Ok(Variable::Arg(name.to_string())),
+            _ => Err(format!("unknown selector `{}`", s)),
+        },
+    }
+}
+
+fn parse_selector(i: &str) -> PResult<Variable> {
+    let name = take_while1(|c: char| c.is_ascii_alphanumeric() || "_-.".contains(c));
+
+    context("selector", map_res(name, selector))(i)
+}
+
+fn parse_op(i: &str) -> PResult<Op> {
+    context(
+        "operator",
+        alt((
+            map(tag("=~"), |_| Op::Match),
+            map(tag("!~"), |_| Op::NotMatch),
+            map(tag("=="), |_| Op::Eq),
+            map(tag("!="), |_| Op::Ne),
+            map(tag("<="), |_| Op::Le),
+            map(tag(">="), |_| Op::Ge),
+            map(tag("<"), |_| Op::Lt),
+            map(tag(">"), |_| Op::Gt),
+            map(tag("="), |_| Op::Eq),
+        )),
+    )(i)
+}
+
+fn parse_number(i: &str) -> PResult<f64> {
+    map_res(
+        recognize(tuple((
+            opt(char('-')),
+            digit1,
+            opt(pair(char('.'), digit1)),
+        ))),
+        |s: &str| s.parse::<f64>(),
+    )(i)
+}
+
+fn parse_value<'a>(op: Op) -> impl FnMut(&'a str) -> PResult<'a, Value> {
+    move |i: &'a str| match op {
+        Op::Match | Op::NotMatch => context(
+            "quoted regex",
+            map_res(parse_quoted, |s: String| {
+                ComparableRegex::new(&s).map(Value::Regex)
+            }),
+        )(i),
+        _ => context(
+            "value",
+            alt((
+                map(parse_quoted, Value::Str),
+                map(parse_number, Value::Number),
+            )),
+        )(i),
+    }
+}
+
+fn comparison(i: &str) -> PResult<Expr> {
+    let (i, var) = parse_selector(i)?;
+    let (i, op) = cut(delimited(sp, parse_op, sp))(i)?;
+    let (i, value) = cut(parse_value(op))(i)?;
+
+    Ok((i, Expr::Compare(var, op, value)))
+}
+
+fn nested(i: &str) -> PResult<Expr> {
+    let (i, _) = tag("(")(i)?;
+
+    cut(terminated(or, expect(")")))(i)
+}
+
+fn primary(i: &str) -> PResult<Expr> {
+    delimited(sp, alt((nested, comparison)), sp)(i)
+}
+
+fn and(i: &str) -> PResult<Expr> {
+    let (mut i, mut lhs) = primary(i)?;
+
+    while let Ok((rest, _)) = tag::<_, _, ()>("&&")(i) {
+        let (rest, rhs) = cut(primary)(rest)?;
+        lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+        i = rest;
+    }
+
+    Ok((i, lhs))
+}
+
+fn or(i: &str) -> PResult<Expr> {
+    let (mut i, mut lhs) = and(i)?;
+
+    while let Ok((rest, _)) = tag::<_, _, ()>("||")(i) {
+        let (rest, rhs) = cut(and)(rest)?;
+        lhs = Expr::Or(Box::new(lhs), Box::new(rhs));
+        i = rest;
+    }
+
+    Ok((i, lhs))
+}
+
+fn top_level(i: &str) -> PResult<Expr> {
+    terminated(or, context("end of expression", eof))(i)
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    fn eval(expr: &str, req: &HyperRequest) -> bool {
+        let ctx = GatewayContext::new(Some("10.0.0.1:1234".parse().unwrap()), Scheme::HTTP, req);
+        Expr::parse(expr).unwrap().evaluate(&ctx, req)
+    }
+
+    fn request(uri: &str, version: &str) -> HyperRequest {
+        hyper::Request::builder()
+            .uri(uri)
+            .header("X-Api-Version", version)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_expr() {
+        assert_eq!(
+            Expr::parse("header.X-Api-Version >= 2 && (query.beta = 'true' || path != '/')"),
+            Ok(Expr::And(
+                Box::new(Expr::Compare(
+                    Variable::Http("x-api-version".to_string()),
+                    Op::Ge,
+                    Value::Number(2.0)
+                )),
+                Box::new(Expr::Or(
+                    Box::new(Expr::Compare(
+                        Variable::Arg("beta".to_string()),
+                        Op::Eq,
+                        Value::Str("true".to_string())
+                    )),
+                    Box::new(Expr::Compare(
+                        Variable::Uri,
+                        Op::Ne,
+                        Value::Str("/".to_string())
+                    )),
+                ))
+            ))
+        );
+
+        // `&&` binds tighter than `||`
+        assert!(matches!(
+            Expr::parse("method = 'GET' || method = 'PUT' && path = '/'"),
+            Ok(Expr::Or(_, rhs)) if matches!(*rhs, Expr::And(..))
+        ));
+    }
+
+    #[test]
+    fn parse_error_position() {
+        let err = Expr::parse("header.x >= 2 && cookie.a = '1'").unwrap_err();
+        assert_eq!(err.message, "unknown selector `cookie.a`");
+        assert_eq!(err.column, 17);
+
+        let err = Expr::parse("path '/'").unwrap_err();
+        assert_eq!(err.message, "expected operator");
+        assert_eq!(err.column, 5);
+
+        let err = Expr::parse("path = ").unwrap_err();
+        assert_eq!(err.message, "expected value");
+
+        let err = Expr::parse("(path = '/'").unwrap_err();
+        assert_eq!(err.message, "expected `)`");
+
+        let err = Expr::parse("path =~ '['").unwrap_err();
+        assert!(err.message.starts_with("regex parse error"));
+
+        let err = Expr::parse("path = '/' &&").unwrap_err();
+        assert_eq!(err.message, "expected selector");
+
+        let err = Expr::parse("path = '/' & method = 'GET'").unwrap_err();
+        assert_eq!(err.message, "expected end of expression");
+        assert_eq!(err.column, 11);
+    }
+
+    #[test]
+    fn numeric_and_string_comparison() {
+        let req = request("/", "10");
+
+        assert!(eval("header.x-api-version > 2", &req));
+        assert!(eval("header.x-api-version = 10.0", &req));
+        // quoted value is compared as string
+        assert!(!eval("header.x-api-version > '2'", &req));
+        assert!(!eval("header.x-api-version = '10.0'", &req));
+
+        let req = request("/", "beta");
+        assert!(!eval("header.x-api-version >= 2", &req));
+        assert!(!eval("header.x-api-version = 2", &req));
+        assert!(eval("header.x-api-version != 2", &req));
+        assert!(eval("header.x-api-version > 'alpha'", &req));
+    }
+
+    #[test]
+    fn missing_attribute() {
+        let req = request("/api?page=2", "1");
+
+        assert!(eval("query.page <= 2", &req));
+        assert!(!eval("query.size <= 2", &req));
+        assert!(!eval("query.size != 2", &req));
+        assert!(!eval("header.x-missing != 'x'", &req));
+        assert!(!eval("header.x-missing !~ 'x'", &req));
+        assert!(eval(
+            "header.x-missing = 'x' || remote_ip = '10.0.0.1'",
+            &req
+        ));
+    }
+
+    #[test]
+    fn regex_operators() {
+        let req = request("/v2/users?page=3", "2");
+
+        assert!(eval("path =~ '^/v[0-9]+/'", &req));
+        assert!(!eval("path !~ '^/v[0-9]+/'", &req));
+        assert!(eval(
+            "method =~ '^(GET|HEAD)$' && query.page =~ '^[0-9]+$'",
+            &req
+        ));
+        assert!(eval("remote_ip !~ '^192[.]168[.]'", &req));
+        assert!(Expr::parse("path =~ 2").is_err());
+    }
+}
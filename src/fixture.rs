@@ -0,0 +1,222 @@
+//! Tests shipped with routes in the registry file, checking where requests go.
+//!
+//! Requests are never forwarded, the upstreams are left out.
+
+use std::{collections::HashMap, sync::Arc};
+
+use hyper::{http::uri::Scheme, Body};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::HyperRequest;
+use crate::registry::{Registry, RegistryConfig};
+use crate::router::HostRouter;
+use crate::services::GatewayService;
+use crate::upstream::UpstreamMap;
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RouteTest {
+    pub name: String,
+    pub request: TestRequest,
+    pub expect: TestExpect,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TestRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// path, or absolute uri for host routing
+    pub uri: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+impl TestRequest {
+    fn build(&self) -> Result<HyperRequest, hyper::http::Error> {
+        let mut builder = hyper::Request::builder()
+            .method(self.method.as_str())
+            .uri(self.uri.as_str());
+
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+
+        builder.body(Body::empty())
+    }
+}
+
+/// Fields not set are not checked.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TestExpect {
+    /// `""` for no route matched
+    #[serde(default)]
+    pub route_id: Option<String>,
+    #[serde(default)]
+    pub upstream_id: Option<String>,
+    /// status of responses answered by gateway, like 404 or plugin rejections,
+    /// requests reaching for upstream get 502
+    #[serde(default)]
+    pub status: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TestResult {
+    pub name: String,
+    pub passed: bool,
+    pub failures: Vec<String>,
+}
+
+/// Run `tests` of config, with plugins of their own.
+pub async fn run_tests(cfg: &RegistryConfig) -> Result<Vec<TestResult>, ConfigError> {
+    let router = Registry::build_router(cfg)?;
+    let upstreams = Arc::new(UpstreamMap::new());
+
+    let mut results = Vec::with_capacity(cfg.tests.len());
+    for test in &cfg.tests {
+        let failures = run_test(&router, &upstreams, test).await;
+
+        results.push(TestResult {
+            name: test.name.clone(),
+            passed: failures.is_empty(),
+            failures,
+        });
+    }
+
+    Ok(results)
+}
+
+async fn run_test(
+    router: &HostRouter,
+    upstreams: &Arc<UpstreamMap>,
+    test: &RouteTest,
+) -> Vec<String> {
+    let req = match test.request.build() {
+        Ok(req) => req,
+        Err(err) => return vec![format!("invalid request: {}", err)],
+    };
+    let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+    let route = GatewayService::find_route(router, &ctx, &req).map(|(route, _)| route);
+    let mut failures = Vec::new();
+
+    let mut check = |field: &str, expected: Option<&str>, actual: &str| {
+        if let Some(expected) = expected.filter(|e| *e != actual) {
+            failures.push(format!(
+                "{}: expected <{}>, got <{}>",
+                field, expected, actual
+            ));
+        }
+    };
+
+    check(
+        "route_id",
+        test.expect.route_id.as_deref(),
+        route.map(|r| r.id.as_str()).unwrap_or_default(),
+    );
+    check(
+        "upstream_id",
+        test.expect.upstream_id.as_deref(),
+        route.map(|r| r.upstream_id.as_str()).unwrap_or_default(),
+    );
+
+    if let Some(expected) = test.expect.status {
+        let resp = GatewayService::serve(router, upstreams, ctx, req).await;
+
+        if resp.status().as_u16() != expected {
+            failures.push(format!(
+                "status: expected <{}>, got <{}>",
+                expected,
+                resp.status().as_u16()
+            ));
+        }
+    }
+
+    failures
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn route_tests() {
+        let cfg: RegistryConfig = serde_json::from_value(json!({
+            "routes": [{
+                "id": "users",
+                "name": "users",
+                "desc": "",
+                "uris": ["/users/:id"],
+                "upstream_id": "users",
+                "matcher": "Method('GET')"
+            }, {
+                "id": "admin",
+                "name": "admin",
+                "desc": "",
+                "uris": ["/admin"],
+                "upstream_id": "users",
+                "plugins": {
+                    "key_auth": { "enable": true, "keys": [{ "key": "secret" }] }
+                }
+            }],
+            "upstreams": [{
+                "id": "users",
+                "name": "users",
+                "desc": "",
+                "strategy": "random",
+                "endpoints": [{ "addr": "http://127.0.0.1:1", "weight": 1 }],
+                "health_check": {
+                    "timeout": 0, "interval": 0, "path": "", "status_regex": "",
+                    "rise": 1, "fall": 1, "default_down": false
+                }
+            }],
+            "tests": [{
+                "name": "get user",
+                "request": { "uri": "/users/1" },
+                "expect": { "route_id": "users", "upstream_id": "users" }
+            }, {
+                "name": "delete user",
+                "request": { "method": "DELETE", "uri": "/users/1" },
+                "expect": { "route_id": "", "status": 404 }
+            }, {
+                "name": "admin needs key",
+                "request": { "uri": "/admin" },
+                "expect": { "route_id": "admin", "status": 401 }
+            }, {
+                "name": "wrong",
+                "request": { "uri": "/admin", "headers": { "x-api-key": "secret" } },
+                "expect": { "route_id": "users", "status": 401 }
+            }]
+        }))
+        .unwrap();
+
+        let results = run_tests(&cfg).await.unwrap();
+
+        let passed: Vec<_> = results
+            .iter()
+            .map(|r| (r.name.as_str(), r.passed))
+            .collect();
+        assert_eq!(
+            passed,
+            vec![
+                ("get user", true),
+                ("delete user", true),
+                ("admin needs key", true),
+                ("wrong", false)
+            ]
+        );
+        assert_eq!(
+            results[3].failures,
+            vec![
+                "route_id: expected <users>, got <admin>",
+                "status: expected <401>, got <502>"
+            ]
+        );
+    }
+}
@@ -1,33 +1,107 @@
-use std::{fmt::Write, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, RwLock},
+    time::Instant,
+};
 
 use headers::HeaderValue;
-use hyper::{client::HttpConnector, header::HOST, http::uri::Scheme, Body, Client, Uri};
-use hyper_rustls::HttpsConnector;
-use tower::Service;
+use hyper::{
+    client::{HttpConnector, ResponseFuture},
+    header::HOST,
+    http::uri::Scheme,
+    Body, Client, Uri,
+};
+use rustls::ClientConfig;
 
 use crate::{
-    context::GatewayContext,
+    config::{BufferConfig, TlsProvider},
+    context::{GatewayContext, SelectedEndpoint},
+    error::ConfigError,
     http::{HyperRequest, HyperResponse},
     load_balance::LoadBalanceStrategy,
+    matcher::split_host_port,
+    outlier::outlier_stats,
+    protocol::{finish_response, prepare_request},
+    tls::{pinned_client_config, tls_provider},
 };
 
+/// Client of `tls_provider`.
+#[derive(Clone)]
+enum HttpsClient {
+    Rustls(Client<hyper_rustls::HttpsConnector<HttpConnector>, Body>),
+    #[cfg(feature = "native-tls")]
+    NativeTls(Client<hyper_tls::HttpsConnector<HttpConnector>, Body>),
+}
+
+impl HttpsClient {
+    fn request(&self, req: HyperRequest) -> ResponseFuture {
+        match self {
+            HttpsClient::Rustls(client) => client.request(req),
+            #[cfg(feature = "native-tls")]
+            HttpsClient::NativeTls(client) => client.request(req),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct HttpClient {
-    client: hyper::Client<HttpsConnector<HttpConnector>, Body>,
+    client: HttpsClient,
+    /// Pinned tls config, native roots when none.
+    tls: Option<ClientConfig>,
+    buffer: BufferConfig,
+    /// Clients of https endpoints sending `upstream_host` as SNI, by server name.
+    sni_clients: Arc<RwLock<HashMap<String, HttpsClient>>>,
 }
 
 impl HttpClient {
-    pub fn new() -> Self {
-        let https = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .https_or_http()
-            .enable_http1()
-            .enable_http2()
-            .build();
+    pub fn new(buffer: &BufferConfig) -> Self {
+        Self::with_tls_config(buffer, None)
+    }
 
-        let inner: Client<_, hyper::Body> = Client::builder().build(https);
+    /// Client requiring certificates of https endpoints to match `pins`.
+    pub fn with_tls_pins(buffer: &BufferConfig, pins: &[String]) -> Result<Self, ConfigError> {
+        if pins.is_empty() {
+            return Ok(Self::new(buffer));
+        }
+        if tls_provider() != TlsProvider::Rustls {
+            return Err(ConfigError::Message(
+                "tls_pins needs rustls provider".to_string(),
+            ));
+        }
 
-        HttpClient { client: inner }
+        Ok(Self::with_tls_config(
+            buffer,
+            Some(pinned_client_config(pins)?),
+        ))
+    }
+
+    fn with_tls_config(buffer: &BufferConfig, tls: Option<ClientConfig>) -> Self {
+        HttpClient {
+            client: build_client(tls.as_ref(), None, buffer),
+            tls,
+            buffer: buffer.clone(),
+            sni_clients: Default::default(),
+        }
+    }
+
+    /// Client sending `server_name` as SNI instead of host of endpoints.
+    fn sni_client(&self, server_name: &str) -> HttpsClient {
+        if let Some(client) = self.sni_clients.read().unwrap().get(server_name) {
+            return client.clone();
+        }
+
+        self.sni_clients
+            .write()
+            .unwrap()
+            .entry(server_name.to_string())
+            .or_insert_with(|| build_client(self.tls.as_ref(), Some(server_name), &self.buffer))
+            .clone()
+    }
+
+    /// Send a request originated by gateway itself, `req` should have an absolute uri.
+    pub async fn request(&self, req: HyperRequest) -> Result<HyperResponse, hyper::Error> {
+        self.client.request(req).await
     }
 
     pub async fn do_forward<'a>(
@@ -43,12 +117,65 @@ impl HttpClient {
 
         let uri = Uri::from_parts(parts).expect("build uri failed");
 
+        // SNI follows `upstream_host`, so virtual hosts behind a shared address get their certificate,
+        // native tls client always sends host of endpoint
+        let sni =
+            uri.scheme() == Some(&Scheme::HTTPS) && matches!(self.client, HttpsClient::Rustls(_));
         *req.uri_mut() = uri;
 
-        let resp = Service::call(&mut self.client, req).await;
+        let server_name = ctx
+            .upstream_host
+            .as_ref()
+            .filter(|_| sni)
+            .and_then(|host| host.to_str().ok())
+            .map(server_name);
+
+        match server_name {
+            Some(server_name) => self.sni_client(server_name).request(req).await,
+            None => self.client.request(req).await,
+        }
+    }
+}
+
+fn build_client(
+    tls: Option<&ClientConfig>,
+    server_name: Option<&str>,
+    buffer: &BufferConfig,
+) -> HttpsClient {
+    let mut builder = Client::builder();
 
-        resp
+    if let Some(size) = buffer.http1_max_buf_size {
+        builder.http1_max_buf_size(size);
     }
+    builder
+        .http2_initial_stream_window_size(buffer.http2_stream_window_size)
+        .http2_initial_connection_window_size(buffer.http2_connection_window_size);
+
+    // speaks http1 only, without alpn
+    #[cfg(feature = "native-tls")]
+    if tls_provider() == TlsProvider::NativeTls {
+        return HttpsClient::NativeTls(builder.build(hyper_tls::HttpsConnector::new()));
+    }
+
+    let https = hyper_rustls::HttpsConnectorBuilder::new();
+    let https = match tls {
+        Some(tls) => https.with_tls_config(tls.clone()),
+        None => https.with_native_roots(),
+    };
+    let https = https.https_or_http();
+    let https = match server_name {
+        Some(server_name) => https.with_server_name(server_name.to_string()),
+        None => https,
+    };
+
+    HttpsClient::Rustls(builder.build(https.enable_http1().enable_http2().build()))
+}
+
+/// Host of `host[:port]` without port and brackets of IPv6 literal.
+fn server_name(host: &str) -> &str {
+    let (host, _) = split_host_port(host);
+
+    host.trim_start_matches('[').trim_end_matches(']')
 }
 
 #[derive(Clone)]
@@ -70,27 +197,59 @@ impl Fowarder {
         // add forward info
         Self::append_proxy_headers(ctx, &mut req);
 
-        if ctx.overwrite_host {
-            let host = req.uri().host().expect("get host failed");
-            let host = HeaderValue::from_str(host).expect("HeaderValue failed");
+        let upgrade = prepare_request(&ctx.protocol, &mut req);
+
+        // none, e.g. all endpoints still warming up
+        let endpoint = self
+            .strategy
+            .select_endpoint(ctx, &req)
+            .cloned()
+            .ok_or_else(|| crate::Error::Message("no endpoint available".to_string()))?;
+        tracing::Span::current().record("endpoint", endpoint.to_string().as_str());
+        ctx.set(SelectedEndpoint(endpoint.clone()));
+
+        if let Some(host) = Self::upstream_host(ctx, &endpoint)? {
             req.headers_mut().insert(HOST, host);
         }
 
-        let endpoint = self.strategy.select_endpoint(ctx, &req).to_owned();
+        let start = Instant::now();
+        let resp = {
+            // released also when cancelled by a timeout
+            let _in_flight = self.strategy.on_send_request(ctx, &endpoint);
+            self.client.do_forward(ctx, req, &endpoint).await
+        };
 
-        self.strategy.on_send_request(&ctx, &endpoint);
+        if let Some(upstream_id) = &ctx.upstream_id {
+            let success = matches!(&resp, Ok(resp) if !resp.status().is_server_error());
+            outlier_stats().record(upstream_id, &endpoint, success, start.elapsed());
+        }
+
+        resp.map(|resp| finish_response(ctx, upgrade, resp)).map_err(Into::into)
+    }
 
-        let resp = self.client.do_forward(ctx, req, &endpoint).await;
+    /// Host header replacing the one of client, `upstream_host` of route or host of endpoint.
+    fn upstream_host(ctx: &GatewayContext, target: &Uri) -> crate::Result<Option<HeaderValue>> {
+        if let Some(ref host) = ctx.upstream_host {
+            return Ok(Some(host.clone()));
+        }
+
+        if !ctx.overwrite_host {
+            return Ok(None);
+        }
 
-        self.strategy.on_request_done(&ctx, &endpoint);
+        let authority = target.authority().ok_or_else(|| {
+            crate::Error::Message(format!("endpoint<{}> has no host", target))
+        })?;
 
-        resp.map_err(Into::into)
+        HeaderValue::from_str(authority.as_str())
+            .map(Some)
+            .map_err(|_| crate::Error::Message(format!("invalid host<{}>", authority)))
     }
 
     fn append_proxy_headers(ctx: &GatewayContext, req: &mut HyperRequest) {
         let x_forwarded_for = req.headers().get(crate::http::X_FORWARDED_FOR);
 
-        if let Some(remote_addr) = ctx.remote_addr {
+        if let Some(remote_addr) = ctx.remote_addr() {
             let x_forwarded_for = match x_forwarded_for {
                 Some(exist_forwarded_for) => {
                     let mut forwarded_for = exist_forwarded_for.to_str().unwrap_or("").to_string();
@@ -113,14 +272,29 @@ impl Fowarder {
 
         req.headers_mut().insert(
             crate::http::X_FORWARDED_PROTO,
-            HeaderValue::from_str(ctx.orig_scheme.as_str()).expect("HeaderValue failed"),
+            HeaderValue::from_str(ctx.orig_scheme().as_str()).expect("HeaderValue failed"),
         );
 
-        if let Some(ref host) = ctx.orig_host {
+        if let Some(host) = ctx.orig_host() {
             req.headers_mut().insert(
                 crate::http::X_FORWARDED_HOST,
                 HeaderValue::from_str(host).expect("HeaderValue failed"),
             );
         }
+
+        crate::telemetry::inject_context(req.headers_mut());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sni_of_upstream_host() {
+        assert_eq!(server_name("example.com"), "example.com");
+        assert_eq!(server_name("example.com:8443"), "example.com");
+        assert_eq!(server_name("[::1]:8443"), "::1");
+        assert_eq!(server_name("[::1]"), "::1");
     }
 }
@@ -4,15 +4,40 @@ use std::{
     time::{Duration, Instant},
 };
 
-use hyper::{client::HttpConnector, http::uri::Scheme, Client, Method, Request, Uri};
-use hyper_rustls::HttpsConnector;
+use hyper::{
+    client::{connect::Connection, HttpConnector, ResponseFuture},
+    http::uri::Scheme,
+    service::Service,
+    Client, Method, Request, Uri,
+};
 use hyper_timeout::TimeoutConnector;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc::{Receiver, Sender},
+};
 
-use crate::{registry::Registry, upstream::Upstream};
+use crate::{outlier::outlier_stats, registry::Registry, upstream::Upstream};
+#[cfg(feature = "native-tls")]
+use crate::{config::TlsProvider, tls::tls_provider};
+
+/// Client of `tls_provider`, probing endpoints like forwarded requests do.
+#[derive(Clone)]
+enum HttpClient {
+    Rustls(Client<TimeoutConnector<hyper_rustls::HttpsConnector<HttpConnector>>, hyper::Body>),
+    #[cfg(feature = "native-tls")]
+    NativeTls(Client<TimeoutConnector<hyper_tls::HttpsConnector<HttpConnector>>, hyper::Body>),
+}
 
-type HttpClient = Client<TimeoutConnector<HttpsConnector<HttpConnector>>, hyper::Body>;
+impl HttpClient {
+    fn request(&self, req: Request<hyper::Body>) -> ResponseFuture {
+        match self {
+            HttpClient::Rustls(client) => client.request(req),
+            #[cfg(feature = "native-tls")]
+            HttpClient::NativeTls(client) => client.request(req),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
 pub struct HealthConfig {
@@ -27,6 +52,47 @@ pub struct HealthConfig {
     pub rise: u64,
     pub fall: u64,
     pub default_down: bool,
+    #[serde(default)]
+    pub flap: Option<FlapConfig>,
+}
+
+/// Hold endpoint down when its status changed too often, instead of churning load balance.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FlapConfig {
+    /// status changes allowed within `window`
+    pub max_transitions: usize,
+    /// window in seconds
+    pub window: u64,
+    /// seconds held down once flapping
+    pub quarantine: u64,
+}
+
+/// Synthetic request sent to a new endpoint, admit it to load balance after success.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WarmupConfig {
+    /// request path
+    pub path: String,
+    /// request timeout in milliseconds
+    #[serde(default = "default_warmup_timeout")]
+    pub timeout: u64,
+    /// give up after attempts, the endpoint is marked down
+    #[serde(default = "default_warmup_attempts")]
+    pub attempts: u32,
+    /// interval between attempts in milliseconds
+    #[serde(default = "default_warmup_interval")]
+    pub interval: u64,
+}
+
+fn default_warmup_timeout() -> u64 {
+    3000
+}
+
+fn default_warmup_attempts() -> u32 {
+    3
+}
+
+fn default_warmup_interval() -> u64 {
+    1000
 }
 
 struct HealthChecker {
@@ -64,6 +130,8 @@ impl UpstreamChecker {
 
             tokio::spawn(Self::check_endpoint(
                 health_config,
+                self.upstream.id.clone(),
+                ep.target.clone(),
                 status_store.clone(),
                 tx.clone(),
                 client.clone(),
@@ -74,12 +142,15 @@ impl UpstreamChecker {
 
     async fn check_endpoint(
         cfg: HealthConfig,
+        upstream_id: String,
+        target: Uri,
         status_store: Arc<RwLock<Healthiness>>,
         statuc_tx: Sender<()>,
         client: HttpClient,
         uri: Uri,
     ) {
         let mut status_ring = StatusRing::new(&cfg);
+        let mut flap = cfg.flap.clone().map(|flap| FlapDetector::new(flap, status_ring.status()));
         // init status
         let status = status_ring.status();
         *status_store.write().unwrap() = status;
@@ -95,11 +166,39 @@ impl UpstreamChecker {
                else => {
                     // check and set status
                     let status = detect_endpoint_health(client.clone(), uri.clone()).await;
-                    let status = status_ring.append(status);
+                    let mut status = status_ring.append(status);
+
+                    let mut reason = "health check failed";
+                    if let Some(ref mut flap) = flap {
+                        let now = Instant::now();
+                        let was_quarantined = flap.quarantined(now);
+
+                        status = flap.update(status, now);
+
+                        if flap.quarantined(now) {
+                            reason = "flapping";
+                            if !was_quarantined {
+                                tracing::warn!(
+                                    %upstream_id,
+                                    %target,
+                                    quarantine = flap.cfg.quarantine,
+                                    "endpoint flapping, held down"
+                                );
+                            }
+                        }
+                    }
 
                     let orig_status =  { *status_store.read().unwrap() };
                     if orig_status != status {
                         *status_store.write().unwrap() = status;
+
+                        match status {
+                            Healthiness::Down => {
+                                outlier_stats().eject(&upstream_id, &target, reason)
+                            }
+                            Healthiness::Up => outlier_stats().recover(&upstream_id, &target),
+                            Healthiness::Warming => {}
+                        }
                     }
                     // wait for next
                     tokio::time::sleep(Duration::from_millis(cfg.interval)).await;
@@ -109,10 +208,13 @@ impl UpstreamChecker {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Healthiness {
     Up,
     Down,
+    /// warm-up request not succeeded yet
+    Warming,
 }
 
 struct StatusRing {
@@ -161,6 +263,7 @@ impl StatusRing {
                     self.status = status;
                 }
             }
+            Healthiness::Warming => {}
         }
 
         self.status
@@ -176,22 +279,133 @@ impl StatusRing {
     }
 }
 
+struct FlapDetector {
+    cfg: FlapConfig,
+    /// status of checks, before held down
+    last: Healthiness,
+    transitions: VecDeque<Instant>,
+    quarantined_until: Option<Instant>,
+}
+
+impl FlapDetector {
+    fn new(cfg: FlapConfig, status: Healthiness) -> Self {
+        FlapDetector {
+            cfg,
+            last: status,
+            transitions: VecDeque::new(),
+            quarantined_until: None,
+        }
+    }
+
+    fn quarantined(&self, now: Instant) -> bool {
+        self.quarantined_until.map_or(false, |until| now < until)
+    }
+
+    /// Status to publish for status of checks, `Down` while quarantined.
+    fn update(&mut self, status: Healthiness, now: Instant) -> Healthiness {
+        if status != self.last {
+            self.last = status;
+            self.transitions.push_back(now);
+        }
+
+        let window = Duration::from_secs(self.cfg.window);
+        while let Some(&first) = self.transitions.front() {
+            if now.duration_since(first) <= window {
+                break;
+            }
+            self.transitions.pop_front();
+        }
+
+        if self.quarantined_until.map_or(false, |until| now >= until) {
+            // start over, changes before quarantine ended are not counted
+            self.quarantined_until = None;
+            self.transitions.clear();
+        }
+
+        if self.quarantined_until.is_none() && self.transitions.len() > self.cfg.max_transitions {
+            self.quarantined_until = Some(now + Duration::from_secs(self.cfg.quarantine));
+        }
+
+        if self.quarantined(now) {
+            Healthiness::Down
+        } else {
+            status
+        }
+    }
+}
+
 fn create_http_client(cfg: &HealthConfig) -> HttpClient {
+    let timeout = Duration::from_millis(cfg.timeout);
+    let mut builder = Client::builder();
+    builder.pool_max_idle_per_host(0);
+
+    #[cfg(feature = "native-tls")]
+    if tls_provider() == TlsProvider::NativeTls {
+        let https = hyper_tls::HttpsConnector::new();
+        return HttpClient::NativeTls(builder.build(with_timeout(https, timeout)));
+    }
+
     let https = hyper_rustls::HttpsConnectorBuilder::new()
         .with_native_roots()
         .https_or_http()
         .enable_http1()
         .build();
-    let mut connector = TimeoutConnector::new(https);
-    let timeout = Some(Duration::from_millis(cfg.timeout));
-    connector.set_connect_timeout(timeout);
-    connector.set_read_timeout(timeout);
-    connector.set_write_timeout(timeout);
 
-    let client: Client<_, hyper::Body> =
-        Client::builder().pool_max_idle_per_host(0).build(connector);
+    HttpClient::Rustls(builder.build(with_timeout(https, timeout)))
+}
 
-    client
+fn with_timeout<C>(connector: C, timeout: Duration) -> TimeoutConnector<C>
+where
+    C: Service<Uri> + Send,
+    C::Response: AsyncRead + AsyncWrite + Connection + Send + Unpin,
+    C::Future: Send + 'static,
+    C::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut connector = TimeoutConnector::new(connector);
+    connector.set_connect_timeout(Some(timeout));
+    connector.set_read_timeout(Some(timeout));
+    connector.set_write_timeout(Some(timeout));
+
+    connector
+}
+
+/// Warm up endpoint, it stays `Warming` until the warm-up request succeeded.
+pub fn spawn_warmup(
+    cfg: WarmupConfig,
+    upstream_id: String,
+    target: Uri,
+    status_store: Arc<RwLock<Healthiness>>,
+) {
+    *status_store.write().unwrap() = Healthiness::Warming;
+
+    tokio::spawn(async move {
+        let client = create_http_client(&HealthConfig {
+            timeout: cfg.timeout,
+            ..Default::default()
+        });
+
+        let parts = target.clone().into_parts();
+        let uri = Uri::builder()
+            .scheme(parts.scheme.unwrap_or(Scheme::HTTP))
+            .authority(parts.authority.expect("endpoint authority empty"))
+            .path_and_query(cfg.path.as_str())
+            .build()
+            .expect("build warmup uri failed");
+
+        for attempt in 1..=cfg.attempts {
+            if detect_endpoint_health(client.clone(), uri.clone()).await == Healthiness::Up {
+                tracing::info!(%target, attempt, "endpoint warmed up");
+                *status_store.write().unwrap() = Healthiness::Up;
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(cfg.interval)).await;
+        }
+
+        tracing::warn!(%target, attempts = cfg.attempts, "endpoint warm-up failed");
+        *status_store.write().unwrap() = Healthiness::Down;
+        outlier_stats().eject(&upstream_id, &target, "warm-up failed");
+    });
 }
 
 pub async fn health_check() {}
@@ -239,3 +453,44 @@ async fn detect_endpoint_health(client: HttpClient, uri: Uri) -> Healthiness {
         Err(err) => Healthiness::Down,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flap_quarantine() {
+        let cfg = FlapConfig {
+            max_transitions: 3,
+            window: 60,
+            quarantine: 300,
+        };
+        let mut flap = FlapDetector::new(cfg, Healthiness::Up);
+
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        // slow changes never add up
+        assert_eq!(flap.update(Healthiness::Down, at(0)), Healthiness::Down);
+        assert_eq!(flap.update(Healthiness::Up, at(100)), Healthiness::Up);
+        assert_eq!(flap.update(Healthiness::Down, at(200)), Healthiness::Down);
+        assert_eq!(flap.update(Healthiness::Up, at(300)), Healthiness::Up);
+        assert!(!flap.quarantined(at(300)));
+
+        // oscillating within window
+        assert_eq!(flap.update(Healthiness::Down, at(310)), Healthiness::Down);
+        assert_eq!(flap.update(Healthiness::Up, at(320)), Healthiness::Up);
+        assert_eq!(flap.update(Healthiness::Down, at(330)), Healthiness::Down);
+        assert_eq!(flap.update(Healthiness::Up, at(340)), Healthiness::Down);
+        assert!(flap.quarantined(at(340)));
+
+        // held down even when healthy
+        assert_eq!(flap.update(Healthiness::Up, at(500)), Healthiness::Down);
+        assert_eq!(flap.update(Healthiness::Up, at(640)), Healthiness::Up);
+        assert!(!flap.quarantined(at(640)));
+
+        // changes during quarantine are forgotten
+        assert_eq!(flap.update(Healthiness::Down, at(650)), Healthiness::Down);
+        assert_eq!(flap.update(Healthiness::Up, at(660)), Healthiness::Up);
+    }
+}
@@ -1,12 +1,25 @@
-use std::pin::Pin;
+use std::{pin::Pin, time::Duration};
 
 use futures::Future;
-use hyper::StatusCode;
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER, TRANSFER_ENCODING},
+    StatusCode,
+};
+
+use crate::budget::{memory_budget, BudgetPermit, MemoryBudget};
+use crate::error::BufferError;
 
 pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
 pub const X_FORWARDED_HOST: &str = "x-forwarded-host";
 pub const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
 pub const X_REAL_IP: &str = "x-real-ip";
+pub const X_CIRCUIT_OPEN: &str = "x-circuit-open";
+pub const X_REQUEST_ID: &str = "x-request-id";
+pub const X_RATELIMIT_LIMIT: &str = "x-ratelimit-limit";
+pub const X_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+/// why gateway failed the request, on errors not coming from upstream
+pub const X_GATEWAY_ERROR: &str = "x-gateway-error";
 
 pub type HyperRequest = hyper::Request<hyper::Body>;
 pub type HyperResponse = hyper::Response<hyper::Body>;
@@ -28,9 +41,319 @@ pub fn upstream_unavailable() -> HyperResponse {
         .unwrap()
 }
 
+pub fn gateway_timeout() -> HyperResponse {
+    hyper::Response::builder()
+        .status(StatusCode::GATEWAY_TIMEOUT)
+        .body(hyper::Body::from("Gateway Timeout"))
+        .unwrap()
+}
+
+/// Response for backpressure like rate limiting, tell client when to retry.
+pub fn service_unavailable(retry_after: Duration) -> HyperResponse {
+    hyper::Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(RETRY_AFTER, retry_after.as_secs().max(1))
+        .body(hyper::Body::from("Service Unavailable"))
+        .unwrap()
+}
+
+pub fn loop_detected() -> HyperResponse {
+    hyper::Response::builder()
+        .status(StatusCode::LOOP_DETECTED)
+        .body(hyper::Body::from("Loop Detected"))
+        .unwrap()
+}
+
 pub fn bad_gateway() -> HyperResponse {
     hyper::Response::builder()
         .status(StatusCode::BAD_GATEWAY)
         .body(hyper::Body::from("Bad Gateway"))
         .unwrap()
 }
+
+/// Upstream response larger than allowed by route.
+pub fn response_too_large() -> HyperResponse {
+    let mut resp = json_error(StatusCode::BAD_GATEWAY, "upstream response too large");
+    resp.headers_mut().insert(
+        X_GATEWAY_ERROR,
+        HeaderValue::from_static("response_too_large"),
+    );
+    resp
+}
+
+/// Error response with json body like `{"message": "..."}`.
+pub fn json_error(status: StatusCode, message: &str) -> HyperResponse {
+    hyper::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(
+            serde_json::json!({ "message": message }).to_string(),
+        ))
+        .unwrap()
+}
+
+/// Buffer the whole body, fails when larger than `limit` bytes.
+pub async fn read_body(mut body: hyper::Body, limit: usize) -> crate::Result<hyper::body::Bytes> {
+    use hyper::body::HttpBody;
+
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if buf.len() + chunk.len() > limit {
+            return Err(crate::Error::Message(format!(
+                "body larger than {} bytes",
+                limit
+            )));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf.into())
+}
+
+pub enum BufferedBody {
+    Full(hyper::body::Bytes),
+    /// body larger than limit, chunks read are put back
+    Partial(hyper::Body),
+}
+
+/// Buffer body no larger than `limit`, otherwise give back the body as it was.
+pub async fn buffer_body(
+    mut body: hyper::Body,
+    limit: usize,
+) -> Result<BufferedBody, hyper::Error> {
+    use futures::StreamExt;
+    use hyper::body::HttpBody;
+
+    let mut chunks = Vec::new();
+    let mut size = 0;
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        chunks.push(chunk);
+
+        if size > limit {
+            let read = futures::stream::iter(chunks.into_iter().map(Ok));
+            return Ok(BufferedBody::Partial(hyper::Body::wrap_stream(
+                read.chain(body),
+            )));
+        }
+    }
+
+    let mut buf = Vec::with_capacity(size);
+    for chunk in chunks {
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(BufferedBody::Full(buf.into()))
+}
+
+/// Response body buffered for `after_forward` plugins, kept in response extensions.
+struct BufferedResponseBody {
+    bytes: Bytes,
+    modified: bool,
+    /// memory budget of `bytes`, held until the body is sent
+    permit: Option<BudgetPermit<'static>>,
+}
+
+/// Set in response extensions when the body was larger than a plugin could buffer,
+/// it is streamed untouched.
+#[derive(Debug, Clone, Copy)]
+pub struct StreamedResponseBody;
+
+/// Buffer response body no larger than `limit` for `after_forward` plugins, later plugins
+/// get the same bytes. The body is put back by `finish_response_body`, until then the
+/// response carries an empty body.
+///
+/// `None` when larger than `limit`, the body is then streamed untouched. Bodies are as
+/// upstream sent them, check `Content-Encoding` before parsing. When memory budget
+/// exhausted, the body is left unread.
+pub async fn buffer_response_body(
+    resp: &mut HyperResponse,
+    limit: usize,
+) -> Result<Option<Bytes>, BufferError> {
+    buffer_response_body_in(memory_budget(), resp, limit).await
+}
+
+async fn buffer_response_body_in(
+    budget: &'static MemoryBudget,
+    resp: &mut HyperResponse,
+    limit: usize,
+) -> Result<Option<Bytes>, BufferError> {
+    if let Some(buffered) = resp.extensions().get::<BufferedResponseBody>() {
+        return Ok(Some(buffered.bytes.clone()).filter(|bytes| bytes.len() <= limit));
+    }
+
+    if resp.extensions().get::<StreamedResponseBody>().is_some() {
+        return Ok(None);
+    }
+
+    let content_length = resp
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+    let mut permit = budget
+        .try_reserve(content_length.map_or(limit, |len| len.min(limit)))
+        .ok_or(BufferError::BudgetExhausted)?;
+
+    match buffer_body(std::mem::take(resp.body_mut()), limit).await? {
+        BufferedBody::Full(bytes) => {
+            permit.shrink(bytes.len());
+            resp.extensions_mut().insert(BufferedResponseBody {
+                bytes: bytes.clone(),
+                modified: false,
+                permit: Some(permit),
+            });
+
+            Ok(Some(bytes))
+        }
+        BufferedBody::Partial(body) => {
+            *resp.body_mut() = body;
+            resp.extensions_mut().insert(StreamedResponseBody);
+
+            Ok(None)
+        }
+    }
+}
+
+/// Replace response body, sent by `finish_response_body` with its `Content-Length`.
+/// Memory budget reserved for the buffered body is kept for the new one.
+pub fn set_response_body(resp: &mut HyperResponse, body: impl Into<Bytes>) {
+    *resp.body_mut() = hyper::Body::empty();
+    resp.extensions_mut().remove::<StreamedResponseBody>();
+    let permit = resp
+        .extensions_mut()
+        .remove::<BufferedResponseBody>()
+        .and_then(|buffered| buffered.permit);
+    resp.extensions_mut().insert(BufferedResponseBody {
+        bytes: body.into(),
+        modified: true,
+        permit,
+    });
+}
+
+/// Put back body buffered by `after_forward` plugins, once all of them ran.
+pub fn finish_response_body(mut resp: HyperResponse) -> HyperResponse {
+    let buffered = match resp.extensions_mut().remove::<BufferedResponseBody>() {
+        Some(buffered) => buffered,
+        None => return resp,
+    };
+
+    // headers of untouched body stay right, like `Content-Length` of HEAD responses
+    let headers = resp.headers_mut();
+    if buffered.modified {
+        headers.remove(TRANSFER_ENCODING);
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(buffered.bytes.len()));
+    }
+
+    let body = match buffered.permit {
+        Some(permit) if !buffered.bytes.is_empty() => {
+            use futures::StreamExt;
+
+            // length is lost by the stream, keep it unless the body is chunked
+            if !headers.contains_key(TRANSFER_ENCODING) && !headers.contains_key(CONTENT_LENGTH) {
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(buffered.bytes.len()));
+            }
+
+            // the permit goes with the body, released once the body is sent or dropped
+            let chunks =
+                futures::stream::iter(Some(Ok::<_, std::convert::Infallible>(buffered.bytes)));
+            hyper::Body::wrap_stream(chunks.map(move |chunk| {
+                let _permit = &permit;
+                chunk
+            }))
+        }
+        _ => hyper::Body::from(buffered.bytes),
+    };
+    *resp.body_mut() = body;
+
+    resp
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Body;
+
+    use super::*;
+
+    fn chunked(chunks: Vec<&'static str>) -> HyperResponse {
+        hyper::Response::builder()
+            .header(TRANSFER_ENCODING, "chunked")
+            .body(Body::wrap_stream(futures::stream::iter(
+                chunks.into_iter().map(Ok::<_, std::io::Error>),
+            )))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rewrite_response_body() {
+        let mut resp = chunked(vec!["hello, ", "world"]);
+
+        // two plugins rewriting in turn, the second sees output of the first
+        let body = buffer_response_body(&mut resp, 64).await.unwrap().unwrap();
+        set_response_body(&mut resp, body.to_ascii_uppercase());
+
+        let body = buffer_response_body(&mut resp, 64).await.unwrap().unwrap();
+        assert_eq!(&body[..], b"HELLO, WORLD");
+        set_response_body(&mut resp, format!("[{}]", String::from_utf8_lossy(&body)));
+
+        let resp = finish_response_body(resp);
+        assert_eq!(resp.headers()[CONTENT_LENGTH], "14");
+        assert!(!resp.headers().contains_key(TRANSFER_ENCODING));
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"[HELLO, WORLD]");
+
+        // read only, headers kept
+        let mut resp = chunked(vec!["hello"]);
+        assert!(buffer_response_body(&mut resp, 64).await.unwrap().is_some());
+        let resp = finish_response_body(resp);
+        assert_eq!(resp.headers()[TRANSFER_ENCODING], "chunked");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+    }
+
+    #[tokio::test]
+    async fn stream_large_response_body() {
+        let mut resp = chunked(vec!["hello, ", "world"]);
+
+        assert_eq!(buffer_response_body(&mut resp, 4).await.unwrap(), None);
+        assert!(resp.extensions().get::<StreamedResponseBody>().is_some());
+        assert_eq!(buffer_response_body(&mut resp, 64).await.unwrap(), None);
+
+        let resp = finish_response_body(resp);
+        assert_eq!(resp.headers()[TRANSFER_ENCODING], "chunked");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello, world");
+    }
+
+    #[tokio::test]
+    async fn response_body_budget() {
+        let budget: &'static MemoryBudget = Box::leak(Box::new(MemoryBudget::new(16)));
+
+        let mut resp = chunked(vec!["hello, ", "world"]);
+        let body = buffer_response_body_in(budget, &mut resp, 64)
+            .await
+            .unwrap();
+        assert_eq!(budget.used(), 12);
+        set_response_body(&mut resp, body.unwrap().to_ascii_uppercase());
+        assert_eq!(budget.used(), 12);
+
+        // no room for another
+        let mut other = chunked(vec!["hello"]);
+        assert!(matches!(
+            buffer_response_body_in(budget, &mut other, 64).await,
+            Err(BufferError::BudgetExhausted)
+        ));
+        let body = hyper::body::to_bytes(other.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+
+        // held until the body is sent
+        let resp = finish_response_body(resp);
+        assert_eq!(budget.used(), 12);
+        assert_eq!(resp.headers()[CONTENT_LENGTH], "12");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"HELLO, WORLD");
+        assert_eq!(budget.used(), 0);
+    }
+}
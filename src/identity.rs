@@ -0,0 +1,104 @@
+//! Identity found by auth plugins, asserted to upstream by headers only gateway sets.
+
+use hyper::{header::HeaderValue, HeaderMap};
+
+use crate::context::{Consumer, GatewayContext};
+use crate::plugins::{ApiKeyName, TokenIntrospection};
+
+pub const X_CONSUMER_ID: &str = "x-consumer-id";
+pub const X_CONSUMER_NAME: &str = "x-consumer-name";
+pub const X_AUTH_SUBJECT: &str = "x-auth-subject";
+/// space separated, as in oauth2
+pub const X_AUTH_SCOPES: &str = "x-auth-scopes";
+
+pub const IDENTITY_HEADERS: &[&str] = &[
+    X_CONSUMER_ID,
+    X_CONSUMER_NAME,
+    X_AUTH_SUBJECT,
+    X_AUTH_SCOPES,
+];
+
+/// Remove identity headers sent by client, upstream should trust only the ones of gateway.
+pub fn strip_identity_headers(headers: &mut HeaderMap) {
+    for name in IDENTITY_HEADERS {
+        headers.remove(*name);
+    }
+}
+
+/// Set identity headers from what auth plugins stored in context.
+pub fn forward_identity(ctx: &GatewayContext, headers: &mut HeaderMap) {
+    strip_identity_headers(headers);
+
+    let introspection = ctx.get::<TokenIntrospection>();
+
+    let consumer = ctx.get::<Consumer>().map(|c| c.0.as_str());
+    let key_name = ctx.get::<ApiKeyName>().map(|n| n.0.as_str());
+    let subject = introspection.and_then(|i| i.sub.as_deref());
+    let scopes = introspection.and_then(|i| i.scope.as_deref());
+
+    let values = [
+        (X_CONSUMER_ID, consumer),
+        (X_CONSUMER_NAME, key_name),
+        (X_AUTH_SUBJECT, subject),
+        (X_AUTH_SCOPES, scopes),
+    ];
+
+    for (name, value) in values {
+        let value = match value.map(HeaderValue::from_str) {
+            Some(Ok(value)) => value,
+            Some(Err(_)) => {
+                tracing::warn!(route_id = ?ctx.route_id, header = name, "invalid identity value");
+                continue;
+            }
+            None => continue,
+        };
+
+        headers.insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    #[test]
+    fn identity_headers() {
+        let req = hyper::Request::builder()
+            .header(X_CONSUMER_ID, "admin")
+            .header(X_AUTH_SCOPES, "root")
+            .header("x-other", "1")
+            .body(Body::empty())
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        let (mut parts, _) = req.into_parts();
+
+        // nothing authenticated, spoofed headers are dropped
+        forward_identity(&ctx, &mut parts.headers);
+        for name in IDENTITY_HEADERS {
+            assert!(!parts.headers.contains_key(*name), "{}", name);
+        }
+        assert_eq!(parts.headers["x-other"], "1");
+
+        ctx.set(Consumer("user-1".to_string()));
+        ctx.set(TokenIntrospection {
+            sub: Some("user-1".to_string()),
+            scope: Some("read write".to_string()),
+        });
+        forward_identity(&ctx, &mut parts.headers);
+        assert_eq!(parts.headers[X_CONSUMER_ID], "user-1");
+        assert_eq!(parts.headers[X_AUTH_SUBJECT], "user-1");
+        assert_eq!(parts.headers[X_AUTH_SCOPES], "read write");
+        assert!(!parts.headers.contains_key(X_CONSUMER_NAME));
+
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &hyper::Request::new(Body::empty()));
+        ctx.set(Consumer("mobile".to_string()));
+        ctx.set(ApiKeyName("mobile".to_string()));
+        forward_identity(&ctx, &mut parts.headers);
+        assert_eq!(parts.headers[X_CONSUMER_ID], "mobile");
+        assert_eq!(parts.headers[X_CONSUMER_NAME], "mobile");
+        assert!(!parts.headers.contains_key(X_AUTH_SUBJECT));
+        assert!(!parts.headers.contains_key(X_AUTH_SCOPES));
+    }
+}
@@ -0,0 +1,140 @@
+//! Build and runtime information, the first thing to ask for when triaging an issue.
+
+use serde::Serialize;
+
+use crate::registry::RegistryConfig;
+use crate::server::ServerContext;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// short sha of the built commit, `unknown` when built without git
+pub const GIT_SHA: &str = env!("APIRECEPTION_GIT_SHA");
+
+/// Enabled cargo features.
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+
+    if cfg!(feature = "profiling") {
+        features.push("profiling");
+    }
+    if cfg!(feature = "console") {
+        features.push("console");
+    }
+    if cfg!(feature = "redis-store") {
+        features.push("redis-store");
+    }
+    if cfg!(feature = "native-tls") {
+        features.push("native-tls");
+    }
+
+    features
+}
+
+pub fn version() -> String {
+    format!("apireception {} ({})", VERSION, GIT_SHA)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Listeners {
+    pub http: String,
+    /// absent without tls config
+    pub https: Option<String>,
+    pub admin: Option<String>,
+}
+
+impl Listeners {
+    pub fn new(srv_ctx: &ServerContext) -> Self {
+        Listeners {
+            http: srv_ctx.http_addr.to_string(),
+            https: srv_ctx
+                .tls_acceptor
+                .as_ref()
+                .map(|_| srv_ctx.https_addr.to_string()),
+            admin: srv_ctx.adminapi_addr.map(|addr| addr.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GatewayInfo {
+    pub version: &'static str,
+    pub git_sha: &'static str,
+    pub features: Vec<&'static str>,
+    /// registry provider, like `file` or `etcd`
+    pub provider: &'static str,
+    pub listeners: Listeners,
+    pub routes: usize,
+    pub upstreams: usize,
+    /// plugins configured on all routes
+    pub plugins: usize,
+}
+
+impl GatewayInfo {
+    pub fn new(provider: &'static str, listeners: Listeners, registry: &RegistryConfig) -> Self {
+        GatewayInfo {
+            version: VERSION,
+            git_sha: GIT_SHA,
+            features: features(),
+            provider,
+            listeners,
+            routes: registry.routes.len(),
+            upstreams: registry.upstreams.len(),
+            plugins: registry.routes.iter().map(|r| r.plugins.len()).sum(),
+        }
+    }
+}
+
+/// Log version and where the gateway listens, once servers are ready.
+pub fn log_banner(srv_ctx: &ServerContext) {
+    let listeners = Listeners::new(srv_ctx);
+
+    tracing::info!(
+        version = VERSION,
+        git_sha = GIT_SHA,
+        features = ?features(),
+        provider = srv_ctx.config.registry_provider.kind(),
+        http = %listeners.http,
+        https = ?listeners.https,
+        admin = ?listeners.admin,
+        "{} started",
+        version()
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{PluginConfig, RouteConfig};
+
+    #[test]
+    fn gateway_info() {
+        let mut route = RouteConfig {
+            id: "hello".to_string(),
+            ..Default::default()
+        };
+        route.plugins.insert(
+            "mock".to_string(),
+            PluginConfig {
+                enable: true,
+                when: None,
+                order: None,
+                config: serde_json::json!({}),
+            },
+        );
+        let registry = RegistryConfig {
+            routes: vec![route.clone(), route],
+            ..Default::default()
+        };
+        let listeners = Listeners {
+            http: "0.0.0.0:80".to_string(),
+            https: None,
+            admin: None,
+        };
+
+        let info = GatewayInfo::new("file", listeners, &registry);
+        assert_eq!((info.routes, info.upstreams, info.plugins), (2, 0, 2));
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_sha.is_empty());
+        assert!(version().contains(GIT_SHA));
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::header::{HeaderMap, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::context::GatewayContext;
+use crate::http::{HyperRequest, HyperResponse, X_REQUEST_ID};
+
+/// entries kept, older ones are dropped
+const CAPACITY: usize = 1000;
+
+lazy_static::lazy_static! {
+    static ref G_JOURNAL: RequestJournal = RequestJournal::default();
+}
+
+pub fn journal() -> &'static RequestJournal {
+    &G_JOURNAL
+}
+
+/// Capture requests of a route, or with request id matching a pattern, until expired.
+#[derive(Debug, Clone)]
+struct Capture {
+    route_id: Option<String>,
+    request_id: Option<Regex>,
+    until: Instant,
+}
+
+impl Capture {
+    fn matchs(&self, route_id: Option<&str>, request_id: Option<&str>) -> bool {
+        let route_matched = match self.route_id {
+            Some(ref expect) => route_id == Some(expect.as_str()),
+            None => true,
+        };
+
+        let request_id_matched = match self.request_id {
+            Some(ref pattern) => request_id.map(|id| pattern.is_match(id)).unwrap_or(false),
+            None => true,
+        };
+
+        route_matched && request_id_matched
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureInfo {
+    pub route_id: Option<String>,
+    pub request_id: Option<String>,
+    pub remaining_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalEntry {
+    /// unix time in milliseconds
+    pub time: u64,
+    pub request_id: Option<String>,
+    pub route_id: Option<String>,
+    pub remote_addr: Option<String>,
+    pub method: String,
+    pub uri: String,
+    pub request_headers: Vec<(String, String)>,
+    pub status: u16,
+    pub response_headers: Vec<(String, String)>,
+    pub latency_ms: u64,
+}
+
+/// Request side of an entry, taken before the request is consumed.
+pub struct PendingEntry {
+    time: u64,
+    start: Instant,
+    request_id: Option<String>,
+    remote_addr: Option<String>,
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+}
+
+impl PendingEntry {
+    pub fn new(ctx: &GatewayContext, req: &HyperRequest) -> Self {
+        PendingEntry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            start: Instant::now(),
+            request_id: req
+                .headers()
+                .get(X_REQUEST_ID)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string()),
+            remote_addr: ctx.remote_addr().map(|addr| addr.to_string()),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            headers: redacted_headers(req.headers()),
+        }
+    }
+}
+
+/// Recent requests captured for live debugging, in a bounded ring buffer.
+#[derive(Debug, Default)]
+pub struct RequestJournal {
+    active: AtomicBool,
+    captures: Mutex<Vec<Capture>>,
+    entries: Mutex<VecDeque<JournalEntry>>,
+}
+
+impl RequestJournal {
+    pub fn capture(
+        &self,
+        route_id: Option<String>,
+        request_id: Option<&str>,
+        duration: Duration,
+    ) -> Result<(), regex::Error> {
+        let request_id = request_id.map(Regex::new).transpose()?;
+
+        self.captures.lock().unwrap().push(Capture {
+            route_id,
+            request_id,
+            until: Instant::now() + duration,
+        });
+        self.active.store(true, Ordering::Release);
+
+        Ok(())
+    }
+
+    pub fn stop(&self) {
+        self.captures.lock().unwrap().clear();
+        self.active.store(false, Ordering::Release);
+    }
+
+    /// Cheap check before taking anything from request.
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    pub fn record(&self, pending: PendingEntry, route_id: Option<&str>, resp: &HyperResponse) {
+        if !self.should_capture(route_id, pending.request_id.as_deref()) {
+            return;
+        }
+
+        let entry = JournalEntry {
+            time: pending.time,
+            request_id: pending.request_id,
+            route_id: route_id.map(|id| id.to_string()),
+            remote_addr: pending.remote_addr,
+            method: pending.method,
+            uri: pending.uri,
+            request_headers: pending.headers,
+            status: resp.status().as_u16(),
+            response_headers: redacted_headers(resp.headers()),
+            latency_ms: pending.start.elapsed().as_millis() as u64,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Entries from the oldest.
+    pub fn entries(&self) -> Vec<JournalEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn captures(&self) -> Vec<CaptureInfo> {
+        let now = Instant::now();
+
+        self.captures
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.until > now)
+            .map(|c| CaptureInfo {
+                route_id: c.route_id.clone(),
+                request_id: c.request_id.as_ref().map(|r| r.as_str().to_string()),
+                remaining_secs: (c.until - now).as_secs(),
+            })
+            .collect()
+    }
+
+    fn should_capture(&self, route_id: Option<&str>, request_id: Option<&str>) -> bool {
+        let now = Instant::now();
+        let mut captures = self.captures.lock().unwrap();
+
+        captures.retain(|c| c.until > now);
+        if captures.is_empty() {
+            self.active.store(false, Ordering::Release);
+            return false;
+        }
+
+        captures.iter().any(|c| c.matchs(route_id, request_id))
+    }
+}
+
+fn redacted_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE].contains(name) {
+                "<redacted>".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).to_string()
+            };
+
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    fn record(journal: &RequestJournal, route_id: &str, request_id: &str) {
+        let req = hyper::Request::builder()
+            .uri("/orders")
+            .header(X_REQUEST_ID, request_id)
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        journal.record(
+            PendingEntry::new(&ctx, &req),
+            Some(route_id),
+            &HyperResponse::default(),
+        );
+    }
+
+    #[test]
+    fn capture_requests() {
+        let journal = RequestJournal::default();
+        assert!(!journal.is_active());
+
+        journal
+            .capture(Some("orders".to_string()), None, Duration::from_secs(60))
+            .unwrap();
+        journal
+            .capture(None, Some("^debug-"), Duration::from_secs(60))
+            .unwrap();
+        assert!(journal.is_active());
+
+        record(&journal, "orders", "a1");
+        record(&journal, "users", "debug-b2");
+        record(&journal, "users", "c3");
+
+        let entries = journal.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].route_id.as_deref(), Some("orders"));
+        assert_eq!(entries[1].request_id.as_deref(), Some("debug-b2"));
+        assert!(entries[0]
+            .request_headers
+            .contains(&("authorization".to_string(), "<redacted>".to_string())));
+
+        journal.stop();
+        assert!(!journal.is_active());
+        assert!(journal.capture(None, Some("("), Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn capture_expired() {
+        let journal = RequestJournal::default();
+        journal
+            .capture(Some("orders".to_string()), None, Duration::ZERO)
+            .unwrap();
+
+        record(&journal, "orders", "a1");
+
+        assert!(journal.entries().is_empty());
+        assert!(journal.captures().is_empty());
+        assert!(!journal.is_active());
+    }
+}
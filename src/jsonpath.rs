@@ -0,0 +1,178 @@
+use serde_json::{Map, Value};
+
+use crate::error::ConfigError;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Field(String),
+    Index(usize),
+    /// all elements of array or values of object
+    Wildcard,
+}
+
+/// Subset of JSONPath, like `$.data.items[0]`, `$['user name']` and `$.items[*].id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    pub fn parse(s: &str) -> Result<JsonPath, ConfigError> {
+        let err = || ConfigError::Message(format!("invalid json path<{}>", s));
+
+        let mut rest = s.trim().strip_prefix('$').ok_or_else(err)?;
+        let mut segments = Vec::new();
+
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix('.') {
+                let end = r.find(|c: char| c == '.' || c == '[').unwrap_or(r.len());
+                let name = &r[..end];
+
+                segments.push(match name {
+                    "" => return Err(err()),
+                    "*" => Segment::Wildcard,
+                    name => Segment::Field(name.to_string()),
+                });
+                rest = &r[end..];
+            } else if let Some(r) = rest.strip_prefix('[') {
+                let end = r.find(']').ok_or_else(err)?;
+                let inner = r[..end].trim();
+
+                let quoted = |q: char| inner.strip_prefix(q).and_then(|n| n.strip_suffix(q));
+
+                segments.push(if inner == "*" {
+                    Segment::Wildcard
+                } else if let Some(name) = quoted('\'').or_else(|| quoted('"')) {
+                    Segment::Field(name.to_string())
+                } else {
+                    Segment::Index(inner.parse().map_err(|_| err())?)
+                });
+                rest = &r[end + 1..];
+            } else {
+                return Err(err());
+            }
+        }
+
+        Ok(JsonPath { segments })
+    }
+
+    /// Selected value, `null` when missing, wildcard yields an array.
+    pub fn select(&self, value: &Value) -> Value {
+        select(&self.segments, value)
+    }
+}
+
+fn select(segments: &[Segment], value: &Value) -> Value {
+    let (segment, rest) = match segments.split_first() {
+        Some(s) => s,
+        None => return value.clone(),
+    };
+
+    match segment {
+        Segment::Field(name) => value
+            .get(name.as_str())
+            .map(|v| select(rest, v))
+            .unwrap_or(Value::Null),
+        Segment::Index(i) => value.get(*i).map(|v| select(rest, v)).unwrap_or(Value::Null),
+        Segment::Wildcard => match value {
+            Value::Array(items) => items.iter().map(|v| select(rest, v)).collect(),
+            Value::Object(map) => map.values().map(|v| select(rest, v)).collect(),
+            _ => Value::Null,
+        },
+    }
+}
+
+/// Json value whose strings starting with `$` are json paths, others are kept as is.
+/// Strings starting with `$$` are literals with one `$` removed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonTemplate {
+    Path(JsonPath),
+    Literal(Value),
+    Array(Vec<JsonTemplate>),
+    Object(Vec<(String, JsonTemplate)>),
+}
+
+impl JsonTemplate {
+    pub fn parse(value: &Value) -> Result<JsonTemplate, ConfigError> {
+        let tpl = match value {
+            Value::String(s) if s.starts_with("$$") => {
+                JsonTemplate::Literal(Value::String(s[1..].to_string()))
+            }
+            Value::String(s) if s.starts_with('$') => JsonTemplate::Path(JsonPath::parse(s)?),
+            Value::Array(items) => JsonTemplate::Array(
+                items
+                    .iter()
+                    .map(JsonTemplate::parse)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Value::Object(map) => JsonTemplate::Object(
+                map.iter()
+                    .map(|(k, v)| Ok((k.clone(), JsonTemplate::parse(v)?)))
+                    .collect::<Result<_, ConfigError>>()?,
+            ),
+            v => JsonTemplate::Literal(v.clone()),
+        };
+
+        Ok(tpl)
+    }
+
+    pub fn render(&self, input: &Value) -> Value {
+        match self {
+            JsonTemplate::Path(path) => path.select(input),
+            JsonTemplate::Literal(v) => v.clone(),
+            JsonTemplate::Array(items) => items.iter().map(|t| t.render(input)).collect(),
+            JsonTemplate::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(k, t)| (k.clone(), t.render(input)))
+                    .collect::<Map<_, _>>(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn select_path() {
+        let input = json!({
+            "data": {
+                "user name": "tom",
+                "items": [{"id": 1}, {"id": 2}],
+            }
+        });
+
+        let select = |path: &str| JsonPath::parse(path).unwrap().select(&input);
+
+        assert_eq!(select("$"), input);
+        assert_eq!(select("$.data['user name']"), json!("tom"));
+        assert_eq!(select("$.data.items[1].id"), json!(2));
+        assert_eq!(select("$.data.items[*].id"), json!([1, 2]));
+        assert_eq!(select("$.data.missing.id"), Value::Null);
+
+        assert!(JsonPath::parse("data").is_err());
+        assert!(JsonPath::parse("$.data[x]").is_err());
+        assert!(JsonPath::parse("$..data").is_err());
+    }
+
+    #[test]
+    fn render_template() {
+        let tpl = JsonTemplate::parse(&json!({
+            "id": "$.user_id",
+            "names": ["$.profile.name", "$$literal"],
+            "version": 2,
+        }))
+        .unwrap();
+
+        let input = json!({"user_id": 42, "profile": {"name": "tom"}});
+
+        assert_eq!(
+            tpl.render(&input),
+            json!({"id": 42, "names": ["tom", "$literal"], "version": 2})
+        );
+    }
+}
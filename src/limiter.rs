@@ -0,0 +1,127 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Business priority of route, lower classes are shed first under overload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriorityClass {
+    High,
+    Normal,
+    Low,
+}
+
+impl Default for PriorityClass {
+    fn default() -> Self {
+        PriorityClass::Normal
+    }
+}
+
+impl PriorityClass {
+    /// Share of the concurrency limit the class may occupy.
+    fn share(self) -> f64 {
+        match self {
+            PriorityClass::High => 1.0,
+            PriorityClass::Normal => 0.9,
+            PriorityClass::Low => 0.7,
+        }
+    }
+}
+
+/// In-flight request limit of an upstream, a limit of 0 means unlimited.
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    limit: usize,
+    in_flight: AtomicUsize,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(limit: usize) -> Self {
+        ConcurrencyLimiter {
+            limit,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Admit a request of `class`, the slot is released when the permit dropped.
+    pub fn try_acquire(self: &Arc<Self>, class: PriorityClass) -> Option<LimiterPermit> {
+        let threshold = ((self.limit as f64 * class.share()) as usize).max(1);
+
+        let mut in_flight = self.in_flight.load(Ordering::Relaxed);
+        loop {
+            if self.limit != 0 && in_flight >= threshold {
+                return None;
+            }
+
+            match self.in_flight.compare_exchange_weak(
+                in_flight,
+                in_flight + 1,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => in_flight = actual,
+            }
+        }
+
+        Some(LimiterPermit {
+            limiter: self.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct LimiterPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+}
+
+impl Drop for LimiterPermit {
+    fn drop(&mut self) {
+        self.limiter.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shed_low_priority_first() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(10));
+
+        let low = (0..7)
+            .map(|_| limiter.try_acquire(PriorityClass::Low).unwrap())
+            .collect::<Vec<_>>();
+        assert!(limiter.try_acquire(PriorityClass::Low).is_none());
+
+        let normal = (0..2)
+            .map(|_| limiter.try_acquire(PriorityClass::Normal).unwrap())
+            .collect::<Vec<_>>();
+        assert!(limiter.try_acquire(PriorityClass::Normal).is_none());
+
+        let high = limiter.try_acquire(PriorityClass::High).unwrap();
+        assert!(limiter.try_acquire(PriorityClass::High).is_none());
+        assert_eq!(limiter.in_flight(), 10);
+
+        drop(low);
+        drop(normal);
+        drop(high);
+        assert_eq!(limiter.in_flight(), 0);
+
+        let unlimited = Arc::new(ConcurrencyLimiter::new(0));
+        let _permits = (0..100)
+            .map(|_| unlimited.try_acquire(PriorityClass::Low).unwrap())
+            .collect::<Vec<_>>();
+    }
+}
@@ -1,4 +1,10 @@
-use std::{collections::HashMap, sync::RwLock};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+};
 
 use hyper::Uri;
 use rand::{thread_rng, Rng};
@@ -6,12 +12,34 @@ use rand::{thread_rng, Rng};
 use crate::{context::GatewayContext, http::HyperRequest};
 
 pub trait LoadBalanceStrategy: Send + Sync + std::fmt::Debug {
-    fn select_endpoint<'a>(&self, ctx: &'a GatewayContext, req: &HyperRequest) -> &'a Uri;
-    fn on_send_request(&self, ctx: &GatewayContext, endpoint: &Uri) {
+    /// `None` when no endpoint available.
+    fn select_endpoint<'a>(&self, ctx: &'a GatewayContext, req: &HyperRequest) -> Option<&'a Uri>;
+    /// The request is done when the returned guard dropped, also when it is cancelled.
+    fn on_send_request<'a>(&'a self, ctx: &GatewayContext, endpoint: &Uri) -> InFlight<'a> {
         let _ = endpoint;
+        InFlight::untracked()
     }
-    fn on_request_done(&self, ctx: &GatewayContext, endpoint: &Uri) {
-        let _ = endpoint;
+}
+
+/// Request in flight to an endpoint, counted by strategy until dropped.
+#[must_use]
+pub struct InFlight<'a>(Option<Box<dyn FnOnce() + Send + 'a>>);
+
+impl<'a> InFlight<'a> {
+    pub fn new(done: impl FnOnce() + Send + 'a) -> Self {
+        InFlight(Some(Box::new(done)))
+    }
+
+    pub fn untracked() -> Self {
+        InFlight(None)
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(done) = self.0.take() {
+            done();
+        }
     }
 }
 
@@ -25,10 +53,14 @@ impl Random {
 }
 
 impl LoadBalanceStrategy for Random {
-    fn select_endpoint<'a>(&self, ctx: &'a GatewayContext, req: &HyperRequest) -> &'a Uri {
+    fn select_endpoint<'a>(&self, ctx: &'a GatewayContext, req: &HyperRequest) -> Option<&'a Uri> {
+        if ctx.available_endpoints.is_empty() {
+            return None;
+        }
+
         let index = thread_rng().gen_range(0..ctx.available_endpoints.len());
 
-        &ctx.available_endpoints[index].target
+        Some(&ctx.available_endpoints[index].target)
     }
 }
 
@@ -42,19 +74,23 @@ impl WeightedRandom {
 }
 
 impl LoadBalanceStrategy for WeightedRandom {
-    fn select_endpoint<'a>(&self, ctx: &'a GatewayContext, req: &HyperRequest) -> &'a Uri {
+    fn select_endpoint<'a>(&self, ctx: &'a GatewayContext, req: &HyperRequest) -> Option<&'a Uri> {
         let total_weigth = ctx
             .available_endpoints
             .iter()
             .fold(0, |sum, a| sum + a.weight);
 
+        if total_weigth == 0 {
+            return None;
+        }
+
         let random = thread_rng().gen_range(0..total_weigth);
 
         let mut curr = 0;
         for ep in &ctx.available_endpoints {
             curr += ep.weight;
             if random < curr {
-                return &ep.target;
+                return Some(&ep.target);
             }
         }
 
@@ -62,6 +98,96 @@ impl LoadBalanceStrategy for WeightedRandom {
     }
 }
 
+/// Endpoints in turn, the counter is taken modulo the current available endpoints,
+/// as they may change between requests.
+#[derive(Debug)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        RoundRobin {
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalanceStrategy for RoundRobin {
+    fn select_endpoint<'a>(&self, ctx: &'a GatewayContext, req: &HyperRequest) -> Option<&'a Uri> {
+        if ctx.available_endpoints.is_empty() {
+            return None;
+        }
+
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % ctx.available_endpoints.len();
+
+        Some(&ctx.available_endpoints[index].target)
+    }
+}
+
+/// Power of two choices, the less busy one of two random endpoints. In-flight counters
+/// are created for all endpoints of upstream up front, so no lock is taken.
+#[derive(Debug)]
+pub struct P2C {
+    in_flight: HashMap<Uri, AtomicUsize>,
+}
+
+impl P2C {
+    pub fn new(endpoints: impl IntoIterator<Item = Uri>) -> Self {
+        P2C {
+            in_flight: endpoints
+                .into_iter()
+                .map(|uri| (uri, AtomicUsize::new(0)))
+                .collect(),
+        }
+    }
+
+    fn in_flight(&self, endpoint: &Uri) -> usize {
+        self.in_flight
+            .get(endpoint)
+            .map(|n| n.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+}
+
+impl LoadBalanceStrategy for P2C {
+    fn select_endpoint<'a>(&self, ctx: &'a GatewayContext, req: &HyperRequest) -> Option<&'a Uri> {
+        let endpoints = &ctx.available_endpoints;
+
+        match endpoints.len() {
+            0 => return None,
+            1 => return Some(&endpoints[0].target),
+            _ => {}
+        }
+
+        let mut rng = thread_rng();
+        let a = rng.gen_range(0..endpoints.len());
+        let mut b = rng.gen_range(0..endpoints.len() - 1);
+        if b >= a {
+            b += 1;
+        }
+
+        let (a, b) = (&endpoints[a].target, &endpoints[b].target);
+        if self.in_flight(b) < self.in_flight(a) {
+            Some(b)
+        } else {
+            Some(a)
+        }
+    }
+
+    fn on_send_request<'a>(&'a self, ctx: &GatewayContext, endpoint: &Uri) -> InFlight<'a> {
+        match self.in_flight.get(endpoint) {
+            Some(n) => {
+                n.fetch_add(1, Ordering::Relaxed);
+                InFlight::new(move || {
+                    n.fetch_sub(1, Ordering::Relaxed);
+                })
+            }
+            None => InFlight::untracked(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct LeastRequest {
     connections: RwLock<HashMap<Uri, usize>>,
@@ -76,7 +202,15 @@ impl LeastRequest {
 }
 
 impl LoadBalanceStrategy for LeastRequest {
-    fn select_endpoint<'a>(&self, context: &'a GatewayContext, req: &HyperRequest) -> &'a Uri {
+    fn select_endpoint<'a>(
+        &self,
+        context: &'a GatewayContext,
+        req: &HyperRequest,
+    ) -> Option<&'a Uri> {
+        if context.available_endpoints.is_empty() {
+            return None;
+        }
+
         let connections = self.connections.read().unwrap();
 
         let address_indices: Vec<usize> =
@@ -111,22 +245,23 @@ impl LoadBalanceStrategy for LeastRequest {
             };
 
         if address_indices.len() == 1 {
-            &context.available_endpoints[address_indices[0]].target
+            Some(&context.available_endpoints[address_indices[0]].target)
         } else {
             let index = thread_rng().gen_range(0..address_indices.len());
 
-            &context.available_endpoints[address_indices[index]].target
+            Some(&context.available_endpoints[address_indices[index]].target)
         }
     }
 
-    fn on_send_request(&self, ctx: &GatewayContext, endpoint: &Uri) {
+    fn on_send_request<'a>(&'a self, ctx: &GatewayContext, endpoint: &Uri) -> InFlight<'a> {
         let mut connections = self.connections.write().unwrap();
         *connections.entry(endpoint.clone()).or_insert(0) += 1;
-    }
 
-    fn on_request_done(&self, ctx: &GatewayContext, endpoint: &Uri) {
-        let mut connections = self.connections.write().unwrap();
-        *connections.entry(endpoint.clone()).or_insert(0) -= 1;
+        let endpoint = endpoint.clone();
+        InFlight::new(move || {
+            let mut connections = self.connections.write().unwrap();
+            *connections.entry(endpoint).or_insert(0) -= 1;
+        })
     }
 }
 
@@ -158,12 +293,13 @@ mod test {
         let req = HyperRequest::new("".into());
 
         let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        ctx.available_endpoints = endpoints;
 
         let weighted = WeightedRandom::new();
 
         let mut result: HashMap<&Uri, u32> = HashMap::new();
         for _ in 0..100000 {
-            let got = weighted.select_endpoint(&ctx, &req);
+            let got = weighted.select_endpoint(&ctx, &req).unwrap();
 
             result.entry(got).and_modify(|sum| *sum += 1).or_default();
         }
@@ -174,11 +310,148 @@ mod test {
 
         let mut result: HashMap<&Uri, u32> = HashMap::new();
         for _ in 0..1000 {
-            let got = random.select_endpoint(&ctx, &req);
+            let got = random.select_endpoint(&ctx, &req).unwrap();
 
             result.entry(got).and_modify(|sum| *sum += 1).or_default();
         }
 
         println!("random ret= {:?}", result);
     }
+
+    #[test]
+    fn test_round_robin() {
+        let req = HyperRequest::new("".into());
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        ctx.available_endpoints = ["http://aaa.com/", "http://bbb.com/", "http://ccc.com/"]
+            .into_iter()
+            .map(|uri| Endpoint {
+                target: Uri::from_static(uri),
+                weight: 1,
+            })
+            .collect();
+
+        let round_robin = RoundRobin::new();
+
+        let mut result: HashMap<&Uri, u32> = HashMap::new();
+        for _ in 0..9 {
+            let got = round_robin.select_endpoint(&ctx, &req).unwrap();
+
+            *result.entry(got).or_default() += 1;
+        }
+
+        assert_eq!(result.len(), 3);
+        assert!(result.values().all(|n| *n == 3));
+
+        // fewer endpoints available, e.g. one went down
+        ctx.available_endpoints.truncate(2);
+        let picked: Vec<_> = (0..4)
+            .map(|_| {
+                round_robin
+                    .select_endpoint(&ctx, &req)
+                    .unwrap()
+                    .host()
+                    .unwrap()
+            })
+            .collect();
+        assert_ne!(picked[0], picked[1]);
+        assert_eq!(picked[0], picked[2]);
+        assert_eq!(picked[1], picked[3]);
+    }
+
+    fn context_of(uris: &[&'static str], req: &HyperRequest) -> GatewayContext {
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, req);
+        ctx.available_endpoints = uris
+            .iter()
+            .map(|uri| Endpoint::new(Uri::from_static(*uri), 1))
+            .collect();
+        ctx
+    }
+
+    #[test]
+    fn test_p2c() {
+        let req = HyperRequest::new("".into());
+        let p2c = P2C::new(vec![
+            Uri::from_static("http://aaa.com/"),
+            Uri::from_static("http://bbb.com/"),
+        ]);
+
+        let ctx = context_of(&[], &req);
+        assert_eq!(p2c.select_endpoint(&ctx, &req), None);
+
+        let ctx = context_of(&["http://aaa.com/"], &req);
+        assert_eq!(p2c.select_endpoint(&ctx, &req).unwrap(), "http://aaa.com/");
+
+        // with two endpoints both are compared, the busy one is never picked
+        let ctx = context_of(&["http://aaa.com/", "http://bbb.com/"], &req);
+        let busy = Uri::from_static("http://aaa.com/");
+        let sent = p2c.on_send_request(&ctx, &busy);
+        for _ in 0..100 {
+            assert_eq!(p2c.select_endpoint(&ctx, &req).unwrap(), "http://bbb.com/");
+        }
+
+        // cancelled, e.g. by a timeout, before the response arrived
+        let cancelled = async move {
+            let _sent = sent;
+            futures::future::pending::<()>().await
+        };
+        drop(cancelled);
+        assert_eq!(p2c.in_flight(&busy), 0);
+    }
+
+    #[test]
+    fn test_p2c_concurrent() {
+        const URIS: [&str; 4] = [
+            "http://aaa.com/",
+            "http://bbb.com/",
+            "http://ccc.com/",
+            "http://ddd.com/",
+        ];
+
+        let p2c = std::sync::Arc::new(P2C::new(URIS.into_iter().map(Uri::from_static)));
+
+        let workers: Vec<_> = (0..8)
+            .map(|_| {
+                let p2c = p2c.clone();
+
+                std::thread::spawn(move || {
+                    let req = HyperRequest::new("".into());
+                    let ctx = context_of(&URIS, &req);
+                    let mut in_flight = std::collections::VecDeque::new();
+                    let mut picked: HashMap<Uri, u32> = HashMap::new();
+
+                    for _ in 0..2000 {
+                        let endpoint = p2c.select_endpoint(&ctx, &req).unwrap().clone();
+                        in_flight.push_back(p2c.on_send_request(&ctx, &endpoint));
+                        *picked.entry(endpoint).or_default() += 1;
+
+                        // keep some requests in flight
+                        if in_flight.len() > 16 {
+                            in_flight.pop_front();
+                        }
+                    }
+
+                    drop(in_flight);
+                    picked
+                })
+            })
+            .collect();
+
+        let mut result: HashMap<Uri, u32> = HashMap::new();
+        for worker in workers {
+            for (uri, n) in worker.join().unwrap() {
+                *result.entry(uri).or_default() += n;
+            }
+        }
+
+        // 16000 requests over 4 endpoints
+        assert_eq!(result.len(), 4);
+        assert!(
+            result.values().all(|n| (3200..=4800).contains(n)),
+            "{:?}",
+            result
+        );
+        assert!(URIS
+            .into_iter()
+            .all(|uri| p2c.in_flight(&Uri::from_static(uri)) == 0));
+    }
 }
@@ -1,21 +1,48 @@
-// mod adminapi;
+mod access_log;
+mod adminapi;
+mod aggregate;
+mod alert;
+mod budget;
+#[cfg(feature = "chaos")]
+mod chaos;
 mod config;
 mod context;
+mod daemon;
+mod diagnostics;
 mod error;
+mod expr;
+mod fixture;
 mod forwarder;
 mod health;
 mod http;
+mod identity;
+mod info;
+mod journal;
+mod jsonpath;
+mod limiter;
 mod load_balance;
 mod matcher;
+mod migration;
+mod outlier;
 mod peer_addr;
 mod plugins;
+mod protocol;
 mod registry;
 mod router;
+mod selector;
 mod server;
 mod services;
+mod slo;
+mod statsd;
+mod store;
+mod systemd;
+mod telemetry;
+mod tls;
 mod trace;
 mod upstream;
+mod variable;
 
+use std::future::Future;
 use std::process::exit;
 
 pub use error::{Error, Result};
@@ -23,27 +50,102 @@ pub use error::{Error, Result};
 use hyper::http::uri::Scheme;
 use server::Server;
 
+use crate::adminapi::AdminApi;
 use crate::server::ServerContext;
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+const CONFIG_PATH: &str = "config/config.yaml";
 
-    match run().await {
+fn main() {
+    match std::env::args().nth(1).as_deref() {
+        Some("--version") => {
+            println!("{}", info::version());
+            return;
+        }
+        Some("--test") => match run_fixtures() {
+            Ok(true) => return,
+            Ok(false) => exit(1),
+            Err(e) => {
+                println!("run tests error: {:?}", e);
+                exit(1);
+            }
+        },
+        _ => {}
+    }
+
+    #[cfg(windows)]
+    let ret = match std::env::args().nth(1).as_deref() {
+        Some("install-service") => daemon::service::install(),
+        Some("uninstall-service") => daemon::service::uninstall(),
+        Some(daemon::service::SERVICE_ARG) => daemon::service::run(),
+        _ => start(shutdown_signal()),
+    };
+
+    #[cfg(not(windows))]
+    let ret = start(shutdown_signal());
+
+    match ret {
         Ok(_) => {
             println!("server run done, exit...");
         }
         Err(e) => {
             println!("server run error: {:?}", e);
+            exit(1);
         }
     }
 }
 
-async fn run() -> Result<()> {
-    let cfg = config::Config::load_file("config/config.yaml")?;
+/// Load config and serve until `shutdown` resolved, then drain connections.
+fn start(shutdown: impl Future<Output = ()>) -> Result<()> {
+    let cfg = config::Config::load_file(CONFIG_PATH)?;
+
+    // fork before any thread started
+    let _pid_file = daemon::daemonize(&cfg.server.daemon)?;
+
+    let rt = tokio::runtime::Runtime::new()?;
+    {
+        // exporter of telemetry runs on the runtime
+        let _guard = rt.enter();
+        diagnostics::init_tracing(cfg.server.telemetry.as_ref())?;
+    }
+
+    let ret = rt.block_on(run(cfg, shutdown));
+    telemetry::shutdown();
+
+    ret
+}
+
+/// Run tests of registry without serving, `false` when any failed.
+fn run_fixtures() -> Result<bool> {
+    let cfg = config::Config::load_file(CONFIG_PATH)?;
+    let registry = registry::RegistryConfig::load(&cfg.registry_provider)?;
+
+    let results = tokio::runtime::Runtime::new()?.block_on(fixture::run_tests(&registry))?;
+
+    for result in &results {
+        if result.passed {
+            println!("ok      {}", result.name);
+        } else {
+            println!("FAILED  {}", result.name);
+            for failure in &result.failures {
+                println!("        {}", failure);
+            }
+        }
+    }
+
+    let failed = results.iter().filter(|r| !r.passed).count();
+    println!("{} passed, {} failed", results.len() - failed, failed);
 
+    Ok(failed == 0)
+}
+
+async fn run(cfg: config::Config, shutdown: impl Future<Output = ()>) -> Result<()> {
     tracing::debug!(?cfg, "load config done");
 
+    diagnostics::spawn_runtime_monitor(std::time::Duration::from_secs(
+        cfg.server.runtime_metrics_interval,
+    ));
+    alert::spawn_evaluator();
+
     let (drain_tx, drain_rx) = drain::channel();
     let srv_ctx = ServerContext::new(cfg, drain_rx).await?;
 
@@ -51,9 +153,19 @@ async fn run() -> Result<()> {
 
     let srv_ctx_cloned = srv_ctx.clone();
 
+    // servers report listen address when ready
+    let (ready_tx, mut ready_rx) = tokio::sync::mpsc::channel(2);
+    let mut servers = 1;
+
     // Serve HTTP
+    let http_ready = ready_tx.clone();
     tokio::spawn(async move {
-        let srv = Server::new(Scheme::HTTP, srv_ctx_cloned.registry_reader);
+        let srv = Server::new(
+            Scheme::HTTP,
+            srv_ctx_cloned.registry_reader,
+            srv_ctx_cloned.config.server.clone(),
+        )
+        .with_ready(http_ready);
         let ret = srv
             .run(srv_ctx_cloned.http_addr, srv_ctx_cloned.watch)
             .await;
@@ -69,32 +181,104 @@ async fn run() -> Result<()> {
         }
     });
 
-    // TODO: add serve https
-    // let srv_ctx_cloned = srv_ctx.clone();
-
-    // if srv_ctx_cloned.config.admin.enable {
-    //     let adminapi_addr = srv_ctx.adminapi_addr.unwrap();
-    //     tokio::spawn(async move {
-    //         let adminapi = AdminApi::new(srv_ctx_cloned);
-    //         match adminapi.run(adminapi_addr).await {
-    //             Ok(_) => {
-    //                 tracing::info!("adminapi server done");
-    //             }
-    //             Err(err) => {
-    //                 tracing::error!(?err, "adminapi server error");
-    //             }
-    //         }
-    //     });
-    // }
+    // Serve HTTPS
+    if let Some(tls_acceptor) = srv_ctx.tls_acceptor.clone() {
+        tls::spawn_expiry_monitor(
+            srv_ctx.certificates.clone(),
+            srv_ctx.config.server.cert_expiry_warn_days,
+        );
+
+        let srv_ctx_cloned = srv_ctx.clone();
+        let https_ready = ready_tx.clone();
+        servers += 1;
+
+        tokio::spawn(async move {
+            let srv = Server::new(
+                Scheme::HTTPS,
+                srv_ctx_cloned.registry_reader,
+                srv_ctx_cloned.config.server.clone(),
+            )
+            .with_tls(tls_acceptor)
+            .with_ready(https_ready);
+            let ret = srv
+                .run(srv_ctx_cloned.https_addr, srv_ctx_cloned.watch)
+                .await;
 
+            match ret {
+                Ok(_) => {
+                    tracing::info!("https server done");
+                }
+                Err(err) => {
+                    tracing::error!(?err, "https server error");
+                    exit(1);
+                }
+            }
+        });
+    }
+
+    let srv_ctx_cloned = srv_ctx.clone();
+
+    if let Some(adminapi_addr) = srv_ctx.adminapi_addr {
+        tokio::spawn(async move {
+            let adminapi = AdminApi::new(srv_ctx_cloned);
+            match adminapi.run(adminapi_addr).await {
+                Ok(_) => {
+                    tracing::info!("adminapi server done");
+                }
+                Err(err) => {
+                    tracing::error!(?err, "adminapi server error");
+                }
+            }
+        });
+    }
+
+    drop(ready_tx);
+    for _ in 0..servers {
+        if ready_rx.recv().await.is_none() {
+            break;
+        }
+    }
+    info::log_banner(&srv_ctx);
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog();
+
+    shutdown.await;
+    drop(srv_ctx.watch.ignore_signaled());
+
+    systemd::notify("STOPPING=1");
+    drain_tx.drain().await;
+
+    Ok(())
+}
+
+async fn shutdown_signal() {
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             println!("got ctrl_c, shutting down...");
-            let _shutdown = srv_ctx.watch.ignore_signaled();
+        }
+        _ = terminate() => {
+            println!("got terminate, shutting down...");
         }
     }
+}
 
-    drain_tx.drain().await;
+/// SIGTERM, sent by service managers like systemd on stop.
+#[cfg(unix)]
+async fn terminate() {
+    use tokio::signal::unix::{signal, SignalKind};
 
-    Ok(())
+    match signal(SignalKind::terminate()) {
+        Ok(mut sig) => {
+            sig.recv().await;
+        }
+        Err(err) => {
+            tracing::error!(?err, "listen SIGTERM failed");
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    futures::future::pending::<()>().await;
 }
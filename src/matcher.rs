@@ -1,16 +1,22 @@
-use headers::{Cookie, HeaderMapExt};
-use hyper::{header::HOST, Body, Method};
+use headers::{ContentType, Cookie, HeaderMapExt};
+use hyper::{header::HOST, http::uri::Scheme, Body, Method};
+use ipnet::IpNet;
 use nom::{
     branch::alt,
     bytes::{complete::tag, complete::take_while},
+    character::complete::char,
     combinator::{eof, map_res},
-    sequence::{delimited, separated_pair},
+    error::{context, ContextError, ErrorKind, FromExternalError, ParseError},
+    sequence::{delimited, preceded, separated_pair, terminated},
     IResult,
 };
 use regex::Regex;
-use std::{collections::HashMap, convert::TryFrom, ops::Deref};
+use std::{collections::HashMap, convert::TryFrom, net::IpAddr, ops::Deref};
 
+use crate::context::GatewayContext;
 use crate::error::MatcherParseError;
+use crate::expr::Expr;
+use crate::variable::Variable;
 
 const ESCAPE_CHARS: &str = r#"\'"()"#;
 
@@ -44,9 +50,18 @@ pub enum RouteMatcher {
     Host(String),
     HostRegexp(ComparableRegex),
     Path(String),
+    PathI(String),
     PathRegexp(ComparableRegex),
     Query(String, String),
     Cookie(String, String),
+    Header(String, String),
+    ContentType(String),
+    Scheme(Scheme),
+    /// network of client address
+    ClientIp(IpNet),
+    Var(Variable, String),
+    /// comparisons over request attributes, see `Expr`
+    Expr(Expr),
     And(Box<RouteMatcher>, Box<RouteMatcher>),
     Or(Box<RouteMatcher>, Box<RouteMatcher>),
     Empty,
@@ -58,20 +73,28 @@ impl RouteMatcher {
             return Ok(RouteMatcher::Empty);
         }
 
-        let (_i, matcher) = top_level(i).map_err(|e| MatcherParseError::new(e.to_string()))?;
+        let (_i, matcher) = top_level(i).map_err(|e| match e {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e.into_error(i),
+            nom::Err::Incomplete(_) => MatcherParseError::new("unexpected end", i, i.len()),
+        })?;
         Ok(matcher)
     }
 
-    pub fn matchs(&self, req: &hyper::Request<Body>) -> bool {
+    pub fn matchs(&self, ctx: &GatewayContext, req: &hyper::Request<Body>) -> bool {
         match self {
             RouteMatcher::Method(method) => req.method() == method,
-            RouteMatcher::Host(host) => req.headers().get(HOST).map(|h| h == host).unwrap_or(false),
+            RouteMatcher::Host(host) => req
+                .headers()
+                .get(HOST)
+                .and_then(|h| Some(host_matchs(host, h.to_str().ok()?)))
+                .unwrap_or(false),
             RouteMatcher::HostRegexp(host_regex) => req
                 .headers()
                 .get(HOST)
                 .and_then(|h| Some(host_regex.is_match(h.to_str().ok()?)))
                 .unwrap_or(false),
             RouteMatcher::Path(path) => req.uri().path() == path,
+            RouteMatcher::PathI(path) => req.uri().path().eq_ignore_ascii_case(path),
             RouteMatcher::PathRegexp(path_regex) => path_regex.is_match(req.uri().path()),
             RouteMatcher::Query(key, value) => {
                 let query_params: HashMap<String, String> = req
@@ -94,159 +117,598 @@ impl RouteMatcher {
                 .typed_get::<Cookie>()
                 .map(|cookie| cookie.get(key) == Some(value))
                 .unwrap_or(false),
-            RouteMatcher::And(lhs, rhs) => lhs.matchs(req) && rhs.matchs(req),
-            RouteMatcher::Or(lhs, rhs) => lhs.matchs(req) || rhs.matchs(req),
+            RouteMatcher::Header(name, value) => req
+                .headers()
+                .get_all(name.as_str())
+                .iter()
+                .any(|h| h.as_bytes() == value.as_bytes()),
+            RouteMatcher::ContentType(essence) => req
+                .headers()
+                .typed_get::<ContentType>()
+                .map(|ct| content_type_matchs(essence, mime::Mime::from(ct).essence_str()))
+                .unwrap_or(false),
+            RouteMatcher::Scheme(scheme) => ctx.orig_scheme() == scheme,
+            RouteMatcher::ClientIp(net) => ctx
+                .remote_addr()
+                .map(|addr| net.contains(&canonical_ip(addr.ip())))
+                .unwrap_or(false),
+            RouteMatcher::Var(var, value) => var.resolve(ctx, req).as_ref() == Some(value),
+            RouteMatcher::Expr(expr) => expr.evaluate(ctx, req),
+            RouteMatcher::And(lhs, rhs) => lhs.matchs(ctx, req) && rhs.matchs(ctx, req),
+            RouteMatcher::Or(lhs, rhs) => lhs.matchs(ctx, req) || rhs.matchs(ctx, req),
             RouteMatcher::Empty => true,
         }
     }
+
+    /// Describe the sub-expression which failed, `None` when matched.
+    pub fn explain(&self, ctx: &GatewayContext, req: &hyper::Request<Body>) -> Option<String> {
+        match self {
+            RouteMatcher::And(lhs, rhs) => lhs.explain(ctx, req).or_else(|| rhs.explain(ctx, req)),
+            RouteMatcher::Or(lhs, rhs) => {
+                let lhs = lhs.explain(ctx, req)?;
+                let rhs = rhs.explain(ctx, req)?;
+
+                Some(format!("({}) || ({})", lhs, rhs))
+            }
+            m if m.matchs(ctx, req) => None,
+            m => Some(format!("{:?}", m)),
+        }
+    }
+
+    /// Methods the request would match with, other conditions are checked against `req`.
+    pub fn allowed_methods(
+        &self,
+        ctx: &GatewayContext,
+        req: &hyper::Request<Body>,
+    ) -> AllowedMethods {
+        match self {
+            RouteMatcher::Method(method) => AllowedMethods::Only(vec![method.clone()]),
+            RouteMatcher::And(lhs, rhs) => lhs
+                .allowed_methods(ctx, req)
+                .intersect(rhs.allowed_methods(ctx, req)),
+            RouteMatcher::Or(lhs, rhs) => lhs
+                .allowed_methods(ctx, req)
+                .union(rhs.allowed_methods(ctx, req)),
+            m if m.matchs(ctx, req) => AllowedMethods::Any,
+            _ => AllowedMethods::Only(Vec::new()),
+        }
+    }
 }
 
-fn in_quotes(input: &str) -> IResult<&str, String> {
-    let mut ret = String::new();
-    let mut iter = input.chars().peekable();
-    let mut offset = 0;
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedMethods {
+    Any,
+    /// empty when nothing matches
+    Only(Vec<Method>),
+}
 
-    loop {
-        match iter.next() {
-            Some('\'') => {
-                return Ok((&input[offset..], ret));
+impl AllowedMethods {
+    pub fn intersect(self, other: AllowedMethods) -> AllowedMethods {
+        match (self, other) {
+            (AllowedMethods::Any, m) | (m, AllowedMethods::Any) => m,
+            (AllowedMethods::Only(a), AllowedMethods::Only(b)) => {
+                AllowedMethods::Only(a.into_iter().filter(|m| b.contains(m)).collect())
             }
-            Some('\\') => {
-                let ch = iter
-                    .peek()
-                    .ok_or(nom::Err::Incomplete(nom::Needed::Unknown))?;
-
-                if ESCAPE_CHARS.contains(*ch) {
-                    ret.push(iter.next().unwrap());
-                    offset += 1;
+        }
+    }
+
+    pub fn union(self, other: AllowedMethods) -> AllowedMethods {
+        match (self, other) {
+            (AllowedMethods::Any, _) | (_, AllowedMethods::Any) => AllowedMethods::Any,
+            (AllowedMethods::Only(mut a), AllowedMethods::Only(b)) => {
+                for m in b {
+                    if !a.contains(&m) {
+                        a.push(m);
+                    }
                 }
+                AllowedMethods::Only(a)
             }
-            Some(ch) => {
-                ret.push(ch);
+        }
+    }
+
+    pub fn contains(&self, method: &Method) -> bool {
+        match self {
+            AllowedMethods::Any => true,
+            AllowedMethods::Only(methods) => methods.contains(method),
+        }
+    }
+}
+
+impl RouteMatcher {
+    /// Whether no request can match both, `false` when not sure.
+    pub fn is_disjoint(&self, other: &RouteMatcher) -> bool {
+        use RouteMatcher as M;
+
+        match (self, other) {
+            (M::And(lhs, rhs), m) | (m, M::And(lhs, rhs)) => {
+                lhs.is_disjoint(m) || rhs.is_disjoint(m)
+            }
+            (M::Or(lhs, rhs), m) | (m, M::Or(lhs, rhs)) => {
+                lhs.is_disjoint(m) && rhs.is_disjoint(m)
             }
-            None => {
-                return Err(nom::Err::Incomplete(nom::Needed::Unknown));
+            (M::Method(a), M::Method(b)) => a != b,
+            (M::Host(a), M::Host(b)) => {
+                let (host_a, port_a) = split_host_port(a);
+                let (host_b, port_b) = split_host_port(b);
+
+                !host_a.eq_ignore_ascii_case(host_b)
+                    || matches!((port_a, port_b), (Some(pa), Some(pb)) if pa != pb)
+            }
+            (M::Path(a), M::Path(b)) => a != b,
+            (M::PathI(a), M::PathI(b)) | (M::Path(a), M::PathI(b)) | (M::PathI(a), M::Path(b)) => {
+                !a.eq_ignore_ascii_case(b)
+            }
+            (M::Query(k1, v1), M::Query(k2, v2))
+            | (M::Cookie(k1, v1), M::Cookie(k2, v2))
+            | (M::Header(k1, v1), M::Header(k2, v2)) => k1 == k2 && v1 != v2,
+            (M::ContentType(a), M::ContentType(b)) => {
+                !content_type_matchs(a, b) && !content_type_matchs(b, a)
             }
+            (M::Scheme(a), M::Scheme(b)) => a != b,
+            (M::ClientIp(a), M::ClientIp(b)) => !a.contains(b) && !b.contains(a),
+            (M::Var(n1, v1), M::Var(n2, v2)) => n1 == n2 && v1 != v2,
+            _ => false,
         }
-        offset += 1;
     }
 }
 
-fn key_value(i: &str) -> IResult<&str, (String, String)> {
-    separated_pair(parse_str, tag(","), parse_str)(i)
+/// Split `host[:port]`, take care of bracketed IPv6 literal like `[::1]:8080`.
+pub(crate) fn split_host_port(s: &str) -> (&str, Option<&str>) {
+    if s.starts_with('[') {
+        match s.find(']') {
+            Some(end) => (&s[..=end], s[end + 1..].strip_prefix(':')),
+            None => (s, None),
+        }
+    } else {
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') => (host, Some(port)),
+            _ => (s, None),
+        }
+    }
 }
 
-fn sp(i: &str) -> IResult<&str, &str> {
+/// `expect` is an essence like `application/json`, subtype or both may be `*`.
+pub(crate) fn content_type_matchs(expect: &str, essence: &str) -> bool {
+    match expect.split_once('/') {
+        Some(("*", "*")) => true,
+        Some((ty, "*")) => essence
+            .split_once('/')
+            .map(|(t, _)| t == ty)
+            .unwrap_or(false),
+        _ => expect == essence,
+    }
+}
+
+/// Treat ipv4-mapped ipv6 address as ipv4.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+/// Host compared case-insensitively, port only checked when the matcher has one.
+fn host_matchs(expect: &str, host: &str) -> bool {
+    let (expect_host, expect_port) = split_host_port(expect);
+    let (host, port) = split_host_port(host);
+
+    expect_host.eq_ignore_ascii_case(host) && (expect_port.is_none() || expect_port == port)
+}
+
+/// Parser error, keep the failure which made the most progress.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ParseFailure<'a> {
+    input: &'a str,
+    kind: FailureKind,
+    within: Option<&'static str>,
+}
+
+#[derive(Debug, PartialEq)]
+enum FailureKind {
+    Unknown,
+    Token(&'static str),
+    Expected(&'static str),
+    Invalid(String),
+}
+
+pub(crate) type PResult<'a, O> = IResult<&'a str, O, ParseFailure<'a>>;
+
+impl<'a> ParseFailure<'a> {
+    fn token(input: &'a str, token: &'static str) -> Self {
+        ParseFailure {
+            input,
+            kind: FailureKind::Token(token),
+            within: None,
+        }
+    }
+
+    fn expected(input: &'a str, expected: &'static str) -> Self {
+        ParseFailure {
+            input,
+            kind: FailureKind::Expected(expected),
+            within: None,
+        }
+    }
+
+    fn invalid(input: &'a str, message: String) -> Self {
+        ParseFailure {
+            input,
+            kind: FailureKind::Invalid(message),
+            within: None,
+        }
+    }
+
+    fn within(mut self, name: &'static str) -> Self {
+        self.within.get_or_insert(name);
+        self
+    }
+
+    pub(crate) fn into_error(self, full: &str) -> MatcherParseError {
+        let message = match (self.kind, self.within) {
+            (FailureKind::Token(")"), Some(name)) => {
+                format!("expected `)` after {} argument", name)
+            }
+            (FailureKind::Token(token), Some(name)) => format!("expected `{}` in {}", token, name),
+            (FailureKind::Token(token), None) => format!("expected `{}`", token),
+            (FailureKind::Expected(expected), Some(name)) => {
+                format!("expected {} in {}", expected, name)
+            }
+            (FailureKind::Expected(expected), None) => format!("expected {}", expected),
+            (FailureKind::Invalid(err), Some(name)) => format!("invalid {} argument, {}", name, err),
+            (FailureKind::Invalid(err), None) => err,
+            (FailureKind::Unknown, _) => "unknown matcher".to_string(),
+        };
+
+        // point right after the last accepted token
+        let offset = full[..full.len() - self.input.len()].trim_end().len();
+
+        MatcherParseError::new(message, full, offset)
+    }
+}
+
+impl<'a> ParseError<&'a str> for ParseFailure<'a> {
+    fn from_error_kind(input: &'a str, _kind: ErrorKind) -> Self {
+        ParseFailure {
+            input,
+            kind: FailureKind::Unknown,
+            within: None,
+        }
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(self, other: Self) -> Self {
+        if other.input.len() <= self.input.len() {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+impl<'a> ContextError<&'a str> for ParseFailure<'a> {
+    fn add_context(input: &'a str, ctx: &'static str, other: Self) -> Self {
+        if other.input.len() == input.len() && other.kind == FailureKind::Unknown {
+            ParseFailure::expected(input, ctx)
+        } else {
+            other
+        }
+    }
+}
+
+impl<'a, E: std::fmt::Display> FromExternalError<&'a str, E> for ParseFailure<'a> {
+    fn from_external_error(input: &'a str, _kind: ErrorKind, e: E) -> Self {
+        ParseFailure {
+            input,
+            kind: FailureKind::Invalid(e.to_string()),
+            within: None,
+        }
+    }
+}
+
+/// Content of a quoted string, until the unescaped `quote` which opened it.
+fn in_quotes<'a>(quote: char) -> impl FnMut(&'a str) -> PResult<'a, String> {
+    let closing = if quote == '"' { "closing `\"`" } else { "closing `'`" };
+
+    move |input: &'a str| {
+        let mut ret = String::new();
+        let mut iter = input.char_indices().peekable();
+
+        while let Some((offset, ch)) = iter.next() {
+            match ch {
+                ch if ch == quote => {
+                    return Ok((&input[offset..], ret));
+                }
+                '\\' => {
+                    let (_, ch) = iter.peek().ok_or_else(|| {
+                        nom::Err::Error(ParseFailure::expected(&input[input.len()..], closing))
+                    })?;
+
+                    if ESCAPE_CHARS.contains(*ch) {
+                        ret.push(*ch);
+                        iter.next();
+                    }
+                }
+                ch => {
+                    ret.push(ch);
+                }
+            }
+        }
+
+        Err(nom::Err::Error(ParseFailure::expected(
+            &input[input.len()..],
+            closing,
+        )))
+    }
+}
+
+pub(crate) fn expect<'a>(t: &'static str) -> impl FnMut(&'a str) -> PResult<'a, &'a str> {
+    move |i: &'a str| tag(t)(i).map_err(|e| e.map(|_| ParseFailure::token(i, t)))
+}
+
+/// `Name(args)`, failures after the opening parenthesis remember the matcher name.
+fn func<'a, O, F>(name: &'static str, mut args: F) -> impl FnMut(&'a str) -> PResult<'a, O>
+where
+    F: FnMut(&'a str) -> PResult<'a, O>,
+{
+    move |i: &'a str| {
+        let (i, _) = tag(name)(i)?;
+        let (i, _) = expect("(")(i)?;
+        let (i, o) = args(i).map_err(|e| e.map(|e| e.within(name)))?;
+        let (i, _) = expect(")")(i).map_err(|e| e.map(|e| e.within(name)))?;
+
+        Ok((i, o))
+    }
+}
+
+fn key_value(i: &str) -> PResult<(String, String)> {
+    separated_pair(parse_str, expect(","), parse_str)(i)
+}
+
+pub(crate) fn sp(i: &str) -> PResult<&str> {
     let chars = " \t\r\n";
 
     take_while(move |c| chars.contains(c))(i)
 }
 
-fn parse_single_quoted(input: &str) -> IResult<&str, String> {
-    delimited(tag("\'"), in_quotes, tag("\'"))(input)
+/// `'...'` or `"..."`, closed by the same quote which opened it.
+pub(crate) fn parse_quoted(input: &str) -> PResult<String> {
+    let (i, quote) = context("quoted string", alt((char('\''), char('"'))))(input)?;
+    let (i, s) = in_quotes(quote)(i)?;
+    let (i, _) = char(quote)(i)?;
+
+    Ok((i, s))
 }
 
-fn parse_str(i: &str) -> IResult<&str, String> {
-    delimited(sp, parse_single_quoted, sp)(i)
+fn parse_str(i: &str) -> PResult<String> {
+    delimited(sp, parse_quoted, sp)(i)
 }
 
-fn host(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, s) = delimited(tag("Host("), parse_str, tag(")"))(i)?;
+fn host(i: &str) -> PResult<RouteMatcher> {
+    let (i, s) = func("Host", parse_str)(i)?;
 
     Ok((i, RouteMatcher::Host(s)))
 }
 
-fn host_regexp(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, regexp) = map_res(
-        delimited(tag("HostRegexp("), parse_str, tag(")")),
-        |s: String| ComparableRegex::new(&s),
+/// `Host` already compares case-insensitively, `HostI` is accepted as an alias.
+fn host_i(i: &str) -> PResult<RouteMatcher> {
+    let (i, s) = func("HostI", parse_str)(i)?;
+
+    Ok((i, RouteMatcher::Host(s)))
+}
+
+fn host_regexp(i: &str) -> PResult<RouteMatcher> {
+    let (i, regexp) = func(
+        "HostRegexp",
+        map_res(parse_str, |s: String| ComparableRegex::new(&s)),
     )(i)?;
 
     Ok((i, RouteMatcher::HostRegexp(regexp)))
 }
 
-fn method(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, m) = map_res(
-        delimited(tag("Method("), parse_str, tag(")")),
-        |s: String| Method::try_from(s.as_str()),
+fn method(i: &str) -> PResult<RouteMatcher> {
+    let (i, m) = func(
+        "Method",
+        map_res(parse_str, |s: String| Method::try_from(s.as_str())),
     )(i)?;
 
     Ok((i, RouteMatcher::Method(m)))
 }
 
-fn path(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, s) = delimited(tag("Path("), parse_str, tag(")"))(i)?;
+fn path(i: &str) -> PResult<RouteMatcher> {
+    let (i, s) = func("Path", parse_str)(i)?;
 
     Ok((i, RouteMatcher::Path(s)))
 }
 
-fn path_regexp(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, regexp) = map_res(
-        delimited(tag("PathRegexp("), parse_str, tag(")")),
-        |s: String| ComparableRegex::new(&s),
+fn path_i(i: &str) -> PResult<RouteMatcher> {
+    let (i, s) = func("PathI", parse_str)(i)?;
+
+    Ok((i, RouteMatcher::PathI(s)))
+}
+
+fn path_regexp(i: &str) -> PResult<RouteMatcher> {
+    let (i, regexp) = func(
+        "PathRegexp",
+        map_res(parse_str, |s: String| ComparableRegex::new(&s)),
     )(i)?;
 
     Ok((i, RouteMatcher::PathRegexp(regexp)))
 }
 
-fn query(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, (k, v)) = delimited(tag("Query("), key_value, tag(")"))(i)?;
+fn query(i: &str) -> PResult<RouteMatcher> {
+    let (i, (k, v)) = func("Query", key_value)(i)?;
 
     Ok((i, RouteMatcher::Query(k, v)))
 }
 
-fn cookie(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, (k, v)) = delimited(tag("Cookie("), key_value, tag(")"))(i)?;
+fn cookie(i: &str) -> PResult<RouteMatcher> {
+    let (i, (k, v)) = func("Cookie", key_value)(i)?;
 
     Ok((i, RouteMatcher::Cookie(k, v)))
 }
 
-fn and(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, (lhs, rhs)) = separated_pair(value, tag("&&"), value)(i)?;
+fn header(i: &str) -> PResult<RouteMatcher> {
+    let (i, (k, v)) = func("Header", key_value)(i)?;
+
+    Ok((i, RouteMatcher::Header(k.to_ascii_lowercase(), v)))
+}
+
+fn content_type(i: &str) -> PResult<RouteMatcher> {
+    let (i, m) = func(
+        "ContentType",
+        map_res(parse_str, |s: String| s.trim().parse::<mime::Mime>()),
+    )(i)?;
+
+    Ok((i, RouteMatcher::ContentType(m.essence_str().to_string())))
+}
+
+fn scheme(i: &str) -> PResult<RouteMatcher> {
+    let (i, scheme) = func(
+        "Scheme",
+        map_res(parse_str, |s: String| match s.to_ascii_lowercase().as_str() {
+            "http" => Ok(Scheme::HTTP),
+            "https" => Ok(Scheme::HTTPS),
+            _ => Err(format!("unsupported scheme `{}`", s)),
+        }),
+    )(i)?;
+
+    Ok((i, RouteMatcher::Scheme(scheme)))
+}
+
+fn client_ip(i: &str) -> PResult<RouteMatcher> {
+    let (i, net) = func(
+        "ClientIp",
+        map_res(parse_str, |s: String| {
+            let s = s.trim();
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("invalid cidr `{}`", s))
+        }),
+    )(i)?;
+
+    Ok((i, RouteMatcher::ClientIp(net.trunc())))
+}
+
+fn var(i: &str) -> PResult<RouteMatcher> {
+    let name = map_res(parse_str, |name: String| Variable::parse(&name));
+    let (i, (var, value)) = func("Var", separated_pair(name, expect(","), parse_str))(i)?;
+
+    Ok((i, RouteMatcher::Var(var, value)))
+}
+
+/// Offset in `raw` quoted content of `offset` in its unescaped string.
+fn raw_offset(raw: &str, offset: usize) -> usize {
+    let mut unescaped = 0;
+    let mut iter = raw.char_indices().peekable();
+
+    while let Some((pos, ch)) = iter.next() {
+        if unescaped >= offset {
+            return pos;
+        }
+
+        match ch {
+            '\\' => {
+                if let Some((_, ch)) = iter.next_if(|(_, ch)| ESCAPE_CHARS.contains(*ch)) {
+                    unescaped += ch.len_utf8();
+                }
+            }
+            ch => unescaped += ch.len_utf8(),
+        }
+    }
+
+    raw.len()
+}
+
+/// Quoted expression, errors inside point into the quoted content.
+fn parse_expr(input: &str) -> PResult<Expr> {
+    let (i, _) = sp(input)?;
+    let (rest, s) = parse_quoted(i)?;
+
+    let expr = Expr::parse(&s).map_err(|e| {
+        let raw = &i[1..i.len() - rest.len() - 1];
+        let at = &i[1 + raw_offset(raw, e.offset)..];
+
+        nom::Err::Failure(ParseFailure::invalid(at, e.message))
+    })?;
+    let (rest, _) = sp(rest)?;
+
+    Ok((rest, expr))
+}
+
+fn expr(i: &str) -> PResult<RouteMatcher> {
+    let (i, expr) = func("Expr", parse_expr)(i)?;
+
+    Ok((i, RouteMatcher::Expr(expr)))
+}
+
+fn and(i: &str) -> PResult<RouteMatcher> {
+    let (i, (lhs, rhs)) = separated_pair(value, expect("&&"), value)(i)?;
 
     Ok((i, RouteMatcher::And(Box::new(lhs), Box::new(rhs))))
 }
 
-fn or(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, (lhs, rhs)) = separated_pair(value, tag("||"), value)(i)?;
+fn or(i: &str) -> PResult<RouteMatcher> {
+    let (i, (lhs, rhs)) = separated_pair(value, expect("||"), value)(i)?;
 
     Ok((i, RouteMatcher::Or(Box::new(lhs), Box::new(rhs))))
 }
 
-fn chained(i: &str) -> IResult<&str, RouteMatcher> {
+fn chained(i: &str) -> PResult<RouteMatcher> {
     alt((and, or))(i)
 }
 
-fn value(i: &str) -> IResult<&str, RouteMatcher> {
-    let nested = delimited(tag("("), alt((chained, value)), tag(")"));
+fn value(i: &str) -> PResult<RouteMatcher> {
+    let nested = preceded(
+        tag("("),
+        alt((
+            terminated(chained, expect(")")),
+            terminated(value, expect(")")),
+        )),
+    );
 
     delimited(
         sp,
         alt((
             host,
+            host_i,
             host_regexp,
             path,
+            path_i,
             path_regexp,
             method,
             query,
             cookie,
+            header,
+            content_type,
+            scheme,
+            client_ip,
+            var,
+            expr,
             nested,
         )),
         sp,
     )(i)
 }
 
-fn top_level(i: &str) -> IResult<&str, RouteMatcher> {
-    let (i, m) = alt((chained, value))(i)?;
-    let (i, _) = eof(i)?;
-
-    Ok((i, m))
+fn top_level(i: &str) -> PResult<RouteMatcher> {
+    // check eof in each branch, so the error which made the most progress is reported
+    alt((
+        terminated(chained, context("end of matcher", eof)),
+        terminated(value, context("end of matcher", eof)),
+    ))(i)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    fn ctx(req: &hyper::Request<Body>) -> GatewayContext {
+        GatewayContext::new(None, Scheme::HTTP, req)
+    }
+
     #[test]
     fn test_matcher() {
         let input = "Cookie('env','dev')";
@@ -257,7 +719,38 @@ mod test {
             .body(Body::empty())
             .unwrap();
 
-        assert_eq!(matcher.matchs(&req), true);
+        assert_eq!(matcher.matchs(&ctx(&req), &req), true);
+    }
+
+    #[test]
+    fn test_host_matcher() {
+        let matchs_host = |matcher: &RouteMatcher, host: &str| {
+            let req = hyper::Request::builder()
+                .header("Host", host)
+                .body(Body::empty())
+                .unwrap();
+            matcher.matchs(&ctx(&req), &req)
+        };
+
+        let matcher = RouteMatcher::parse("Host('api.example.com')").unwrap();
+        assert!(matchs_host(&matcher, "api.example.com"));
+        assert!(matchs_host(&matcher, "api.example.com:8080"));
+        assert!(matchs_host(&matcher, "API.Example.COM:8080"));
+        assert!(!matchs_host(&matcher, "www.example.com"));
+
+        let matcher = RouteMatcher::parse("Host('api.example.com:8080')").unwrap();
+        assert!(matchs_host(&matcher, "api.example.com:8080"));
+        assert!(!matchs_host(&matcher, "api.example.com:9090"));
+        assert!(!matchs_host(&matcher, "api.example.com"));
+
+        let matcher = RouteMatcher::parse("Host('[::1]')").unwrap();
+        assert!(matchs_host(&matcher, "[::1]:8080"));
+        assert!(matchs_host(&matcher, "[::1]"));
+        assert!(!matchs_host(&matcher, "[::2]:8080"));
+
+        let matcher = RouteMatcher::parse("Host('[::1]:8080')").unwrap();
+        assert!(matchs_host(&matcher, "[::1]:8080"));
+        assert!(!matchs_host(&matcher, "[::1]:80"));
     }
 
     #[test]
@@ -285,6 +778,37 @@ mod test {
             RouteMatcher::parse(input),
             Ok(RouteMatcher::Host(r#"www.'go"ogle.\com"#.to_string()))
         );
+
+        let input = r#"Host("www.\'go\"ogle.\\com")"#;
+
+        assert_eq!(
+            RouteMatcher::parse(input),
+            Ok(RouteMatcher::Host(r#"www.'go"ogle.\com"#.to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_double_quoted() {
+        assert_eq!(
+            RouteMatcher::parse(r#"Host("api.example.com")"#),
+            RouteMatcher::parse("Host('api.example.com')")
+        );
+
+        assert_eq!(
+            RouteMatcher::parse(r#"Path("/it's")"#),
+            Ok(RouteMatcher::Path("/it's".to_string()))
+        );
+
+        assert_eq!(
+            RouteMatcher::parse(r#"Cookie("env", 'dev') && Path("/api")"#),
+            Ok(RouteMatcher::And(
+                Box::new(RouteMatcher::Cookie("env".to_string(), "dev".to_string())),
+                Box::new(RouteMatcher::Path("/api".to_string()))
+            ))
+        );
+
+        assert!(RouteMatcher::parse(r#"Host('api.example.com")"#).is_err());
+        assert!(RouteMatcher::parse(r#"Host("api.example.com')"#).is_err());
     }
 
     #[test]
@@ -297,6 +821,42 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_case_insensitive_matcher() {
+        let matcher = RouteMatcher::parse("PathI('/api/user')").unwrap();
+
+        let req = hyper::Request::builder()
+            .uri("/API/User")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matcher.matchs(&ctx(&req), &req));
+        assert!(!RouteMatcher::Path("/api/user".into()).matchs(&ctx(&req), &req));
+
+        let req = hyper::Request::builder()
+            .uri("/api/users")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!matcher.matchs(&ctx(&req), &req));
+
+        // only ASCII letters are folded, non-ASCII left untouched, `/café` is sent encoded
+        let req = hyper::Request::builder()
+            .uri("/caf%C3%A9")
+            .body(Body::empty())
+            .unwrap();
+        let matchs = |m: &str| RouteMatcher::parse(m).unwrap().matchs(&ctx(&req), &req);
+        assert!(!matchs("PathI('/CAFÉ')"));
+        // `É` encoded
+        assert!(!matchs("PathI('/CAF%C3%89')"));
+        assert!(matchs("PathI('/CAF%C3%A9')"));
+
+        let matcher = RouteMatcher::parse("HostI('maybe.Example.com')").unwrap();
+        let req = hyper::Request::builder()
+            .header("Host", "MAYBE.example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matcher.matchs(&ctx(&req), &req));
+    }
+
     #[test]
     fn parse_host_regexp() {
         let input = "HostRegexp('[0-9]+')";
@@ -341,6 +901,258 @@ mod test {
         );
     }
 
+    #[test]
+    fn parse_content_type() {
+        let input = "ContentType( 'Application/JSON' )";
+
+        assert_eq!(
+            RouteMatcher::parse(input),
+            Ok(RouteMatcher::ContentType("application/json".into()))
+        );
+
+        assert!(RouteMatcher::parse("ContentType('json')").is_err());
+
+        assert_eq!(
+            RouteMatcher::parse("ContentType('Multipart/*')"),
+            Ok(RouteMatcher::ContentType("multipart/*".into()))
+        );
+    }
+
+    #[test]
+    fn test_content_type_matcher() {
+        let matcher = RouteMatcher::parse("ContentType('application/json')").unwrap();
+
+        let req = hyper::Request::builder()
+            .header("Content-Type", "application/JSON;  charset=utf-8")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matcher.matchs(&ctx(&req), &req));
+
+        let req = hyper::Request::builder()
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!matcher.matchs(&ctx(&req), &req));
+
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        assert!(!matcher.matchs(&ctx(&req), &req));
+
+        let matcher = RouteMatcher::parse("ContentType('multipart/*')").unwrap();
+
+        let req = hyper::Request::builder()
+            .header("Content-Type", "multipart/form-data; boundary=xyz")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matcher.matchs(&ctx(&req), &req));
+
+        let req = hyper::Request::builder()
+            .header("Content-Type", "application/json")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!matcher.matchs(&ctx(&req), &req));
+    }
+
+    #[test]
+    fn parse_error_position() {
+        let err = RouteMatcher::parse("Host('x' && Path('/y')").unwrap_err();
+        assert_eq!(err.message, "expected `)` after Host argument");
+        assert_eq!((err.offset, err.column), (8, 9));
+        assert_eq!(
+            err.to_string(),
+            "expected `)` after Host argument at column 9"
+        );
+
+        let err = RouteMatcher::parse("Host('x') && Path('/y'").unwrap_err();
+        assert_eq!(err.message, "expected `)` after Path argument");
+        assert_eq!(err.column, 23);
+
+        let err = RouteMatcher::parse("Query('a' 'b')").unwrap_err();
+        assert_eq!(err.message, "expected `,` in Query");
+        assert_eq!(err.column, 10);
+
+        let err = RouteMatcher::parse("Path('/a') & Path('/b')").unwrap_err();
+        assert_eq!(err.message, "expected end of matcher");
+        assert_eq!(err.column, 11);
+
+        let err = RouteMatcher::parse("PathRegexp('[')").unwrap_err();
+        assert!(err.message.starts_with("invalid PathRegexp argument"));
+        assert_eq!(err.column, 12);
+
+        let err = RouteMatcher::parse("Host('abc").unwrap_err();
+        assert_eq!(err.message, "expected closing `'` in Host");
+        assert_eq!(err.column, 10);
+
+        let err = RouteMatcher::parse("Hots('x')").unwrap_err();
+        assert_eq!(err.message, "unknown matcher");
+        assert_eq!(err.column, 1);
+    }
+
+    #[test]
+    fn test_scheme_matcher() {
+        assert_eq!(
+            RouteMatcher::parse("Scheme('HTTPS')"),
+            Ok(RouteMatcher::Scheme(Scheme::HTTPS))
+        );
+        assert!(RouteMatcher::parse("Scheme('ftp')").is_err());
+
+        let matcher = RouteMatcher::parse("Scheme('https') && Path('/payment/callback')").unwrap();
+
+        let req = hyper::Request::builder()
+            .uri("/payment/callback")
+            .body(Body::empty())
+            .unwrap();
+
+        let https_ctx = GatewayContext::new(None, Scheme::HTTPS, &req);
+        let http_ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        assert!(matcher.matchs(&https_ctx, &req));
+        assert!(!matcher.matchs(&http_ctx, &req));
+    }
+
+    #[test]
+    fn test_client_ip_matcher() {
+        assert_eq!(
+            RouteMatcher::parse("ClientIp('10.1.2.3/8')"),
+            Ok(RouteMatcher::ClientIp("10.0.0.0/8".parse().unwrap()))
+        );
+        assert!(RouteMatcher::parse("ClientIp('10.0.0.0/33')").is_err());
+
+        let matcher =
+            RouteMatcher::parse("ClientIp('10.0.0.0/8') || ClientIp('fd00::/8')").unwrap();
+
+        let req = hyper::Request::builder()
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        let ctx_of =
+            |addr: &str| GatewayContext::new(Some(addr.parse().unwrap()), Scheme::HTTP, &req);
+
+        assert!(matcher.matchs(&ctx_of("10.8.0.1:1234"), &req));
+        assert!(matcher.matchs(&ctx_of("[::ffff:10.8.0.1]:1234"), &req));
+        assert!(matcher.matchs(&ctx_of("[fd00::1]:1234"), &req));
+        assert!(!matcher.matchs(&ctx_of("192.168.1.1:1234"), &req));
+        assert!(!matcher.matchs(&ctx(&req), &req));
+    }
+
+    #[test]
+    fn test_disjoint_matcher() {
+        let parse = |s| RouteMatcher::parse(s).unwrap();
+
+        assert!(parse("Method('GET')").is_disjoint(&parse("Method('POST')")));
+        assert!(parse("Query('v', '1') && Host('a.com')").is_disjoint(&parse("Query('v', '2')")));
+        assert!(!parse("Query('v', '1') || Host('a.com')").is_disjoint(&parse("Query('v', '2')")));
+        assert!(!parse("Method('GET')").is_disjoint(&parse("Host('a.com')")));
+        assert!(parse("ContentType('image/*')").is_disjoint(&parse("ContentType('text/plain')")));
+        assert!(!parse("ContentType('image/*')").is_disjoint(&parse("ContentType('image/png')")));
+        assert!(parse("ClientIp('10.0.0.0/8')").is_disjoint(&parse("ClientIp('192.168.0.0/16')")));
+        assert!(!parse("ClientIp('10.0.0.0/8')").is_disjoint(&parse("ClientIp('10.1.0.0/16')")));
+        assert!(!RouteMatcher::Empty.is_disjoint(&parse("Host('a.com')")));
+    }
+
+    #[test]
+    fn test_header_matcher() {
+        let matcher = RouteMatcher::parse("Header('X-Debug', '1')").unwrap();
+        assert_eq!(
+            matcher,
+            RouteMatcher::Header("x-debug".to_string(), "1".to_string())
+        );
+
+        let req = hyper::Request::builder()
+            .uri("/")
+            .header("x-debug", "1")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matcher.matchs(&ctx(&req), &req));
+
+        let req = hyper::Request::builder()
+            .uri("/")
+            .header("x-debug", "0")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!matcher.matchs(&ctx(&req), &req));
+    }
+
+    #[test]
+    fn test_var_matcher() {
+        let matcher = RouteMatcher::parse("Var('http_x_env', 'canary')").unwrap();
+        assert_eq!(
+            matcher,
+            RouteMatcher::Var(Variable::Http("x-env".to_string()), "canary".to_string())
+        );
+
+        let err = RouteMatcher::parse("Var('x_env', 'canary')").unwrap_err();
+        assert_eq!(err.message, "invalid Var argument, unknown variable `$x_env`");
+        assert_eq!(err.column, 5);
+
+        let req = hyper::Request::builder()
+            .uri("/")
+            .header("x-env", "canary")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matcher.matchs(&ctx(&req), &req));
+
+        let req = hyper::Request::builder()
+            .uri("/")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!matcher.matchs(&ctx(&req), &req));
+    }
+
+    #[test]
+    fn test_expr_matcher() {
+        let matcher = RouteMatcher::parse(
+            r"Path('/api') && Expr('header.x-api-version >= 2 && query.beta = \'true\'')",
+        )
+        .unwrap();
+        assert_eq!(
+            matcher,
+            RouteMatcher::And(
+                Box::new(RouteMatcher::Path("/api".to_string())),
+                Box::new(RouteMatcher::Expr(
+                    Expr::parse("header.x-api-version >= 2 && query.beta = 'true'").unwrap()
+                )),
+            )
+        );
+
+        let matchs = |uri: &str, version: &str| {
+            let req = hyper::Request::builder()
+                .uri(uri)
+                .header("x-api-version", version)
+                .body(Body::empty())
+                .unwrap();
+            matcher.matchs(&ctx(&req), &req)
+        };
+        assert!(matchs("/api?beta=true", "2"));
+        assert!(matchs("/api?beta=true", "10"));
+        assert!(!matchs("/api?beta=true", "1"));
+        assert!(!matchs("/api", "2"));
+        assert!(!matchs("/other?beta=true", "2"));
+
+        let matcher =
+            RouteMatcher::parse(r#"Method('POST') || (Expr("path =~ '^/v2/'"))"#).unwrap();
+        let req = hyper::Request::builder()
+            .uri("/v2/users")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matcher.matchs(&ctx(&req), &req));
+    }
+
+    #[test]
+    fn parse_expr_error_position() {
+        let err = RouteMatcher::parse(r"Path('/a') && Expr('header.v >= 2 && cookie.a = \'1\'')")
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "invalid Expr argument, unknown selector `cookie.a`"
+        );
+        assert_eq!(err.column, 37);
+
+        // escaped quotes before the failure are counted in the outer input
+        let err = RouteMatcher::parse(r"Expr('method = \'GET\' && path ~ \'/\'')").unwrap_err();
+        assert_eq!(err.message, "invalid Expr argument, expected operator");
+        assert_eq!(err.column, 31);
+    }
+
     #[test]
     fn parse_and() {
         let input = "Host('www.google.com') && Path('/api/user')";
@@ -385,4 +1197,77 @@ mod test {
             Ok(RouteMatcher::And(host, path))
         );
     }
+
+    #[test]
+    fn explain_failure() {
+        let matcher =
+            RouteMatcher::parse("Method('GET') && (Header('X-Debug','1') || Query('debug','1'))")
+                .unwrap();
+
+        let req = hyper::Request::builder()
+            .uri("/api?debug=1")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(matcher.explain(&ctx(&req), &req), None);
+
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("/api?debug=1")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            matcher.explain(&ctx(&req), &req),
+            Some("Method(POST)".to_string())
+        );
+
+        let req = hyper::Request::builder()
+            .uri("/api")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            matcher.explain(&ctx(&req), &req),
+            Some(r#"(Header("x-debug", "1")) || (Query("debug", "1"))"#.to_string())
+        );
+    }
+
+    #[test]
+    fn allowed_methods() {
+        let matcher = RouteMatcher::parse(
+            "((Method('GET') || (Method('PUT') || Header('x-admin','1'))) && Method('PUT')) \
+             || Method('POST')",
+        )
+        .unwrap();
+
+        let req = hyper::Request::builder()
+            .method("OPTIONS")
+            .uri("/api")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            matcher.allowed_methods(&ctx(&req), &req),
+            AllowedMethods::Only(vec![Method::PUT, Method::POST])
+        );
+
+        let matcher = RouteMatcher::parse("Header('x-admin','1') || Method('GET')").unwrap();
+        assert_eq!(
+            matcher.allowed_methods(&ctx(&req), &req),
+            AllowedMethods::Only(vec![Method::GET])
+        );
+
+        let req = hyper::Request::builder()
+            .method("OPTIONS")
+            .header("x-admin", "1")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            matcher.allowed_methods(&ctx(&req), &req),
+            AllowedMethods::Any
+        );
+
+        let matcher = RouteMatcher::parse("Header('x-admin','2') && Method('GET')").unwrap();
+        assert_eq!(
+            matcher.allowed_methods(&ctx(&req), &req),
+            AllowedMethods::Only(vec![])
+        );
+    }
 }
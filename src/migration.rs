@@ -0,0 +1,152 @@
+//! Versioned migrations of registry documents, applied before deserializing.
+
+use serde_json::{Map, Value};
+
+use crate::error::ConfigError;
+
+/// Version of registry documents written by this build.
+pub const REGISTRY_VERSION: u32 = 2;
+
+/// Documents without `version` are written before versioning.
+pub const LEGACY_VERSION: u32 = 1;
+
+/// Upgrade a document by one version.
+type Migration = fn(&mut Map<String, Value>);
+
+/// `MIGRATIONS[i]` upgrades version `i + 1` to `i + 2`.
+const MIGRATIONS: &[Migration] = &[v1_to_v2];
+
+/// Upgrade document in place to `REGISTRY_VERSION`, return the version it was.
+pub fn migrate(doc: &mut Value) -> Result<u32, ConfigError> {
+    let doc = doc
+        .as_object_mut()
+        .ok_or_else(|| ConfigError::Message("registry document should be a map".to_string()))?;
+
+    let from = match doc.get("version") {
+        None => LEGACY_VERSION,
+        Some(v) => v
+            .as_u64()
+            .filter(|v| *v >= LEGACY_VERSION as u64)
+            .ok_or_else(|| ConfigError::Message(format!("invalid registry version<{}>", v)))?
+            as u32,
+    };
+
+    if from > REGISTRY_VERSION {
+        return Err(ConfigError::Message(format!(
+            "registry version<{}> is newer than supported<{}>",
+            from, REGISTRY_VERSION
+        )));
+    }
+
+    for (i, migration) in MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip((from - LEGACY_VERSION) as usize)
+    {
+        migration(doc);
+        tracing::debug!(to = i as u32 + LEGACY_VERSION + 1, "registry migrated");
+    }
+
+    doc.insert("version".to_string(), Value::from(REGISTRY_VERSION));
+
+    Ok(from)
+}
+
+fn each_mut<'a>(
+    doc: &'a mut Map<String, Value>,
+    key: &str,
+) -> impl Iterator<Item = &'a mut Map<String, Value>> {
+    doc.get_mut(key)
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_object_mut)
+}
+
+/// - upstream `is_https` moved into scheme of endpoint `addr`
+/// - plugin settings nested under `config` moved up beside `enable`
+fn v1_to_v2(doc: &mut Map<String, Value>) {
+    for upstream in each_mut(doc, "upstreams") {
+        let is_https = upstream
+            .remove("is_https")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if !is_https {
+            continue;
+        }
+
+        for endpoint in each_mut(upstream, "endpoints") {
+            if let Some(Value::String(addr)) = endpoint.get_mut("addr") {
+                if !addr.contains("://") {
+                    *addr = format!("https://{}", addr);
+                }
+            }
+        }
+    }
+
+    for route in each_mut(doc, "routes") {
+        let plugins = match route.get_mut("plugins").and_then(Value::as_object_mut) {
+            Some(plugins) => plugins,
+            None => continue,
+        };
+
+        for plugin in plugins.values_mut().filter_map(Value::as_object_mut) {
+            if let Some(Value::Object(config)) = plugin.remove("config") {
+                for (key, value) in config {
+                    plugin.entry(key).or_insert(value);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn migrate_v1() {
+        let mut doc = json!({
+            "routes": [{
+                "id": "hello",
+                "plugins": {
+                    "rate_limit": {"enable": true, "config": {"count": 10}},
+                    "ua_block": {"enable": false, "mode": "deny"}
+                }
+            }],
+            "upstreams": [
+                {"id": "a", "is_https": true, "endpoints": [{"addr": "10.0.0.1:443"}]},
+                {"id": "b", "is_https": false, "endpoints": [{"addr": "10.0.0.2:80"}]}
+            ]
+        });
+
+        assert_eq!(migrate(&mut doc).unwrap(), LEGACY_VERSION);
+        assert_eq!(
+            doc,
+            json!({
+                "version": REGISTRY_VERSION,
+                "routes": [{
+                    "id": "hello",
+                    "plugins": {
+                        "rate_limit": {"enable": true, "count": 10},
+                        "ua_block": {"enable": false, "mode": "deny"}
+                    }
+                }],
+                "upstreams": [
+                    {"id": "a", "endpoints": [{"addr": "https://10.0.0.1:443"}]},
+                    {"id": "b", "endpoints": [{"addr": "10.0.0.2:80"}]}
+                ]
+            })
+        );
+
+        // already current
+        let migrated = doc.clone();
+        assert_eq!(migrate(&mut doc).unwrap(), REGISTRY_VERSION);
+        assert_eq!(doc, migrated);
+
+        assert!(migrate(&mut json!({ "version": REGISTRY_VERSION + 1 })).is_err());
+        assert!(migrate(&mut json!({ "version": 0 })).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::Uri;
+use serde::Serialize;
+
+use crate::health::Healthiness;
+
+/// rolling window of samples
+const WINDOW: Duration = Duration::from_secs(60);
+/// samples kept in window, older ones are dropped first
+const MAX_SAMPLES: usize = 1024;
+/// ejections kept per endpoint
+const MAX_EJECTIONS: usize = 16;
+
+lazy_static::lazy_static! {
+    static ref G_OUTLIER_STATS: OutlierStats = OutlierStats::default();
+}
+
+pub fn outlier_stats() -> &'static OutlierStats {
+    &G_OUTLIER_STATS
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Ejection {
+    /// unix time in milliseconds
+    pub time: u64,
+    pub reason: String,
+    /// unix time in milliseconds, absent while still ejected
+    pub recovered: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointStats {
+    pub endpoint: String,
+    pub health: Healthiness,
+    /// requests in window
+    pub requests: u64,
+    /// 1.0 when no request in window
+    pub success_rate: f64,
+    pub p95_latency_ms: u64,
+    pub consecutive_failures: u32,
+    /// from the oldest
+    pub ejections: Vec<Ejection>,
+}
+
+#[derive(Debug)]
+struct Sample {
+    at: Instant,
+    success: bool,
+    latency: Duration,
+}
+
+#[derive(Debug, Default)]
+struct EndpointRecord {
+    samples: VecDeque<Sample>,
+    consecutive_failures: u32,
+    ejections: VecDeque<Ejection>,
+}
+
+impl EndpointRecord {
+    fn expire(&mut self, now: Instant) {
+        while let Some(front) = self.samples.front() {
+            if now.duration_since(front.at) > WINDOW || self.samples.len() > MAX_SAMPLES {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+/// Rolling statistics of upstream endpoints, showing why an endpoint is avoided.
+#[derive(Debug, Default)]
+pub struct OutlierStats {
+    /// keyed by upstream id and endpoint
+    endpoints: RwLock<HashMap<(String, String), Arc<Mutex<EndpointRecord>>>>,
+}
+
+impl OutlierStats {
+    fn entry(&self, upstream_id: &str, endpoint: &Uri) -> Arc<Mutex<EndpointRecord>> {
+        let key = (upstream_id.to_string(), endpoint.to_string());
+
+        if let Some(record) = self.endpoints.read().unwrap().get(&key) {
+            return record.clone();
+        }
+
+        self.endpoints
+            .write()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .clone()
+    }
+
+    /// Record a forwarded request, failed means error or 5xx response.
+    pub fn record(&self, upstream_id: &str, endpoint: &Uri, success: bool, latency: Duration) {
+        let record = self.entry(upstream_id, endpoint);
+        let mut record = record.lock().unwrap();
+
+        let now = Instant::now();
+        record.samples.push_back(Sample {
+            at: now,
+            success,
+            latency,
+        });
+        record.expire(now);
+
+        if success {
+            record.consecutive_failures = 0;
+        } else {
+            record.consecutive_failures += 1;
+        }
+    }
+
+    /// Endpoint taken out of load balance, like failing health checks.
+    pub fn eject(&self, upstream_id: &str, endpoint: &Uri, reason: &str) {
+        let record = self.entry(upstream_id, endpoint);
+        let mut record = record.lock().unwrap();
+
+        if record.ejections.len() >= MAX_EJECTIONS {
+            record.ejections.pop_front();
+        }
+        record.ejections.push_back(Ejection {
+            time: unix_millis(),
+            reason: reason.to_string(),
+            recovered: None,
+        });
+    }
+
+    pub fn recover(&self, upstream_id: &str, endpoint: &Uri) {
+        let record = self.entry(upstream_id, endpoint);
+        let mut record = record.lock().unwrap();
+
+        if let Some(ejection) = record.ejections.back_mut() {
+            if ejection.recovered.is_none() {
+                ejection.recovered = Some(unix_millis());
+            }
+        }
+    }
+
+    pub fn stats(&self, upstream_id: &str, endpoint: &Uri, health: Healthiness) -> EndpointStats {
+        let key = (upstream_id.to_string(), endpoint.to_string());
+        let record = self.endpoints.read().unwrap().get(&key).cloned();
+
+        let mut stats = EndpointStats {
+            endpoint: endpoint.to_string(),
+            health,
+            requests: 0,
+            success_rate: 1.0,
+            p95_latency_ms: 0,
+            consecutive_failures: 0,
+            ejections: Vec::new(),
+        };
+
+        let record = match record {
+            Some(record) => record,
+            None => return stats,
+        };
+        let mut record = record.lock().unwrap();
+        record.expire(Instant::now());
+
+        let requests = record.samples.len();
+        if requests > 0 {
+            let succeeded = record.samples.iter().filter(|s| s.success).count();
+
+            let mut latencies: Vec<Duration> = record.samples.iter().map(|s| s.latency).collect();
+            latencies.sort_unstable();
+            let p95 = latencies[(requests * 95).div_ceil(100).max(1) - 1];
+
+            stats.requests = requests as u64;
+            stats.success_rate = succeeded as f64 / requests as f64;
+            stats.p95_latency_ms = p95.as_millis() as u64;
+        }
+
+        stats.consecutive_failures = record.consecutive_failures;
+        stats.ejections = record.ejections.iter().cloned().collect();
+
+        stats
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn endpoint_stats() {
+        let stats = OutlierStats::default();
+        let endpoint: Uri = "http://10.0.0.1:80".parse().unwrap();
+
+        for i in 1..=20 {
+            stats.record("up", &endpoint, true, Duration::from_millis(i * 10));
+        }
+        stats.record("up", &endpoint, false, Duration::from_millis(5));
+        stats.record("up", &endpoint, false, Duration::from_millis(5));
+
+        stats.eject("up", &endpoint, "health check failed");
+        stats.recover("up", &endpoint);
+        stats.eject("up", &endpoint, "health check failed");
+
+        let report = stats.stats("up", &endpoint, Healthiness::Down);
+        assert_eq!(report.requests, 22);
+        assert!((report.success_rate - 20.0 / 22.0).abs() < 1e-9);
+        assert_eq!(report.p95_latency_ms, 190);
+        assert_eq!(report.consecutive_failures, 2);
+        assert_eq!(report.ejections.len(), 2);
+        assert!(report.ejections[0].recovered.is_some());
+        assert!(report.ejections[1].recovered.is_none());
+
+        // other upstream sharing the endpoint
+        let report = stats.stats("other", &endpoint, Healthiness::Up);
+        assert_eq!(report.requests, 0);
+        assert_eq!(report.success_rate, 1.0);
+    }
+}
@@ -21,3 +21,10 @@ impl<T: PeerAddr> PeerAddr for tokio_rustls::server::TlsStream<T> {
         self.get_ref().0.peer_addr()
     }
 }
+
+#[cfg(feature = "native-tls")]
+impl<T: PeerAddr> PeerAddr for tokio_native_tls::TlsStream<T> {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.get_ref().get_ref().get_ref().peer_addr()
+    }
+}
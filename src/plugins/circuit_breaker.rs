@@ -0,0 +1,358 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{service_unavailable, HyperRequest, HyperResponse, X_CIRCUIT_OPEN};
+
+use super::Plugin;
+
+/// Stop forwarding to a failing upstream for a while, 5xx responses count as failures.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    /// consecutive failures to open the circuit
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// milliseconds the circuit stays open before probing
+    #[serde(default = "default_open_duration_ms")]
+    pub open_duration_ms: u64,
+    /// probe requests in flight when half open
+    #[serde(default = "default_half_open_max")]
+    pub half_open_max: u32,
+}
+
+fn default_failure_threshold() -> u32 {
+    5
+}
+
+fn default_open_duration_ms() -> u64 {
+    10_000
+}
+
+fn default_half_open_max() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    /// probing, closed by a successful probe
+    HalfOpen,
+}
+
+/// Transition of circuit caused by the request, shared by `GatewayContext::set`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitTransition {
+    pub from: CircuitState,
+    pub to: CircuitState,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: CircuitState,
+    failures: u32,
+    opened_at: Instant,
+    probes: u32,
+    /// changed on every transition, results of stale probes are ignored
+    generation: u64,
+}
+
+impl Breaker {
+    fn transit(&mut self, to: CircuitState) -> CircuitTransition {
+        let from = self.state;
+
+        self.state = to;
+        self.failures = 0;
+        self.probes = 0;
+        self.generation += 1;
+        if to == CircuitState::Open {
+            self.opened_at = Instant::now();
+        }
+
+        CircuitTransition { from, to }
+    }
+}
+
+enum Admission {
+    Pass,
+    Probe(u64),
+    Reject(Duration),
+}
+
+/// Probe slot of half open circuit, released when dropped without a result.
+struct Probe {
+    breaker: Arc<Mutex<Breaker>>,
+    generation: u64,
+}
+
+impl Drop for Probe {
+    fn drop(&mut self) {
+        let mut breaker = self.breaker.lock().unwrap();
+
+        if breaker.generation == self.generation {
+            breaker.probes = breaker.probes.saturating_sub(1);
+        }
+    }
+}
+
+pub(crate) struct CircuitBreakerPlugin {
+    failure_threshold: u32,
+    open_duration: Duration,
+    half_open_max: u32,
+    breaker: Arc<Mutex<Breaker>>,
+}
+
+impl CircuitBreakerPlugin {
+    pub fn new(cfg: CircuitBreakerConfig) -> Result<Self, ConfigError> {
+        if cfg.failure_threshold == 0 || cfg.half_open_max == 0 {
+            return Err(ConfigError::Message(
+                "failure_threshold and half_open_max should be positive".to_string(),
+            ));
+        }
+
+        Ok(CircuitBreakerPlugin {
+            failure_threshold: cfg.failure_threshold,
+            open_duration: Duration::from_millis(cfg.open_duration_ms),
+            half_open_max: cfg.half_open_max,
+            breaker: Arc::new(Mutex::new(Breaker {
+                state: CircuitState::Closed,
+                failures: 0,
+                opened_at: Instant::now(),
+                probes: 0,
+                generation: 0,
+            })),
+        })
+    }
+
+    fn admit(&self) -> (Admission, Option<CircuitTransition>) {
+        let mut breaker = self.breaker.lock().unwrap();
+        let mut transition = None;
+
+        if breaker.state == CircuitState::Open {
+            let elapsed = breaker.opened_at.elapsed();
+            if elapsed < self.open_duration {
+                return (Admission::Reject(self.open_duration - elapsed), None);
+            }
+
+            transition = Some(breaker.transit(CircuitState::HalfOpen));
+        }
+
+        let admission = match breaker.state {
+            CircuitState::Closed => Admission::Pass,
+            CircuitState::HalfOpen if breaker.probes < self.half_open_max => {
+                breaker.probes += 1;
+                Admission::Probe(breaker.generation)
+            }
+            _ => Admission::Reject(Duration::from_secs(1)),
+        };
+
+        (admission, transition)
+    }
+
+    /// `probe` is the generation of probe, `None` for requests passed when closed.
+    fn report(&self, probe: Option<u64>, success: bool) -> Option<CircuitTransition> {
+        let mut breaker = self.breaker.lock().unwrap();
+
+        match (breaker.state, probe) {
+            (CircuitState::Closed, None) => {
+                if success {
+                    breaker.failures = 0;
+                    return None;
+                }
+
+                breaker.failures += 1;
+                (breaker.failures >= self.failure_threshold)
+                    .then(|| breaker.transit(CircuitState::Open))
+            }
+            (CircuitState::HalfOpen, Some(generation)) if generation == breaker.generation => {
+                let to = if success {
+                    CircuitState::Closed
+                } else {
+                    CircuitState::Open
+                };
+                Some(breaker.transit(to))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn on_transition(ctx: &mut GatewayContext, transition: Option<CircuitTransition>) {
+    if let Some(transition) = transition {
+        tracing::info!(
+            route_id = ?ctx.route_id,
+            from = ?transition.from,
+            to = ?transition.to,
+            "circuit breaker transition"
+        );
+        ctx.set(transition);
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for CircuitBreakerPlugin {
+    fn name(&self) -> &str {
+        "circuit_breaker"
+    }
+
+    fn priority(&self) -> u32 {
+        1700
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let (admission, transition) = self.admit();
+        on_transition(ctx, transition);
+
+        match admission {
+            Admission::Pass => Ok(req),
+            Admission::Probe(generation) => {
+                ctx.set(Probe {
+                    breaker: self.breaker.clone(),
+                    generation,
+                });
+                Ok(req)
+            }
+            Admission::Reject(retry_after) => {
+                let mut resp = service_unavailable(retry_after);
+                resp.headers_mut()
+                    .insert(X_CIRCUIT_OPEN, "true".parse().unwrap());
+                Err(resp)
+            }
+        }
+    }
+
+    async fn after_forward(&self, ctx: &mut GatewayContext, resp: HyperResponse) -> HyperResponse {
+        let probe = ctx.remove::<Probe>();
+
+        let transition = self.report(
+            probe.as_ref().map(|p| p.generation),
+            !resp.status().is_server_error(),
+        );
+        on_transition(ctx, transition);
+
+        resp
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body, StatusCode};
+
+    use super::*;
+
+    fn new_ctx() -> (GatewayContext, HyperRequest) {
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        (ctx, req)
+    }
+
+    /// Forward to an upstream answering `status`, return the final status and transition.
+    async fn call(
+        plugin: &CircuitBreakerPlugin,
+        status: StatusCode,
+    ) -> (StatusCode, Option<CircuitTransition>) {
+        let (mut ctx, req) = new_ctx();
+
+        let status = match plugin.on_access(&mut ctx, req).await {
+            Ok(_) => {
+                let upstream = hyper::Response::builder()
+                    .status(status)
+                    .body(Body::empty())
+                    .unwrap();
+                plugin.after_forward(&mut ctx, upstream).await.status()
+            }
+            Err(resp) => {
+                assert_eq!(resp.headers()[X_CIRCUIT_OPEN], "true");
+                resp.status()
+            }
+        };
+
+        (status, ctx.get::<CircuitTransition>().copied())
+    }
+
+    fn transition(from: CircuitState, to: CircuitState) -> Option<CircuitTransition> {
+        Some(CircuitTransition { from, to })
+    }
+
+    #[tokio::test]
+    async fn open_half_open_close() {
+        use CircuitState::*;
+
+        let plugin = CircuitBreakerPlugin::new(CircuitBreakerConfig {
+            failure_threshold: 3,
+            open_duration_ms: 50,
+            half_open_max: 1,
+        })
+        .unwrap();
+        let failed = StatusCode::INTERNAL_SERVER_ERROR;
+        let rejected = StatusCode::SERVICE_UNAVAILABLE;
+
+        // always 500
+        assert_eq!(call(&plugin, failed).await, (failed, None));
+        assert_eq!(call(&plugin, failed).await, (failed, None));
+        assert_eq!(
+            call(&plugin, failed).await,
+            (failed, transition(Closed, Open))
+        );
+        assert_eq!(call(&plugin, failed).await, (rejected, None));
+
+        // failed probe opens again
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let (status, _) = call(&plugin, failed).await;
+        assert_eq!(status, failed);
+        assert_eq!(plugin.breaker.lock().unwrap().state, Open);
+        assert_eq!(call(&plugin, StatusCode::OK).await, (rejected, None));
+
+        // successful probe closes
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(
+            call(&plugin, StatusCode::OK).await,
+            (StatusCode::OK, transition(HalfOpen, Closed))
+        );
+        assert_eq!(call(&plugin, failed).await, (failed, None));
+    }
+
+    #[tokio::test]
+    async fn probes_in_flight() {
+        let plugin = CircuitBreakerPlugin::new(CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_duration_ms: 0,
+            half_open_max: 1,
+        })
+        .unwrap();
+
+        let failed = StatusCode::INTERNAL_SERVER_ERROR;
+        assert_eq!(call(&plugin, failed).await.0, failed);
+
+        let (mut probe_ctx, req) = new_ctx();
+        assert!(plugin.on_access(&mut probe_ctx, req).await.is_ok());
+        assert_eq!(
+            probe_ctx.get::<CircuitTransition>(),
+            Some(&CircuitTransition {
+                from: CircuitState::Open,
+                to: CircuitState::HalfOpen
+            })
+        );
+
+        // only one probe at a time
+        assert_eq!(
+            call(&plugin, StatusCode::OK).await.0,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // probe dropped without result, like rejected by a later plugin
+        drop(probe_ctx);
+        assert_eq!(call(&plugin, StatusCode::OK).await.0, StatusCode::OK);
+        assert_eq!(plugin.breaker.lock().unwrap().state, CircuitState::Closed);
+    }
+}
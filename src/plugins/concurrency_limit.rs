@@ -0,0 +1,185 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{service_unavailable, HyperRequest, HyperResponse};
+
+use super::Plugin;
+
+/// Limit requests of route forwarding at the same time.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ConcurrencyLimitConfig {
+    /// max requests in flight
+    pub max_in_flight: usize,
+    /// max requests waiting for a slot, 0 means rejecting at once
+    #[serde(default)]
+    pub queue: usize,
+    /// timeout in milliseconds waiting in queue
+    #[serde(default = "default_queue_timeout")]
+    pub queue_timeout_ms: u64,
+}
+
+fn default_queue_timeout() -> u64 {
+    100
+}
+
+/// Slot of an in-flight request, released when dropped with `GatewayContext`
+/// in case `after_forward` never runs.
+struct InFlight {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Place taken in queue, left when dropped.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for Queued<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) struct ConcurrencyLimitPlugin {
+    slots: Arc<Semaphore>,
+    queue: usize,
+    queued: AtomicUsize,
+    queue_timeout: Duration,
+}
+
+impl ConcurrencyLimitPlugin {
+    pub fn new(cfg: ConcurrencyLimitConfig) -> Result<Self, ConfigError> {
+        if cfg.max_in_flight == 0 || cfg.max_in_flight > Semaphore::MAX_PERMITS {
+            return Err(ConfigError::Message(format!(
+                "invalid max_in_flight<{}>",
+                cfg.max_in_flight
+            )));
+        }
+
+        Ok(ConcurrencyLimitPlugin {
+            slots: Arc::new(Semaphore::new(cfg.max_in_flight)),
+            queue: cfg.queue,
+            queued: AtomicUsize::new(0),
+            queue_timeout: Duration::from_millis(cfg.queue_timeout_ms),
+        })
+    }
+
+    /// Take a slot, waiting in queue when there is a place.
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+
+        let _queued = Queued(&self.queued);
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.queue {
+            return None;
+        }
+
+        tokio::time::timeout(self.queue_timeout, self.slots.clone().acquire_owned())
+            .await
+            .ok()?
+            .ok()
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for ConcurrencyLimitPlugin {
+    fn name(&self) -> &str {
+        "concurrency_limit"
+    }
+
+    fn priority(&self) -> u32 {
+        2100
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        match self.acquire().await {
+            Some(permit) => {
+                ctx.set(InFlight { _permit: permit });
+                Ok(req)
+            }
+            None => {
+                tracing::debug!(route_id = ?ctx.route_id, "concurrency limit reached");
+                Err(service_unavailable(Duration::from_secs(1)))
+            }
+        }
+    }
+
+    async fn after_forward(&self, ctx: &mut GatewayContext, resp: HyperResponse) -> HyperResponse {
+        ctx.remove::<InFlight>();
+        resp
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body, StatusCode};
+
+    use super::*;
+
+    fn new_ctx() -> (GatewayContext, HyperRequest) {
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        (ctx, req)
+    }
+
+    async fn access(plugin: &ConcurrencyLimitPlugin) -> Result<GatewayContext, StatusCode> {
+        let (mut ctx, req) = new_ctx();
+
+        match plugin.on_access(&mut ctx, req).await {
+            Ok(_) => Ok(ctx),
+            Err(resp) => Err(resp.status()),
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_in_queue() {
+        let plugin = ConcurrencyLimitPlugin::new(ConcurrencyLimitConfig {
+            max_in_flight: 1,
+            queue: 1,
+            queue_timeout_ms: 50,
+        })
+        .unwrap();
+
+        let mut first = access(&plugin).await.unwrap();
+
+        // queue timeout
+        assert_eq!(
+            access(&plugin).await.err(),
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        );
+
+        // queue full
+        let (queued, full) = tokio::join!(access(&plugin), async {
+            tokio::task::yield_now().await;
+            access(&plugin).await
+        });
+        assert_eq!(queued.err(), Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(full.err(), Some(StatusCode::SERVICE_UNAVAILABLE));
+
+        // slot released while waiting
+        let (second, _) = tokio::join!(access(&plugin), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            plugin
+                .after_forward(&mut first, HyperResponse::default())
+                .await
+        });
+        let second = second.unwrap();
+        assert_eq!(plugin.queued.load(Ordering::SeqCst), 0);
+
+        // slot released with context
+        drop(second);
+        assert!(access(&plugin).await.is_ok());
+    }
+}
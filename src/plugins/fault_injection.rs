@@ -0,0 +1,169 @@
+use std::{sync::Mutex, time::Duration};
+
+use hyper::{Body, StatusCode};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{json_error, HyperRequest, HyperResponse};
+
+use super::Plugin;
+
+/// Inject faults on a percentage of requests, for testing client resilience.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct FaultInjectionConfig {
+    #[serde(default)]
+    pub abort: Option<AbortFault>,
+    #[serde(default)]
+    pub delay: Option<DelayFault>,
+    /// fixed seed of rng, for reproducible tests
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+/// Respond without touching upstream.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AbortFault {
+    /// percentage of requests, like `0.5` for 0.5%
+    pub percentage: f64,
+    pub status_code: u16,
+    /// absent means a json error body
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+/// Delay before forwarding.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DelayFault {
+    /// percentage of requests, like `0.5` for 0.5%
+    pub percentage: f64,
+    /// delay in milliseconds
+    pub duration_ms: u64,
+}
+
+pub(crate) struct FaultInjectionPlugin {
+    abort: Option<(f64, StatusCode, Option<String>)>,
+    delay: Option<(f64, Duration)>,
+    rng: Mutex<StdRng>,
+}
+
+impl FaultInjectionPlugin {
+    pub fn new(cfg: FaultInjectionConfig) -> Result<Self, ConfigError> {
+        let abort = match cfg.abort {
+            Some(abort) => {
+                check_percentage(abort.percentage)?;
+                let status = StatusCode::from_u16(abort.status_code).map_err(|_| {
+                    ConfigError::Message(format!("invalid status<{}>", abort.status_code))
+                })?;
+                Some((abort.percentage, status, abort.body))
+            }
+            None => None,
+        };
+
+        let delay = match cfg.delay {
+            Some(delay) => {
+                check_percentage(delay.percentage)?;
+                Some((delay.percentage, Duration::from_millis(delay.duration_ms)))
+            }
+            None => None,
+        };
+
+        let rng = match cfg.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+
+        Ok(FaultInjectionPlugin {
+            abort,
+            delay,
+            rng: Mutex::new(rng),
+        })
+    }
+
+    fn hit(&self, percentage: f64) -> bool {
+        self.rng.lock().unwrap().gen::<f64>() * 100.0 < percentage
+    }
+}
+
+fn check_percentage(percentage: f64) -> Result<(), ConfigError> {
+    if !(0.0..=100.0).contains(&percentage) {
+        return Err(ConfigError::Message(format!(
+            "invalid percentage<{}>",
+            percentage
+        )));
+    }
+
+    Ok(())
+}
+
+#[async_trait::async_trait]
+impl Plugin for FaultInjectionPlugin {
+    fn name(&self) -> &str {
+        "fault_injection"
+    }
+
+    fn priority(&self) -> u32 {
+        1800
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        // faults are evaluated independently, a request may be delayed then aborted
+        if let Some((percentage, duration)) = self.delay {
+            if self.hit(percentage) {
+                tracing::debug!(route_id = ?ctx.route_id, ?duration, "inject delay");
+                tokio::time::sleep(duration).await;
+            }
+        }
+
+        if let Some((percentage, status, ref body)) = self.abort {
+            if self.hit(percentage) {
+                tracing::debug!(route_id = ?ctx.route_id, %status, "inject abort");
+
+                return Err(match body {
+                    Some(body) => hyper::Response::builder()
+                        .status(status)
+                        .body(Body::from(body.clone()))
+                        .unwrap(),
+                    None => json_error(status, "fault injected"),
+                });
+            }
+        }
+
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fractional_percentage() {
+        let plugin = FaultInjectionPlugin::new(FaultInjectionConfig {
+            seed: Some(7),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let hits = (0..100_000).filter(|_| plugin.hit(0.5)).count();
+        assert!((300..700).contains(&hits), "hits {}", hits);
+
+        assert_eq!((0..1000).filter(|_| plugin.hit(0.0)).count(), 0);
+        assert_eq!((0..1000).filter(|_| plugin.hit(100.0)).count(), 1000);
+
+        assert!(FaultInjectionPlugin::new(FaultInjectionConfig {
+            abort: Some(AbortFault {
+                percentage: 150.0,
+                status_code: 503,
+                body: None,
+            }),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
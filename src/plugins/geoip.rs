@@ -0,0 +1,263 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::SystemTime,
+};
+
+use hyper::StatusCode;
+use ipnet::IpNet;
+use maxminddb::{geoip2, Mmap, Reader};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{json_error, HyperRequest, HyperResponse};
+
+use super::ip_restriction::{client_ip, parse_nets, IpPolicy};
+use super::Plugin;
+
+type GeoipReader = Reader<Mmap>;
+
+/// Allow or deny requests by country of client ip.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GeoipConfig {
+    /// MaxMind GeoLite2 or GeoIP2 country database, `.mmdb`
+    pub database: PathBuf,
+    /// ISO 3166-1 alpha-2 codes, other countries are denied when not empty
+    #[serde(default)]
+    pub allow_countries: Vec<String>,
+    /// ISO 3166-1 alpha-2 codes, evaluated before `allow_countries`
+    #[serde(default)]
+    pub deny_countries: Vec<String>,
+    /// policy for ips without a country in database
+    pub fallback: IpPolicy,
+    /// take client ip from `x-forwarded-for`
+    #[serde(default)]
+    pub trust_forwarded: bool,
+    /// proxies skipped when walking `x-forwarded-for` from right
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+lazy_static::lazy_static! {
+    /// Opened databases, shared by plugins of all routes and registry copies.
+    static ref G_GEOIP_READERS: Mutex<HashMap<PathBuf, (SystemTime, Weak<GeoipReader>)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Map `path`, or reuse the mapping while the file is unchanged.
+fn open_reader(path: &Path) -> Result<Arc<GeoipReader>, ConfigError> {
+    let modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|err| {
+            ConfigError::Message(format!("open geoip database<{}>: {}", path.display(), err))
+        })?;
+
+    let mut readers = G_GEOIP_READERS.lock().unwrap();
+    readers.retain(|_, (_, reader)| reader.strong_count() > 0);
+
+    if let Some((m, reader)) = readers.get(path) {
+        if let Some(reader) = reader.upgrade().filter(|_| *m == modified) {
+            return Ok(reader);
+        }
+    }
+
+    let reader = Reader::open_mmap(path).map_err(|err| {
+        ConfigError::Message(format!("open geoip database<{}>: {}", path.display(), err))
+    })?;
+    let reader = Arc::new(reader);
+    readers.insert(path.to_path_buf(), (modified, Arc::downgrade(&reader)));
+
+    Ok(reader)
+}
+
+fn parse_countries(codes: &[String]) -> Result<Vec<String>, ConfigError> {
+    codes
+        .iter()
+        .map(|code| {
+            let code = code.trim();
+            if code.len() == 2 && code.chars().all(|c| c.is_ascii_alphabetic()) {
+                Ok(code.to_ascii_uppercase())
+            } else {
+                Err(ConfigError::Message(format!(
+                    "invalid country code<{}>",
+                    code
+                )))
+            }
+        })
+        .collect()
+}
+
+pub(crate) struct GeoipPlugin {
+    reader: Arc<GeoipReader>,
+    allow: Vec<String>,
+    deny: Vec<String>,
+    fallback: IpPolicy,
+    trust_forwarded: bool,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl GeoipPlugin {
+    pub fn new(cfg: GeoipConfig) -> Result<Self, ConfigError> {
+        Ok(GeoipPlugin {
+            reader: open_reader(&cfg.database)?,
+            allow: parse_countries(&cfg.allow_countries)?,
+            deny: parse_countries(&cfg.deny_countries)?,
+            fallback: cfg.fallback,
+            trust_forwarded: cfg.trust_forwarded,
+            trusted_proxies: parse_nets(&cfg.trusted_proxies)?,
+        })
+    }
+
+    /// ISO code of `ip`, `None` when not in database.
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        match self.reader.lookup::<geoip2::Country>(ip) {
+            Ok(record) => record.country?.iso_code.map(str::to_ascii_uppercase),
+            Err(err) => {
+                tracing::trace!(%ip, ?err, "geoip lookup failed");
+                None
+            }
+        }
+    }
+
+    fn check(&self, country: Option<&str>) -> IpPolicy {
+        match country {
+            Some(c) if self.deny.iter().any(|d| d == c) => IpPolicy::Deny,
+            Some(c) if self.allow.is_empty() || self.allow.iter().any(|a| a == c) => {
+                IpPolicy::Allow
+            }
+            Some(_) => IpPolicy::Deny,
+            None => self.fallback,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for GeoipPlugin {
+    fn name(&self) -> &str {
+        "geoip"
+    }
+
+    fn priority(&self) -> u32 {
+        2900
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let ip = client_ip(ctx, &req, self.trust_forwarded, &self.trusted_proxies);
+        let country = ip.and_then(|ip| self.country(ip));
+
+        match self.check(country.as_deref()) {
+            IpPolicy::Allow => Ok(req),
+            IpPolicy::Deny => {
+                tracing::debug!(?ip, ?country, "country restricted");
+                let message = match country {
+                    Some(country) => format!("country<{}> not allowed", country),
+                    None => "unknown country not allowed".to_string(),
+                };
+                Err(json_error(StatusCode::FORBIDDEN, &message))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+    use crate::http::X_FORWARDED_FOR;
+
+    /// 1.2.3.0/24 is CN, 8.8.8.0/24 is US and 81.2.69.0/24 is GB, see testdata/gen_geoip.py
+    const DATABASE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/geoip-country.mmdb");
+
+    fn new_plugin(allow: &[&str], deny: &[&str], fallback: IpPolicy) -> GeoipPlugin {
+        GeoipPlugin::new(GeoipConfig {
+            database: PathBuf::from(DATABASE),
+            allow_countries: allow.iter().map(|s| s.to_string()).collect(),
+            deny_countries: deny.iter().map(|s| s.to_string()).collect(),
+            fallback,
+            trust_forwarded: true,
+            trusted_proxies: vec!["10.0.0.0/8".to_string()],
+        })
+        .unwrap()
+    }
+
+    /// Body of rejection, `None` when allowed.
+    fn access(plugin: &GeoipPlugin, remote: &str, xff: Option<&str>) -> Option<String> {
+        let mut builder = hyper::Request::builder();
+        if let Some(xff) = xff {
+            builder = builder.header(X_FORWARDED_FOR, xff);
+        }
+        let req = builder.body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(Some(remote.parse().unwrap()), Scheme::HTTP, &req);
+
+        let resp = futures::executor::block_on(plugin.on_access(&mut ctx, req)).err()?;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = futures::executor::block_on(hyper::body::to_bytes(resp.into_body())).unwrap();
+
+        Some(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn allow_countries() {
+        let plugin = new_plugin(&["us", "GB"], &[], IpPolicy::Allow);
+
+        assert_eq!(access(&plugin, "8.8.8.8:1234", None), None);
+        assert_eq!(access(&plugin, "[::ffff:81.2.69.160]:1234", None), None);
+
+        let body = access(&plugin, "1.2.3.4:1234", None).unwrap();
+        assert!(body.contains("CN"), "{}", body);
+
+        // client behind trusted proxy
+        assert!(access(&plugin, "10.0.0.1:1234", Some("1.2.3.4, 10.0.0.2")).is_some());
+        assert_eq!(access(&plugin, "10.0.0.1:1234", Some("8.8.8.8")), None);
+
+        // spoofed by an untrusted peer
+        assert!(access(&plugin, "1.2.3.4:1234", Some("8.8.8.8")).is_some());
+        assert!(access(&plugin, "1.2.3.4:1234", Some("8.8.8.8, 10.0.0.2")).is_some());
+    }
+
+    #[test]
+    fn deny_countries() {
+        let plugin = new_plugin(&[], &["CN"], IpPolicy::Allow);
+
+        let body = access(&plugin, "1.2.3.4:1234", None).unwrap();
+        assert!(body.contains("CN"), "{}", body);
+        assert_eq!(access(&plugin, "8.8.8.8:1234", None), None);
+        assert_eq!(access(&plugin, "81.2.69.160:1234", None), None);
+    }
+
+    #[test]
+    fn unknown_country() {
+        let plugin = new_plugin(&[], &["CN"], IpPolicy::Allow);
+        assert_eq!(access(&plugin, "9.9.9.9:1234", None), None);
+        assert_eq!(access(&plugin, "[2001:db8::1]:1234", None), None);
+
+        let plugin = new_plugin(&["US"], &[], IpPolicy::Deny);
+        assert!(access(&plugin, "9.9.9.9:1234", None).is_some());
+        assert!(access(&plugin, "[2001:db8::1]:1234", None).is_some());
+    }
+
+    #[test]
+    fn shared_database() {
+        let a = new_plugin(&[], &[], IpPolicy::Allow);
+        let b = new_plugin(&[], &[], IpPolicy::Allow);
+        assert!(Arc::ptr_eq(&a.reader, &b.reader));
+
+        let cfg = serde_json::json!({ "database": "/no/such/geoip.mmdb", "fallback": "allow" });
+        assert!(super::super::init_plugin("geoip", cfg).is_err());
+
+        let cfg = serde_json::json!({
+            "database": DATABASE,
+            "deny_countries": ["China"],
+            "fallback": "allow"
+        });
+        assert!(super::super::init_plugin("geoip", cfg).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{HyperRequest, HyperResponse};
+use crate::variable::Template;
+
+use super::Plugin;
+
+/// Add, overwrite or remove headers of request and response.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HeadersConfig {
+    #[serde(default)]
+    pub request: HeaderTransform,
+    /// variables are resolved against the request
+    #[serde(default)]
+    pub response: HeaderTransform,
+}
+
+/// Applied in order of `remove`, `set`, `add`, values may contain variables like `$route_id`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HeaderTransform {
+    /// replace existing values
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+    /// append to existing values
+    #[serde(default)]
+    pub add: HashMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+struct Transform {
+    set: Vec<(HeaderName, Template)>,
+    add: Vec<(HeaderName, Template)>,
+    remove: Vec<HeaderName>,
+}
+
+impl Transform {
+    fn new(cfg: HeaderTransform) -> Result<Self, ConfigError> {
+        let parse = |headers: HashMap<String, String>| {
+            headers
+                .into_iter()
+                .map(|(name, value)| {
+                    // literal parts are checked here, rendered values at runtime
+                    HeaderValue::from_str(&value).map_err(|_| {
+                        ConfigError::Message(format!(
+                            "invalid value<{}> of header<{}>",
+                            value, name
+                        ))
+                    })?;
+
+                    Ok((parse_name(&name)?, Template::parse(&value)?))
+                })
+                .collect::<Result<Vec<_>, ConfigError>>()
+        };
+
+        Ok(Transform {
+            set: parse(cfg.set)?,
+            add: parse(cfg.add)?,
+            remove: cfg
+                .remove
+                .iter()
+                .map(|name| parse_name(name))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.add.is_empty() && self.remove.is_empty()
+    }
+
+    fn render(&self, ctx: &GatewayContext, req: &HyperRequest) -> RenderedHeaders {
+        let render = |headers: &[(HeaderName, Template)]| {
+            headers
+                .iter()
+                .filter_map(|(name, tpl)| {
+                    let value = tpl.render(ctx, req);
+                    match HeaderValue::from_str(&value) {
+                        Ok(value) => Some((name.clone(), value)),
+                        Err(_) => {
+                            tracing::debug!(
+                                route_id = ?ctx.route_id,
+                                %name,
+                                %value,
+                                "invalid header value"
+                            );
+                            None
+                        }
+                    }
+                })
+                .collect()
+        };
+
+        RenderedHeaders {
+            set: render(&self.set),
+            add: render(&self.add),
+            remove: self.remove.clone(),
+        }
+    }
+}
+
+fn parse_name(name: &str) -> Result<HeaderName, ConfigError> {
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| ConfigError::Message(format!("invalid header name<{}>", name)))
+}
+
+/// Response headers rendered on access, while the request is at hand.
+struct RenderedHeaders {
+    set: Vec<(HeaderName, HeaderValue)>,
+    add: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl RenderedHeaders {
+    fn apply(self, headers: &mut HeaderMap) {
+        for name in self.remove {
+            headers.remove(name);
+        }
+        for (name, value) in self.set {
+            headers.insert(name, value);
+        }
+        for (name, value) in self.add {
+            headers.append(name, value);
+        }
+    }
+}
+
+pub(crate) struct HeadersPlugin {
+    request: Transform,
+    response: Transform,
+}
+
+impl HeadersPlugin {
+    pub fn new(cfg: HeadersConfig) -> Result<Self, ConfigError> {
+        Ok(HeadersPlugin {
+            request: Transform::new(cfg.request)?,
+            response: Transform::new(cfg.response)?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for HeadersPlugin {
+    fn name(&self) -> &str {
+        "headers"
+    }
+
+    fn priority(&self) -> u32 {
+        600
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        mut req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        if !self.response.is_empty() {
+            let rendered = self.response.render(ctx, &req);
+            ctx.set(rendered);
+        }
+
+        if !self.request.is_empty() {
+            let rendered = self.request.render(ctx, &req);
+            rendered.apply(req.headers_mut());
+        }
+
+        Ok(req)
+    }
+
+    async fn after_forward(
+        &self,
+        ctx: &mut GatewayContext,
+        mut resp: HyperResponse,
+    ) -> HyperResponse {
+        if let Some(rendered) = ctx.remove::<RenderedHeaders>() {
+            rendered.apply(resp.headers_mut());
+        }
+
+        resp
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use hyper::{http::uri::Scheme, Body};
+    use serde_json::json;
+
+    use super::*;
+
+    fn new_plugin(cfg: serde_json::Value) -> Result<HeadersPlugin, ConfigError> {
+        HeadersPlugin::new(serde_json::from_value(cfg).unwrap())
+    }
+
+    fn values(headers: &HeaderMap, name: &str) -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|v| v.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn transform_headers() {
+        let plugin = new_plugin(json!({
+            "request": {
+                "set": { "x-tenant-id": "acme", "x-forwarded-route": "$route_id" },
+                "add": { "x-trace": "gateway" },
+                "remove": ["x-internal"]
+            },
+            "response": {
+                "set": { "cache-control": "no-store", "x-served-for": "$remote_addr@$host" },
+                "add": { "vary": "x-tenant-id" },
+                "remove": ["server"]
+            }
+        }))
+        .unwrap();
+
+        let req = hyper::Request::builder()
+            .header("host", "example.com")
+            .header("x-tenant-id", "spoofed")
+            .header("x-trace", "client")
+            .header("x-internal", "1")
+            .body(Body::empty())
+            .unwrap();
+        let remote_addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut ctx = GatewayContext::new(Some(remote_addr), Scheme::HTTP, &req);
+        ctx.route_id = Some("orders".to_string());
+
+        let req = futures::executor::block_on(plugin.on_access(&mut ctx, req)).unwrap();
+        let headers = req.headers();
+
+        assert_eq!(values(headers, "x-tenant-id"), vec!["acme"]);
+        assert_eq!(values(headers, "x-forwarded-route"), vec!["orders"]);
+        assert_eq!(values(headers, "x-trace"), vec!["client", "gateway"]);
+        assert!(!headers.contains_key("x-internal"));
+
+        let upstream = hyper::Response::builder()
+            .header("server", "nginx")
+            .header("cache-control", "max-age=60")
+            .header("vary", "accept")
+            .body(Body::empty())
+            .unwrap();
+        let resp = futures::executor::block_on(plugin.after_forward(&mut ctx, upstream));
+        let headers = resp.headers();
+
+        assert_eq!(values(headers, "cache-control"), vec!["no-store"]);
+        assert_eq!(
+            values(headers, "x-served-for"),
+            vec!["10.0.0.1@example.com"]
+        );
+        assert_eq!(values(headers, "vary"), vec!["accept", "x-tenant-id"]);
+        assert!(!headers.contains_key("server"));
+    }
+
+    #[test]
+    fn invalid_headers() {
+        assert!(new_plugin(json!({ "request": { "set": { "bad header": "1" } } })).is_err());
+        assert!(new_plugin(json!({ "response": { "add": { "x-a": "line\nbreak" } } })).is_err());
+        assert!(new_plugin(json!({ "response": { "remove": ["bad:name"] } })).is_err());
+        assert!(new_plugin(json!({ "request": { "set": { "x-a": "${host" } } })).is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use std::convert::TryFrom;
+
+use hyper::{http::uri::PathAndQuery, StatusCode, Uri};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{json_error, HyperRequest, HyperResponse};
+use crate::variable::Template;
+
+use super::Plugin;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct InternalRedirectConfig {
+    /// new path and query, variables and path params like `{id}` are rendered,
+    /// original query is kept when absent
+    pub uri: String,
+}
+
+/// Rewrite uri then route the request again, without forwarding.
+pub(crate) struct InternalRedirectPlugin {
+    uri: Template,
+}
+
+impl InternalRedirectPlugin {
+    pub fn new(cfg: InternalRedirectConfig) -> Result<Self, ConfigError> {
+        if !cfg.uri.starts_with('/') {
+            return Err(ConfigError::Message(format!(
+                "internal redirect uri<{}> should start with `/`",
+                cfg.uri
+            )));
+        }
+
+        Ok(InternalRedirectPlugin {
+            uri: Template::parse_path(&cfg.uri)?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for InternalRedirectPlugin {
+    fn name(&self) -> &str {
+        "internal_redirect"
+    }
+
+    fn priority(&self) -> u32 {
+        900
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        mut req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let mut uri = self.uri.render(ctx, &req);
+        if !uri.contains('?') {
+            if let Some(q) = req.uri().query() {
+                uri = uri + "?" + q;
+            }
+        }
+
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(PathAndQuery::try_from(uri.as_str()).map_err(|err| {
+            tracing::error!(%err, %uri, "invalid internal redirect uri");
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "invalid redirect uri")
+        })?);
+
+        *req.uri_mut() = Uri::from_parts(parts).unwrap();
+        ctx.internal_redirect = true;
+
+        Ok(req)
+    }
+}
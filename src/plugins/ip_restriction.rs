@@ -0,0 +1,263 @@
+use std::net::IpAddr;
+
+use hyper::StatusCode;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{json_error, HyperRequest, HyperResponse, X_FORWARDED_FOR};
+
+use super::Plugin;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IpRestrictionConfig {
+    /// CIDRs or addresses
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// CIDRs or addresses, evaluated before `allow`
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// policy when neither list matches
+    pub fallback: IpPolicy,
+    /// take client ip from `x-forwarded-for`, needs `trusted_proxies`
+    #[serde(default)]
+    pub trust_forwarded: bool,
+    /// proxies skipped when walking `x-forwarded-for` from right
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPolicy {
+    Allow,
+    Deny,
+}
+
+pub(crate) struct IpRestrictionPlugin {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    fallback: IpPolicy,
+    trust_forwarded: bool,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl IpRestrictionPlugin {
+    pub fn new(cfg: IpRestrictionConfig) -> Result<Self, ConfigError> {
+        // no hop trusted, `x-forwarded-for` would never be read
+        if cfg.trust_forwarded && cfg.trusted_proxies.is_empty() {
+            return Err(ConfigError::Message(
+                "trust_forwarded needs trusted_proxies".to_string(),
+            ));
+        }
+
+        Ok(IpRestrictionPlugin {
+            allow: parse_nets(&cfg.allow)?,
+            deny: parse_nets(&cfg.deny)?,
+            fallback: cfg.fallback,
+            trust_forwarded: cfg.trust_forwarded,
+            trusted_proxies: parse_nets(&cfg.trusted_proxies)?,
+        })
+    }
+
+    fn client_ip(&self, ctx: &GatewayContext, req: &HyperRequest) -> Option<IpAddr> {
+        client_ip(ctx, req, self.trust_forwarded, &self.trusted_proxies)
+    }
+
+    fn check(&self, ip: Option<IpAddr>) -> IpPolicy {
+        match ip {
+            Some(ip) if contains(&self.deny, &ip) => IpPolicy::Deny,
+            Some(ip) if contains(&self.allow, &ip) => IpPolicy::Allow,
+            _ => self.fallback,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for IpRestrictionPlugin {
+    fn name(&self) -> &str {
+        "ip_restriction"
+    }
+
+    fn priority(&self) -> u32 {
+        3000
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let ip = self.client_ip(ctx, &req);
+
+        match self.check(ip) {
+            IpPolicy::Allow => Ok(req),
+            IpPolicy::Deny => {
+                tracing::debug!(?ip, "ip restricted");
+                Err(json_error(StatusCode::FORBIDDEN, "ip not allowed"))
+            }
+        }
+    }
+}
+
+/// Client ip, with `trust_forwarded` the remote addr and `x-forwarded-for` entries are
+/// walked from right while they are trusted proxies, the first untrusted hop is the client.
+/// `None` when an entry reached on the walk is not an ip.
+pub(super) fn client_ip(
+    ctx: &GatewayContext,
+    req: &HyperRequest,
+    trust_forwarded: bool,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let mut client = canonical(ctx.remote_addr()?.ip());
+
+    if !trust_forwarded {
+        return Some(client);
+    }
+
+    let values: Vec<_> = req.headers().get_all(X_FORWARDED_FOR).iter().collect();
+    let forwarded = values
+        .iter()
+        .rev()
+        .flat_map(|v| v.to_str().unwrap_or("?").rsplit(','))
+        .map(|ip| ip.trim().parse::<IpAddr>().ok().map(canonical));
+
+    for hop in forwarded {
+        if !contains(trusted_proxies, &client) {
+            break;
+        }
+
+        client = hop?;
+    }
+
+    Some(client)
+}
+
+pub(super) fn parse_nets(nets: &[String]) -> Result<Vec<IpNet>, ConfigError> {
+    nets.iter()
+        .map(|s| {
+            let s = s.trim();
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| ConfigError::Message(format!("invalid cidr<{}>", s)))
+        })
+        .collect()
+}
+
+pub(super) fn contains(nets: &[IpNet], ip: &IpAddr) -> bool {
+    nets.iter().any(|net| net.contains(ip))
+}
+
+/// Treat ipv4-mapped ipv6 address as ipv4.
+pub(super) fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    fn new_plugin(allow: &[&str], deny: &[&str], trust_forwarded: bool) -> IpRestrictionPlugin {
+        IpRestrictionPlugin::new(IpRestrictionConfig {
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            deny: deny.iter().map(|s| s.to_string()).collect(),
+            fallback: IpPolicy::Deny,
+            trust_forwarded,
+            trusted_proxies: vec!["10.0.0.0/8".to_string()],
+        })
+        .unwrap()
+    }
+
+    fn access(plugin: &IpRestrictionPlugin, remote: &str, xff: Option<&str>) -> bool {
+        let mut builder = hyper::Request::builder().uri("/admin");
+        if let Some(xff) = xff {
+            builder = builder.header(X_FORWARDED_FOR, xff);
+        }
+        let req = builder.body(Body::empty()).unwrap();
+
+        let mut ctx = GatewayContext::new(Some(remote.parse().unwrap()), Scheme::HTTP, &req);
+
+        futures::executor::block_on(plugin.on_access(&mut ctx, req)).is_ok()
+    }
+
+    #[test]
+    fn ipv4() {
+        let plugin = new_plugin(&["192.168.0.0/16", "1.2.3.4"], &["192.168.1.0/24"], false);
+
+        assert!(access(&plugin, "192.168.2.1:1234", None));
+        assert!(access(&plugin, "1.2.3.4:1234", None));
+        assert!(!access(&plugin, "192.168.1.1:1234", None));
+        assert!(!access(&plugin, "8.8.8.8:1234", None));
+        assert!(access(&plugin, "[::ffff:1.2.3.4]:1234", None));
+    }
+
+    #[test]
+    fn ipv6() {
+        let plugin = new_plugin(&["2001:db8::/32"], &["2001:db8:dead::/48"], false);
+
+        assert!(access(&plugin, "[2001:db8::1]:1234", None));
+        assert!(!access(&plugin, "[2001:db8:dead::1]:1234", None));
+        assert!(!access(&plugin, "[::1]:1234", None));
+    }
+
+    #[test]
+    fn trust_forwarded() {
+        let plugin = new_plugin(&["1.2.3.0/24"], &[], true);
+
+        // right-most untrusted entry is the client
+        assert!(access(&plugin, "10.0.0.1:1234", Some("8.8.8.8, 1.2.3.4, 10.0.0.2")));
+        assert!(!access(&plugin, "10.0.0.1:1234", Some("1.2.3.4, 8.8.8.8")));
+        // fallback to remote addr
+        assert!(!access(&plugin, "10.0.0.1:1234", None));
+        // only the trusted proxy chain is walked
+        let via_proxy = |xff| access(&plugin, "10.0.0.1:1234", Some(xff));
+        assert!(!via_proxy("1.2.3.4, bogus, 10.0.0.2"));
+        assert!(!via_proxy("1.2.3.4, 10.0.0.2, 8.8.8.8"));
+
+        let plugin = new_plugin(&["1.2.3.0/24"], &[], false);
+        assert!(!access(&plugin, "10.0.0.1:1234", Some("1.2.3.4")));
+    }
+
+    #[test]
+    fn spoofed_forwarded() {
+        let plugin = new_plugin(&["1.2.3.0/24"], &[], true);
+
+        // untrusted peer, its `x-forwarded-for` is ignored
+        assert!(!access(&plugin, "8.8.8.8:1234", Some("1.2.3.4")));
+        assert!(!access(&plugin, "8.8.8.8:1234", Some("1.2.3.4, 10.0.0.2")));
+        assert!(access(&plugin, "1.2.3.5:1234", Some("8.8.8.8")));
+
+        // all entries untrusted, the right-most one is the client
+        assert!(!access(&plugin, "10.0.0.1:1234", Some("1.2.3.4, 8.8.8.8")));
+    }
+
+    #[test]
+    fn invalid_cidr() {
+        assert!(IpRestrictionPlugin::new(IpRestrictionConfig {
+            allow: vec!["10.0.0.0/33".to_string()],
+            deny: vec![],
+            fallback: IpPolicy::Allow,
+            trust_forwarded: false,
+            trusted_proxies: vec![],
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn trust_forwarded_without_proxies() {
+        assert!(IpRestrictionPlugin::new(IpRestrictionConfig {
+            allow: vec![],
+            deny: vec![],
+            fallback: IpPolicy::Allow,
+            trust_forwarded: true,
+            trusted_proxies: vec![],
+        })
+        .is_err());
+    }
+}
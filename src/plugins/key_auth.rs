@@ -0,0 +1,240 @@
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::context::{Consumer, GatewayContext};
+use crate::error::ConfigError;
+use crate::http::{json_error, HyperRequest, HyperResponse};
+use crate::variable::Variable;
+
+use super::Plugin;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct KeyAuthConfig {
+    /// header carrying the key
+    #[serde(default = "default_header")]
+    pub header: String,
+    /// query parameter carrying the key, checked when header is absent
+    #[serde(default)]
+    pub query: Option<String>,
+    pub keys: Vec<ApiKeyConfig>,
+}
+
+fn default_header() -> String {
+    "x-api-key".to_string()
+}
+
+/// One of `key` and `sha256` should be set.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ApiKeyConfig {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub key: Option<String>,
+    /// hex encoded sha256 of the key
+    #[serde(default)]
+    pub sha256: Option<String>,
+}
+
+/// Name of the authenticated key, shared by `GatewayContext::set`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyName(pub String);
+
+pub(super) type KeyDigest = [u8; 32];
+
+pub(crate) struct KeyAuthPlugin {
+    header: Variable,
+    query: Option<Variable>,
+    keys: Vec<(KeyDigest, String)>,
+}
+
+impl KeyAuthPlugin {
+    pub fn new(cfg: KeyAuthConfig) -> Result<Self, ConfigError> {
+        let mut keys = Vec::with_capacity(cfg.keys.len());
+
+        for (i, key) in cfg.keys.iter().enumerate() {
+            let digest = match (&key.key, &key.sha256) {
+                (Some(k), None) => digest(k),
+                (None, Some(h)) => decode_digest(h).ok_or_else(|| {
+                    ConfigError::Message(format!("invalid sha256 of api key<{}>", i))
+                })?,
+                _ => {
+                    return Err(ConfigError::Message(format!(
+                        "api key<{}> should have either key or sha256",
+                        i
+                    )))
+                }
+            };
+
+            let name = key.name.clone().unwrap_or_else(|| format!("key-{}", i));
+
+            keys.push((digest, name));
+        }
+
+        Ok(KeyAuthPlugin {
+            header: Variable::Http(cfg.header.to_lowercase()),
+            query: cfg.query.map(Variable::Arg),
+            keys,
+        })
+    }
+
+    fn extract_key(&self, ctx: &GatewayContext, req: &HyperRequest) -> Option<String> {
+        self.header
+            .resolve(ctx, req)
+            .or_else(|| self.query.as_ref().and_then(|q| q.resolve(ctx, req)))
+            .filter(|k| !k.is_empty())
+    }
+
+    /// Check against all keys, no early return to keep timing constant.
+    fn lookup(&self, key: &str) -> Option<&str> {
+        let presented = digest(key);
+
+        let mut found = None;
+        for (digest, name) in &self.keys {
+            if constant_time_eq(digest, &presented) {
+                found = Some(name.as_str());
+            }
+        }
+
+        found
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for KeyAuthPlugin {
+    fn name(&self) -> &str {
+        "key_auth"
+    }
+
+    fn priority(&self) -> u32 {
+        2500
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let key = match self.extract_key(ctx, &req) {
+            Some(key) => key,
+            None => return Err(json_error(StatusCode::UNAUTHORIZED, "missing api key")),
+        };
+
+        match self.lookup(&key) {
+            Some(name) => {
+                ctx.set(ApiKeyName(name.to_string()));
+                ctx.set(Consumer(name.to_string()));
+                Ok(req)
+            }
+            None => Err(json_error(StatusCode::FORBIDDEN, "invalid api key")),
+        }
+    }
+}
+
+pub(super) fn digest(key: &str) -> KeyDigest {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn decode_digest(hex: &str) -> Option<KeyDigest> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut digest = [0u8; 32];
+    for (i, b) in digest.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(digest)
+}
+
+pub(super) fn constant_time_eq(a: &KeyDigest, b: &KeyDigest) -> bool {
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    fn request(uri: &str, key: Option<&str>) -> HyperRequest {
+        let mut builder = hyper::Request::builder().uri(uri);
+        if let Some(key) = key {
+            builder = builder.header("X-Api-Key", key);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn access(plugin: &KeyAuthPlugin, req: HyperRequest) -> Result<Option<String>, StatusCode> {
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        futures::executor::block_on(plugin.on_access(&mut ctx, req))
+            .map(|_| ctx.get::<ApiKeyName>().map(|n| n.0.clone()))
+            .map_err(|resp| resp.status())
+    }
+
+    #[test]
+    fn header_and_query_key() {
+        let plugin = KeyAuthPlugin::new(KeyAuthConfig {
+            header: default_header(),
+            query: Some("apikey".to_string()),
+            keys: vec![ApiKeyConfig {
+                name: Some("alice".to_string()),
+                key: Some("secret".to_string()),
+                sha256: None,
+            }],
+        })
+        .unwrap();
+
+        let name = Ok(Some("alice".to_string()));
+
+        assert_eq!(access(&plugin, request("/", Some("secret"))), name);
+        assert_eq!(access(&plugin, request("/?apikey=secret", None)), name);
+        assert_eq!(
+            access(&plugin, request("/", None)),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            access(&plugin, request("/", Some("wrong"))),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[test]
+    fn hashed_key() {
+        // sha256 of `secret`
+        let hash = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+        let plugin = KeyAuthPlugin::new(KeyAuthConfig {
+            header: default_header(),
+            query: None,
+            keys: vec![ApiKeyConfig {
+                name: None,
+                key: None,
+                sha256: Some(hash.to_string()),
+            }],
+        })
+        .unwrap();
+
+        assert_eq!(
+            access(&plugin, request("/", Some("secret"))),
+            Ok(Some("key-0".to_string()))
+        );
+        assert_eq!(
+            access(&plugin, request("/?x-api-key=secret", None)),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        assert!(KeyAuthPlugin::new(KeyAuthConfig {
+            header: default_header(),
+            query: None,
+            keys: vec![ApiKeyConfig {
+                sha256: Some("abc".to_string()),
+                ..Default::default()
+            }],
+        })
+        .is_err());
+    }
+}
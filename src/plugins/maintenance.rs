@@ -0,0 +1,226 @@
+use hyper::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    Body, StatusCode,
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{HyperRequest, HyperResponse};
+
+use super::ip_restriction::{canonical, contains, parse_nets};
+use super::key_auth::{constant_time_eq, digest, KeyDigest};
+use super::Plugin;
+
+/// Answer requests with a maintenance page, upstream is not touched.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MaintenanceConfig {
+    #[serde(default = "default_status")]
+    pub status: u16,
+    /// seconds sent in `Retry-After`, 0 means not sent
+    #[serde(default)]
+    pub retry_after: u64,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    #[serde(default = "default_body")]
+    pub body: String,
+    /// CIDRs or addresses of clients passing through, like operators
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// requests with `bypass_secret` in this header pass through
+    #[serde(default)]
+    pub bypass_header: Option<String>,
+    #[serde(default)]
+    pub bypass_secret: Option<String>,
+}
+
+fn default_status() -> u16 {
+    503
+}
+
+fn default_content_type() -> String {
+    "application/json".to_string()
+}
+
+fn default_body() -> String {
+    r#"{"message":"service under maintenance"}"#.to_string()
+}
+
+pub(crate) struct MaintenancePlugin {
+    status: StatusCode,
+    retry_after: u64,
+    content_type: HeaderValue,
+    body: String,
+    allow: Vec<IpNet>,
+    bypass: Option<(HeaderName, KeyDigest)>,
+}
+
+impl MaintenancePlugin {
+    pub fn new(cfg: MaintenanceConfig) -> Result<Self, ConfigError> {
+        let status = StatusCode::from_u16(cfg.status)
+            .map_err(|_| ConfigError::Message(format!("invalid status<{}>", cfg.status)))?;
+        let content_type = HeaderValue::from_str(&cfg.content_type).map_err(|_| {
+            ConfigError::Message(format!("invalid content_type<{}>", cfg.content_type))
+        })?;
+
+        let bypass = match (cfg.bypass_header, cfg.bypass_secret) {
+            (Some(header), Some(secret)) if !secret.is_empty() => {
+                let header = HeaderName::from_bytes(header.as_bytes()).map_err(|_| {
+                    ConfigError::Message(format!("invalid header name<{}>", header))
+                })?;
+                Some((header, digest(&secret)))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError::Message(
+                    "bypass_header and non-empty bypass_secret should be set together".to_string(),
+                ))
+            }
+        };
+
+        Ok(MaintenancePlugin {
+            status,
+            retry_after: cfg.retry_after,
+            content_type,
+            body: cfg.body,
+            allow: parse_nets(&cfg.allow)?,
+            bypass,
+        })
+    }
+
+    fn bypassed(&self, ctx: &GatewayContext, req: &HyperRequest) -> bool {
+        let allowed = ctx
+            .remote_addr()
+            .map(|addr| contains(&self.allow, &canonical(addr.ip())))
+            .unwrap_or(false);
+
+        let presented = self.bypass.as_ref().and_then(|(header, secret)| {
+            let value = req.headers().get(header)?.to_str().ok()?;
+            Some(constant_time_eq(secret, &digest(value)))
+        });
+
+        allowed || presented.unwrap_or(false)
+    }
+
+    fn response(&self) -> HyperResponse {
+        let mut resp = hyper::Response::new(Body::from(self.body.clone()));
+        *resp.status_mut() = self.status;
+
+        let headers = resp.headers_mut();
+        headers.insert(CONTENT_TYPE, self.content_type.clone());
+        if self.retry_after > 0 {
+            headers.insert(RETRY_AFTER, HeaderValue::from(self.retry_after));
+        }
+
+        resp
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for MaintenancePlugin {
+    fn name(&self) -> &str {
+        "maintenance"
+    }
+
+    fn priority(&self) -> u32 {
+        3400
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        mut req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        if !self.bypassed(ctx, &req) {
+            return Err(self.response());
+        }
+
+        // secret is for gateway only
+        if let Some((header, _)) = &self.bypass {
+            req.headers_mut().remove(header);
+        }
+
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::http::uri::Scheme;
+
+    use super::*;
+
+    fn new_plugin(cfg: serde_json::Value) -> MaintenancePlugin {
+        MaintenancePlugin::new(serde_json::from_value(cfg).unwrap()).unwrap()
+    }
+
+    fn run(
+        plugin: &MaintenancePlugin,
+        remote: &str,
+        header: Option<(&str, &str)>,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let mut builder = hyper::Request::builder();
+        if let Some((name, value)) = header {
+            builder = builder.header(name, value);
+        }
+        let req = builder.body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(Some(remote.parse().unwrap()), Scheme::HTTP, &req);
+
+        futures::executor::block_on(plugin.on_access(&mut ctx, req))
+    }
+
+    #[test]
+    fn maintenance_response() {
+        let plugin = new_plugin(serde_json::json!({
+            "retry_after": 120,
+            "content_type": "text/html",
+            "body": "<h1>back soon</h1>"
+        }));
+
+        let resp = run(&plugin, "1.2.3.4:1000", None).unwrap_err();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[RETRY_AFTER], "120");
+        assert_eq!(resp.headers()[CONTENT_TYPE], "text/html");
+        let body = futures::executor::block_on(hyper::body::to_bytes(resp.into_body())).unwrap();
+        assert_eq!(body, "<h1>back soon</h1>");
+
+        let plugin = new_plugin(serde_json::json!({ "status": 502 }));
+        let resp = run(&plugin, "1.2.3.4:1000", None).unwrap_err();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert!(!resp.headers().contains_key(RETRY_AFTER));
+
+        let cfg = serde_json::json!({ "content_type": "text/html\r\nx-injected: 1" });
+        assert!(MaintenancePlugin::new(serde_json::from_value(cfg).unwrap()).is_err());
+    }
+
+    #[test]
+    fn maintenance_bypass() {
+        let plugin = new_plugin(serde_json::json!({
+            "allow": ["10.0.0.0/8"],
+            "bypass_header": "x-maintenance-bypass",
+            "bypass_secret": "s3cret"
+        }));
+
+        assert!(run(&plugin, "10.1.2.3:1000", None).is_ok());
+        assert!(run(&plugin, "[::ffff:10.1.2.3]:1000", None).is_ok());
+        assert!(run(&plugin, "1.2.3.4:1000", None).is_err());
+        assert!(run(
+            &plugin,
+            "1.2.3.4:1000",
+            Some(("x-maintenance-bypass", "wrong"))
+        )
+        .is_err());
+
+        let req = run(
+            &plugin,
+            "1.2.3.4:1000",
+            Some(("x-maintenance-bypass", "s3cret")),
+        )
+        .unwrap();
+        assert!(!req.headers().contains_key("x-maintenance-bypass"));
+
+        let cfg = serde_json::json!({ "bypass_header": "x-maintenance-bypass" });
+        assert!(MaintenancePlugin::new(serde_json::from_value(cfg).unwrap()).is_err());
+    }
+}
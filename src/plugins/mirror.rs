@@ -0,0 +1,612 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING},
+    http::response::Parts,
+    Body, StatusCode,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::oneshot;
+
+use crate::budget::{memory_budget, BudgetPermit};
+use crate::context::GatewayContext;
+use crate::error::{BufferError, ConfigError};
+use crate::http::{
+    bad_gateway, buffer_body, buffer_response_body, json_error, BufferedBody, HyperRequest,
+    HyperResponse,
+};
+
+use super::Plugin;
+
+/// Mirror a percentage of requests to another upstream, responses of mirror are dropped.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MirrorConfig {
+    pub upstream_id: String,
+    /// percentage of requests, like `0.5` for 0.5%
+    #[serde(default = "default_percentage")]
+    pub percentage: f64,
+    /// mirror request body, buffered up to `max_body_size`
+    #[serde(default)]
+    pub include_body: bool,
+    /// max body size in bytes, larger requests are not mirrored
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    /// timeout in milliseconds of mirror request
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// compare responses of mirror with the ones of primary upstream
+    #[serde(default)]
+    pub compare: Option<CompareConfig>,
+}
+
+/// Differences are logged and counted by route, see `mirror_stats`.
+///
+/// Primary responses are buffered up to `max_body_size` for comparing, larger bodies
+/// are streamed and left out of comparison.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CompareConfig {
+    /// headers compared besides status and body
+    #[serde(default)]
+    pub headers: Vec<String>,
+    /// JSON pointers of body fields ignored, like `/timestamp`
+    #[serde(default)]
+    pub ignore_fields: Vec<String>,
+}
+
+fn default_percentage() -> f64 {
+    100.0
+}
+
+fn default_max_body_size() -> usize {
+    1024 * 1024
+}
+
+fn default_timeout() -> u64 {
+    5000
+}
+
+pub(crate) struct MirrorPlugin {
+    upstream_id: String,
+    percentage: f64,
+    include_body: bool,
+    max_body_size: usize,
+    timeout: Duration,
+    compare: Option<Arc<Comparison>>,
+}
+
+impl MirrorPlugin {
+    pub fn new(cfg: MirrorConfig) -> Result<Self, ConfigError> {
+        if !(0.0..=100.0).contains(&cfg.percentage) {
+            return Err(ConfigError::Message(format!(
+                "invalid percentage<{}>",
+                cfg.percentage
+            )));
+        }
+
+        let compare = cfg.compare.map(Comparison::new).transpose()?.map(Arc::new);
+
+        Ok(MirrorPlugin {
+            upstream_id: cfg.upstream_id,
+            percentage: cfg.percentage,
+            include_body: cfg.include_body,
+            max_body_size: cfg.max_body_size,
+            timeout: Duration::from_millis(cfg.timeout),
+            compare,
+        })
+    }
+}
+
+struct Comparison {
+    headers: Vec<HeaderName>,
+    ignore_fields: Vec<String>,
+}
+
+/// at most this many differences logged for a response
+const MAX_DIFFERENCES: usize = 16;
+
+impl Comparison {
+    fn new(cfg: CompareConfig) -> Result<Self, ConfigError> {
+        let headers = cfg
+            .headers
+            .iter()
+            .map(|h| {
+                HeaderName::from_bytes(h.as_bytes())
+                    .map_err(|_| ConfigError::Message(format!("invalid header name<{}>", h)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        if let Some(field) = cfg.ignore_fields.iter().find(|f| !f.starts_with('/')) {
+            return Err(ConfigError::Message(format!(
+                "invalid ignore field<{}>, should be a JSON pointer",
+                field
+            )));
+        }
+
+        Ok(Comparison {
+            headers,
+            ignore_fields: cfg.ignore_fields,
+        })
+    }
+
+    fn summary(&self, parts: &Parts, body: Option<Bytes>) -> ResponseSummary {
+        ResponseSummary {
+            status: parts.status,
+            headers: self
+                .headers
+                .iter()
+                .map(|h| parts.headers.get(h).cloned())
+                .collect(),
+            body,
+            permit: None,
+        }
+    }
+
+    /// Body is not compared when out of memory budget.
+    async fn read_summary(&self, resp: HyperResponse, limit: usize) -> ResponseSummary {
+        let (parts, body) = resp.into_parts();
+
+        let mut permit = memory_budget().try_reserve(limit);
+        let body = match permit {
+            Some(_) => match buffer_body(body, limit).await {
+                Ok(BufferedBody::Full(bytes)) => Some(bytes),
+                _ => None,
+            },
+            None => None,
+        };
+
+        if let Some(permit) = permit.as_mut() {
+            permit.shrink(body.as_ref().map_or(0, Bytes::len));
+        }
+
+        let mut summary = self.summary(&parts, body);
+        summary.permit = permit;
+        summary
+    }
+
+    fn diff(&self, primary: &ResponseSummary, mirror: &ResponseSummary) -> Vec<Difference> {
+        let mut diffs = Vec::new();
+
+        if primary.status != mirror.status {
+            diffs.push(Difference::Status(primary.status, mirror.status));
+        }
+
+        let headers = self
+            .headers
+            .iter()
+            .zip(&primary.headers)
+            .zip(&mirror.headers);
+        for ((name, p), m) in headers {
+            if p != m {
+                diffs.push(Difference::Header(name.clone(), p.clone(), m.clone()));
+            }
+        }
+
+        // bodies too large on either side are not compared
+        if let (Some(p), Some(m)) = (&primary.body, &mirror.body) {
+            let json = serde_json::from_slice::<Value>(p)
+                .and_then(|p| Ok((p, serde_json::from_slice::<Value>(m)?)));
+
+            match json {
+                Ok((mut p, mut m)) => {
+                    for field in &self.ignore_fields {
+                        remove_field(&mut p, field);
+                        remove_field(&mut m, field);
+                    }
+
+                    let mut paths = Vec::new();
+                    json_diff("", &p, &m, &mut paths);
+                    diffs.extend(paths.into_iter().map(Difference::Body));
+                }
+                Err(_) if p != m => diffs.push(Difference::Body(String::new())),
+                Err(_) => {}
+            }
+        }
+
+        diffs.truncate(MAX_DIFFERENCES);
+        diffs
+    }
+}
+
+/// Remove field at `pointer`, elements of arrays are nulled to keep indexes.
+fn remove_field(value: &mut Value, pointer: &str) {
+    let (parent, key) = match pointer.rsplit_once('/') {
+        Some(split) => split,
+        None => return,
+    };
+
+    if let Some(Value::Object(map)) = value.pointer_mut(parent) {
+        map.remove(&key.replace("~1", "/").replace("~0", "~"));
+    } else if let Some(v) = value.pointer_mut(pointer) {
+        *v = Value::Null;
+    }
+}
+
+/// JSON pointers of values differing, objects compared regardless of key order.
+fn json_diff(path: &str, a: &Value, b: &Value, out: &mut Vec<String>) {
+    if out.len() >= MAX_DIFFERENCES {
+        return;
+    }
+
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+
+            for key in keys {
+                let path = format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"));
+
+                match (a.get(key), b.get(key)) {
+                    (Some(a), Some(b)) => json_diff(&path, a, b, out),
+                    _ => out.push(path),
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (a, b)) in a.iter().zip(b).enumerate() {
+                json_diff(&format!("{}/{}", path, i), a, b, out);
+            }
+        }
+        _ if a != b => out.push(path.to_string()),
+        _ => {}
+    }
+}
+
+/// What is compared of a response.
+struct ResponseSummary {
+    status: StatusCode,
+    /// values of `Comparison.headers` in order
+    headers: Vec<Option<HeaderValue>>,
+    /// `None` when too large to buffer
+    body: Option<Bytes>,
+    /// memory budget of `body`, held until compared
+    permit: Option<BudgetPermit<'static>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Difference {
+    Status(StatusCode, StatusCode),
+    Header(HeaderName, Option<HeaderValue>, Option<HeaderValue>),
+    /// JSON pointer of the field, empty for the whole body
+    Body(String),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Status(p, m) => write!(f, "status {} != {}", p.as_u16(), m.as_u16()),
+            Difference::Header(name, p, m) => write!(f, "header {} {:?} != {:?}", name, p, m),
+            Difference::Body(path) if path.is_empty() => write!(f, "body"),
+            Difference::Body(path) => write!(f, "body {}", path),
+        }
+    }
+}
+
+/// Mirror response of the request, sent by the task forwarding it.
+struct PendingComparison(oneshot::Receiver<ResponseSummary>);
+
+lazy_static::lazy_static! {
+    static ref G_MIRROR_STATS: MirrorStats = MirrorStats::default();
+}
+
+pub fn mirror_stats() -> &'static MirrorStats {
+    &G_MIRROR_STATS
+}
+
+/// Comparison counts of a route since start.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MirrorReport {
+    pub route_id: String,
+    pub compared: u64,
+    pub mismatched: u64,
+    pub status_mismatched: u64,
+    pub header_mismatched: u64,
+    pub body_mismatched: u64,
+    /// mirror request failed or timed out, nothing to compare
+    pub failed: u64,
+    pub mismatch_rate: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct MirrorStats {
+    routes: Mutex<HashMap<String, MirrorReport>>,
+}
+
+impl MirrorStats {
+    fn record(&self, route_id: &str, diffs: &[Difference]) {
+        let mut routes = self.routes.lock().unwrap();
+        let report = Self::report(&mut routes, route_id);
+
+        report.compared += 1;
+        if !diffs.is_empty() {
+            report.mismatched += 1;
+        }
+
+        let has = |f: fn(&Difference) -> bool| diffs.iter().any(f) as u64;
+        report.status_mismatched += has(|d| matches!(d, Difference::Status(..)));
+        report.header_mismatched += has(|d| matches!(d, Difference::Header(..)));
+        report.body_mismatched += has(|d| matches!(d, Difference::Body(..)));
+
+        report.mismatch_rate = report.mismatched as f64 / report.compared as f64;
+    }
+
+    fn record_failed(&self, route_id: &str) {
+        let mut routes = self.routes.lock().unwrap();
+
+        Self::report(&mut routes, route_id).failed += 1;
+    }
+
+    fn report<'a>(
+        routes: &'a mut HashMap<String, MirrorReport>,
+        route_id: &str,
+    ) -> &'a mut MirrorReport {
+        routes
+            .entry(route_id.to_string())
+            .or_insert_with(|| MirrorReport {
+                route_id: route_id.to_string(),
+                ..Default::default()
+            })
+    }
+
+    pub fn reports(&self) -> Vec<MirrorReport> {
+        let mut reports: Vec<_> = self.routes.lock().unwrap().values().cloned().collect();
+        reports.sort_by(|a, b| a.route_id.cmp(&b.route_id));
+        reports
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for MirrorPlugin {
+    fn name(&self) -> &str {
+        "mirror"
+    }
+
+    fn priority(&self) -> u32 {
+        800
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        mut req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        if rand::thread_rng().gen::<f64>() * 100.0 >= self.percentage {
+            return Ok(req);
+        }
+
+        let upstream = match ctx
+            .upstreams
+            .as_ref()
+            .and_then(|u| u.get(&self.upstream_id))
+        {
+            Some(upstream) => upstream,
+            None => {
+                tracing::warn!(upstream_id = %self.upstream_id, "mirror upstream not found");
+                return Ok(req);
+            }
+        };
+
+        let mirror_body = if self.include_body {
+            match ctx.buffer_body(&mut req, self.max_body_size).await {
+                Ok(Some(bytes)) => Body::from(bytes),
+                Ok(None) | Err(BufferError::BudgetExhausted) => {
+                    tracing::debug!(route_id = ?ctx.route_id, "request too large to mirror");
+                    return Ok(req);
+                }
+                Err(err) => {
+                    tracing::debug!(route_id = ?ctx.route_id, %err, "read request body failed");
+                    return Err(json_error(
+                        StatusCode::BAD_REQUEST,
+                        "read request body failed",
+                    ));
+                }
+            }
+        } else {
+            Body::empty()
+        };
+
+        let mut mirror_req = hyper::Request::builder()
+            .method(req.method().clone())
+            .uri(req.uri().clone())
+            .version(req.version())
+            .body(mirror_body)
+            .unwrap();
+        *mirror_req.headers_mut() = req.headers().clone();
+        if !self.include_body {
+            mirror_req.headers_mut().remove(CONTENT_LENGTH);
+            mirror_req.headers_mut().remove(TRANSFER_ENCODING);
+        }
+
+        let mut mirror_ctx =
+            GatewayContext::new(ctx.remote_addr(), ctx.orig_scheme().clone(), &mirror_req);
+        mirror_ctx.route_id = ctx.route_id.clone();
+        mirror_ctx.upstream_id = Some(self.upstream_id.clone());
+        mirror_ctx.overwrite_host = ctx.overwrite_host;
+        mirror_ctx.upstream_host = ctx.upstream_host.clone();
+
+        let mut forwarder = upstream.read().unwrap().forwarder(&mut mirror_ctx);
+        let timeout = self.timeout;
+        let max_body_size = self.max_body_size;
+
+        // mirror response is handed over to `after_forward` of primary
+        let compare = self.compare.clone().map(|compare| {
+            let (tx, rx) = oneshot::channel();
+            ctx.set(PendingComparison(rx));
+            (compare, tx)
+        });
+
+        // primary request goes on without waiting
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let forwarded =
+                tokio::time::timeout(timeout, forwarder.forward(&mut mirror_ctx, mirror_req)).await;
+
+            let route_id = mirror_ctx.route_id;
+            let upstream_id = mirror_ctx.upstream_id;
+            let latency = start.elapsed();
+
+            match forwarded {
+                Ok(Ok(resp)) => {
+                    tracing::debug!(
+                        ?route_id,
+                        ?upstream_id,
+                        status = %resp.status(),
+                        ?latency,
+                        "mirror request done"
+                    );
+
+                    if let Some((compare, tx)) = compare {
+                        let summary = compare.read_summary(resp, max_body_size);
+                        if let Ok(summary) = tokio::time::timeout(timeout, summary).await {
+                            let _ = tx.send(summary);
+                        }
+                    }
+                }
+                Ok(Err(err)) => {
+                    tracing::warn!(
+                        ?route_id,
+                        ?upstream_id,
+                        ?err,
+                        ?latency,
+                        "mirror request failed"
+                    )
+                }
+                Err(_) => {
+                    tracing::warn!(?route_id, ?upstream_id, ?latency, "mirror request timeout")
+                }
+            }
+        });
+
+        Ok(req)
+    }
+
+    async fn after_forward(
+        &self,
+        ctx: &mut GatewayContext,
+        mut resp: HyperResponse,
+    ) -> HyperResponse {
+        let pending = ctx.remove::<PendingComparison>();
+        let (compare, pending) = match (&self.compare, pending) {
+            (Some(compare), Some(pending)) => (compare.clone(), pending),
+            _ => return resp,
+        };
+
+        let primary_body = match buffer_response_body(&mut resp, self.max_body_size).await {
+            Ok(body) => body,
+            Err(BufferError::BudgetExhausted) => None,
+            Err(err) => {
+                tracing::debug!(route_id = ?ctx.route_id, %err, "read upstream body failed");
+                return bad_gateway();
+            }
+        };
+
+        // kept for comparing after the response is sent, reserved apart from the response
+        let permit = primary_body
+            .as_ref()
+            .and_then(|body| memory_budget().try_reserve(body.len()));
+        let primary_body = primary_body.filter(|_| permit.is_some());
+
+        let (parts, body) = resp.into_parts();
+        let mut primary = compare.summary(&parts, primary_body);
+        primary.permit = permit;
+        let route_id = ctx.route_id.clone().unwrap_or_default();
+        let timeout = self.timeout;
+
+        tokio::spawn(async move {
+            let mirror = match tokio::time::timeout(timeout, pending.0).await {
+                Ok(Ok(mirror)) => mirror,
+                _ => {
+                    mirror_stats().record_failed(&route_id);
+                    return;
+                }
+            };
+
+            let diffs = compare.diff(&primary, &mirror);
+            mirror_stats().record(&route_id, &diffs);
+
+            if !diffs.is_empty() {
+                let diffs: Vec<String> = diffs.iter().map(|d| d.to_string()).collect();
+                tracing::warn!(%route_id, ?diffs, "mirror response differs");
+            }
+        });
+
+        HyperResponse::from_parts(parts, body)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn summary(compare: &Comparison, status: u16, etag: &str, body: &str) -> ResponseSummary {
+        let resp = hyper::Response::builder()
+            .status(status)
+            .header("etag", etag)
+            .header("date", "now")
+            .body(())
+            .unwrap();
+        let (parts, _) = resp.into_parts();
+
+        compare.summary(&parts, Some(Bytes::from(body.to_string())))
+    }
+
+    #[test]
+    fn compare_responses() {
+        let compare = Comparison::new(CompareConfig {
+            headers: vec!["etag".to_string(), "x-missing".to_string()],
+            ignore_fields: vec!["/at".to_string()],
+        })
+        .unwrap();
+
+        let primary = summary(&compare, 200, "1", r#"{"a": 1, "b": [1, 2], "at": 10}"#);
+
+        // key order, ignored fields and headers not compared make no difference
+        let mirror = summary(&compare, 200, "1", r#"{"at": 20, "b": [1, 2], "a": 1}"#);
+        assert!(compare.diff(&primary, &mirror).is_empty());
+
+        let mirror = summary(&compare, 500, "2", r#"{"a": 2, "b": [1, 3], "c/d": 0}"#);
+        let diffs: Vec<_> = compare
+            .diff(&primary, &mirror)
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            diffs,
+            vec![
+                "status 200 != 500",
+                r#"header etag Some("1") != Some("2")"#,
+                "body /a",
+                "body /b/1",
+                "body /c~1d",
+            ]
+        );
+
+        let mirror = summary(&compare, 200, "1", "not json");
+        assert_eq!(
+            compare.diff(&primary, &mirror),
+            vec![Difference::Body(String::new())]
+        );
+
+        let stats = MirrorStats::default();
+        stats.record("r1", &[]);
+        stats.record("r1", &compare.diff(&primary, &mirror));
+        stats.record_failed("r1");
+        let report = &stats.reports()[0];
+        assert_eq!(
+            (
+                report.compared,
+                report.mismatched,
+                report.body_mismatched,
+                report.failed
+            ),
+            (2, 1, 1, 1)
+        );
+        assert_eq!(report.mismatch_rate, 0.5);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{collections::HashMap, time::Duration};
+
+use base64::Engine;
+use hyper::{
+    body::Bytes,
+    header::{HeaderName, HeaderValue, CONTENT_TYPE},
+    Body, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{HyperRequest, HyperResponse};
+
+use super::Plugin;
+
+/// Answer with a canned response, the route needs no upstream.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MockConfig {
+    #[serde(default = "default_status_code")]
+    pub status_code: u16,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    /// base64 encoded body, for binary content
+    #[serde(default)]
+    pub body_base64: Option<String>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    /// delay in milliseconds before responding
+    #[serde(default)]
+    pub delay: u64,
+}
+
+fn default_status_code() -> u16 {
+    200
+}
+
+pub(crate) struct MockPlugin {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+    delay: Option<Duration>,
+}
+
+impl MockPlugin {
+    pub fn new(cfg: MockConfig) -> Result<Self, ConfigError> {
+        let status = StatusCode::from_u16(cfg.status_code)
+            .map_err(|_| ConfigError::Message(format!("invalid status<{}>", cfg.status_code)))?;
+
+        let body = match (cfg.body, cfg.body_base64) {
+            (Some(_), Some(_)) => {
+                return Err(ConfigError::Message(
+                    "only one of body and body_base64 should be set".to_string(),
+                ))
+            }
+            (Some(body), None) => Bytes::from(body),
+            (None, Some(encoded)) => base64::engine::general_purpose::STANDARD
+                .decode(encoded.trim())
+                .map(Bytes::from)
+                .map_err(|e| ConfigError::Message(format!("invalid body_base64: {}", e)))?,
+            (None, None) => Bytes::new(),
+        };
+
+        let mut headers = cfg
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| ConfigError::Message(format!("invalid header name<{}>", name)))?;
+                let value = HeaderValue::from_str(value).map_err(|_| {
+                    ConfigError::Message(format!("invalid value<{}> of header<{}>", value, name))
+                })?;
+                Ok((name, value))
+            })
+            .collect::<Result<Vec<_>, ConfigError>>()?;
+
+        if let Some(content_type) = cfg.content_type {
+            let value = HeaderValue::from_str(&content_type).map_err(|_| {
+                ConfigError::Message(format!("invalid content_type<{}>", content_type))
+            })?;
+            headers.retain(|(name, _)| name != CONTENT_TYPE);
+            headers.push((CONTENT_TYPE, value));
+        }
+
+        Ok(MockPlugin {
+            status,
+            headers,
+            body,
+            delay: Some(Duration::from_millis(cfg.delay)).filter(|d| !d.is_zero()),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for MockPlugin {
+    fn name(&self) -> &str {
+        "mock"
+    }
+
+    fn priority(&self) -> u32 {
+        1000
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        _req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
+
+        tracing::debug!(route_id = ?ctx.route_id, status = %self.status, "mock response");
+
+        let mut resp = HyperResponse::new(Body::from(self.body.clone()));
+        *resp.status_mut() = self.status;
+        for (name, value) in &self.headers {
+            resp.headers_mut().append(name.clone(), value.clone());
+        }
+
+        Err(resp)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use hyper::http::uri::Scheme;
+    use serde_json::json;
+
+    use super::*;
+
+    fn new_plugin(cfg: serde_json::Value) -> Result<MockPlugin, ConfigError> {
+        MockPlugin::new(serde_json::from_value(cfg).unwrap())
+    }
+
+    async fn respond(plugin: &MockPlugin) -> (hyper::http::response::Parts, Bytes) {
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let resp = plugin.on_access(&mut ctx, req).await.unwrap_err();
+        let (parts, body) = resp.into_parts();
+
+        (parts, hyper::body::to_bytes(body).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn mock_json() {
+        let plugin = new_plugin(json!({
+            "status_code": 201,
+            "headers": { "x-mock": "1" },
+            "body": r#"{"id": 7}"#,
+            "content_type": "application/json"
+        }))
+        .unwrap();
+
+        let (parts, body) = respond(&plugin).await;
+        assert_eq!(parts.status, StatusCode::CREATED);
+        assert_eq!(parts.headers["x-mock"], "1");
+        assert_eq!(parts.headers[CONTENT_TYPE], "application/json");
+
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, json!({ "id": 7 }));
+
+        let plugin = new_plugin(json!({ "body_base64": "AAEC/w==" })).unwrap();
+        let (parts, body) = respond(&plugin).await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(&body[..], &[0, 1, 2, 255]);
+
+        assert!(new_plugin(json!({ "body": "a", "body_base64": "YQ==" })).is_err());
+        assert!(new_plugin(json!({ "body_base64": "not base64!" })).is_err());
+        assert!(new_plugin(json!({ "status_code": 1000 })).is_err());
+    }
+
+    #[tokio::test]
+    async fn mock_delay() {
+        let plugin = new_plugin(json!({ "body": "late", "delay": 100 })).unwrap();
+
+        let start = Instant::now();
+        let (_, body) = respond(&plugin).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(&body[..], b"late");
+    }
+}
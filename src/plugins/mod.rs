@@ -1,23 +1,101 @@
+pub mod circuit_breaker;
+pub mod concurrency_limit;
+pub mod fault_injection;
+pub mod geoip;
+pub mod headers;
+pub mod internal_redirect;
+pub mod ip_restriction;
+pub mod key_auth;
+pub mod maintenance;
+pub mod mirror;
+pub mod mock;
+pub mod multipart_limit;
+pub mod normalize_path;
+pub mod oauth2_introspection;
 pub mod path_rewrite;
+pub mod rate_limit;
+pub mod request_id;
+pub mod request_validation;
+pub mod response_signing;
+pub mod response_template;
 pub mod script;
+pub mod security_headers;
+pub mod timeout;
 pub mod traffic_split;
+pub mod ua_block;
+pub mod virus_scan;
 
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
 use serde::de::DeserializeOwned;
 
+use crate::config::{PluginConfig, PluginLoadMode};
 use crate::context::GatewayContext;
-use crate::error::ConfigError;
+use crate::error::{ConfigError, PluginError};
 use crate::http::{HyperRequest, HyperResponse};
+use crate::matcher::RouteMatcher;
+use crate::router::RoutePlugin;
 
+use self::circuit_breaker::CircuitBreakerPlugin;
+pub use self::circuit_breaker::{CircuitBreakerConfig, CircuitState, CircuitTransition};
+pub use self::concurrency_limit::ConcurrencyLimitConfig;
+use self::concurrency_limit::ConcurrencyLimitPlugin;
+use self::fault_injection::FaultInjectionPlugin;
+pub use self::fault_injection::{AbortFault, DelayFault, FaultInjectionConfig};
+pub use self::geoip::GeoipConfig;
+use self::geoip::GeoipPlugin;
+pub use self::headers::{HeaderTransform, HeadersConfig};
+use self::headers::HeadersPlugin;
+pub use self::internal_redirect::InternalRedirectConfig;
+use self::internal_redirect::InternalRedirectPlugin;
+use self::ip_restriction::IpRestrictionPlugin;
+pub use self::ip_restriction::{IpPolicy, IpRestrictionConfig};
+use self::key_auth::KeyAuthPlugin;
+pub use self::key_auth::{ApiKeyConfig, ApiKeyName, KeyAuthConfig};
+pub use self::maintenance::MaintenanceConfig;
+use self::maintenance::MaintenancePlugin;
+pub use self::mirror::{CompareConfig, MirrorConfig};
+use self::mirror::MirrorPlugin;
+pub use self::mock::MockConfig;
+use self::mock::MockPlugin;
+pub use self::multipart_limit::MultipartLimitConfig;
+use self::multipart_limit::MultipartLimitPlugin;
+pub use self::normalize_path::NormalizePathConfig;
+use self::normalize_path::NormalizePathPlugin;
+use self::oauth2_introspection::OAuth2IntrospectionPlugin;
+pub use self::oauth2_introspection::{OAuth2IntrospectionConfig, TokenIntrospection};
 pub use self::path_rewrite::PathRewriteConfig;
 use self::path_rewrite::PathRewritePlugin;
+use self::rate_limit::RateLimitPlugin;
+pub use self::rate_limit::{RateLimitConfig, RatePeriod};
+pub use self::request_id::{IdGenerator, RequestId, RequestIdConfig};
+use self::request_id::RequestIdPlugin;
+pub use self::request_validation::RequestValidationConfig;
+use self::request_validation::RequestValidationPlugin;
+use self::response_signing::ResponseSigningPlugin;
+pub use self::response_signing::{ResponseSigningConfig, SigningAlgorithm};
+pub use self::response_template::ResponseTemplateConfig;
+use self::response_template::ResponseTemplatePlugin;
 pub use self::script::ScriptConfig;
 use self::script::ScriptPlugin;
+use self::security_headers::SecurityHeadersPlugin;
+pub use self::security_headers::{HeaderSetting, SecurityHeadersConfig};
+pub use self::timeout::TimeoutConfig;
+use self::timeout::TimeoutPlugin;
 use self::traffic_split::TrafficSplitPlugin;
-pub use self::traffic_split::{TrafficSplitConfig, TrafficSplitRule};
+pub use self::traffic_split::{
+    StickyConfig, TrafficSplitConfig, TrafficSplitRule, TrafficSplitVariant,
+};
+use self::ua_block::UaBlockPlugin;
+pub use self::ua_block::{UaBlockConfig, UaBlockMode};
+pub use self::virus_scan::VirusScanConfig;
+use self::virus_scan::VirusScanPlugin;
 
-pub trait Plugin {
+#[async_trait::async_trait]
+pub trait Plugin: Send + Sync {
     /// Get plugin name.
     fn name(&self) -> &str {
         std::any::type_name::<Self>()
@@ -27,7 +105,7 @@ pub trait Plugin {
     fn priority(&self) -> u32;
 
     /// when a request arrived, check or rewrite request.
-    fn on_access(
+    async fn on_access(
         &self,
         ctx: &mut GatewayContext,
         req: HyperRequest,
@@ -37,28 +115,264 @@ pub trait Plugin {
     }
 
     /// after forward request, check or rewrite response.
-    fn after_forward(&self, ctx: &mut GatewayContext, resp: HyperResponse) -> HyperResponse {
+    async fn after_forward(&self, ctx: &mut GatewayContext, resp: HyperResponse) -> HyperResponse {
         let _ = ctx;
         resp
     }
+
+    /// after route of plugin published, spawn background tasks here.
+    fn on_start(&self) {}
+
+    /// after plugin left the published registry, stop background tasks here.
+    fn on_stop(&self) {}
 }
 
+/// Plugins answering every request themselves, routes running them need no upstream.
+pub const TERMINATING_PLUGINS: &[&str] = &["mock"];
+
 fn parse_config<T: DeserializeOwned>(cfg: serde_json::Value) -> Result<T, ConfigError> {
     serde_json::from_value(cfg).map_err(Into::into)
 }
 
+/// Build a plugin from its config, the part of `PluginConfig` besides `enable` and `when`.
+pub type PluginCtor = fn(serde_json::Value) -> Result<Box<dyn Plugin + Send + Sync>, ConfigError>;
+
+/// Constructors of plugins by name, routes resolve their plugins here.
+pub struct PluginRegistry {
+    ctors: HashMap<String, PluginCtor>,
+}
+
+impl PluginRegistry {
+    pub fn register(&mut self, name: &str, ctor: PluginCtor) -> Result<(), ConfigError> {
+        if self.ctors.contains_key(name) {
+            return Err(ConfigError::DuplicatePlugin(name.to_string()));
+        }
+
+        self.ctors.insert(name.to_string(), ctor);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<PluginCtor> {
+        self.ctors.get(name).copied()
+    }
+}
+
+macro_rules! builtin {
+    ($registry:ident, $($name:literal => $plugin:ident,)*) => {
+        $(
+            $registry
+                .register($name, |cfg| Ok(Box::new($plugin::new(parse_config(cfg)?)?)))
+                .unwrap();
+        )*
+    };
+}
+
+impl Default for PluginRegistry {
+    /// Registry of built-in plugins.
+    fn default() -> Self {
+        let mut registry = PluginRegistry {
+            ctors: HashMap::new(),
+        };
+
+        builtin! {
+            registry,
+            "circuit_breaker" => CircuitBreakerPlugin,
+            "concurrency_limit" => ConcurrencyLimitPlugin,
+            "fault_injection" => FaultInjectionPlugin,
+            "geoip" => GeoipPlugin,
+            "headers" => HeadersPlugin,
+            "internal_redirect" => InternalRedirectPlugin,
+            "ip_restriction" => IpRestrictionPlugin,
+            "key_auth" => KeyAuthPlugin,
+            "maintenance" => MaintenancePlugin,
+            "mirror" => MirrorPlugin,
+            "mock" => MockPlugin,
+            "multipart_limit" => MultipartLimitPlugin,
+            "normalize_path" => NormalizePathPlugin,
+            "oauth2_introspection" => OAuth2IntrospectionPlugin,
+            "path_rewrite" => PathRewritePlugin,
+            "rate_limit" => RateLimitPlugin,
+            "request_id" => RequestIdPlugin,
+            "request_validation" => RequestValidationPlugin,
+            "traffic_split" => TrafficSplitPlugin,
+            "response_signing" => ResponseSigningPlugin,
+            "response_template" => ResponseTemplatePlugin,
+            "script" => ScriptPlugin,
+            "security_headers" => SecurityHeadersPlugin,
+            "timeout" => TimeoutPlugin,
+            "ua_block" => UaBlockPlugin,
+            "virus_scan" => VirusScanPlugin,
+        }
+
+        registry
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref G_PLUGIN_REGISTRY: RwLock<PluginRegistry> = RwLock::new(PluginRegistry::default());
+    static ref G_PLUGIN_LOAD_MODE: RwLock<PluginLoadMode> = RwLock::new(PluginLoadMode::default());
+}
+
+pub fn set_plugin_load_mode(mode: PluginLoadMode) {
+    *G_PLUGIN_LOAD_MODE.write().unwrap() = mode;
+}
+
+pub fn plugin_load_mode() -> PluginLoadMode {
+    *G_PLUGIN_LOAD_MODE.read().unwrap()
+}
+
+/// Make plugin `name` usable in route configs, before loading them.
+#[allow(dead_code)] // for embedders, the gateway binary only uses built-in plugins
+pub fn register_plugin(name: &str, ctor: PluginCtor) -> Result<(), ConfigError> {
+    G_PLUGIN_REGISTRY.write().unwrap().register(name, ctor)
+}
+
 pub fn init_plugin(
     name: &str,
     cfg: serde_json::Value,
 ) -> Result<Arc<Box<dyn Plugin + Send + Sync>>, ConfigError> {
-    let plugin: Box<dyn Plugin + Send + Sync> = match name {
-        "path_rewrite" => Box::new(PathRewritePlugin::new(parse_config(cfg)?)?),
-        "traffic_split" => Box::new(TrafficSplitPlugin::new(parse_config(cfg)?)?),
-        "script" => Box::new(ScriptPlugin::new(parse_config(cfg)?)?),
-        _ => {
-            return Err(ConfigError::Message("Unkown plugin".to_string()));
+    // lock is not held by constructors
+    let ctor = G_PLUGIN_REGISTRY
+        .read()
+        .unwrap()
+        .get(name)
+        .ok_or_else(|| ConfigError::Message(format!("unknown plugin<{}>", name)))?;
+
+    Ok(Arc::new(ctor(cfg)?))
+}
+
+/// Build plugin `name` and drop it, checking config before it is applied.
+pub fn validate_plugin(name: &str, cfg: serde_json::Value) -> Result<(), ConfigError> {
+    init_plugin(name, cfg).map(drop)
+}
+
+/// Build enabled plugins of route `route_id`, errors of all plugins are reported together.
+pub fn init_plugins(
+    route_id: &str,
+    plugins: &HashMap<String, PluginConfig>,
+    mode: PluginLoadMode,
+) -> Result<Vec<RoutePlugin>, ConfigError> {
+    let mut built = Vec::new();
+    let mut errors = Vec::new();
+
+    for (name, config) in plugins.iter().filter(|(_, p)| p.enable) {
+        let unknown = G_PLUGIN_REGISTRY.read().unwrap().get(name).is_none();
+        if unknown && mode == PluginLoadMode::Lenient {
+            tracing::warn!(route_id, plugin = %name, "unknown plugin skipped");
+            continue;
         }
-    };
 
-    Ok(Arc::new(plugin))
+        let plugin = init_plugin(name, config.config.clone()).and_then(|plugin| {
+            let when = match config.when {
+                Some(ref when) => Some(RouteMatcher::parse(when)?),
+                None => None,
+            };
+            let order = config
+                .order
+                .map(i64::from)
+                .unwrap_or_else(|| i64::from(plugin.priority()));
+            Ok(RoutePlugin {
+                name: name.clone(),
+                order,
+                when,
+                plugin,
+            })
+        });
+
+        match plugin {
+            Ok(plugin) => built.push(plugin),
+            Err(err) => errors.push(PluginError {
+                name: name.clone(),
+                message: error_chain(&err),
+            }),
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+        return Err(ConfigError::InvalidPlugins(route_id.to_string(), errors));
+    }
+
+    Ok(built)
+}
+
+fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+
+    // variants like `MatcherParse` already show their source
+    while let Some(err) = source {
+        let detail = err.to_string();
+        if !message.ends_with(&detail) {
+            message.push_str(": ");
+            message.push_str(&detail);
+        }
+        source = err.source();
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn plugins(value: serde_json::Value) -> HashMap<String, PluginConfig> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn names(plugins: Vec<RoutePlugin>) -> Vec<String> {
+        let mut names: Vec<_> = plugins.into_iter().map(|p| p.name).collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn disabled_plugins() {
+        let cfg = plugins(serde_json::json!({
+            "headers": { "enable": true },
+            "mock": { "enable": false, "status_code": "not a status" },
+            "no_such_plugin": { "enable": false },
+        }));
+
+        let built = init_plugins("route-001", &cfg, PluginLoadMode::Strict).unwrap();
+        assert_eq!(names(built), vec!["headers"]);
+    }
+
+    #[test]
+    fn plugin_load_modes() {
+        let cfg = plugins(serde_json::json!({
+            "headers": { "enable": true },
+            "no_such_plugin": { "enable": true },
+        }));
+
+        let err = init_plugins("route-001", &cfg, PluginLoadMode::Strict)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "route<route-001> has invalid plugins: \
+             plugin<no_such_plugin>: unknown plugin<no_such_plugin>"
+        );
+
+        let built = init_plugins("route-001", &cfg, PluginLoadMode::Lenient).unwrap();
+        assert_eq!(names(built), vec!["headers"]);
+
+        // only unknown plugins are skipped
+        let cfg = plugins(serde_json::json!({
+            "no_such_plugin": { "enable": true },
+            "mock": { "enable": true, "status_code": "not a status" },
+        }));
+        let err = init_plugins("route-001", &cfg, PluginLoadMode::Lenient)
+            .err()
+            .unwrap();
+        match err {
+            ConfigError::InvalidPlugins(route_id, errors) => {
+                assert_eq!(route_id, "route-001");
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].name, "mock");
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
 }
@@ -0,0 +1,651 @@
+use std::{
+    borrow::Cow,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Body, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{json_error, HyperRequest, HyperResponse};
+use crate::matcher::content_type_matchs;
+
+use super::Plugin;
+
+/// max size in bytes of the header section of a part
+const MAX_PART_HEADER_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct MultipartLimitConfig {
+    /// max number of parts, 0 means unlimited
+    #[serde(default)]
+    pub max_parts: usize,
+    /// max body size in bytes of each part, 0 means unlimited
+    #[serde(default)]
+    pub max_part_size: usize,
+    /// content types allowed for file parts like `image/*`, empty means any
+    #[serde(default)]
+    pub allowed_content_types: Vec<String>,
+    /// strip directories and unsafe characters from file names
+    #[serde(default)]
+    pub sanitize_filename: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Violation {
+    TooManyParts,
+    PartTooLarge,
+    ContentTypeNotAllowed,
+    Malformed,
+}
+
+impl Violation {
+    fn status(self) -> StatusCode {
+        match self {
+            Violation::TooManyParts | Violation::PartTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Violation::ContentTypeNotAllowed => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Violation::Malformed => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Violation::TooManyParts => "too many multipart parts",
+            Violation::PartTooLarge => "multipart part too large",
+            Violation::ContentTypeNotAllowed => "multipart content type not allowed",
+            Violation::Malformed => "malformed multipart body",
+        }
+    }
+}
+
+/// Violation found while streaming the body, checked after forward.
+#[derive(Debug, Clone, Default)]
+struct ViolationSlot(Arc<Mutex<Option<Violation>>>);
+
+#[derive(Debug)]
+struct Limits {
+    max_parts: usize,
+    max_part_size: usize,
+    allowed_content_types: Vec<String>,
+    sanitize_filename: bool,
+}
+
+pub(crate) struct MultipartLimitPlugin {
+    limits: Arc<Limits>,
+}
+
+impl MultipartLimitPlugin {
+    pub fn new(cfg: MultipartLimitConfig) -> Result<Self, ConfigError> {
+        let allowed_content_types = cfg
+            .allowed_content_types
+            .iter()
+            .map(|s| {
+                s.trim()
+                    .parse::<mime::Mime>()
+                    .map(|m| m.essence_str().to_string())
+                    .map_err(|_| ConfigError::Message(format!("invalid content type<{}>", s)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(MultipartLimitPlugin {
+            limits: Arc::new(Limits {
+                max_parts: cfg.max_parts,
+                max_part_size: cfg.max_part_size,
+                allowed_content_types,
+                sanitize_filename: cfg.sanitize_filename,
+            }),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for MultipartLimitPlugin {
+    fn name(&self) -> &str {
+        "multipart_limit"
+    }
+
+    fn priority(&self) -> u32 {
+        1500
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok()?.parse::<mime::Mime>().ok());
+
+        let boundary = match content_type {
+            Some(ref m) if m.type_() == mime::MULTIPART => match m.get_param(mime::BOUNDARY) {
+                Some(b) if !b.as_str().is_empty() => b.as_str().to_string(),
+                _ => {
+                    let v = Violation::Malformed;
+                    return Err(json_error(v.status(), v.message()));
+                }
+            },
+            _ => return Ok(req),
+        };
+
+        let slot = ViolationSlot::default();
+        ctx.set(slot.clone());
+
+        let (mut parts, body) = req.into_parts();
+        if self.limits.sanitize_filename {
+            // part headers may be rewritten
+            parts.headers.remove(CONTENT_LENGTH);
+        }
+
+        let scanner = MultipartScanner::new(self.limits.clone(), &boundary);
+
+        Ok(HyperRequest::from_parts(
+            parts,
+            guard_body(body, scanner, slot),
+        ))
+    }
+
+    async fn after_forward(&self, ctx: &mut GatewayContext, resp: HyperResponse) -> HyperResponse {
+        let violation = ctx
+            .get::<ViolationSlot>()
+            .and_then(|slot| *slot.0.lock().unwrap());
+
+        match violation {
+            Some(v) => {
+                tracing::debug!(
+                    route_id = ?ctx.route_id,
+                    violation = ?v,
+                    "multipart limit exceeded"
+                );
+                json_error(v.status(), v.message())
+            }
+            None => resp,
+        }
+    }
+}
+
+/// Check body while streaming, a violation aborts the body sent to upstream.
+fn guard_body(body: Body, scanner: MultipartScanner, slot: ViolationSlot) -> Body {
+    let stream = futures::stream::unfold(Some((body, scanner)), move |state| {
+        let slot = slot.clone();
+
+        async move {
+            let (mut body, mut scanner) = state?;
+
+            let violation = loop {
+                match body.data().await {
+                    Some(Ok(chunk)) => match scanner.feed(&chunk) {
+                        Ok(out) if out.is_empty() => continue,
+                        Ok(out) => return Some((Ok(Bytes::from(out)), Some((body, scanner)))),
+                        Err(v) => break v,
+                    },
+                    Some(Err(err)) => {
+                        let err = std::io::Error::new(std::io::ErrorKind::Other, err);
+                        return Some((Err(err), None));
+                    }
+                    None => match scanner.finish() {
+                        Ok(()) => return None,
+                        Err(v) => break v,
+                    },
+                }
+            };
+
+            *slot.0.lock().unwrap() = Some(violation);
+
+            let err = std::io::Error::new(std::io::ErrorKind::InvalidData, violation.message());
+            Some((Err(err), None))
+        }
+    });
+
+    Body::wrap_stream(stream)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Preamble,
+    /// after a delimiter, either `--` closing or `\r\n` of next part
+    Delimiter,
+    Headers,
+    Body,
+    Epilogue,
+}
+
+/// Incremental multipart scanner, passes bytes through except rewritten part headers.
+struct MultipartScanner {
+    limits: Arc<Limits>,
+    /// `\r\n--boundary`
+    delimiter: Vec<u8>,
+    state: State,
+    buf: Vec<u8>,
+    parts: usize,
+    part_size: usize,
+}
+
+impl MultipartScanner {
+    fn new(limits: Arc<Limits>, boundary: &str) -> Self {
+        MultipartScanner {
+            limits,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            state: State::Preamble,
+            buf: Vec::new(),
+            parts: 0,
+            part_size: 0,
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> Result<Vec<u8>, Violation> {
+        self.buf.extend_from_slice(chunk);
+        let mut out = Vec::with_capacity(self.buf.len());
+
+        loop {
+            match self.state {
+                State::Preamble => {
+                    // the first delimiter may open the body without leading CRLF
+                    let dash_boundary = &self.delimiter[2..];
+
+                    match find(&self.buf, dash_boundary) {
+                        Some(pos) => {
+                            let end = pos + dash_boundary.len();
+                            out.extend(self.buf.drain(..end));
+                            self.state = State::Delimiter;
+                        }
+                        None => {
+                            let n = self.buf.len().saturating_sub(dash_boundary.len() - 1);
+                            out.extend(self.buf.drain(..n));
+                            break;
+                        }
+                    }
+                }
+                State::Delimiter => {
+                    if self.buf.len() < 2 {
+                        break;
+                    }
+
+                    match &self.buf[..2] {
+                        b"--" => {
+                            out.extend(self.buf.drain(..2));
+                            self.state = State::Epilogue;
+                        }
+                        b"\r\n" => {
+                            // CRLF kept as the start of header section
+                            self.parts += 1;
+                            if self.limits.max_parts != 0 && self.parts > self.limits.max_parts {
+                                return Err(Violation::TooManyParts);
+                            }
+                            self.state = State::Headers;
+                        }
+                        _ => return Err(Violation::Malformed),
+                    }
+                }
+                State::Headers => match find(&self.buf, b"\r\n\r\n") {
+                    Some(pos) => {
+                        let section = self.buf.drain(..pos + 4).collect::<Vec<_>>();
+                        out.extend_from_slice(&self.check_headers(&section)?);
+                        self.part_size = 0;
+                        self.state = State::Body;
+                    }
+                    None if self.buf.len() > MAX_PART_HEADER_SIZE => {
+                        return Err(Violation::Malformed);
+                    }
+                    None => break,
+                },
+                State::Body => {
+                    let (n, found) = match find(&self.buf, &self.delimiter) {
+                        Some(pos) => (pos, true),
+                        None => (
+                            self.buf.len().saturating_sub(self.delimiter.len() - 1),
+                            false,
+                        ),
+                    };
+
+                    self.part_size += n;
+                    if self.limits.max_part_size != 0 && self.part_size > self.limits.max_part_size
+                    {
+                        return Err(Violation::PartTooLarge);
+                    }
+
+                    if !found {
+                        out.extend(self.buf.drain(..n));
+                        break;
+                    }
+
+                    out.extend(self.buf.drain(..n + self.delimiter.len()));
+                    self.state = State::Delimiter;
+                }
+                State::Epilogue => {
+                    out.append(&mut self.buf);
+                    break;
+                }
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Body ended, all parts should be closed.
+    fn finish(&self) -> Result<(), Violation> {
+        match self.state {
+            State::Epilogue => Ok(()),
+            _ => Err(Violation::Malformed),
+        }
+    }
+
+    /// Check header section of a part, which is `\r\n` + header lines + `\r\n\r\n`,
+    /// return the section to send.
+    fn check_headers<'a>(&self, section: &'a [u8]) -> Result<Cow<'a, [u8]>, Violation> {
+        let lines = section
+            .strip_prefix(b"\r\n")
+            .and_then(|s| s.strip_suffix(b"\r\n\r\n"))
+            .unwrap_or_default();
+        let text = String::from_utf8_lossy(lines);
+
+        let mut content_type = None;
+        let mut disposition = None;
+
+        for (i, line) in text.split("\r\n").enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, value) = line.split_once(':').ok_or(Violation::Malformed)?;
+            let name = name.trim();
+
+            if name.eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.trim());
+            } else if name.eq_ignore_ascii_case("content-disposition") {
+                disposition = Some((i, value.trim()));
+            }
+        }
+
+        let params = disposition.map(|(_, v)| params(v)).unwrap_or_default();
+        let is_file = params.iter().any(|(name, _)| {
+            name.eq_ignore_ascii_case("filename") || name.eq_ignore_ascii_case("filename*")
+        });
+
+        if is_file && !self.limits.allowed_content_types.is_empty() {
+            // RFC 7578, default to text/plain
+            let essence = content_type
+                .unwrap_or("text/plain")
+                .parse::<mime::Mime>()
+                .map_err(|_| Violation::ContentTypeNotAllowed)?
+                .essence_str()
+                .to_string();
+
+            if !self
+                .limits
+                .allowed_content_types
+                .iter()
+                .any(|allowed| content_type_matchs(allowed, &essence))
+            {
+                return Err(Violation::ContentTypeNotAllowed);
+            }
+        }
+
+        let (index, value) = match disposition {
+            Some(d) if is_file && self.limits.sanitize_filename => d,
+            _ => return Ok(Cow::Borrowed(section)),
+        };
+
+        // rebuild disposition, `filename*` dropped so the sanitized name is used
+        let mut rewritten = value[..value.find(';').unwrap_or(value.len())]
+            .trim()
+            .to_string();
+        for (name, range) in &params {
+            if name.eq_ignore_ascii_case("filename*") {
+                continue;
+            }
+
+            rewritten.push_str("; ");
+            if name.eq_ignore_ascii_case("filename") {
+                let filename = sanitize_filename(&unquote(&value[range.clone()]));
+                rewritten.push_str(&format!("filename=\"{}\"", filename));
+            } else {
+                rewritten.push_str(&format!("{}={}", name, &value[range.clone()]));
+            }
+        }
+
+        let mut out = String::with_capacity(section.len());
+        out.push_str("\r\n");
+        for (i, line) in text.split("\r\n").enumerate() {
+            if i == index {
+                out.push_str("Content-Disposition: ");
+                out.push_str(&rewritten);
+            } else {
+                out.push_str(line);
+            }
+            out.push_str("\r\n");
+        }
+        out.push_str("\r\n");
+
+        Ok(Cow::Owned(out.into_bytes()))
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Parameters of a header value like `form-data; name="a"; filename="b.png"`,
+/// with the range of each raw value.
+fn params(value: &str) -> Vec<(&str, Range<usize>)> {
+    let mut params = Vec::new();
+    let mut start = match value.find(';') {
+        Some(i) => i + 1,
+        None => return params,
+    };
+    let first = start;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, c) in value[first..]
+        .char_indices()
+        .map(|(i, c)| (first + i, c))
+        .chain(std::iter::once((value.len(), ';')))
+    {
+        if in_quotes {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_quotes = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' => in_quotes = true,
+            ';' => {
+                let segment = &value[start..i];
+                if let Some(eq) = segment.find('=') {
+                    let raw = &segment[eq + 1..];
+                    let begin = start + eq + 1 + (raw.len() - raw.trim_start().len());
+                    let end = start + eq + 1 + raw.trim_end().len();
+
+                    params.push((segment[..eq].trim(), begin..end.max(begin)));
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+
+    params
+}
+
+fn unquote(s: &str) -> String {
+    match s.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+        Some(inner) => {
+            let mut out = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => out.extend(chars.next()),
+                    c => out.push(c),
+                }
+            }
+            out
+        }
+        None => s.to_string(),
+    }
+}
+
+/// Keep the last path component, replace unsafe characters with `_`.
+fn sanitize_filename(name: &str) -> String {
+    let name = name
+        .rsplit(|c| c == '/' || c == '\\')
+        .next()
+        .unwrap_or_default();
+
+    let sanitized = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "._- ()".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    let sanitized = sanitized.trim_start_matches('.').trim();
+
+    if sanitized.is_empty() {
+        "file".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BODY: &str = "preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        hello\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; filename=\"../../etc/pa;ss wd\"\r\n\
+        Content-Type: image/png\r\n\
+        \r\n\
+        0123456789\r\n\
+        --XyZ--\r\n";
+
+    fn scan(cfg: MultipartLimitConfig, chunk_size: usize) -> Result<String, Violation> {
+        let plugin = MultipartLimitPlugin::new(cfg).unwrap();
+        let mut scanner = MultipartScanner::new(plugin.limits, "XyZ");
+
+        let mut out = Vec::new();
+        for chunk in BODY.as_bytes().chunks(chunk_size) {
+            out.extend(scanner.feed(chunk)?);
+        }
+        scanner.finish()?;
+
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn pass_through() {
+        for chunk_size in [1, 3, 7, BODY.len()] {
+            assert_eq!(
+                scan(MultipartLimitConfig::default(), chunk_size).as_deref(),
+                Ok(BODY)
+            );
+        }
+
+        let cfg = MultipartLimitConfig {
+            max_parts: 2,
+            max_part_size: 10,
+            allowed_content_types: vec!["image/*".to_string()],
+            sanitize_filename: false,
+        };
+        assert_eq!(scan(cfg, 5).as_deref(), Ok(BODY));
+    }
+
+    #[test]
+    fn exceed_limits() {
+        let limited = |cfg: MultipartLimitConfig| {
+            [1, 4, BODY.len()]
+                .iter()
+                .map(|size| scan(cfg.clone(), *size).unwrap_err())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            limited(MultipartLimitConfig {
+                max_parts: 1,
+                ..Default::default()
+            }),
+            vec![Violation::TooManyParts; 3]
+        );
+        assert_eq!(
+            limited(MultipartLimitConfig {
+                max_part_size: 9,
+                ..Default::default()
+            }),
+            vec![Violation::PartTooLarge; 3]
+        );
+        assert_eq!(
+            limited(MultipartLimitConfig {
+                allowed_content_types: vec!["application/pdf".to_string()],
+                ..Default::default()
+            }),
+            vec![Violation::ContentTypeNotAllowed; 3]
+        );
+
+        let plugin = MultipartLimitPlugin::new(MultipartLimitConfig::default()).unwrap();
+        let mut scanner = MultipartScanner::new(plugin.limits, "XyZ");
+        scanner.feed(&BODY.as_bytes()[..60]).unwrap();
+        assert_eq!(scanner.finish(), Err(Violation::Malformed));
+    }
+
+    #[test]
+    fn sanitize() {
+        let cfg = MultipartLimitConfig {
+            sanitize_filename: true,
+            ..Default::default()
+        };
+
+        let expected = BODY.replace("filename=\"../../etc/pa;ss wd\"", "filename=\"pa_ss wd\"");
+        for chunk_size in [1, 6, BODY.len()] {
+            assert_eq!(scan(cfg.clone(), chunk_size), Ok(expected.clone()));
+        }
+
+        assert_eq!(sanitize_filename("..\\..\\win.ini"), "win.ini");
+        assert_eq!(sanitize_filename("../.."), "file");
+        assert_eq!(sanitize_filename("résumé <1>.pdf"), "résumé _1_.pdf");
+    }
+
+    #[tokio::test]
+    async fn abort_body() {
+        let plugin = MultipartLimitPlugin::new(MultipartLimitConfig {
+            max_part_size: 4,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let req = hyper::Request::builder()
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=XyZ")
+            .body(Body::from(BODY))
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, hyper::http::uri::Scheme::HTTP, &req);
+
+        let req = plugin.on_access(&mut ctx, req).await.unwrap();
+        assert!(hyper::body::to_bytes(req.into_body()).await.is_err());
+
+        let resp = plugin
+            .after_forward(&mut ctx, HyperResponse::default())
+            .await;
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
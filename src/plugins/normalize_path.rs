@@ -0,0 +1,286 @@
+use std::convert::TryFrom;
+
+use hyper::{
+    header::{HeaderValue, HOST},
+    http::uri::PathAndQuery,
+    StatusCode, Uri,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{json_error, HyperRequest, HyperResponse};
+
+use super::Plugin;
+
+/// Normalize request path before plugins and upstream see it.
+///
+/// Runs after routing, route uris are matched against the path as sent by client.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NormalizePathConfig {
+    /// `//` to `/`
+    #[serde(default = "default_true")]
+    pub merge_slashes: bool,
+    /// resolve `.` and `..` segments, going above root is rejected with 400
+    #[serde(default = "default_true")]
+    pub resolve_dot_segments: bool,
+    /// decode percent-encoded unreserved characters like `%7E`, others like `%2F` are kept
+    #[serde(default = "default_true")]
+    pub decode_unreserved: bool,
+    #[serde(default = "default_true")]
+    pub lowercase_host: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for NormalizePathConfig {
+    fn default() -> Self {
+        NormalizePathConfig {
+            merge_slashes: true,
+            resolve_dot_segments: true,
+            decode_unreserved: true,
+            lowercase_host: true,
+        }
+    }
+}
+
+pub(crate) struct NormalizePathPlugin {
+    cfg: NormalizePathConfig,
+}
+
+impl NormalizePathPlugin {
+    pub fn new(cfg: NormalizePathConfig) -> Result<Self, ConfigError> {
+        Ok(NormalizePathPlugin { cfg })
+    }
+
+    /// `None` when `..` goes above root.
+    fn normalize(&self, path: &str) -> Option<String> {
+        let mut path = path.to_string();
+
+        // decode first, so `%2e%2e` can not bypass dot segments
+        if self.cfg.decode_unreserved {
+            path = decode_unreserved(&path);
+        }
+
+        if self.cfg.merge_slashes {
+            path = merge_slashes(&path);
+        }
+
+        if self.cfg.resolve_dot_segments {
+            path = resolve_dot_segments(&path)?;
+        }
+
+        Some(path)
+    }
+}
+
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = String::with_capacity(path.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let decoded = match bytes.get(i..i + 3) {
+            Some([b'%', h, l]) => hex_value(*h).zip(hex_value(*l)).map(|(h, l)| h << 4 | l),
+            _ => None,
+        };
+
+        match decoded {
+            Some(b) if b.is_ascii_alphanumeric() || b"-._~".contains(&b) => {
+                out.push(b as char);
+                i += 3;
+            }
+            // reserved or non-ascii stay encoded, in uppercase
+            Some(b) => {
+                out.push_str(&format!("%{:02X}", b));
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i] as char);
+                i += 1;
+            }
+        }
+    }
+
+    out
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+fn merge_slashes(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+
+    for c in path.chars() {
+        if c == '/' && out.ends_with('/') {
+            continue;
+        }
+        out.push(c);
+    }
+
+    out
+}
+
+/// `/a/b/../c/./` to `/a/c/`, `None` when `..` goes above root.
+fn resolve_dot_segments(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path.strip_prefix('/').unwrap_or(path).split('/').collect();
+    let last = segments.len() - 1;
+    let mut out: Vec<&str> = Vec::with_capacity(segments.len());
+
+    for (i, segment) in segments.into_iter().enumerate() {
+        match segment {
+            "." | ".." => {
+                if segment == ".." {
+                    out.pop()?;
+                }
+                // keep the trailing slash, like `/a/b/..` to `/a/`
+                if i == last {
+                    out.push("");
+                }
+            }
+            _ => out.push(segment),
+        }
+    }
+
+    Some(format!("/{}", out.join("/")))
+}
+
+#[async_trait::async_trait]
+impl Plugin for NormalizePathPlugin {
+    fn name(&self) -> &str {
+        "normalize_path"
+    }
+
+    fn priority(&self) -> u32 {
+        3200
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        mut req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let path = match self.normalize(req.uri().path()) {
+            Some(path) => path,
+            None => {
+                tracing::debug!(route_id = ?ctx.route_id, uri = %req.uri(), "path traversal");
+                return Err(json_error(StatusCode::BAD_REQUEST, "invalid path"));
+            }
+        };
+
+        let mut parts = req.uri().clone().into_parts();
+
+        if path != req.uri().path() {
+            parts.path_and_query = PathAndQuery::try_from(match req.uri().query() {
+                Some(q) => path + "?" + q,
+                None => path,
+            })
+            .ok();
+        }
+
+        if self.cfg.lowercase_host {
+            parts.authority = parts
+                .authority
+                .map(|a| a.as_str().to_ascii_lowercase().parse().unwrap_or(a));
+
+            let host = req.headers().get(HOST).and_then(|h| h.to_str().ok());
+            if let Some(host) = host.filter(|h| h.bytes().any(|b| b.is_ascii_uppercase())) {
+                if let Ok(host) = HeaderValue::from_str(&host.to_ascii_lowercase()) {
+                    req.headers_mut().insert(HOST, host);
+                }
+            }
+        }
+
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
+
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    fn normalize(cfg: NormalizePathConfig, uri: &str) -> Result<HyperRequest, HyperResponse> {
+        let plugin = NormalizePathPlugin::new(cfg).unwrap();
+
+        let req = hyper::Request::builder()
+            .uri(uri)
+            .header("host", "API.Example.com")
+            .body(Body::empty())
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        futures::executor::block_on(plugin.on_access(&mut ctx, req))
+    }
+
+    fn normalized(uri: &str) -> String {
+        normalize(NormalizePathConfig::default(), uri)
+            .unwrap()
+            .uri()
+            .to_string()
+    }
+
+    #[test]
+    fn normalize_path() {
+        assert_eq!(
+            normalized("/api//v1/../v1/users%2f1?a=%7E"),
+            "/api/v1/users%2F1?a=%7E"
+        );
+        assert_eq!(normalized("/a/./b/%7euser/%41"), "/a/b/~user/A");
+        assert_eq!(normalized("/a/b/.."), "/a/");
+        assert_eq!(normalized("/a/b/."), "/a/b/");
+        assert_eq!(normalized("/"), "/");
+
+        // encoded slashes are not segment separators
+        assert_eq!(normalized("/a%2F..%2Fb"), "/a%2F..%2Fb");
+        assert_eq!(normalized("/a/%2e%2e/b"), "/b");
+
+        for uri in ["/..", "/a/../../b", "/%2e%2e/etc/passwd", "//a/../../b"] {
+            let resp = normalize(NormalizePathConfig::default(), uri).unwrap_err();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+
+        // normalizing twice changes nothing
+        for uri in [
+            "/api//v1/../v1/users%2f1",
+            "/a/%2e%2E/b/./",
+            "/x%zz/%c3%a9//y",
+        ] {
+            let once = normalized(uri);
+            assert_eq!(normalized(&once), once, "{}", uri);
+        }
+
+        let req = normalize(NormalizePathConfig::default(), "/").unwrap();
+        assert_eq!(req.headers()["host"], "api.example.com");
+    }
+
+    #[test]
+    fn normalize_toggles() {
+        let cfg = NormalizePathConfig {
+            merge_slashes: false,
+            resolve_dot_segments: false,
+            decode_unreserved: false,
+            lowercase_host: false,
+        };
+        let req = normalize(cfg.clone(), "/a//./%7e/..").unwrap();
+        assert_eq!(req.uri(), "/a//./%7e/..");
+        assert_eq!(req.headers()["host"], "API.Example.com");
+
+        let only = |f: fn(&mut NormalizePathConfig)| {
+            let mut cfg = cfg.clone();
+            f(&mut cfg);
+            normalize(cfg, "/a//./%7e/..").unwrap().uri().to_string()
+        };
+        assert_eq!(only(|c| c.merge_slashes = true), "/a/./%7e/..");
+        assert_eq!(only(|c| c.resolve_dot_segments = true), "/a//");
+        assert_eq!(only(|c| c.decode_unreserved = true), "/a//./~/..");
+    }
+}
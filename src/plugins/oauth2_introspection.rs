@@ -0,0 +1,376 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use headers::{
+    authorization::{Basic, Bearer},
+    Authorization, HeaderMapExt,
+};
+use hyper::{
+    header::{CONTENT_TYPE, WWW_AUTHENTICATE},
+    Body, Method, StatusCode, Uri,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::BufferConfig;
+use crate::context::{Consumer, GatewayContext};
+use crate::error::ConfigError;
+use crate::forwarder::HttpClient;
+use crate::http::{json_error, read_body, HyperRequest, HyperResponse};
+use crate::store::store;
+
+use super::Plugin;
+
+/// max body size in bytes of introspection response
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OAuth2IntrospectionConfig {
+    /// RFC 7662 introspection endpoint
+    pub endpoint: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// seconds to cache active tokens, 0 means no cache
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: u64,
+    /// timeout in milliseconds of introspection request
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// pass requests when introspection fails
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+fn default_cache_ttl() -> u64 {
+    60
+}
+
+fn default_timeout() -> u64 {
+    3000
+}
+
+/// Introspection result of an active token, shared by `GatewayContext::set`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TokenIntrospection {
+    #[serde(default)]
+    pub sub: Option<String>,
+    #[serde(default)]
+    pub scope: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(default)]
+    exp: Option<u64>,
+    #[serde(flatten)]
+    info: TokenIntrospection,
+}
+
+pub(crate) struct OAuth2IntrospectionPlugin {
+    endpoint: Uri,
+    credentials: Authorization<Basic>,
+    cache_ttl: Duration,
+    timeout: Duration,
+    fail_open: bool,
+    client: HttpClient,
+}
+
+impl OAuth2IntrospectionPlugin {
+    pub fn new(cfg: OAuth2IntrospectionConfig) -> Result<Self, ConfigError> {
+        let endpoint: Uri = cfg.endpoint.parse().map_err(|_| {
+            ConfigError::Message(format!("invalid introspection endpoint<{}>", cfg.endpoint))
+        })?;
+
+        if endpoint.scheme().is_none() || endpoint.host().is_none() {
+            return Err(ConfigError::Message(format!(
+                "introspection endpoint<{}> should be absolute",
+                cfg.endpoint
+            )));
+        }
+
+        Ok(OAuth2IntrospectionPlugin {
+            endpoint,
+            credentials: Authorization::basic(&cfg.client_id, &cfg.client_secret),
+            cache_ttl: Duration::from_secs(cfg.cache_ttl),
+            timeout: Duration::from_millis(cfg.timeout),
+            fail_open: cfg.fail_open,
+            client: HttpClient::new(&BufferConfig::default()),
+        })
+    }
+
+    async fn introspect(&self, token: &str) -> Result<IntrospectionResponse, String> {
+        let form = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", token)
+            .append_pair("token_type_hint", "access_token")
+            .finish();
+
+        let mut req = hyper::Request::builder()
+            .method(Method::POST)
+            .uri(self.endpoint.clone())
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form))
+            .map_err(|e| e.to_string())?;
+        req.headers_mut().typed_insert(self.credentials.clone());
+
+        let resp = tokio::time::timeout(self.timeout, self.client.request(req))
+            .await
+            .map_err(|_| "timeout".to_string())?
+            .map_err(|e| e.to_string())?;
+
+        if !resp.status().is_success() {
+            return Err(format!("status {}", resp.status()));
+        }
+
+        let body = read_body(resp.into_body(), MAX_RESPONSE_SIZE)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        serde_json::from_slice(&body).map_err(|e| e.to_string())
+    }
+
+    async fn cached(&self, key: &str) -> Option<TokenIntrospection> {
+        if self.cache_ttl.is_zero() {
+            return None;
+        }
+
+        match store().get(key).await {
+            Ok(value) => value.and_then(|v| serde_json::from_slice(&v).ok()),
+            Err(err) => {
+                tracing::warn!(%err, "read introspection cache failed");
+                None
+            }
+        }
+    }
+
+    /// Cache active token, no longer than its `exp`.
+    async fn cache(&self, key: &str, resp: &IntrospectionResponse) {
+        let mut ttl = self.cache_ttl;
+
+        if let Some(exp) = resp.exp {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            ttl = ttl.min(Duration::from_secs(exp.saturating_sub(now)));
+        }
+
+        if ttl.is_zero() {
+            return;
+        }
+
+        let value = serde_json::to_vec(&resp.info).unwrap_or_default();
+        if let Err(err) = store().set(key, value, Some(ttl)).await {
+            tracing::warn!(%err, "write introspection cache failed");
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for OAuth2IntrospectionPlugin {
+    fn name(&self) -> &str {
+        "oauth2_introspection"
+    }
+
+    fn priority(&self) -> u32 {
+        2400
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let token = match req.headers().typed_get::<Authorization<Bearer>>() {
+            Some(auth) => auth.token().to_string(),
+            None => return Err(unauthorized("missing access token")),
+        };
+
+        let key = cache_key(&token);
+
+        if let Some(info) = self.cached(&key).await {
+            authenticated(ctx, info);
+            return Ok(req);
+        }
+
+        match self.introspect(&token).await {
+            Ok(resp) if resp.active => {
+                self.cache(&key, &resp).await;
+                authenticated(ctx, resp.info);
+                Ok(req)
+            }
+            Ok(_) => Err(unauthorized("inactive access token")),
+            Err(err) => {
+                tracing::error!(route_id = ?ctx.route_id, %err, "token introspection failed");
+
+                if self.fail_open {
+                    Ok(req)
+                } else {
+                    Err(unauthorized("token introspection failed"))
+                }
+            }
+        }
+    }
+}
+
+fn authenticated(ctx: &mut GatewayContext, info: TokenIntrospection) {
+    if let Some(ref sub) = info.sub {
+        ctx.set(Consumer(sub.clone()));
+    }
+    ctx.set(info);
+}
+
+fn cache_key(token: &str) -> String {
+    format!(
+        "oauth2_introspection:{:x}",
+        Sha256::digest(token.as_bytes())
+    )
+}
+
+fn unauthorized(message: &str) -> HyperResponse {
+    let mut resp = json_error(StatusCode::UNAUTHORIZED, message);
+    resp.headers_mut()
+        .insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+    resp
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use hyper::{
+        http::uri::Scheme,
+        service::{make_service_fn, service_fn},
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    /// Introspection endpoint treats tokens starting with `good` as active.
+    fn start_server(calls: Arc<AtomicUsize>) -> SocketAddr {
+        let make_svc = make_service_fn(move |_| {
+            let calls = calls.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: HyperRequest| {
+                    let calls = calls.clone();
+                    async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+
+                        let authorized = req
+                            .headers()
+                            .typed_get::<Authorization<Basic>>()
+                            .map(|a| a.username() == "gw" && a.password() == "secret")
+                            .unwrap_or(false);
+                        if !authorized {
+                            return Ok::<_, Infallible>(
+                                hyper::Response::builder()
+                                    .status(StatusCode::UNAUTHORIZED)
+                                    .body(Body::empty())
+                                    .unwrap(),
+                            );
+                        }
+
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        let token = url::form_urlencoded::parse(&body)
+                            .find(|(k, _)| k == "token")
+                            .map(|(_, v)| v.into_owned())
+                            .unwrap_or_default();
+
+                        let resp = if token.starts_with("good") {
+                            json!({"active": true, "sub": token, "scope": "read write"})
+                        } else {
+                            json!({"active": false})
+                        };
+
+                        Ok(hyper::Response::new(Body::from(resp.to_string())))
+                    }
+                }))
+            }
+        });
+
+        let srv = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = srv.local_addr();
+        tokio::spawn(srv);
+
+        addr
+    }
+
+    fn new_plugin(
+        endpoint: String,
+        client_secret: &str,
+        fail_open: bool,
+    ) -> OAuth2IntrospectionPlugin {
+        OAuth2IntrospectionPlugin::new(OAuth2IntrospectionConfig {
+            endpoint,
+            client_id: "gw".to_string(),
+            client_secret: client_secret.to_string(),
+            cache_ttl: default_cache_ttl(),
+            timeout: default_timeout(),
+            fail_open,
+        })
+        .unwrap()
+    }
+
+    async fn access(
+        plugin: &OAuth2IntrospectionPlugin,
+        token: Option<&str>,
+    ) -> Result<Option<TokenIntrospection>, StatusCode> {
+        let mut builder = hyper::Request::builder().uri("/");
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {}", token));
+        }
+        let req = builder.body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        match plugin.on_access(&mut ctx, req).await {
+            Ok(_) => Ok(ctx.get::<TokenIntrospection>().cloned()),
+            Err(resp) => Err(resp.status()),
+        }
+    }
+
+    #[tokio::test]
+    async fn introspect_token() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let addr = start_server(calls.clone());
+        let plugin = new_plugin(format!("http://{}/introspect", addr), "secret", false);
+
+        let info = access(&plugin, Some("good-4d1a")).await.unwrap().unwrap();
+        assert_eq!(info.sub.as_deref(), Some("good-4d1a"));
+        assert_eq!(info.scope.as_deref(), Some("read write"));
+
+        // cached
+        let cached = access(&plugin, Some("good-4d1a")).await.unwrap().unwrap();
+        assert_eq!(cached, info);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        assert_eq!(
+            access(&plugin, Some("revoked")).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(access(&plugin, None).await, Err(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn introspection_failure() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let addr = start_server(calls.clone());
+        let endpoint = format!("http://{}/introspect", addr);
+
+        // rejected client credentials
+        let plugin = new_plugin(endpoint.clone(), "wrong", false);
+        assert_eq!(
+            access(&plugin, Some("good-7c2e")).await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+
+        let plugin = new_plugin(endpoint, "wrong", true);
+        assert_eq!(access(&plugin, Some("good-7c2e")).await, Ok(None));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -4,7 +4,10 @@ use hyper::{http::uri::PathAndQuery, Uri};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::context::GatewayContext;
 use crate::error::ConfigError;
+use crate::http::{HyperRequest, HyperResponse};
+use crate::variable::Template;
 
 use super::Plugin;
 
@@ -14,6 +17,9 @@ pub enum PathRewriteConfig {
     Keep,
     Static(String),
     RegexReplace(String, String),
+    /// remove a mount prefix like `/billing`, matched on segment boundary
+    StripPrefix(String),
+    AddPrefix(String),
 }
 
 impl Default for PathRewriteConfig {
@@ -25,33 +31,68 @@ impl Default for PathRewriteConfig {
 #[derive(Debug, Clone)]
 pub(crate) enum PathRewritePlugin {
     Keep,
-    Static(String),
+    /// static path, variables like `$arg_name` and path params like `{id}` are rendered
+    Static(Template),
     RegexReplace(regex::Regex, String),
+    /// without trailing `/`
+    StripPrefix(String),
+    AddPrefix(String),
 }
 
 impl PathRewritePlugin {
     pub fn new(cfg: PathRewriteConfig) -> Result<Self, ConfigError> {
         let path_rewrite = match cfg {
             PathRewriteConfig::Keep => PathRewritePlugin::Keep,
-            PathRewriteConfig::Static(ref s) => PathRewritePlugin::Static(s.to_string()),
+            PathRewriteConfig::Static(ref s) => PathRewritePlugin::Static(Template::parse_path(s)?),
             PathRewriteConfig::RegexReplace(ref m, ref r) => {
                 let re = Regex::new(m).map_err(|e| ConfigError::Message(e.to_string()))?;
                 PathRewritePlugin::RegexReplace(re, r.to_string())
             }
+            PathRewriteConfig::StripPrefix(ref prefix) => {
+                PathRewritePlugin::StripPrefix(parse_prefix(prefix)?)
+            }
+            PathRewriteConfig::AddPrefix(ref prefix) => {
+                PathRewritePlugin::AddPrefix(parse_prefix(prefix)?)
+            }
         };
 
         Ok(path_rewrite)
     }
 
-    pub fn path_rewrite<'a>(&self, path: &'a str) -> Cow<'a, str> {
+    pub fn path_rewrite<'a>(
+        &self,
+        ctx: &GatewayContext,
+        req: &HyperRequest,
+        path: &'a str,
+    ) -> Cow<'a, str> {
         match self {
             PathRewritePlugin::Keep => Cow::Borrowed(path),
-            PathRewritePlugin::Static(ref s) => Cow::Owned(s.to_owned()),
+            PathRewritePlugin::Static(ref tpl) => Cow::Owned(tpl.render(ctx, req)),
             PathRewritePlugin::RegexReplace(ref re, ref pat) => re.replace(path, pat),
+            PathRewritePlugin::StripPrefix(ref prefix) => {
+                match path.strip_prefix(prefix.as_str()) {
+                    Some("") => Cow::Borrowed("/"),
+                    Some(rest) if rest.starts_with('/') => Cow::Borrowed(rest),
+                    _ => Cow::Borrowed(path),
+                }
+            }
+            PathRewritePlugin::AddPrefix(ref prefix) => Cow::Owned(format!("{}{}", prefix, path)),
         }
     }
 }
 
+fn parse_prefix(prefix: &str) -> Result<String, ConfigError> {
+    if !prefix.starts_with('/') {
+        return Err(ConfigError::Message(format!(
+            "path prefix<{}> should start with `/`",
+            prefix
+        )));
+    }
+
+    Ok(prefix.trim_end_matches('/').to_string())
+}
+
+#[async_trait::async_trait]
 impl Plugin for PathRewritePlugin {
     fn name(&self) -> &str {
         "path_rewrite"
@@ -61,15 +102,14 @@ impl Plugin for PathRewritePlugin {
         1002
     }
 
-    fn on_access(
+    async fn on_access(
         &self,
-        ctx: &mut crate::context::GatewayContext,
-        mut req: crate::http::HyperRequest,
-    ) -> Result<crate::http::HyperRequest, crate::http::HyperResponse> {
-        let _ = ctx;
+        ctx: &mut GatewayContext,
+        mut req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
         let orig_uri = req.uri().clone();
 
-        let path = self.path_rewrite(orig_uri.path()).to_string();
+        let path = self.path_rewrite(ctx, &req, orig_uri.path()).to_string();
 
         if path != orig_uri.path() {
             let mut parts = orig_uri.into_parts();
@@ -90,3 +130,63 @@ impl Plugin for PathRewritePlugin {
         Ok(req)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    fn rewrite(cfg: PathRewriteConfig, uri: &str) -> String {
+        let plugin = PathRewritePlugin::new(cfg).unwrap();
+
+        let req = hyper::Request::builder()
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let req = futures::executor::block_on(plugin.on_access(&mut ctx, req)).unwrap();
+        req.uri().to_string()
+    }
+
+    #[test]
+    fn strip_prefix() {
+        let strip = |prefix: &str| PathRewriteConfig::StripPrefix(prefix.to_string());
+
+        assert_eq!(
+            rewrite(strip("/billing"), "/billing/api/v1/x?a=1&b=%20"),
+            "/api/v1/x?a=1&b=%20"
+        );
+        assert_eq!(
+            rewrite(strip("/billing/api/"), "/billing/api/v1/x"),
+            "/v1/x"
+        );
+
+        // not on segment boundary, or not matched
+        assert_eq!(rewrite(strip("/billing"), "/billings/x"), "/billings/x");
+        assert_eq!(
+            rewrite(strip("/billing"), "/api/billing/x"),
+            "/api/billing/x"
+        );
+
+        // whole path is the prefix
+        assert_eq!(rewrite(strip("/billing"), "/billing"), "/");
+        assert_eq!(rewrite(strip("/billing"), "/billing/?a=1"), "/?a=1");
+        assert_eq!(rewrite(strip("/billing"), "/billing?a=1"), "/?a=1");
+
+        assert!(PathRewritePlugin::new(strip("billing")).is_err());
+    }
+
+    #[test]
+    fn add_prefix() {
+        let add = |prefix: &str| PathRewriteConfig::AddPrefix(prefix.to_string());
+
+        assert_eq!(
+            rewrite(add("/billing/"), "/api/v1?a=1"),
+            "/billing/api/v1?a=1"
+        );
+        assert_eq!(rewrite(add("/billing"), "/"), "/billing/");
+        assert_eq!(rewrite(add("/"), "/api"), "/api");
+    }
+}
@@ -0,0 +1,616 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::TryFrom,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use hyper::{header::RETRY_AFTER, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::context::{GatewayContext, RateLimitDecision};
+use crate::error::ConfigError;
+use crate::http::{
+    json_error, HyperRequest, HyperResponse, X_RATELIMIT_LIMIT, X_RATELIMIT_REMAINING,
+};
+use crate::variable::{Template, Variable};
+
+use super::Plugin;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    /// tokens refilled each `per`
+    pub rate: u64,
+    /// bucket capacity
+    pub burst: u64,
+    #[serde(default)]
+    pub per: RatePeriod,
+    /// which requests share a bucket
+    #[serde(default)]
+    pub key: RateLimitKey,
+    /// policy for requests without key
+    #[serde(default)]
+    pub missing_key: MissingKeyPolicy,
+    /// max number of keyed buckets, idle and then least recently used ones are evicted
+    #[serde(default = "default_max_keys")]
+    pub max_keys: usize,
+}
+
+fn default_max_keys() -> usize {
+    10_000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RatePeriod {
+    Second,
+    Minute,
+}
+
+impl Default for RatePeriod {
+    fn default() -> Self {
+        RatePeriod::Second
+    }
+}
+
+impl RatePeriod {
+    fn duration(self) -> Duration {
+        match self {
+            RatePeriod::Second => Duration::from_secs(1),
+            RatePeriod::Minute => Duration::from_secs(60),
+        }
+    }
+}
+
+/// `route`, `client_ip`, `consumer`, `header:<name>` or a template of variables like
+/// `$consumer:$arg_tenant`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RateLimitKey {
+    /// one bucket for the route
+    Route,
+    /// `client_ip`, `consumer` and `header:<name>` are short for `$remote_addr`, `$consumer`
+    /// and `$http_<name>`
+    Var(Variable),
+    /// missing when any variable is missing
+    Template(Template),
+}
+
+impl Default for RateLimitKey {
+    fn default() -> Self {
+        RateLimitKey::Route
+    }
+}
+
+impl TryFrom<String> for RateLimitKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "route" => Ok(RateLimitKey::Route),
+            "client_ip" => Ok(RateLimitKey::Var(Variable::RemoteAddr)),
+            "consumer" => Ok(RateLimitKey::Var(Variable::Consumer)),
+            _ if s.contains('$') => Template::parse(&s)
+                .map(RateLimitKey::Template)
+                .map_err(|e| e.to_string()),
+            _ => match s.strip_prefix("header:") {
+                Some(name) if !name.trim().is_empty() => Ok(RateLimitKey::Var(Variable::Http(
+                    name.trim().to_ascii_lowercase(),
+                ))),
+                _ => Err(format!("invalid rate limit key `{}`", s)),
+            },
+        }
+    }
+}
+
+impl From<RateLimitKey> for String {
+    fn from(key: RateLimitKey) -> Self {
+        key.to_string()
+    }
+}
+
+impl fmt::Display for RateLimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitKey::Route => write!(f, "route"),
+            RateLimitKey::Var(Variable::RemoteAddr) => write!(f, "client_ip"),
+            RateLimitKey::Var(Variable::Consumer) => write!(f, "consumer"),
+            RateLimitKey::Var(Variable::Http(name)) => write!(f, "header:{}", name),
+            RateLimitKey::Var(var) => write!(f, "${{{}}}", var),
+            RateLimitKey::Template(template) => write!(f, "{}", template),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingKeyPolicy {
+    /// pass without limit
+    Allow,
+    Deny,
+    /// share one bucket among all requests without key
+    BucketAsAnonymous,
+}
+
+impl Default for MissingKeyPolicy {
+    fn default() -> Self {
+        MissingKeyPolicy::BucketAsAnonymous
+    }
+}
+
+/// Token bucket tracked as the theoretical arrival time (GCRA), so one atomic is enough.
+#[derive(Debug, Default)]
+struct Bucket {
+    /// nanoseconds since plugin start when the bucket would be full again
+    tat: AtomicU64,
+}
+
+impl Bucket {
+    /// Take a token, return remaining tokens, or the time to wait for one.
+    fn acquire(&self, now: u64, interval: u64, capacity: u64) -> Result<u64, Duration> {
+        let mut tat = self.tat.load(Ordering::Relaxed);
+        loop {
+            let new_tat = tat.max(now) + interval;
+            let used = new_tat - now;
+
+            if used > capacity {
+                return Err(Duration::from_nanos(used - capacity));
+            }
+
+            match self
+                .tat
+                .compare_exchange_weak(tat, new_tat, Ordering::AcqRel, Ordering::Relaxed)
+            {
+                Ok(_) => return Ok((capacity - used) / interval),
+                Err(actual) => tat = actual,
+            }
+        }
+    }
+
+    fn remaining(&self, now: u64, interval: u64, capacity: u64) -> u64 {
+        let used = self.tat.load(Ordering::Relaxed).saturating_sub(now);
+
+        capacity.saturating_sub(used) / interval
+    }
+
+    /// Refilled bucket carries no state, safe to drop.
+    fn is_full(&self, now: u64) -> bool {
+        self.tat.load(Ordering::Relaxed) <= now
+    }
+}
+
+#[derive(Debug, Default)]
+struct KeyedBucket {
+    bucket: Bucket,
+    last_seen: u64,
+}
+
+#[derive(Debug, Default)]
+struct KeyedBuckets {
+    buckets: HashMap<Arc<str>, KeyedBucket>,
+    /// keys by `last_seen`, least recently used first
+    lru: BTreeSet<(u64, Arc<str>)>,
+}
+
+impl KeyedBuckets {
+    fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Bucket of `key`, marked as used at `now`.
+    fn touch(&mut self, key: &str, now: u64) -> &Bucket {
+        let key = match self.buckets.get_key_value(key) {
+            Some((key, keyed)) => {
+                self.lru.remove(&(keyed.last_seen, key.clone()));
+                key.clone()
+            }
+            None => Arc::from(key),
+        };
+        self.lru.insert((now, key.clone()));
+
+        let keyed = self.buckets.entry(key).or_default();
+        keyed.last_seen = now;
+        &keyed.bucket
+    }
+
+    /// Drop refilled buckets among the least recently used, then the least recently used
+    /// ones until below `max_keys`.
+    fn evict(&mut self, now: u64, max_keys: usize) {
+        while let Some((_, key)) = self.lru.first() {
+            let refilled = self.buckets[key].bucket.is_full(now);
+            if !refilled && self.buckets.len() < max_keys {
+                break;
+            }
+
+            if let Some((_, key)) = self.lru.pop_first() {
+                self.buckets.remove(&key);
+            }
+        }
+    }
+}
+
+pub(crate) struct RateLimitPlugin {
+    burst: u64,
+    /// nanoseconds to refill one token
+    interval: u64,
+    start: Instant,
+    key: RateLimitKey,
+    missing_key: MissingKeyPolicy,
+    max_keys: usize,
+    route: Bucket,
+    anonymous: Bucket,
+    buckets: Mutex<KeyedBuckets>,
+}
+
+impl RateLimitPlugin {
+    pub fn new(cfg: RateLimitConfig) -> Result<Self, ConfigError> {
+        if cfg.rate == 0 || cfg.burst == 0 {
+            return Err(ConfigError::Message(
+                "rate and burst of rate limit should be positive".to_string(),
+            ));
+        }
+
+        let interval = (cfg.per.duration().as_nanos() as u64 / cfg.rate).max(1);
+
+        Ok(RateLimitPlugin {
+            burst: cfg.burst,
+            interval,
+            start: Instant::now(),
+            key: cfg.key,
+            missing_key: cfg.missing_key,
+            max_keys: cfg.max_keys.max(1),
+            route: Bucket::default(),
+            anonymous: Bucket::default(),
+            buckets: Mutex::default(),
+        })
+    }
+
+    fn capacity(&self) -> u64 {
+        self.interval.saturating_mul(self.burst)
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    fn key_of(&self, ctx: &GatewayContext, req: &HyperRequest) -> Option<String> {
+        match self.key {
+            RateLimitKey::Route => None,
+            RateLimitKey::Var(ref var) => var.resolve(ctx, req).filter(|v| !v.is_empty()),
+            RateLimitKey::Template(ref template) => {
+                template.render_all(ctx, req).filter(|v| !v.is_empty())
+            }
+        }
+    }
+
+    fn acquire_keyed(&self, key: &str) -> Result<u64, Duration> {
+        let now = self.now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if !buckets.buckets.contains_key(key) && buckets.len() >= self.max_keys {
+            buckets.evict(now, self.max_keys);
+        }
+
+        buckets
+            .touch(key, now)
+            .acquire(now, self.interval, self.capacity())
+    }
+
+    fn rejected(&self, wait: Duration) -> HyperResponse {
+        let message = format!("too many requests, limited by {}", self.key);
+        let mut resp = json_error(StatusCode::TOO_MANY_REQUESTS, &message);
+
+        let headers = resp.headers_mut();
+        // round up, retrying earlier is rejected again
+        let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+        headers.insert(RETRY_AFTER, retry_after.max(1).into());
+        headers.insert(X_RATELIMIT_LIMIT, self.burst.into());
+        headers.insert(X_RATELIMIT_REMAINING, 0u64.into());
+
+        resp
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for RateLimitPlugin {
+    fn name(&self) -> &str {
+        "rate_limit"
+    }
+
+    fn priority(&self) -> u32 {
+        2200
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let acquired = if self.key == RateLimitKey::Route {
+            self.route
+                .acquire(self.now(), self.interval, self.capacity())
+        } else {
+            match (self.key_of(ctx, &req), self.missing_key) {
+                (Some(key), _) => self.acquire_keyed(&key),
+                (None, MissingKeyPolicy::Allow) => return Ok(req),
+                (None, MissingKeyPolicy::Deny) => {
+                    let message = format!("missing rate limit key {}", self.key);
+                    return Err(json_error(StatusCode::FORBIDDEN, &message));
+                }
+                (None, MissingKeyPolicy::BucketAsAnonymous) => {
+                    self.anonymous
+                        .acquire(self.now(), self.interval, self.capacity())
+                }
+            }
+        };
+
+        match acquired {
+            Ok(remaining) => {
+                ctx.set(RateLimitDecision {
+                    limit: self.burst,
+                    remaining,
+                });
+                Ok(req)
+            }
+            Err(wait) => {
+                tracing::debug!(route_id = ?ctx.route_id, key = %self.key, ?wait, "rate limited");
+                Err(self.rejected(wait))
+            }
+        }
+    }
+
+    async fn after_forward(
+        &self,
+        ctx: &mut GatewayContext,
+        mut resp: HyperResponse,
+    ) -> HyperResponse {
+        let remaining = if self.key == RateLimitKey::Route {
+            Some(
+                self.route
+                    .remaining(self.now(), self.interval, self.capacity()),
+            )
+        } else {
+            ctx.get::<RateLimitDecision>().map(|d| d.remaining)
+        };
+
+        if let Some(remaining) = remaining {
+            let headers = resp.headers_mut();
+            headers.insert(X_RATELIMIT_LIMIT, self.burst.into());
+            headers.insert(X_RATELIMIT_REMAINING, remaining.into());
+        }
+
+        resp
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    fn new_plugin(rate: u64, burst: u64, per: RatePeriod) -> RateLimitPlugin {
+        keyed_plugin(rate, burst, per, RateLimitKey::Route, default_max_keys())
+    }
+
+    fn keyed_plugin(
+        rate: u64,
+        burst: u64,
+        per: RatePeriod,
+        key: RateLimitKey,
+        max_keys: usize,
+    ) -> RateLimitPlugin {
+        RateLimitPlugin::new(RateLimitConfig {
+            rate,
+            burst,
+            per,
+            key,
+            missing_key: MissingKeyPolicy::default(),
+            max_keys,
+        })
+        .unwrap()
+    }
+
+    fn client_ip() -> RateLimitKey {
+        RateLimitKey::Var(Variable::RemoteAddr)
+    }
+
+    fn acquire(plugin: &RateLimitPlugin) -> Result<u64, Duration> {
+        plugin
+            .route
+            .acquire(plugin.now(), plugin.interval, plugin.capacity())
+    }
+
+    fn access(plugin: &RateLimitPlugin, remote_addr: &str) -> HyperResponse {
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(Some(remote_addr.parse().unwrap()), Scheme::HTTP, &req);
+
+        futures::executor::block_on(async {
+            match plugin.on_access(&mut ctx, req).await {
+                Ok(_) => {
+                    plugin
+                        .after_forward(&mut ctx, HyperResponse::default())
+                        .await
+                }
+                Err(resp) => resp,
+            }
+        })
+    }
+
+    #[test]
+    fn burst_in_window() {
+        let plugin = new_plugin(1, 5, RatePeriod::Minute);
+
+        let passed = (0..5 + 20).filter(|_| acquire(&plugin).is_ok()).count();
+        assert_eq!(passed, 5);
+
+        let wait = acquire(&plugin).unwrap_err();
+        assert!(wait <= Duration::from_secs(60) && wait > Duration::from_secs(59));
+
+        assert!(RateLimitPlugin::new(RateLimitConfig {
+            rate: 0,
+            burst: 1,
+            per: RatePeriod::Second,
+            key: RateLimitKey::Route,
+            missing_key: MissingKeyPolicy::Allow,
+            max_keys: 1,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn concurrent_acquire() {
+        let plugin = Arc::new(new_plugin(1, 100, RatePeriod::Minute));
+
+        let handles = (0..8)
+            .map(|_| {
+                let plugin = plugin.clone();
+                std::thread::spawn(move || (0..50).filter(|_| acquire(&plugin).is_ok()).count())
+            })
+            .collect::<Vec<_>>();
+
+        let passed: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(passed, 100);
+    }
+
+    #[test]
+    fn refill() {
+        let plugin = new_plugin(100, 2, RatePeriod::Second);
+
+        assert_eq!(acquire(&plugin), Ok(1));
+        assert_eq!(acquire(&plugin), Ok(0));
+        assert!(acquire(&plugin).is_err());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(acquire(&plugin).is_ok());
+    }
+
+    #[test]
+    fn rate_limit_headers() {
+        let plugin = new_plugin(1, 2, RatePeriod::Minute);
+
+        let resp = access(&plugin, "10.0.0.1:1000");
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[X_RATELIMIT_LIMIT], "2");
+        assert_eq!(resp.headers()[X_RATELIMIT_REMAINING], "1");
+
+        let resp = access(&plugin, "10.0.0.2:1000");
+        assert_eq!(resp.headers()[X_RATELIMIT_REMAINING], "0");
+
+        let resp = access(&plugin, "10.0.0.3:1000");
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[RETRY_AFTER], "60");
+        assert_eq!(resp.headers()[X_RATELIMIT_REMAINING], "0");
+    }
+
+    #[test]
+    fn parse_key() {
+        let parse = |s: &str| RateLimitKey::try_from(s.to_string());
+
+        assert_eq!(parse("client_ip"), Ok(client_ip()));
+        assert_eq!(
+            parse("header:X-User"),
+            Ok(RateLimitKey::Var(Variable::Http("x-user".to_string())))
+        );
+        assert!(parse("header:").is_err());
+        assert!(parse("ip").is_err());
+
+        let key = parse("$consumer:$arg_tenant").unwrap();
+        assert!(matches!(key, RateLimitKey::Template(_)));
+        assert_eq!(key.to_string(), "${consumer}:${arg_tenant}");
+        assert_eq!(parse(&key.to_string()), Ok(key));
+        assert!(parse("$tenant").is_err());
+    }
+
+    #[test]
+    fn keyed_by_template() {
+        let key = RateLimitKey::try_from("$http_x_user:$arg_tenant".to_string()).unwrap();
+        let plugin = keyed_plugin(1, 1, RatePeriod::Minute, key, 100);
+
+        let request = |uri: &str| {
+            let req = hyper::Request::builder()
+                .uri(uri)
+                .header("x-user", "alice")
+                .body(Body::empty())
+                .unwrap();
+            let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+            plugin.key_of(&ctx, &req)
+        };
+
+        assert_eq!(request("/?tenant=acme").as_deref(), Some("alice:acme"));
+        assert_eq!(request("/"), None);
+    }
+
+    #[test]
+    fn keyed_by_client_ip() {
+        let plugin = keyed_plugin(1, 2, RatePeriod::Minute, client_ip(), 100);
+
+        for ip in ["10.0.0.1:1000", "10.0.0.2:2000"] {
+            let statuses = (0..3)
+                .map(|_| access(&plugin, ip).status())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                statuses,
+                [
+                    StatusCode::OK,
+                    StatusCode::OK,
+                    StatusCode::TOO_MANY_REQUESTS
+                ]
+            );
+        }
+
+        let resp = access(&plugin, "10.0.0.1:1000");
+        let body = futures::executor::block_on(hyper::body::to_bytes(resp.into_body())).unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("limited by client_ip"));
+    }
+
+    #[test]
+    fn evict_keys() {
+        let keys = |plugin: &RateLimitPlugin| {
+            let mut keys = plugin
+                .buckets
+                .lock()
+                .unwrap()
+                .buckets
+                .keys()
+                .map(|key| key.to_string())
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys
+        };
+
+        // least recently used evicted when full
+        let plugin = keyed_plugin(1, 1, RatePeriod::Minute, client_ip(), 2);
+        for ip in ["10.0.0.1:1000", "10.0.0.2:1000", "10.0.0.3:1000"] {
+            assert_eq!(access(&plugin, ip).status(), StatusCode::OK);
+        }
+        assert_eq!(keys(&plugin), ["10.0.0.2", "10.0.0.3"]);
+
+        // idle buckets refilled and evicted
+        let plugin = keyed_plugin(100, 1, RatePeriod::Second, client_ip(), 2);
+        access(&plugin, "10.0.0.1:1000");
+        access(&plugin, "10.0.0.2:1000");
+        std::thread::sleep(Duration::from_millis(30));
+        access(&plugin, "10.0.0.3:1000");
+        assert_eq!(keys(&plugin), ["10.0.0.3"]);
+
+        // used again moves to the back
+        let plugin = keyed_plugin(1, 2, RatePeriod::Minute, client_ip(), 2);
+        for ip in [
+            "10.0.0.1:1000",
+            "10.0.0.2:1000",
+            "10.0.0.1:1000",
+            "10.0.0.3:1000",
+        ] {
+            assert_eq!(access(&plugin, ip).status(), StatusCode::OK);
+        }
+        assert_eq!(keys(&plugin), ["10.0.0.1", "10.0.0.3"]);
+        assert_eq!(plugin.buckets.lock().unwrap().lru.len(), 2);
+    }
+}
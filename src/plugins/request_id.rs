@@ -0,0 +1,181 @@
+use hyper::header::{HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+pub use crate::context::RequestId;
+use crate::error::ConfigError;
+use crate::http::{HyperRequest, HyperResponse, X_REQUEST_ID};
+
+use super::Plugin;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestIdConfig {
+    /// header carrying the id, to upstream and back to client
+    #[serde(default = "default_header")]
+    pub header: String,
+    /// keep id sent by client, otherwise always generate one
+    #[serde(default = "default_true")]
+    pub trust_incoming: bool,
+    #[serde(default)]
+    pub generator: IdGenerator,
+}
+
+fn default_header() -> String {
+    X_REQUEST_ID.to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdGenerator {
+    Uuid,
+    /// sortable by time
+    Ulid,
+}
+
+impl Default for IdGenerator {
+    fn default() -> Self {
+        IdGenerator::Uuid
+    }
+}
+
+impl IdGenerator {
+    fn generate(self) -> String {
+        match self {
+            IdGenerator::Uuid => uuid::Uuid::new_v4().to_string(),
+            IdGenerator::Ulid => ulid::Ulid::new().to_string(),
+        }
+    }
+}
+
+pub(crate) struct RequestIdPlugin {
+    header: HeaderName,
+    trust_incoming: bool,
+    generator: IdGenerator,
+}
+
+impl RequestIdPlugin {
+    pub fn new(cfg: RequestIdConfig) -> Result<Self, ConfigError> {
+        let header = HeaderName::from_bytes(cfg.header.as_bytes())
+            .map_err(|_| ConfigError::Message(format!("invalid header name<{}>", cfg.header)))?;
+
+        Ok(RequestIdPlugin {
+            header,
+            trust_incoming: cfg.trust_incoming,
+            generator: cfg.generator,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for RequestIdPlugin {
+    fn name(&self) -> &str {
+        "request_id"
+    }
+
+    fn priority(&self) -> u32 {
+        3300
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        mut req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let incoming = req
+            .headers()
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|id| self.trust_incoming && !id.is_empty());
+
+        let id = match incoming {
+            Some(id) => id.to_string(),
+            None => self.generator.generate(),
+        };
+
+        // client may send it in several headers
+        req.headers_mut().remove(&self.header);
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            req.headers_mut().insert(self.header.clone(), value);
+        }
+
+        tracing::Span::current().record("request_id", id.as_str());
+        ctx.set(RequestId(id));
+
+        Ok(req)
+    }
+
+    async fn after_forward(
+        &self,
+        ctx: &mut GatewayContext,
+        mut resp: HyperResponse,
+    ) -> HyperResponse {
+        let id = ctx.get::<RequestId>();
+
+        if let Some(value) = id.and_then(|id| HeaderValue::from_str(&id.0).ok()) {
+            resp.headers_mut().insert(self.header.clone(), value);
+        }
+
+        resp
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    fn new_plugin(cfg: serde_json::Value) -> RequestIdPlugin {
+        RequestIdPlugin::new(serde_json::from_value(cfg).unwrap()).unwrap()
+    }
+
+    /// Id forwarded to upstream and echoed to client.
+    fn run(plugin: &RequestIdPlugin, incoming: Option<&str>) -> (String, String) {
+        let mut builder = hyper::Request::builder();
+        if let Some(id) = incoming {
+            builder = builder.header(plugin.header.clone(), id);
+        }
+        let req = builder.body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let req = futures::executor::block_on(plugin.on_access(&mut ctx, req)).unwrap();
+        let forwarded = req.headers()[&plugin.header].to_str().unwrap().to_string();
+        assert_eq!(ctx.get::<RequestId>().unwrap().0, forwarded);
+
+        let resp = HyperResponse::new(Body::empty());
+        let resp = futures::executor::block_on(plugin.after_forward(&mut ctx, resp));
+        let echoed = resp.headers()[&plugin.header].to_str().unwrap().to_string();
+
+        (forwarded, echoed)
+    }
+
+    #[test]
+    fn request_id() {
+        let plugin = new_plugin(serde_json::json!({}));
+
+        let (forwarded, echoed) = run(&plugin, Some("trace-1"));
+        assert_eq!(forwarded, "trace-1");
+        assert_eq!(echoed, "trace-1");
+
+        let (forwarded, echoed) = run(&plugin, None);
+        assert!(uuid::Uuid::parse_str(&forwarded).is_ok());
+        assert_eq!(echoed, forwarded);
+        assert_ne!(run(&plugin, None).0, forwarded);
+
+        let plugin = new_plugin(serde_json::json!({
+            "header": "x-correlation-id",
+            "trust_incoming": false,
+            "generator": "ulid"
+        }));
+
+        let (forwarded, echoed) = run(&plugin, Some("spoofed"));
+        assert_ne!(forwarded, "spoofed");
+        assert!(forwarded.parse::<ulid::Ulid>().is_ok());
+        assert_eq!(echoed, forwarded);
+    }
+}
@@ -0,0 +1,289 @@
+use std::{path::PathBuf, time::Duration};
+
+use hyper::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Body, StatusCode,
+};
+use jsonschema::JSONSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::context::GatewayContext;
+use crate::error::{BufferError, ConfigError};
+use crate::http::{json_error, service_unavailable, HyperRequest, HyperResponse};
+
+use super::Plugin;
+
+/// violations listed in a rejection, the rest are left out
+const MAX_VIOLATIONS: usize = 32;
+
+/// Validate request bodies against a JSON Schema before forwarding.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestValidationConfig {
+    /// inline schema, either this or `schema_path`
+    #[serde(default)]
+    pub schema: Option<Value>,
+    /// json file of schema
+    #[serde(default)]
+    pub schema_path: Option<PathBuf>,
+    /// media types validated, bodies of others pass untouched
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+    /// larger bodies are rejected with 413
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_content_types() -> Vec<String> {
+    vec!["application/json".to_string()]
+}
+
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+pub(crate) struct RequestValidationPlugin {
+    schema: JSONSchema,
+    content_types: Vec<String>,
+    max_body_bytes: usize,
+}
+
+impl RequestValidationPlugin {
+    pub fn new(cfg: RequestValidationConfig) -> Result<Self, ConfigError> {
+        let schema = match (cfg.schema, cfg.schema_path) {
+            (Some(schema), None) => schema,
+            (None, Some(path)) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+            _ => {
+                return Err(ConfigError::Message(
+                    "one of schema and schema_path should be set".to_string(),
+                ))
+            }
+        };
+
+        let schema = JSONSchema::compile(&schema)
+            .map_err(|err| ConfigError::Message(format!("invalid json schema: {}", err)))?;
+
+        Ok(RequestValidationPlugin {
+            schema,
+            content_types: cfg
+                .content_types
+                .iter()
+                .map(|t| t.to_ascii_lowercase())
+                .collect(),
+            max_body_bytes: cfg.max_body_bytes,
+        })
+    }
+
+    fn applies(&self, req: &HyperRequest) -> bool {
+        let essence = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok()?.parse::<mime::Mime>().ok())
+            .map(|m| m.essence_str().to_ascii_lowercase());
+
+        matches!(essence, Some(e) if self.content_types.contains(&e))
+    }
+
+    /// Violations like `{"path": "/items/0", "message": "..."}`, empty when valid.
+    fn violations(&self, body: &Value) -> Vec<Value> {
+        match self.schema.validate(body) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors
+                .take(MAX_VIOLATIONS)
+                .map(|e| violation(&e.instance_path.to_string(), &e.to_string()))
+                .collect(),
+        }
+    }
+}
+
+fn violation(path: &str, message: &str) -> Value {
+    serde_json::json!({ "path": path, "message": message })
+}
+
+fn invalid_body(violations: Vec<Value>) -> HyperResponse {
+    hyper::Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::json!({
+                "message": "invalid request body",
+                "violations": violations,
+            })
+            .to_string(),
+        ))
+        .unwrap()
+}
+
+fn too_large() -> HyperResponse {
+    json_error(StatusCode::PAYLOAD_TOO_LARGE, "request body too large")
+}
+
+#[async_trait::async_trait]
+impl Plugin for RequestValidationPlugin {
+    fn name(&self) -> &str {
+        "request_validation"
+    }
+
+    fn priority(&self) -> u32 {
+        1400
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        mut req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        if !self.applies(&req) {
+            return Ok(req);
+        }
+
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+
+        if content_length.unwrap_or_default() > self.max_body_bytes {
+            return Err(too_large());
+        }
+
+        let bytes = match ctx.buffer_body(&mut req, self.max_body_bytes).await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return Err(too_large()),
+            Err(BufferError::BudgetExhausted) => {
+                return Err(service_unavailable(Duration::from_secs(1)))
+            }
+            Err(err) => {
+                tracing::debug!(route_id = ?ctx.route_id, %err, "read request body failed");
+                return Err(json_error(
+                    StatusCode::BAD_REQUEST,
+                    "read request body failed",
+                ));
+            }
+        };
+
+        let violations = match serde_json::from_slice::<Value>(&bytes) {
+            Ok(body) => self.violations(&body),
+            Err(err) => vec![violation("", &err.to_string())],
+        };
+
+        if !violations.is_empty() {
+            tracing::debug!(route_id = ?ctx.route_id, ?violations, "request body invalid");
+            return Err(invalid_body(violations));
+        }
+
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::http::uri::Scheme;
+    use serde_json::json;
+
+    use super::*;
+
+    fn new_plugin(max_body_bytes: usize) -> RequestValidationPlugin {
+        RequestValidationPlugin::new(
+            serde_json::from_value(json!({
+                "schema": {
+                    "type": "object",
+                    "required": ["name"],
+                    "properties": {
+                        "name": { "type": "string" },
+                        "tags": { "type": "array", "items": { "type": "string" } }
+                    }
+                },
+                "max_body_bytes": max_body_bytes
+            }))
+            .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn validate(
+        plugin: &RequestValidationPlugin,
+        body: &str,
+    ) -> Result<HyperRequest, (StatusCode, Value)> {
+        let req = hyper::Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        futures::executor::block_on(plugin.on_access(&mut ctx, req)).map_err(|resp| {
+            let status = resp.status();
+            let body = futures::executor::block_on(hyper::body::to_bytes(resp.into_body()));
+            (status, serde_json::from_slice(&body.unwrap()).unwrap())
+        })
+    }
+
+    #[test]
+    fn valid_request_body() {
+        let plugin = new_plugin(1024);
+        let body = r#"{"name": "gw", "tags": ["a"]}"#;
+
+        let req = validate(&plugin, body).unwrap();
+        let forwarded = futures::executor::block_on(hyper::body::to_bytes(req.into_body()));
+        assert_eq!(forwarded.unwrap(), body);
+
+        // other content types are not validated
+        let req = hyper::Request::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from("not json"))
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        assert!(futures::executor::block_on(plugin.on_access(&mut ctx, req)).is_ok());
+    }
+
+    #[test]
+    fn invalid_request_body() {
+        let plugin = new_plugin(1024);
+
+        let (status, body) = validate(&plugin, r#"{"tags": ["a", 1, true]}"#).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let mut paths: Vec<_> = body["violations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["path"].as_str().unwrap())
+            .collect();
+        paths.sort_unstable();
+        assert_eq!(paths, vec!["", "/tags/1", "/tags/2"]);
+
+        let (status, body) = validate(&plugin, "{").unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["violations"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn oversized_request_body() {
+        let plugin = new_plugin(16);
+
+        let (status, _) = validate(&plugin, r#"{"name": "a long name"}"#).unwrap_err();
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // no content-length, found while buffering
+        let req = hyper::Request::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::wrap_stream(futures::stream::iter(vec![
+                Ok::<_, std::io::Error>(r#"{"name": "#.to_string()),
+                Ok(r#""a long name"}"#.to_string()),
+            ])))
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        let resp = futures::executor::block_on(plugin.on_access(&mut ctx, req)).unwrap_err();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn invalid_schema() {
+        let cfg = json!({ "schema": { "type": "no-such-type" } });
+        assert!(super::super::init_plugin("request_validation", cfg).is_err());
+
+        let cfg = json!({});
+        assert!(super::super::init_plugin("request_validation", cfg).is_err());
+    }
+}
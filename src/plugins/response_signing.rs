@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::context::GatewayContext;
+use crate::error::{BufferError, ConfigError};
+use crate::http::{bad_gateway, buffer_response_body, service_unavailable, HyperResponse};
+
+use super::Plugin;
+
+/// Attach a digest or signature of response body, letting clients verify integrity.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResponseSigningConfig {
+    pub algorithm: SigningAlgorithm,
+    /// secret key, required by `hmac_sha256`
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// header name, default `Digest` for `sha256`, `X-Signature` for `hmac_sha256`
+    #[serde(default)]
+    pub header: Option<String>,
+    /// max body size in bytes, larger responses are passed unsigned
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SigningAlgorithm {
+    /// `sha-256=<base64>`, as `Digest` of RFC 3230
+    Sha256,
+    /// `sha256=<hex>`
+    HmacSha256,
+}
+
+fn default_max_body_size() -> usize {
+    1024 * 1024
+}
+
+pub(crate) struct ResponseSigningPlugin {
+    algorithm: SigningAlgorithm,
+    secret: Vec<u8>,
+    header: HeaderName,
+    max_body_size: usize,
+}
+
+impl ResponseSigningPlugin {
+    pub fn new(cfg: ResponseSigningConfig) -> Result<Self, ConfigError> {
+        let secret = match (cfg.algorithm, cfg.secret) {
+            (SigningAlgorithm::HmacSha256, None) => {
+                return Err(ConfigError::Message(
+                    "secret required by hmac_sha256".to_string(),
+                ))
+            }
+            (_, secret) => secret.unwrap_or_default().into_bytes(),
+        };
+
+        let header = match cfg.header {
+            Some(header) => header
+                .parse()
+                .map_err(|_| ConfigError::Message(format!("invalid header<{}>", header)))?,
+            None => match cfg.algorithm {
+                SigningAlgorithm::Sha256 => HeaderName::from_static("digest"),
+                SigningAlgorithm::HmacSha256 => HeaderName::from_static("x-signature"),
+            },
+        };
+
+        Ok(ResponseSigningPlugin {
+            algorithm: cfg.algorithm,
+            secret,
+            header,
+            max_body_size: cfg.max_body_size,
+        })
+    }
+
+    fn sign(&self, body: &[u8]) -> String {
+        match self.algorithm {
+            SigningAlgorithm::Sha256 => format!(
+                "sha-256={}",
+                base64::engine::general_purpose::STANDARD.encode(Sha256::digest(body))
+            ),
+            SigningAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+                    .expect("hmac takes key of any size");
+                Mac::update(&mut mac, body);
+                format!("sha256={:x}", mac.finalize().into_bytes())
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for ResponseSigningPlugin {
+    fn name(&self) -> &str {
+        "response_signing"
+    }
+
+    fn priority(&self) -> u32 {
+        100
+    }
+
+    async fn after_forward(
+        &self,
+        ctx: &mut GatewayContext,
+        mut resp: HyperResponse,
+    ) -> HyperResponse {
+        let size = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+
+        // body rewritten by plugins before is signed as it will be sent
+        let body = match buffer_response_body(&mut resp, self.max_body_size).await {
+            Ok(Some(body)) => body,
+            Ok(None) => {
+                tracing::debug!(route_id = ?ctx.route_id, ?size, "response too large to sign");
+                return resp;
+            }
+            Err(BufferError::BudgetExhausted) => {
+                return service_unavailable(Duration::from_secs(1))
+            }
+            Err(err) => {
+                tracing::error!(route_id = ?ctx.route_id, %err, "read upstream body failed");
+                return bad_gateway();
+            }
+        };
+
+        // like responses of HEAD, there is no body to sign
+        if body.is_empty() && size.unwrap_or_default() > 0 {
+            return resp;
+        }
+
+        let signature = self.sign(&body);
+        resp.headers_mut().insert(
+            self.header.clone(),
+            HeaderValue::from_str(&signature).unwrap(),
+        );
+
+        resp
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+    use crate::http::finish_response_body;
+
+    fn new_plugin(algorithm: SigningAlgorithm, secret: Option<&str>) -> ResponseSigningPlugin {
+        ResponseSigningPlugin::new(ResponseSigningConfig {
+            algorithm,
+            secret: secret.map(|s| s.to_string()),
+            header: None,
+            max_body_size: 64,
+        })
+        .unwrap()
+    }
+
+    fn after_forward(plugin: &ResponseSigningPlugin, body: &str) -> HyperResponse {
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        // streamed in chunks, without content-length
+        let chunks = body
+            .as_bytes()
+            .chunks(16)
+            .map(|c| Ok::<_, std::convert::Infallible>(c.to_vec()))
+            .collect::<Vec<_>>();
+        let resp = HyperResponse::new(Body::wrap_stream(futures::stream::iter(chunks)));
+
+        finish_response_body(futures::executor::block_on(
+            plugin.after_forward(&mut ctx, resp),
+        ))
+    }
+
+    #[test]
+    fn sign_response() {
+        let plugin = new_plugin(SigningAlgorithm::Sha256, None);
+        let resp = after_forward(&plugin, "hello");
+        assert_eq!(
+            resp.headers()["digest"],
+            "sha-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+
+        let plugin = new_plugin(SigningAlgorithm::HmacSha256, Some("key"));
+        let resp = after_forward(&plugin, "The quick brown fox jumps over the lazy dog");
+        assert_eq!(
+            resp.headers()["x-signature"],
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+
+        let body = futures::executor::block_on(hyper::body::to_bytes(resp.into_body())).unwrap();
+        assert_eq!(&body[..], b"The quick brown fox jumps over the lazy dog");
+
+        assert!(ResponseSigningPlugin::new(ResponseSigningConfig {
+            algorithm: SigningAlgorithm::HmacSha256,
+            secret: None,
+            header: None,
+            max_body_size: 64,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn large_response_unsigned() {
+        let plugin = new_plugin(SigningAlgorithm::Sha256, None);
+        let large = "0123456789abcdef".repeat(8);
+
+        let resp = after_forward(&plugin, &large);
+        assert!(!resp.headers().contains_key("digest"));
+
+        let body = futures::executor::block_on(hyper::body::to_bytes(resp.into_body())).unwrap();
+        assert_eq!(&body[..], large.as_bytes());
+    }
+}
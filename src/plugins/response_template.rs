@@ -0,0 +1,203 @@
+use std::time::Duration;
+
+use hyper::{
+    header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderMap, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::context::GatewayContext;
+use crate::error::{BufferError, ConfigError};
+use crate::http::{
+    bad_gateway, buffer_response_body, service_unavailable, set_response_body, HyperResponse,
+};
+use crate::jsonpath::JsonTemplate;
+
+use super::Plugin;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ResponseTemplateConfig {
+    /// output shape, strings like `$.data.id` select from upstream json
+    pub template: Value,
+    /// status codes to transform, empty means all 2xx,
+    /// `206` is never transformed as its body is only part of the document
+    #[serde(default)]
+    pub status: Vec<u16>,
+    /// max upstream body size in bytes
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+}
+
+fn default_max_body_size() -> usize {
+    1024 * 1024
+}
+
+/// Map upstream json response into the configured shape.
+pub(crate) struct ResponseTemplatePlugin {
+    template: JsonTemplate,
+    status: Vec<StatusCode>,
+    max_body_size: usize,
+}
+
+impl ResponseTemplatePlugin {
+    pub fn new(cfg: ResponseTemplateConfig) -> Result<Self, ConfigError> {
+        let status = cfg
+            .status
+            .iter()
+            .map(|s| {
+                StatusCode::from_u16(*s)
+                    .map_err(|_| ConfigError::Message(format!("invalid status<{}>", s)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(ResponseTemplatePlugin {
+            template: JsonTemplate::parse(&cfg.template)?,
+            status,
+            max_body_size: cfg.max_body_size,
+        })
+    }
+
+    fn should_transform(&self, resp: &HyperResponse) -> bool {
+        if resp.status() == StatusCode::PARTIAL_CONTENT {
+            return false;
+        }
+
+        let status_matched = if self.status.is_empty() {
+            resp.status().is_success()
+        } else {
+            self.status.contains(&resp.status())
+        };
+
+        status_matched && is_json(resp.headers()) && !resp.headers().contains_key(CONTENT_ENCODING)
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for ResponseTemplatePlugin {
+    fn name(&self) -> &str {
+        "response_template"
+    }
+
+    fn priority(&self) -> u32 {
+        500
+    }
+
+    async fn after_forward(
+        &self,
+        ctx: &mut GatewayContext,
+        mut resp: HyperResponse,
+    ) -> HyperResponse {
+        if !self.should_transform(&resp) {
+            return resp;
+        }
+
+        let size = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+            .unwrap_or(self.max_body_size);
+
+        if size > self.max_body_size {
+            tracing::warn!(route_id = ?ctx.route_id, size, "response too large to transform");
+            return bad_gateway();
+        }
+
+        let input = match buffer_response_body(&mut resp, self.max_body_size).await {
+            Ok(Some(bytes)) => serde_json::from_slice::<Value>(&bytes).map_err(|e| e.to_string()),
+            Ok(None) => Err(format!("body larger than {} bytes", self.max_body_size)),
+            Err(BufferError::BudgetExhausted) => {
+                return service_unavailable(Duration::from_secs(1))
+            }
+            Err(err) => Err(err.to_string()),
+        };
+
+        let input = match input {
+            Ok(input) => input,
+            Err(err) => {
+                tracing::error!(route_id = ?ctx.route_id, %err, "read upstream json failed");
+                return bad_gateway();
+            }
+        };
+
+        let output = serde_json::to_vec(&self.template.render(&input)).unwrap_or_default();
+
+        resp.headers_mut()
+            .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        set_response_body(&mut resp, output);
+
+        resp
+    }
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok()?.parse::<mime::Mime>().ok())
+        .map(|m| m.subtype() == mime::JSON || m.suffix() == Some(mime::JSON))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+    use serde_json::json;
+
+    use super::*;
+    use crate::http::finish_response_body;
+
+    #[tokio::test]
+    async fn transform_json() {
+        let plugin = ResponseTemplatePlugin::new(ResponseTemplateConfig {
+            template: json!({"id": "$.data.uid", "tags": "$.data.tags[*].name"}),
+            status: vec![],
+            max_body_size: default_max_body_size(),
+        })
+        .unwrap();
+
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let upstream = json!({"data": {"uid": 7, "tags": [{"name": "a"}, {"name": "b"}]}});
+        let resp = hyper::Response::builder()
+            .header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .body(Body::from(upstream.to_string()))
+            .unwrap();
+
+        let resp = finish_response_body(plugin.after_forward(&mut ctx, resp).await);
+        assert_eq!(
+            resp.headers()[CONTENT_LENGTH],
+            r#"{"id":7,"tags":["a","b"]}"#.len().to_string()
+        );
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body, json!({"id": 7, "tags": ["a", "b"]}));
+
+        // not json, kept as is
+        let resp = hyper::Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from("hello"))
+            .unwrap();
+
+        let resp = plugin.after_forward(&mut ctx, resp).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        assert_eq!(&body[..], b"hello");
+
+        // partial content, kept as is
+        let resp = hyper::Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_TYPE, "application/json")
+            .header("content-range", "bytes 0-8/64")
+            .body(Body::from(r#"{"data": "#))
+            .unwrap();
+
+        let resp = plugin.after_forward(&mut ctx, resp).await;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()["content-range"], "bytes 0-8/64");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        assert_eq!(&body[..], br#"{"data": "#);
+    }
+}
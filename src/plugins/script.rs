@@ -1,39 +1,99 @@
-use std::sync::Arc;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
 
+use base64::Engine;
 use headers::{HeaderName, HeaderValue};
-use hyper::Body;
+use hyper::{
+    body::Bytes,
+    header::{CONTENT_LENGTH, TRANSFER_ENCODING},
+    http::uri::PathAndQuery,
+    Body, HeaderMap, StatusCode, Uri,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
 use rune::{
-    runtime::{Object, RuntimeContext},
+    runtime::{Object, RuntimeContext, VmError},
     ContextError, FromValue, Module, Unit, Value, Vm,
 };
 use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
 
-use crate::error::ConfigError;
+use crate::config::resolve_path;
+use crate::context::{BufferedRequestBody, GatewayContext};
+use crate::error::{BufferError, ConfigError};
+use crate::http::{
+    bad_gateway, buffer_response_body, json_error, service_unavailable, set_response_body,
+    HyperRequest, HyperResponse,
+};
 
 use super::Plugin;
 
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ScriptConfig {
-    pub script: String,
+    /// inline source, exclusive with `file`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+    /// source file, relative to directory of main config file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    /// recompile `file` on change, the previous script is kept when compile failed
+    #[serde(default)]
+    pub watch: bool,
+    /// buffer bodies for body methods of script, otherwise bodies are streamed and
+    /// body methods return an error
+    #[serde(default)]
+    pub buffer_body: bool,
+    /// bodies up to this size are buffered for the script, larger ones are streamed
+    /// and body methods return an error
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// pass requests through unmodified when script failed, instead of answering 500
+    #[serde(default)]
+    pub fail_open: bool,
 }
 
-pub(crate) struct ScriptPlugin {
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+/// Name of inline script in rune diagnostics.
+const SOURCE_NAME: &str = "entry";
+
+/// How often watched files are checked.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Compiled script, replaced as a whole when its file changed.
+struct Script {
+    /// source name, file path for scripts from file
+    name: String,
     unit: Arc<Unit>,
-    registry: Arc<RuntimeContext>,
+    runtime: Arc<RuntimeContext>,
+    /// script defines `on_access`, otherwise requests skip the vm
+    has_on_access: bool,
+    /// script defines `after_forward`, otherwise responses skip the vm
+    has_after_forward: bool,
 }
 
-impl ScriptPlugin {
-    pub fn new(cfg: ScriptConfig) -> Result<Self, ConfigError> {
+impl Script {
+    fn compile(name: &str, source: &str) -> Result<Self, ConfigError> {
         let mut context = rune::Context::with_default_modules()
             .map_err(|e| ConfigError::Message(format!("{:?}", e)))?;
 
-        let m = build_module().unwrap();
-        context.install(&m).unwrap();
+        for module in [build_module(), build_gw_module()] {
+            context
+                .install(&module.map_err(|e| ConfigError::Message(format!("{:?}", e)))?)
+                .map_err(|e| ConfigError::Message(format!("{:?}", e)))?;
+        }
 
-        let registry = Arc::new(context.runtime());
+        let runtime = Arc::new(context.runtime());
 
         let mut sources = rune::Sources::new();
-        sources.insert(rune::Source::new("entry", &cfg.script));
+        sources.insert(rune::Source::new(name, source));
 
         let mut diagnostics = rune::Diagnostics::new();
 
@@ -41,49 +101,350 @@ impl ScriptPlugin {
             .with_context(&context)
             .with_diagnostics(&mut diagnostics)
             .build()
-            .map_err(|e| {
+            .map_err(|_| {
                 ConfigError::Message(format!(
                     "script compile err: {:?}",
                     diagnostics.diagnostics()
                 ))
             })?;
 
+        let unit = Arc::new(unit);
+        let vm = Vm::new(runtime.clone(), unit.clone());
+        let has_on_access = vm.lookup_function(&["on_access"]).is_ok();
+        let has_after_forward = vm.lookup_function(&["after_forward"]).is_ok();
+
+        Ok(Script {
+            name: name.to_string(),
+            unit,
+            runtime,
+            has_on_access,
+            has_after_forward,
+        })
+    }
+
+    fn load(path: &Path) -> Result<Self, ConfigError> {
+        let source = std::fs::read_to_string(path).map_err(|err| {
+            ConfigError::Message(format!("read script<{}>: {}", path.display(), err))
+        })?;
+
+        Self::compile(&path.display().to_string(), &source)
+    }
+
+    fn vm(&self) -> Vm {
+        Vm::new(self.runtime.clone(), self.unit.clone())
+    }
+}
+
+/// Watched script file, polled by the task spawned in `on_start`.
+struct ScriptFile {
+    path: PathBuf,
+    /// modified time of the compiled version
+    modified: Option<SystemTime>,
+    interval: Duration,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+pub(crate) struct ScriptPlugin {
+    /// shared with the watcher task
+    script: Arc<RwLock<Arc<Script>>>,
+    watched: Option<ScriptFile>,
+    buffer_body: bool,
+    max_body_bytes: usize,
+    fail_open: bool,
+}
+
+impl ScriptPlugin {
+    pub fn new(cfg: ScriptConfig) -> Result<Self, ConfigError> {
+        let (script, watched) = match (cfg.script, cfg.file) {
+            (Some(source), None) if !cfg.watch => (Script::compile(SOURCE_NAME, &source)?, None),
+            (Some(_), None) => {
+                return Err(ConfigError::Message(
+                    "only script from file can be watched".to_string(),
+                ))
+            }
+            (None, Some(file)) => {
+                let path = resolve_path(&file);
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                let script = Script::load(&path)?;
+
+                let watched = cfg.watch.then(|| ScriptFile {
+                    path,
+                    modified,
+                    interval: WATCH_INTERVAL,
+                    task: Mutex::new(None),
+                });
+                (script, watched)
+            }
+            _ => {
+                return Err(ConfigError::Message(
+                    "exactly one of script and file should be set".to_string(),
+                ))
+            }
+        };
+
         Ok(ScriptPlugin {
-            unit: Arc::new(unit),
-            registry,
+            script: Arc::new(RwLock::new(Arc::new(script))),
+            watched,
+            buffer_body: cfg.buffer_body,
+            max_body_bytes: cfg.max_body_bytes,
+            fail_open: cfg.fail_open,
         })
     }
+
+    /// Script currently compiled, kept by callers for a whole phase.
+    fn script(&self) -> Arc<Script> {
+        self.script.read().unwrap().clone()
+    }
+
+    fn call_on_access(
+        &self,
+        script: &Script,
+        ctx: &GatewayContext,
+        req: MyRequest,
+    ) -> Result<Result<MyRequest, MyResponse>, VmError> {
+        let mut vm = script.vm();
+
+        let output = with_route(ctx.route_id.as_deref(), || vm.call(&["on_access"], (req,)))?;
+
+        // anything but `Ok(req)` or `Err(resp)` fails here
+        Result::<MyRequest, MyResponse>::from_value(output)
+    }
+
+    fn call_after_forward(
+        &self,
+        script: &Script,
+        ctx: &GatewayContext,
+        resp: MyResponse,
+    ) -> Result<MyResponse, VmError> {
+        let mut vm = script.vm();
+
+        let route_id = ctx.route_id.as_deref();
+        let ctx = MyContext {
+            route_id: ctx.route_id.clone(),
+            params: ctx.path_params.clone(),
+            vars: ctx.vars.clone(),
+        };
+
+        let output = with_route(route_id, || vm.call(&["after_forward"], (ctx, resp)))?;
+
+        MyResponse::from_value(output)
+    }
+}
+
+/// Recompile `path` when modified, until the plugin is dropped.
+async fn watch_script(
+    path: PathBuf,
+    mut modified: Option<SystemTime>,
+    interval: Duration,
+    script: Weak<RwLock<Arc<Script>>>,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let script = match script.upgrade() {
+            Some(script) => script,
+            None => return,
+        };
+
+        let current = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if current.is_none() || current == modified {
+            continue;
+        }
+        modified = current;
+
+        match Script::load(&path) {
+            Ok(compiled) => {
+                *script.write().unwrap() = Arc::new(compiled);
+                tracing::info!(?path, "script reloaded");
+            }
+            Err(err) => {
+                tracing::error!(?path, %err, "script reload failed, previous one kept");
+            }
+        }
+    }
 }
 
+#[async_trait::async_trait]
 impl Plugin for ScriptPlugin {
     fn priority(&self) -> u32 {
         2000
     }
 
-    fn on_access(
+    fn on_start(&self) {
+        let watched = match &self.watched {
+            Some(watched) => watched,
+            None => return,
+        };
+
+        let handle = match tokio::runtime::Handle::try_current() {
+            Ok(handle) => handle,
+            Err(_) => {
+                tracing::warn!(path = ?watched.path, "no runtime, script not watched");
+                return;
+            }
+        };
+
+        let task = handle.spawn(watch_script(
+            watched.path.clone(),
+            watched.modified,
+            watched.interval,
+            Arc::downgrade(&self.script),
+        ));
+        if let Some(previous) = watched.task.lock().unwrap().replace(task) {
+            previous.abort();
+        }
+    }
+
+    fn on_stop(&self) {
+        if let Some(watched) = &self.watched {
+            if let Some(task) = watched.task.lock().unwrap().take() {
+                task.abort();
+            }
+        }
+    }
+
+    async fn on_access(
         &self,
         ctx: &mut crate::context::GatewayContext,
-        req: crate::http::HyperRequest,
+        mut req: crate::http::HyperRequest,
     ) -> Result<crate::http::HyperRequest, crate::http::HyperResponse> {
-        let mut vm = Vm::new(self.registry.clone(), self.unit.clone());
+        let script = self.script();
+        if !script.has_on_access {
+            return Ok(req);
+        }
 
-        let output = vm
-            .call(&["on_access"], (MyRequest { inner: req },))
-            .unwrap();
+        let body = if self.buffer_body {
+            match ctx.buffer_body(&mut req, self.max_body_bytes).await {
+                Ok(body) => ScriptBody::new(body, self.max_body_bytes),
+                Err(BufferError::BudgetExhausted) => {
+                    return Err(service_unavailable(Duration::from_secs(1)))
+                }
+                Err(err) => {
+                    tracing::debug!(route_id = ?ctx.route_id, %err, "read request body failed");
+                    return Err(json_error(
+                        StatusCode::BAD_REQUEST,
+                        "read request body failed",
+                    ));
+                }
+            }
+        } else {
+            ScriptBody::unbuffered()
+        };
+
+        // script sees the head, body kept aside for the original to survive errors
+        let (mut parts, rest) = req.into_parts();
+        let mut head = hyper::Request::new(Body::empty());
+        *head.method_mut() = parts.method.clone();
+        *head.uri_mut() = parts.uri.clone();
+        *head.headers_mut() = parts.headers.clone();
+
+        let head = MyRequest {
+            inner: head,
+            params: ctx.path_params.clone(),
+            body,
+        };
+
+        match self.call_on_access(&script, ctx, head) {
+            Ok(Ok(MyRequest { inner, body, .. })) => {
+                let head = inner.into_parts().0;
+                parts.uri = head.uri;
+                parts.headers = head.headers;
 
-        type MyResult = Result<MyRequest, MyResponse>;
+                let rest = match body.modified() {
+                    Some(bytes) => {
+                        // later plugins read the body rewritten
+                        ctx.set(BufferedRequestBody(bytes.clone()));
+                        Body::from(bytes)
+                    }
+                    None => rest,
+                };
 
-        let ret = MyResult::from_value(output).unwrap();
+                Ok(HyperRequest::from_parts(parts, rest))
+            }
+            Ok(Err(resp)) => Err(resp.inner),
+            Err(err) => {
+                tracing::error!(
+                    route_id = ?ctx.route_id,
+                    source = %script.name,
+                    %err,
+                    "script on_access failed"
+                );
 
-        ret.map(|r| r.inner).map_err(|r| r.inner)
+                if self.fail_open {
+                    Ok(HyperRequest::from_parts(parts, rest))
+                } else {
+                    Err(json_error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "script failed",
+                    ))
+                }
+            }
+        }
     }
 
-    fn after_forward(
+    async fn after_forward(
         &self,
-        ctx: &mut crate::context::GatewayContext,
-        resp: crate::http::HyperResponse,
-    ) -> crate::http::HyperResponse {
-        resp
+        ctx: &mut GatewayContext,
+        mut resp: HyperResponse,
+    ) -> HyperResponse {
+        let script = self.script();
+        if !script.has_after_forward {
+            return resp;
+        }
+
+        let body = if self.buffer_body {
+            match buffer_response_body(&mut resp, self.max_body_bytes).await {
+                Ok(body) => ScriptBody::new(body, self.max_body_bytes),
+                Err(BufferError::BudgetExhausted) => {
+                    return service_unavailable(Duration::from_secs(1))
+                }
+                Err(err) => {
+                    tracing::error!(route_id = ?ctx.route_id, %err, "read upstream body failed");
+                    return bad_gateway();
+                }
+            }
+        } else {
+            ScriptBody::unbuffered()
+        };
+
+        // script sees status and headers, body kept aside for the original to survive errors
+        let (mut parts, rest) = resp.into_parts();
+        let mut head = hyper::Response::new(Body::empty());
+        *head.status_mut() = parts.status;
+        *head.headers_mut() = parts.headers.clone();
+
+        let head = MyResponse {
+            inner: head,
+            forwarded: true,
+            body,
+        };
+
+        match self.call_after_forward(&script, ctx, head) {
+            Ok(MyResponse {
+                inner,
+                forwarded: true,
+                body,
+            }) => {
+                parts.status = inner.status();
+                parts.headers = inner.into_parts().0.headers;
+
+                let mut resp = HyperResponse::from_parts(parts, rest);
+                if let Some(bytes) = body.modified() {
+                    set_response_body(&mut resp, bytes);
+                }
+                resp
+            }
+            Ok(replaced) => replaced.inner,
+            Err(err) => {
+                tracing::error!(
+                    route_id = ?ctx.route_id,
+                    source = %script.name,
+                    %err,
+                    "script after_forward failed"
+                );
+                HyperResponse::from_parts(parts, rest)
+            }
+        }
     }
 }
 
@@ -92,18 +453,233 @@ fn build_module() -> Result<Module, ContextError> {
 
     module.ty::<MyRequest>()?;
     module.ty::<MyResponse>()?;
+    module.ty::<MyContext>()?;
 
     module.function(&["MyResponse", "new"], MyResponse::new)?;
 
+    module.inst_fn("param", MyRequest::param)?;
+    module.inst_fn("get_header", MyRequest::get_header)?;
+    module.inst_fn("set_header", MyRequest::set_header)?;
+    module.inst_fn("remove_header", MyRequest::remove_header)?;
+    module.inst_fn("method", MyRequest::method)?;
+    module.inst_fn("uri", MyRequest::uri)?;
+    module.inst_fn("host", MyRequest::host)?;
+    module.inst_fn("path", MyRequest::path)?;
+    module.inst_fn("set_path", MyRequest::set_path)?;
+    module.inst_fn("query", MyRequest::query)?;
+    module.inst_fn("set_query_param", MyRequest::set_query_param)?;
+    module.inst_fn("body_bytes", MyRequest::body_bytes)?;
+    module.inst_fn("body_string", MyRequest::body_string)?;
+    module.inst_fn("json", MyRequest::json)?;
+    module.inst_fn("set_body", MyRequest::set_body)?;
+
+    module.inst_fn("status", MyResponse::status)?;
+    module.inst_fn("set_status", MyResponse::set_status)?;
+    module.inst_fn("get_header", MyResponse::get_header)?;
+    module.inst_fn("set_header", MyResponse::set_header)?;
+    module.inst_fn("remove_header", MyResponse::remove_header)?;
+    module.inst_fn("body_bytes", MyResponse::body_bytes)?;
+    module.inst_fn("body_string", MyResponse::body_string)?;
+    module.inst_fn("json", MyResponse::json)?;
+    module.inst_fn("set_body", MyResponse::set_body)?;
+
+    module.inst_fn("route_id", MyContext::route_id)?;
+    module.inst_fn("param", MyContext::param)?;
+    module.inst_fn("var", MyContext::var)?;
+
+    Ok(module)
+}
+
+/// Helpers for scripts as `gw::*`, failures are returned as `Err` values.
+fn build_gw_module() -> Result<Module, ContextError> {
+    let mut module = Module::with_crate("gw");
+
+    module.function(&["base64_encode"], base64_encode)?;
+    module.function(&["base64_decode"], base64_decode)?;
+    module.function(&["url_encode"], url_encode)?;
+    module.function(&["url_decode"], url_decode)?;
+    module.function(&["json_parse"], json_parse)?;
+    module.function(&["json_stringify"], json_stringify)?;
+    module.function(&["regex_match"], regex_match)?;
+    module.function(&["now_ms"], now_ms)?;
+    module.function(&["log"], log)?;
+
     Ok(module)
 }
 
+fn base64_encode(input: &str) -> String {
+    base64::engine::general_purpose::STANDARD.encode(input)
+}
+
+/// Standard or url safe alphabet, padded or not, like segments of JWT.
+fn base64_decode(input: &str) -> Result<String, String> {
+    let normalized: String = input
+        .trim_end_matches('=')
+        .chars()
+        .map(|c| match c {
+            '-' => '+',
+            '_' => '/',
+            c => c,
+        })
+        .collect();
+
+    let bytes = base64::engine::general_purpose::STANDARD_NO_PAD
+        .decode(normalized)
+        .map_err(|e| e.to_string())?;
+
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
+
+/// Unreserved characters of RFC 3986 are kept.
+const URL_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+fn url_encode(input: &str) -> String {
+    utf8_percent_encode(input, URL_ENCODE_SET).to_string()
+}
+
+/// `+` decoded as space, as in query strings.
+fn url_decode(input: &str) -> Result<String, String> {
+    percent_decode_str(&input.replace('+', " "))
+        .decode_utf8()
+        .map(|s| s.into_owned())
+        .map_err(|e| e.to_string())
+}
+
+fn json_parse(input: &str) -> Result<Value, String> {
+    serde_json::from_str(input).map_err(|e| e.to_string())
+}
+
+fn json_stringify(value: Value) -> Result<String, String> {
+    serde_json::to_string(&value).map_err(|e| e.to_string())
+}
+
+const REGEX_CACHE_SIZE: usize = 256;
+
+lazy_static::lazy_static! {
+    /// compiled patterns of `gw::regex_match`, shared by all scripts
+    static ref REGEX_CACHE: Mutex<RegexCache> = Mutex::new(RegexCache::default());
+}
+
+#[derive(Default)]
+struct RegexCache {
+    /// pattern to compiled regex and its last use
+    entries: HashMap<String, (Regex, u64)>,
+    tick: u64,
+}
+
+impl RegexCache {
+    fn get(&mut self, pattern: &str) -> Result<Regex, regex::Error> {
+        self.tick += 1;
+
+        if let Some((regex, used)) = self.entries.get_mut(pattern) {
+            *used = self.tick;
+            return Ok(regex.clone());
+        }
+
+        let regex = Regex::new(pattern)?;
+
+        // drop the least recently used
+        if self.entries.len() >= REGEX_CACHE_SIZE {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(pattern, _)| pattern.clone());
+            if let Some(pattern) = lru {
+                self.entries.remove(&pattern);
+            }
+        }
+
+        self.entries
+            .insert(pattern.to_string(), (regex.clone(), self.tick));
+
+        Ok(regex)
+    }
+}
+
+fn regex_match(pattern: &str, text: &str) -> Result<bool, String> {
+    let regex = REGEX_CACHE
+        .lock()
+        .unwrap()
+        .get(pattern)
+        .map_err(|e| e.to_string())?;
+
+    Ok(regex.is_match(text))
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+thread_local! {
+    /// route of the script running on this thread, for `gw::log`
+    static SCRIPT_ROUTE: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// Run `f` with `route_id` tagged on `gw::log`, the vm runs on the calling thread.
+fn with_route<T>(route_id: Option<&str>, f: impl FnOnce() -> T) -> T {
+    SCRIPT_ROUTE.with(|route| *route.borrow_mut() = route_id.map(|id| id.to_string()));
+    let ret = f();
+    SCRIPT_ROUTE.with(|route| route.borrow_mut().take());
+
+    ret
+}
+
+fn log(level: &str, message: &str) -> Result<(), String> {
+    SCRIPT_ROUTE.with(|route| {
+        let route_id = route.borrow();
+        let route_id = route_id.as_deref();
+
+        match level {
+            "error" => tracing::error!(route_id, "{}", message),
+            "warn" => tracing::warn!(route_id, "{}", message),
+            "info" => tracing::info!(route_id, "{}", message),
+            "debug" => tracing::debug!(route_id, "{}", message),
+            "trace" => tracing::trace!(route_id, "{}", message),
+            _ => return Err(format!("unknown log level {}", level)),
+        }
+
+        Ok(())
+    })
+}
+
 #[derive(Debug, rune::Any)]
 struct MyRequest {
     inner: crate::http::HyperRequest,
+    params: HashMap<String, String>,
+    body: ScriptBody,
 }
 
 impl MyRequest {
+    /// path param captured by route uri
+    fn param(&self, name: &str) -> Option<String> {
+        self.params.get(name).cloned()
+    }
+
+    fn body_bytes(&self) -> Result<rune::runtime::Bytes, String> {
+        self.body.bytes()
+    }
+
+    fn body_string(&self) -> Result<String, String> {
+        self.body.string()
+    }
+
+    fn json(&self) -> Result<Value, String> {
+        self.body.json()
+    }
+
+    /// body put back by `on_access`, `inner` only carries the head
+    fn set_body(&mut self, body: Value) -> Result<(), String> {
+        self.body.set(self.inner.headers_mut(), body).map(|_| ())
+    }
+
     fn get_header(&self, key: &str) -> Option<String> {
         self.inner
             .headers()
@@ -111,36 +687,658 @@ impl MyRequest {
             .and_then(|v| v.to_str().ok().map(|s| s.to_string()))
     }
 
+    /// invalid name or value ignored
     fn set_header(&mut self, key: &str, value: &str) {
-        self.inner.headers_mut().insert(
-            HeaderName::from_bytes(key.as_bytes()).unwrap(),
-            HeaderValue::from_str(value).unwrap(),
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            self.inner.headers_mut().insert(name, value);
+        }
+    }
+
+    fn remove_header(&mut self, key: &str) {
+        self.inner.headers_mut().remove(key);
+    }
+
+    fn method(&self) -> String {
+        self.inner.method().to_string()
+    }
+
+    fn uri(&self) -> String {
+        self.inner.uri().to_string()
+    }
+
+    /// `Host` header without port, or host of absolute uri
+    fn host(&self) -> Option<String> {
+        let host = self
+            .inner
+            .headers()
+            .get(hyper::header::HOST)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| crate::matcher::split_host_port(v).0)
+            .or_else(|| self.inner.uri().host())?;
+
+        Some(host.to_string())
+    }
+
+    fn path(&self) -> String {
+        self.inner.uri().path().to_string()
+    }
+
+    fn set_path(&mut self, path: &str) -> Result<(), String> {
+        if !path.starts_with('/') {
+            return Err(format!("path<{}> should start with /", path));
+        }
+
+        let query = self.inner.uri().query().map(|q| q.to_string());
+        self.set_path_and_query(path, query.as_deref())
+    }
+
+    /// query params, the last one wins when repeated
+    fn query(&self) -> HashMap<String, String> {
+        let query = self.inner.uri().query().unwrap_or_default();
+
+        url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect()
+    }
+
+    /// Replace every `key` param with one `key=value`, or append it.
+    fn set_query_param(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let query = self.inner.uri().query().unwrap_or_default();
+
+        let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+        let mut replaced = false;
+        for (k, v) in url::form_urlencoded::parse(query.as_bytes()) {
+            if k != key {
+                serializer.append_pair(&k, &v);
+            } else if !replaced {
+                serializer.append_pair(key, value);
+                replaced = true;
+            }
+        }
+        if !replaced {
+            serializer.append_pair(key, value);
+        }
+
+        let path = self.inner.uri().path().to_string();
+        self.set_path_and_query(&path, Some(&serializer.finish()))
+    }
+
+    fn set_path_and_query(&mut self, path: &str, query: Option<&str>) -> Result<(), String> {
+        let path_and_query = match query {
+            Some(query) if !query.is_empty() => format!("{}?{}", path, query),
+            _ => path.to_string(),
+        };
+
+        let mut parts = self.inner.uri().clone().into_parts();
+        parts.path_and_query = Some(
+            path_and_query
+                .parse::<PathAndQuery>()
+                .map_err(|e| e.to_string())?,
         );
+        *self.inner.uri_mut() = Uri::from_parts(parts).map_err(|e| e.to_string())?;
+
+        Ok(())
     }
 }
 
 #[derive(Debug, rune::Any)]
 struct MyResponse {
     inner: crate::http::HyperResponse,
+    /// head of upstream response, whose body is not in `inner`
+    forwarded: bool,
+    body: ScriptBody,
 }
 
 impl MyResponse {
-    fn new(status: u16, value: Value) -> Self {
-        let mut res = hyper::Response::builder().status(status);
-        {
-            let headers = res.headers_mut().unwrap();
-            headers.insert(
-                hyper::header::CONTENT_TYPE,
-                HeaderValue::from_static("application/json;utf-8"),
-            );
+    /// Fails on invalid status, or value not serializable as json.
+    fn new(status: u16, value: Value) -> Result<Self, String> {
+        let status = StatusCode::from_u16(status).map_err(|e| e.to_string())?;
+        let data = Bytes::from(serde_json::to_vec(&value).map_err(|e| e.to_string())?);
+
+        let mut inner = hyper::Response::new(Body::from(data.clone()));
+        *inner.status_mut() = status;
+        inner.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json;utf-8"),
+        );
+
+        Ok(MyResponse {
+            inner,
+            forwarded: false,
+            body: ScriptBody::new(Some(data), usize::MAX),
+        })
+    }
+
+    fn status(&self) -> u16 {
+        self.inner.status().as_u16()
+    }
+
+    /// invalid status ignored
+    fn set_status(&mut self, status: u16) {
+        if let Ok(status) = StatusCode::from_u16(status) {
+            *self.inner.status_mut() = status;
+        }
+    }
+
+    fn get_header(&self, key: &str) -> Option<String> {
+        self.inner
+            .headers()
+            .get(key)
+            .and_then(|v| v.to_str().ok().map(|s| s.to_string()))
+    }
+
+    /// invalid name or value ignored
+    fn set_header(&mut self, key: &str, value: &str) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            self.inner.headers_mut().insert(name, value);
+        }
+    }
+
+    fn remove_header(&mut self, key: &str) {
+        self.inner.headers_mut().remove(key);
+    }
+
+    fn body_bytes(&self) -> Result<rune::runtime::Bytes, String> {
+        self.body.bytes()
+    }
+
+    fn body_string(&self) -> Result<String, String> {
+        self.body.string()
+    }
+
+    fn json(&self) -> Result<Value, String> {
+        self.body.json()
+    }
+
+    fn set_body(&mut self, body: Value) -> Result<(), String> {
+        let bytes = self.body.set(self.inner.headers_mut(), body)?;
+        *self.inner.body_mut() = Body::from(bytes);
+        Ok(())
+    }
+}
+
+/// Body seen by script, buffered before the script runs.
+#[derive(Debug)]
+struct ScriptBody {
+    /// `None` when larger than `limit`, or not buffered
+    bytes: Option<Bytes>,
+    /// `None` when not buffered
+    limit: Option<usize>,
+    modified: bool,
+}
+
+impl ScriptBody {
+    fn new(bytes: Option<Bytes>, limit: usize) -> Self {
+        ScriptBody {
+            bytes,
+            limit: Some(limit),
+            modified: false,
+        }
+    }
+
+    /// Body streamed past script, which may still replace it.
+    fn unbuffered() -> Self {
+        ScriptBody {
+            bytes: None,
+            limit: None,
+            modified: false,
+        }
+    }
+
+    fn get(&self) -> Result<&Bytes, String> {
+        match (&self.bytes, self.limit) {
+            (Some(bytes), _) => Ok(bytes),
+            (None, Some(limit)) => Err(format!("body larger than {} bytes", limit)),
+            (None, None) => Err("body not buffered, see buffer_body".to_string()),
+        }
+    }
+
+    fn bytes(&self) -> Result<rune::runtime::Bytes, String> {
+        Ok(rune::runtime::Bytes::from_vec(self.get()?.to_vec()))
+    }
+
+    fn string(&self) -> Result<String, String> {
+        String::from_utf8(self.get()?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn json(&self) -> Result<Value, String> {
+        serde_json::from_slice(self.get()?).map_err(|e| e.to_string())
+    }
+
+    /// Replace with strings or bytes as they are, other values as json, and fix
+    /// `Content-Length` in `headers`.
+    fn set(&mut self, headers: &mut HeaderMap, body: Value) -> Result<Bytes, String> {
+        let bytes = match &body {
+            Value::String(s) => Bytes::from(s.borrow_ref().map_err(|e| e.to_string())?.clone()),
+            Value::StaticString(s) => Bytes::from(s.as_str().to_string()),
+            Value::Bytes(b) => Bytes::from(b.borrow_ref().map_err(|e| e.to_string())?.to_vec()),
+            _ => Bytes::from(serde_json::to_vec(&body).map_err(|e| e.to_string())?),
+        };
+
+        headers.remove(TRANSFER_ENCODING);
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+
+        self.bytes = Some(bytes.clone());
+        self.modified = true;
+
+        Ok(bytes)
+    }
+
+    /// Body set by script, if any.
+    fn modified(&self) -> Option<Bytes> {
+        self.bytes.clone().filter(|_| self.modified)
+    }
+}
+
+/// Request context seen by script, read only.
+#[derive(Debug, rune::Any)]
+struct MyContext {
+    route_id: Option<String>,
+    params: HashMap<String, String>,
+    vars: HashMap<String, String>,
+}
+
+impl MyContext {
+    fn route_id(&self) -> Option<String> {
+        self.route_id.clone()
+    }
+
+    /// path param captured by route uri
+    fn param(&self, name: &str) -> Option<String> {
+        self.params.get(name).cloned()
+    }
+
+    /// variable set by plugins
+    fn var(&self, name: &str) -> Option<String> {
+        self.vars.get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::http::uri::Scheme;
+
+    use super::*;
+
+    use crate::http::finish_response_body;
+
+    fn new_plugin(script: &str) -> ScriptPlugin {
+        ScriptPlugin::new(serde_json::from_value(serde_json::json!({ "script": script })).unwrap())
+            .unwrap()
+    }
+
+    async fn after_forward(plugin: &ScriptPlugin, resp: HyperResponse) -> HyperResponse {
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        ctx.route_id = Some("script".to_string());
+
+        finish_response_body(plugin.after_forward(&mut ctx, resp).await)
+    }
+
+    fn upstream_response() -> HyperResponse {
+        hyper::Response::builder()
+            .status(404)
+            .header("server", "upstream")
+            .body(Body::from("not here"))
+            .unwrap()
+    }
+
+    async fn body(resp: HyperResponse) -> String {
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rewrite_response() {
+        let plugin = new_plugin(
+            r#"
+            pub fn after_forward(ctx, resp) {
+                resp.set_header("x-route", ctx.route_id().unwrap());
+                resp.remove_header("server");
+                if resp.status() == 404 {
+                    resp.set_status(410);
+                }
+                resp
+            }
+            "#,
+        );
+        assert!(plugin.script().has_after_forward);
+
+        let resp = after_forward(&plugin, upstream_response()).await;
+        assert_eq!(resp.status(), 410);
+        assert_eq!(resp.headers()["x-route"], "script");
+        assert!(!resp.headers().contains_key("server"));
+        assert_eq!(body(resp).await, "not here");
+
+        // replaced response
+        let plugin =
+            new_plugin(r#"pub fn after_forward(ctx, resp) { MyResponse::new(200, #{})? }"#);
+
+        let resp = after_forward(&plugin, upstream_response()).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(body(resp).await, "{}");
+    }
+
+    #[tokio::test]
+    async fn untouched_response() {
+        // no after_forward, vm skipped
+        let plugin = new_plugin("pub fn on_access(req) { Ok(req) }");
+        assert!(!plugin.script().has_after_forward);
+
+        let resp = after_forward(&plugin, upstream_response()).await;
+        assert_eq!(resp.status(), 404);
+        assert_eq!(resp.headers()["server"], "upstream");
+        assert_eq!(body(resp).await, "not here");
+
+        // failed script keeps original response
+        let plugin = new_plugin("pub fn after_forward(ctx, resp) { resp.no_such_method() }");
+
+        let resp = after_forward(&plugin, upstream_response()).await;
+        assert_eq!(resp.status(), 404);
+        assert_eq!(resp.headers()["server"], "upstream");
+        assert_eq!(body(resp).await, "not here");
+    }
+
+    const MASK_SCRIPT: &str = r#"
+        pub fn after_forward(ctx, resp) {
+            match resp.json() {
+                Ok(body) => {
+                    body["card"] = "****";
+                    resp.set_body(body);
+                }
+                Err(err) => resp.set_header("x-error", err),
+            }
+            resp
         }
+    "#;
 
-        let data = serde_json::to_vec(&value).unwrap();
+    fn card_response() -> HyperResponse {
+        hyper::Response::builder()
+            .header(CONTENT_LENGTH, "33")
+            .body(Body::from(r#"{"card":"4111111","name":"alice"}"#))
+            .unwrap()
+    }
 
-        let res = res.body(Body::from(data));
+    #[tokio::test]
+    async fn rewrite_response_body() {
+        let plugin = ScriptPlugin::new(
+            serde_json::from_value(serde_json::json!({
+                "script": MASK_SCRIPT,
+                "buffer_body": true,
+            }))
+            .unwrap(),
+        )
+        .unwrap();
 
-        MyResponse {
-            inner: res.unwrap(),
+        let resp = after_forward(&plugin, card_response()).await;
+        let length = resp.headers()[CONTENT_LENGTH].clone();
+        let text = body(resp).await;
+        assert_eq!(length, text.len().to_string().as_str());
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&text).unwrap(),
+            serde_json::json!({ "card": "****", "name": "alice" })
+        );
+
+        // larger than limit, script told so and body streamed untouched
+        let plugin = ScriptPlugin::new(ScriptConfig {
+            script: Some(MASK_SCRIPT.to_string()),
+            file: None,
+            watch: false,
+            buffer_body: true,
+            max_body_bytes: 8,
+            fail_open: false,
+        })
+        .unwrap();
+
+        let resp = after_forward(&plugin, card_response()).await;
+        assert_eq!(resp.headers()["x-error"], "body larger than 8 bytes");
+        assert_eq!(body(resp).await, r#"{"card":"4111111","name":"alice"}"#);
+
+        // not buffered unless asked
+        let plugin = new_plugin(MASK_SCRIPT);
+
+        let resp = after_forward(&plugin, card_response()).await;
+        assert_eq!(
+            resp.headers()["x-error"],
+            "body not buffered, see buffer_body"
+        );
+        assert_eq!(body(resp).await, r#"{"card":"4111111","name":"alice"}"#);
+    }
+
+    async fn on_access(plugin: &ScriptPlugin) -> Result<HyperRequest, HyperResponse> {
+        let req = hyper::Request::builder()
+            .method("POST")
+            .header("x-user", "alice")
+            .body(Body::from("hello"))
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        plugin.on_access(&mut ctx, req).await
+    }
+
+    #[tokio::test]
+    async fn failed_on_access() {
+        let missing = "pub fn on_access(req) { req.no_such_method(); Ok(req) }";
+        let wrong_type = "pub fn on_access(req) { 1 }";
+        let invalid_status = "pub fn on_access(req) { Err(MyResponse::new(1000, #{})?) }";
+
+        for script in [missing, wrong_type, invalid_status] {
+            let resp = on_access(&new_plugin(script)).await.unwrap_err();
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+            // passed through as it was
+            let plugin = ScriptPlugin::new(ScriptConfig {
+                script: Some(script.to_string()),
+                file: None,
+                watch: false,
+                buffer_body: true,
+                max_body_bytes: 1024,
+                fail_open: true,
+            })
+            .unwrap();
+            let req = on_access(&plugin).await.unwrap();
+            assert_eq!(req.headers()["x-user"], "alice");
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"hello");
+        }
+
+        // no on_access, vm skipped
+        let plugin = new_plugin("pub fn after_forward(ctx, resp) { 1 }");
+        assert!(on_access(&plugin).await.is_ok());
+    }
+
+    const CLAIMS_SCRIPT: &str = r#"
+        pub fn on_access(req) {
+            let claims = match gw::base64_decode(req.get_header("x-payload").unwrap()) {
+                Ok(payload) => gw::json_parse(payload).unwrap(),
+                Err(err) => return Err(MyResponse::new(400, #{ error: err })?),
+            };
+
+            if !gw::regex_match("^user-[0-9]+$", claims["sub"]).unwrap() {
+                return Err(MyResponse::new(403, #{ error: "bad subject" })?);
+            }
+
+            gw::log("debug", "claims checked").unwrap();
+            req.set_header("x-subject", claims["sub"]);
+            req.set_header("x-claims", gw::json_stringify(claims).unwrap());
+            Ok(req)
         }
+    "#;
+
+    async fn claims(plugin: &ScriptPlugin, payload: &str) -> Result<HyperRequest, HyperResponse> {
+        let req = hyper::Request::builder()
+            .header("x-payload", payload)
+            .body(Body::empty())
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        plugin.on_access(&mut ctx, req).await
+    }
+
+    #[tokio::test]
+    async fn gw_helpers() {
+        let plugin = new_plugin(CLAIMS_SCRIPT);
+        let encode = |claims: serde_json::Value| {
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(claims.to_string())
+        };
+
+        let payload = encode(serde_json::json!({ "sub": "user-42", "scope": "read" }));
+        let req = claims(&plugin, &payload).await.unwrap();
+        assert_eq!(req.headers()["x-subject"], "user-42");
+        let stringified = req.headers()["x-claims"].to_str().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(stringified).unwrap(),
+            serde_json::json!({ "sub": "user-42", "scope": "read" })
+        );
+
+        let payload = encode(serde_json::json!({ "sub": "admin" }));
+        let resp = claims(&plugin, &payload).await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // error value handed to script
+        let resp = claims(&plugin, "not base64!").await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(url_encode("a b/c~"), "a%20b%2Fc~");
+        assert_eq!(url_decode("a%20b+c").unwrap(), "a b c");
+        assert!(url_decode("%FF").is_err());
+        assert!(regex_match("(", "").is_err());
+        assert!(log("loud", "").is_err());
+    }
+
+    fn version_script(version: &str) -> String {
+        format!(
+            r#"pub fn on_access(req) {{ req.set_header("x-version", "{}"); Ok(req) }}"#,
+            version
+        )
+    }
+
+    async fn version(plugin: &ScriptPlugin) -> String {
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let req = plugin.on_access(&mut ctx, req).await.unwrap();
+        req.headers()["x-version"].to_str().unwrap().to_string()
+    }
+
+    fn script_file(file: &Path, watch: bool) -> Result<ScriptPlugin, ConfigError> {
+        ScriptPlugin::new(ScriptConfig {
+            script: None,
+            file: Some(file.to_path_buf()),
+            watch,
+            buffer_body: false,
+            max_body_bytes: 1024,
+            fail_open: false,
+        })
+    }
+
+    #[tokio::test]
+    async fn relative_script_file() {
+        let dir = std::env::temp_dir().join(format!("apireception-script-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("scripts")).unwrap();
+        std::fs::write(dir.join("scripts/version.rn"), version_script("v1")).unwrap();
+
+        crate::config::set_config_dir(&dir);
+        let plugin = script_file(Path::new("scripts/version.rn"), false).unwrap();
+        assert_eq!(version(&plugin).await, "v1");
+
+        // fails config load
+        assert!(script_file(Path::new("scripts/missing.rn"), false).is_err());
+
+        let both: Result<ScriptConfig, _> = serde_json::from_value(serde_json::json!({
+            "script": version_script("v1"),
+            "file": "scripts/version.rn",
+        }));
+        assert!(ScriptPlugin::new(both.unwrap()).is_err());
+        let inline_watched = serde_json::json!({ "script": version_script("v1"), "watch": true });
+        assert!(ScriptPlugin::new(serde_json::from_value(inline_watched).unwrap()).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Wait for watcher to catch up with the file.
+    async fn settle(plugin: &ScriptPlugin, expected: &str) -> String {
+        for _ in 0..100 {
+            let current = version(plugin).await;
+            if current == expected {
+                return current;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        version(plugin).await
+    }
+
+    #[tokio::test]
+    async fn watch_script_file() {
+        let path =
+            std::env::temp_dir().join(format!("apireception-watch-{}.rn", std::process::id()));
+        std::fs::write(&path, version_script("v1")).unwrap();
+
+        let mut plugin = script_file(&path, true).unwrap();
+        plugin.watched.as_mut().unwrap().interval = Duration::from_millis(10);
+        plugin.on_start();
+        assert_eq!(version(&plugin).await, "v1");
+
+        std::fs::write(&path, version_script("v2")).unwrap();
+        assert_eq!(settle(&plugin, "v2").await, "v2");
+
+        // broken one logged, v2 kept
+        std::fs::write(&path, "pub fn on_access(").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(version(&plugin).await, "v2");
+
+        // no longer watched once stopped
+        plugin.on_stop();
+        std::fs::write(&path, version_script("v3")).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(version(&plugin).await, "v2");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_accessors() {
+        let plugin = new_plugin(
+            r#"
+            pub fn on_access(req) {
+                let query = req.query();
+                req.set_header("x-method", req.method());
+                req.set_header("x-host", req.host().unwrap());
+                req.set_header("x-tag", query["tag"]);
+                match req.set_path("relative") {
+                    Ok(_) => {}
+                    Err(err) => req.set_header("x-path-error", err),
+                }
+                req.set_header("x-uri", req.uri());
+                Ok(req)
+            }
+            "#,
+        );
+
+        let req = hyper::Request::builder()
+            .method("PUT")
+            .uri("/items?tag=a&tag=b")
+            .header("host", "example.com:8080")
+            .body(Body::empty())
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let req = plugin.on_access(&mut ctx, req).await.unwrap();
+        let headers = req.headers();
+        assert_eq!(headers["x-method"], "PUT");
+        assert_eq!(headers["x-host"], "example.com");
+        assert_eq!(headers["x-tag"], "b");
+        assert_eq!(
+            headers["x-path-error"],
+            "path<relative> should start with /"
+        );
+        assert_eq!(headers["x-uri"], "/items?tag=a&tag=b");
+        assert_eq!(req.uri(), "/items?tag=a&tag=b");
     }
 }
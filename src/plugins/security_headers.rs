@@ -0,0 +1,213 @@
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY,
+        STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    },
+    http::uri::Scheme,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::HyperResponse;
+
+use super::Plugin;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SecurityHeadersConfig {
+    /// only added to https requests
+    #[serde(default)]
+    pub strict_transport_security: HeaderSetting,
+    #[serde(default)]
+    pub x_content_type_options: HeaderSetting,
+    #[serde(default)]
+    pub x_frame_options: HeaderSetting,
+    #[serde(default)]
+    pub referrer_policy: HeaderSetting,
+    /// disabled by default
+    #[serde(default)]
+    pub content_security_policy: HeaderSetting,
+    /// overwrite headers already set by upstream
+    #[serde(default, rename = "override")]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct HeaderSetting {
+    /// absent means the default of header
+    #[serde(default)]
+    pub enable: Option<bool>,
+    /// absent means the default value of header
+    #[serde(default)]
+    pub value: Option<String>,
+}
+
+pub(crate) struct SecurityHeadersPlugin {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    overwrite: bool,
+}
+
+impl SecurityHeadersPlugin {
+    pub fn new(cfg: SecurityHeadersConfig) -> Result<Self, ConfigError> {
+        let settings = [
+            (
+                STRICT_TRANSPORT_SECURITY,
+                &cfg.strict_transport_security,
+                true,
+                "max-age=31536000; includeSubDomains",
+            ),
+            (
+                X_CONTENT_TYPE_OPTIONS,
+                &cfg.x_content_type_options,
+                true,
+                "nosniff",
+            ),
+            (X_FRAME_OPTIONS, &cfg.x_frame_options, true, "DENY"),
+            (
+                REFERRER_POLICY,
+                &cfg.referrer_policy,
+                true,
+                "strict-origin-when-cross-origin",
+            ),
+            (
+                CONTENT_SECURITY_POLICY,
+                &cfg.content_security_policy,
+                false,
+                "default-src 'self'",
+            ),
+        ];
+
+        let mut headers = Vec::new();
+
+        for (name, setting, enable, value) in settings {
+            if !setting.enable.unwrap_or(enable) {
+                continue;
+            }
+
+            let value = setting.value.as_deref().unwrap_or(value);
+            let value = HeaderValue::from_str(value).map_err(|_| {
+                ConfigError::Message(format!("invalid value<{}> of header<{}>", value, name))
+            })?;
+
+            headers.push((name, value));
+        }
+
+        Ok(SecurityHeadersPlugin {
+            headers,
+            overwrite: cfg.overwrite,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for SecurityHeadersPlugin {
+    fn name(&self) -> &str {
+        "security_headers"
+    }
+
+    fn priority(&self) -> u32 {
+        400
+    }
+
+    async fn after_forward(
+        &self,
+        ctx: &mut GatewayContext,
+        mut resp: HyperResponse,
+    ) -> HyperResponse {
+        let headers = resp.headers_mut();
+
+        for (name, value) in &self.headers {
+            if name == STRICT_TRANSPORT_SECURITY && *ctx.orig_scheme() != Scheme::HTTPS {
+                continue;
+            }
+
+            if self.overwrite || !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+
+        resp
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Body;
+
+    use super::*;
+
+    fn after_forward(
+        plugin: &SecurityHeadersPlugin,
+        scheme: Scheme,
+        resp: HyperResponse,
+    ) -> HyperResponse {
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(None, scheme, &req);
+
+        futures::executor::block_on(plugin.after_forward(&mut ctx, resp))
+    }
+
+    #[test]
+    fn default_headers() {
+        let plugin = SecurityHeadersPlugin::new(SecurityHeadersConfig::default()).unwrap();
+
+        let resp = after_forward(&plugin, Scheme::HTTPS, HyperResponse::default());
+        let headers = resp.headers();
+
+        assert_eq!(
+            headers[STRICT_TRANSPORT_SECURITY],
+            "max-age=31536000; includeSubDomains"
+        );
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[X_FRAME_OPTIONS], "DENY");
+        assert_eq!(headers[REFERRER_POLICY], "strict-origin-when-cross-origin");
+        assert!(!headers.contains_key(CONTENT_SECURITY_POLICY));
+
+        // no hsts over plain http
+        let resp = after_forward(&plugin, Scheme::HTTP, HyperResponse::default());
+
+        assert!(!resp.headers().contains_key(STRICT_TRANSPORT_SECURITY));
+        assert_eq!(resp.headers()[X_FRAME_OPTIONS], "DENY");
+    }
+
+    #[test]
+    fn upstream_headers_kept() {
+        let upstream = || {
+            hyper::Response::builder()
+                .header(X_FRAME_OPTIONS, "SAMEORIGIN")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let cfg = SecurityHeadersConfig {
+            content_security_policy: HeaderSetting {
+                enable: Some(true),
+                value: Some("default-src 'none'".to_string()),
+            },
+            x_content_type_options: HeaderSetting {
+                enable: Some(false),
+                value: None,
+            },
+            ..Default::default()
+        };
+
+        let plugin = SecurityHeadersPlugin::new(cfg.clone()).unwrap();
+        let resp = after_forward(&plugin, Scheme::HTTP, upstream());
+
+        assert_eq!(resp.headers()[X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(
+            resp.headers()[CONTENT_SECURITY_POLICY],
+            "default-src 'none'"
+        );
+        assert!(!resp.headers().contains_key(X_CONTENT_TYPE_OPTIONS));
+
+        let plugin = SecurityHeadersPlugin::new(SecurityHeadersConfig {
+            overwrite: true,
+            ..cfg
+        })
+        .unwrap();
+        let resp = after_forward(&plugin, Scheme::HTTP, upstream());
+
+        assert_eq!(resp.headers()[X_FRAME_OPTIONS], "DENY");
+    }
+}
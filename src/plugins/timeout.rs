@@ -0,0 +1,124 @@
+use std::time::{Duration, Instant};
+
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{json_error, HyperRequest, HyperResponse};
+
+use super::Plugin;
+
+/// Bound end-to-end time of request, including plugins, answer 504 on expiry.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TimeoutConfig {
+    /// timeout in milliseconds since request arrived
+    pub timeout_ms: u64,
+}
+
+pub(crate) struct TimeoutPlugin {
+    timeout: Duration,
+}
+
+impl TimeoutPlugin {
+    pub fn new(cfg: TimeoutConfig) -> Result<Self, ConfigError> {
+        if cfg.timeout_ms == 0 {
+            return Err(ConfigError::Message(
+                "timeout should be positive".to_string(),
+            ));
+        }
+
+        Ok(TimeoutPlugin {
+            timeout: Duration::from_millis(cfg.timeout_ms),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for TimeoutPlugin {
+    fn name(&self) -> &str {
+        "timeout"
+    }
+
+    fn priority(&self) -> u32 {
+        3100
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let elapsed = ctx.start_time().elapsed().unwrap_or_default();
+        // spent in earlier plugins already
+        if elapsed >= self.timeout {
+            return Err(json_error(StatusCode::GATEWAY_TIMEOUT, "request timeout"));
+        }
+
+        let deadline = Instant::now() + self.timeout.saturating_sub(elapsed);
+
+        // the earliest wins, like an internal redirect to a route with shorter timeout
+        ctx.deadline = Some(match ctx.deadline {
+            Some(prev) => prev.min(deadline),
+            None => deadline,
+        });
+
+        Ok(req)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    fn new_ctx() -> (GatewayContext, HyperRequest) {
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        (ctx, req)
+    }
+
+    fn new_plugin(timeout_ms: u64) -> TimeoutPlugin {
+        TimeoutPlugin::new(TimeoutConfig { timeout_ms }).unwrap()
+    }
+
+    #[test]
+    fn reject_zero_timeout() {
+        assert!(TimeoutPlugin::new(TimeoutConfig { timeout_ms: 0 }).is_err());
+    }
+
+    #[tokio::test]
+    async fn set_deadline() {
+        let (mut ctx, req) = new_ctx();
+        let before = Instant::now();
+        assert!(new_plugin(1000).on_access(&mut ctx, req).await.is_ok());
+
+        let deadline = ctx.deadline.unwrap();
+        assert!(deadline > before + Duration::from_millis(900));
+        assert!(deadline <= Instant::now() + Duration::from_millis(1000));
+
+        // the earlier deadline kept
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        assert!(new_plugin(5000).on_access(&mut ctx, req).await.is_ok());
+        assert_eq!(ctx.deadline, Some(deadline));
+
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        assert!(new_plugin(100).on_access(&mut ctx, req).await.is_ok());
+        assert!(ctx.deadline.unwrap() < deadline);
+    }
+
+    #[tokio::test]
+    async fn expired() {
+        let (mut ctx, req) = new_ctx();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let resp = new_plugin(10).on_access(&mut ctx, req).await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(ctx.deadline, None);
+
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["message"], "request timeout");
+    }
+}
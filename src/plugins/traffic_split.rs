@@ -1,27 +1,178 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use hyper::header::{HeaderMap, HeaderValue, COOKIE, SET_COOKIE};
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
-use crate::{error::ConfigError, http::HyperRequest, matcher::RouteMatcher};
+use crate::{
+    context::GatewayContext,
+    error::ConfigError,
+    http::{HyperRequest, HyperResponse},
+    matcher::RouteMatcher,
+};
 
 use super::Plugin;
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct TrafficSplitConfig {
     pub rules: Vec<TrafficSplitRule>,
+    /// keep clients on the upstream first picked for them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sticky: Option<StickyConfig>,
+}
+
+/// Assignment kept in a signed cookie, clients can not pick upstreams themselves.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StickyConfig {
+    #[serde(default = "default_cookie_name")]
+    pub cookie_name: String,
+    /// seconds an assignment lasts
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    /// key of cookie HMAC
+    pub secret: String,
+}
+
+fn default_cookie_name() -> String {
+    "traffic_split".to_string()
+}
+
+fn default_ttl() -> u64 {
+    24 * 3600
 }
 
+/// Without weights, the first matched rule wins. With weights, one of the matched rules
+/// is picked with probability proportional to its weight.
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct TrafficSplitRule {
+    #[serde(default)]
     pub matcher: String,
     pub upstream_id: String,
+    /// set on all rules or none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
 }
 
+/// Upstream picked by `traffic_split`, shared by `GatewayContext::set`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrafficSplitVariant(pub String);
+
+/// Set when the upstream picked differs from the one in cookie, a new cookie is sent.
+struct NewAssignment;
+
 pub(crate) struct TrafficSplitPlugin {
     rules: Vec<TrafficSplitItem>,
+    weighted: bool,
+    sticky: Option<Sticky>,
+}
+
+struct Sticky {
+    cookie_name: String,
+    ttl: u64,
+    secret: Vec<u8>,
+}
+
+impl Sticky {
+    fn new(cfg: StickyConfig) -> Result<Self, ConfigError> {
+        if cfg.secret.is_empty() {
+            return Err(ConfigError::Message(
+                "traffic_split sticky secret required".to_string(),
+            ));
+        }
+
+        let name_valid = !cfg.cookie_name.is_empty()
+            && cfg
+                .cookie_name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !name_valid {
+            return Err(ConfigError::Message(format!(
+                "invalid cookie name<{}>",
+                cfg.cookie_name
+            )));
+        }
+
+        Ok(Sticky {
+            cookie_name: cfg.cookie_name,
+            ttl: cfg.ttl,
+            secret: cfg.secret.into_bytes(),
+        })
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac takes key of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    /// `<base64 upstream_id>.<expires>.<base64 hmac>`
+    fn encode(&self, upstream_id: &str, expires: u64) -> String {
+        let engine = &base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let payload = format!("{}.{}", engine.encode(upstream_id), expires);
+        let signature = engine.encode(self.mac(&payload).finalize().into_bytes());
+
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Upstream id in a valid and unexpired cookie value.
+    fn decode(&self, value: &str, now: u64) -> Option<String> {
+        let engine = &base64::engine::general_purpose::URL_SAFE_NO_PAD;
+        let (payload, signature) = value.rsplit_once('.')?;
+        let signature = engine.decode(signature).ok()?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+
+        let (upstream_id, expires) = payload.split_once('.')?;
+        if expires.parse::<u64>().ok()? <= now {
+            return None;
+        }
+
+        String::from_utf8(engine.decode(upstream_id).ok()?).ok()
+    }
+
+    /// Upstream assigned by cookie of request, if it still exists.
+    fn assigned(&self, ctx: &GatewayContext, headers: &HeaderMap) -> Option<String> {
+        let now = unix_now();
+
+        let upstream_id = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .filter(|(name, _)| *name == self.cookie_name)
+            .find_map(|(_, value)| self.decode(value, now))?;
+
+        ctx.upstreams.as_ref()?.get(&upstream_id)?;
+
+        Some(upstream_id)
+    }
+
+    fn set_cookie(&self, upstream_id: &str) -> Option<HeaderValue> {
+        let value = self.encode(upstream_id, unix_now() + self.ttl);
+
+        HeaderValue::from_str(&format!(
+            "{}={}; Max-Age={}; Path=/; HttpOnly",
+            self.cookie_name, value, self.ttl
+        ))
+        .ok()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
 
 pub(crate) struct TrafficSplitItem {
     matcher: RouteMatcher,
     upstream_id: String,
+    weight: u32,
 }
 
 impl TrafficSplitItem {
@@ -31,31 +182,97 @@ impl TrafficSplitItem {
         Ok(TrafficSplitItem {
             matcher,
             upstream_id: cfg.upstream_id.to_string(),
+            weight: cfg.weight.unwrap_or_default(),
         })
     }
 }
 
 impl TrafficSplitPlugin {
     pub fn new(cfg: TrafficSplitConfig) -> Result<Self, ConfigError> {
+        let weighted = cfg.rules.iter().any(|r| r.weight.is_some());
+
+        if weighted {
+            if cfg.rules.iter().any(|r| r.weight.is_none()) {
+                return Err(ConfigError::Message(
+                    "traffic_split weight should be set on all rules or none".to_string(),
+                ));
+            }
+
+            let total = cfg
+                .rules
+                .iter()
+                .fold(0u64, |sum, r| sum + u64::from(r.weight.unwrap_or_default()));
+            if total == 0 {
+                return Err(ConfigError::Message(
+                    "traffic_split weights sum to zero".to_string(),
+                ));
+            }
+        }
+
         let mut rules = Vec::new();
 
         for rule in &cfg.rules {
             rules.push(TrafficSplitItem::new(rule)?);
         }
 
-        Ok(TrafficSplitPlugin { rules })
+        let sticky = cfg.sticky.map(Sticky::new).transpose()?;
+
+        Ok(TrafficSplitPlugin {
+            rules,
+            weighted,
+            sticky,
+        })
     }
 
-    fn select_upstream(&self, req: &HyperRequest) -> Option<String> {
+    fn select_upstream(&self, ctx: &GatewayContext, req: &HyperRequest) -> Option<String> {
+        if self.weighted {
+            return self.select_weighted(ctx, req);
+        }
+
         for rule in &self.rules {
-            if rule.matcher.matchs(req) {
+            if rule.matcher.matchs(ctx, req) {
                 return Some(rule.upstream_id.clone());
             }
         }
         None
     }
+
+    fn select_weighted(&self, ctx: &GatewayContext, req: &HyperRequest) -> Option<String> {
+        let candidates = self
+            .rules
+            .iter()
+            .filter(|r| r.weight > 0 && r.matcher.matchs(ctx, req))
+            .collect::<Vec<_>>();
+
+        // picked already, like before an internal redirect, keep it
+        if let Some(TrafficSplitVariant(picked)) = ctx.get::<TrafficSplitVariant>() {
+            if candidates.iter().any(|r| &r.upstream_id == picked) {
+                return Some(picked.clone());
+            }
+        }
+
+        let total = candidates
+            .iter()
+            .fold(0u64, |sum, r| sum + u64::from(r.weight));
+        if total == 0 {
+            return None;
+        }
+
+        let random = thread_rng().gen_range(0..total);
+
+        let mut curr = 0;
+        for rule in candidates {
+            curr += u64::from(rule.weight);
+            if random < curr {
+                return Some(rule.upstream_id.clone());
+            }
+        }
+
+        unreachable!()
+    }
 }
 
+#[async_trait::async_trait]
 impl Plugin for TrafficSplitPlugin {
     fn name(&self) -> &str {
         "trafic_split"
@@ -65,13 +282,274 @@ impl Plugin for TrafficSplitPlugin {
         1001
     }
 
-    fn on_access(
+    async fn on_access(
         &self,
         ctx: &mut crate::context::GatewayContext,
         req: crate::http::HyperRequest,
     ) -> Result<crate::http::HyperRequest, crate::http::HyperResponse> {
-        ctx.upstream_id = self.select_upstream(&req);
+        // weighted rules keep the upstream in cookie, as if picked already
+        let presented = self
+            .sticky
+            .as_ref()
+            .and_then(|sticky| sticky.assigned(ctx, req.headers()));
+        if let Some(ref upstream_id) = presented {
+            ctx.set(TrafficSplitVariant(upstream_id.clone()));
+        }
+
+        ctx.upstream_id = self.select_upstream(ctx, &req);
+
+        match ctx.upstream_id {
+            Some(ref upstream_id) => {
+                tracing::Span::current().record("variant", upstream_id.as_str());
+                ctx.set(TrafficSplitVariant(upstream_id.clone()));
+
+                if self.sticky.is_some() && presented.as_ref() != Some(upstream_id) {
+                    ctx.set(NewAssignment);
+                }
+            }
+            None => {
+                ctx.remove::<TrafficSplitVariant>();
+            }
+        }
 
         Ok(req)
     }
+
+    async fn after_forward(
+        &self,
+        ctx: &mut GatewayContext,
+        mut resp: HyperResponse,
+    ) -> HyperResponse {
+        let sticky = match self.sticky {
+            Some(ref sticky) if ctx.remove::<NewAssignment>().is_some() => sticky,
+            _ => return resp,
+        };
+
+        let cookie = ctx
+            .get::<TrafficSplitVariant>()
+            .and_then(|TrafficSplitVariant(upstream_id)| sticky.set_cookie(upstream_id));
+        if let Some(cookie) = cookie {
+            resp.headers_mut().append(SET_COOKIE, cookie);
+        }
+
+        resp
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+    use crate::config::UpstreamConfig;
+    use crate::registry::{Registry, RegistryConfig};
+    use crate::upstream::UpstreamResolver;
+
+    fn rule(matcher: &str, upstream_id: &str, weight: Option<u32>) -> TrafficSplitRule {
+        TrafficSplitRule {
+            matcher: matcher.to_string(),
+            upstream_id: upstream_id.to_string(),
+            weight,
+        }
+    }
+
+    fn request(header: Option<&str>) -> HyperRequest {
+        let mut builder = hyper::Request::builder();
+        if let Some(value) = header {
+            builder = builder.header("x-beta", value);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_weighted_split() {
+        let plugin = TrafficSplitPlugin::new(TrafficSplitConfig {
+            rules: vec![
+                rule("", "stable", Some(90)),
+                rule("", "canary", Some(10)),
+                rule("Header('x-beta', '1')", "beta", Some(100)),
+            ],
+            sticky: None,
+        })
+        .unwrap();
+
+        let req = request(None);
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let mut result: HashMap<String, u32> = HashMap::new();
+        for _ in 0..100000 {
+            let got = plugin.select_upstream(&ctx, &req).unwrap();
+            *result.entry(got).or_default() += 1;
+        }
+
+        println!("split ret= {:?}", result);
+        assert!(!result.contains_key("beta"));
+        let canary = result["canary"];
+        assert!((9000..11000).contains(&canary), "{:?}", result);
+
+        // matcher gates the beta rule in
+        let req = request(Some("1"));
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let mut result: HashMap<String, u32> = HashMap::new();
+        for _ in 0..100000 {
+            let got = plugin.select_upstream(&ctx, &req).unwrap();
+            *result.entry(got).or_default() += 1;
+        }
+
+        println!("split ret= {:?}", result);
+        let beta = result["beta"];
+        assert!((48000..52000).contains(&beta), "{:?}", result);
+    }
+
+    #[test]
+    fn stable_variant() {
+        let plugin = TrafficSplitPlugin::new(TrafficSplitConfig {
+            rules: vec![rule("", "stable", Some(50)), rule("", "canary", Some(50))],
+            sticky: None,
+        })
+        .unwrap();
+
+        let req = request(None);
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let req = futures::executor::block_on(plugin.on_access(&mut ctx, req)).unwrap();
+        let picked = ctx.upstream_id.clone().unwrap();
+        assert_eq!(
+            ctx.get::<TrafficSplitVariant>(),
+            Some(&TrafficSplitVariant(picked.clone()))
+        );
+
+        for _ in 0..100 {
+            assert_eq!(plugin.select_upstream(&ctx, &req), Some(picked.clone()));
+        }
+    }
+
+    #[test]
+    fn matcher_rules() {
+        let plugin = TrafficSplitPlugin::new(TrafficSplitConfig {
+            rules: vec![
+                rule("Header('x-beta', '1')", "beta", None),
+                rule("", "stable", None),
+            ],
+            sticky: None,
+        })
+        .unwrap();
+
+        let req = request(Some("1"));
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        assert_eq!(plugin.select_upstream(&ctx, &req), Some("beta".to_string()));
+
+        let req = request(None);
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        assert_eq!(
+            plugin.select_upstream(&ctx, &req),
+            Some("stable".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_weights() {
+        let zero = TrafficSplitConfig {
+            rules: vec![rule("", "stable", Some(0)), rule("", "canary", Some(0))],
+            sticky: None,
+        };
+        assert!(TrafficSplitPlugin::new(zero).is_err());
+
+        let mixed = TrafficSplitConfig {
+            rules: vec![rule("", "stable", Some(90)), rule("", "canary", None)],
+            sticky: None,
+        };
+        assert!(TrafficSplitPlugin::new(mixed).is_err());
+    }
+
+    #[test]
+    fn sticky_assignment() {
+        let upstream = |id: &str| UpstreamConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            strategy: "random".to_string(),
+            ..Default::default()
+        };
+        let mut registry = Registry::default();
+        registry
+            .reload(RegistryConfig {
+                upstreams: vec![upstream("stable"), upstream("canary")],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let plugin = TrafficSplitPlugin::new(TrafficSplitConfig {
+            rules: vec![rule("", "stable", Some(50)), rule("", "canary", Some(50))],
+            sticky: Some(StickyConfig {
+                cookie_name: "variant".to_string(),
+                ttl: 60,
+                secret: "s3cret".to_string(),
+            }),
+        })
+        .unwrap();
+
+        // upstream picked, and `Set-Cookie` sent if any
+        let serve = |cookie: Option<&str>| {
+            let mut builder = hyper::Request::builder();
+            if let Some(cookie) = cookie {
+                builder = builder.header(COOKIE, cookie);
+            }
+            let req = builder.body(Body::empty()).unwrap();
+            let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+            ctx.upstreams = Some(UpstreamResolver::new(registry.upstreams.clone()));
+
+            futures::executor::block_on(plugin.on_access(&mut ctx, req)).unwrap();
+            let resp = futures::executor::block_on(
+                plugin.after_forward(&mut ctx, HyperResponse::default()),
+            );
+            let set_cookie = resp
+                .headers()
+                .get(SET_COOKIE)
+                .map(|v| v.to_str().unwrap().to_string());
+
+            (ctx.upstream_id.unwrap(), set_cookie)
+        };
+
+        let (picked, set_cookie) = serve(None);
+        let set_cookie = set_cookie.unwrap();
+        assert!(set_cookie.contains("; Max-Age=60;"), "{}", set_cookie);
+        let cookie = set_cookie.split(';').next().unwrap();
+
+        // later requests stay, without new cookies
+        for _ in 0..50 {
+            let presented = format!("session=1; {}", cookie);
+            assert_eq!(serve(Some(&presented)), (picked.clone(), None));
+        }
+
+        // upstream in cookie changed by client, signature no longer matches
+        let other = if picked == "stable" {
+            "canary"
+        } else {
+            "stable"
+        };
+        let (_, signed) = cookie.split_once('.').unwrap();
+        let tampered = format!(
+            "variant={}.{}",
+            base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(other),
+            signed
+        );
+        let results = (0..50).map(|_| serve(Some(&tampered))).collect::<Vec<_>>();
+        assert!(results.iter().all(|(_, set_cookie)| set_cookie.is_some()));
+        assert!(results
+            .iter()
+            .any(|(upstream_id, _)| upstream_id == &picked));
+
+        // upstream removed, or cookie expired
+        let sticky = plugin.sticky.as_ref().unwrap();
+        let removed = format!("variant={}", sticky.encode("removed", unix_now() + 60));
+        let (upstream_id, set_cookie) = serve(Some(&removed));
+        assert_ne!(upstream_id, "removed");
+        assert!(set_cookie.is_some());
+
+        let expired = format!("variant={}", sticky.encode(&picked, unix_now() - 1));
+        assert!(serve(Some(&expired)).1.is_some());
+    }
 }
@@ -0,0 +1,148 @@
+use hyper::{header::USER_AGENT, StatusCode};
+use regex::{RegexSet, RegexSetBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::{json_error, HyperRequest, HyperResponse};
+
+use super::Plugin;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UaBlockConfig {
+    /// regexes of user agent
+    pub patterns: Vec<String>,
+    #[serde(default)]
+    pub mode: UaBlockMode,
+    #[serde(default)]
+    pub case_insensitive: bool,
+    /// whether requests without user agent pass
+    #[serde(default = "default_allow_missing")]
+    pub allow_missing: bool,
+}
+
+fn default_allow_missing() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UaBlockMode {
+    /// matched user agents are blocked
+    Deny,
+    /// only matched user agents pass
+    Allow,
+}
+
+impl Default for UaBlockMode {
+    fn default() -> Self {
+        UaBlockMode::Deny
+    }
+}
+
+pub(crate) struct UaBlockPlugin {
+    patterns: RegexSet,
+    mode: UaBlockMode,
+    allow_missing: bool,
+}
+
+impl UaBlockPlugin {
+    pub fn new(cfg: UaBlockConfig) -> Result<Self, ConfigError> {
+        let patterns = RegexSetBuilder::new(&cfg.patterns)
+            .case_insensitive(cfg.case_insensitive)
+            .build()
+            .map_err(|e| ConfigError::Message(e.to_string()))?;
+
+        Ok(UaBlockPlugin {
+            patterns,
+            mode: cfg.mode,
+            allow_missing: cfg.allow_missing,
+        })
+    }
+
+    fn is_allowed(&self, user_agent: Option<&str>) -> bool {
+        match user_agent {
+            Some(ua) => match self.mode {
+                UaBlockMode::Deny => !self.patterns.is_match(ua),
+                UaBlockMode::Allow => self.patterns.is_match(ua),
+            },
+            None => self.allow_missing,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for UaBlockPlugin {
+    fn name(&self) -> &str {
+        "ua_block"
+    }
+
+    fn priority(&self) -> u32 {
+        2800
+    }
+
+    async fn on_access(
+        &self,
+        _ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .map(|v| v.to_str().unwrap_or_default());
+
+        if self.is_allowed(user_agent) {
+            Ok(req)
+        } else {
+            tracing::debug!(?user_agent, "user agent blocked");
+            Err(json_error(StatusCode::FORBIDDEN, "user agent not allowed"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_plugin(mode: UaBlockMode, case_insensitive: bool, allow_missing: bool) -> UaBlockPlugin {
+        UaBlockPlugin::new(UaBlockConfig {
+            patterns: vec!["curl/".to_string(), "^python-requests".to_string()],
+            mode,
+            case_insensitive,
+            allow_missing,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn deny_mode() {
+        let plugin = new_plugin(UaBlockMode::Deny, false, true);
+
+        assert!(!plugin.is_allowed(Some("curl/7.88.1")));
+        assert!(!plugin.is_allowed(Some("python-requests/2.31")));
+        assert!(plugin.is_allowed(Some("Mozilla/5.0")));
+        assert!(plugin.is_allowed(Some("Curl/7.88.1")));
+        assert!(plugin.is_allowed(None));
+    }
+
+    #[test]
+    fn allow_mode_case_insensitive() {
+        let plugin = new_plugin(UaBlockMode::Allow, true, false);
+
+        assert!(plugin.is_allowed(Some("CURL/7.88.1")));
+        assert!(plugin.is_allowed(Some("Python-Requests/2.31")));
+        assert!(!plugin.is_allowed(Some("Mozilla/5.0")));
+        assert!(!plugin.is_allowed(None));
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        assert!(UaBlockPlugin::new(UaBlockConfig {
+            patterns: vec!["(".to_string()],
+            mode: UaBlockMode::Deny,
+            case_insensitive: false,
+            allow_missing: true,
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use std::time::Duration;
+
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Method, StatusCode, Uri,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::budget::memory_budget;
+use crate::config::BufferConfig;
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::forwarder::HttpClient;
+use crate::http::{json_error, service_unavailable, HyperRequest, HyperResponse};
+
+use super::Plugin;
+
+/// Send request bodies to an http scanning service before forwarding.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VirusScanConfig {
+    /// scanner endpoint, the body is posted as is
+    pub endpoint: String,
+    /// scanner statuses meaning infected, 2xx means clean, others are failures
+    #[serde(default = "default_infected_status")]
+    pub infected_status: Vec<u16>,
+    /// max body size in bytes, larger uploads are rejected
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+    /// timeout in milliseconds waiting for verdict after the body is sent
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// forward requests when scanner fails
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+fn default_infected_status() -> Vec<u16> {
+    vec![403, 406]
+}
+
+fn default_max_body_size() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_timeout() -> u64 {
+    10_000
+}
+
+#[derive(Debug)]
+enum Verdict {
+    Clean,
+    Infected,
+    Failed(String),
+}
+
+#[derive(Debug)]
+enum ReadError {
+    TooLarge,
+    Body(hyper::Error),
+}
+
+pub(crate) struct VirusScanPlugin {
+    endpoint: Uri,
+    infected_status: Vec<StatusCode>,
+    max_body_size: usize,
+    timeout: Duration,
+    fail_open: bool,
+    client: HttpClient,
+}
+
+impl VirusScanPlugin {
+    pub fn new(cfg: VirusScanConfig) -> Result<Self, ConfigError> {
+        let endpoint: Uri = cfg.endpoint.parse().map_err(|_| {
+            ConfigError::Message(format!("invalid scanner endpoint<{}>", cfg.endpoint))
+        })?;
+
+        if endpoint.scheme().is_none() || endpoint.host().is_none() {
+            return Err(ConfigError::Message(format!(
+                "scanner endpoint<{}> should be absolute",
+                cfg.endpoint
+            )));
+        }
+
+        let infected_status = cfg
+            .infected_status
+            .iter()
+            .map(|s| {
+                StatusCode::from_u16(*s)
+                    .map_err(|_| ConfigError::Message(format!("invalid status<{}>", s)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(VirusScanPlugin {
+            endpoint,
+            infected_status,
+            max_body_size: cfg.max_body_size,
+            timeout: Duration::from_millis(cfg.timeout),
+            fail_open: cfg.fail_open,
+            client: HttpClient::new(&BufferConfig::default()),
+        })
+    }
+
+    /// Buffer the body for upstream, and stream it to scanner meanwhile.
+    async fn scan(&self, req: &HyperRequest, body: Body) -> (Result<Bytes, ReadError>, Verdict) {
+        let (sender, scan_body) = Body::channel();
+
+        let mut scan_req = hyper::Request::builder()
+            .method(Method::POST)
+            .uri(self.endpoint.clone())
+            .body(scan_body)
+            .unwrap();
+        for name in [CONTENT_TYPE, CONTENT_LENGTH] {
+            if let Some(value) = req.headers().get(&name) {
+                scan_req.headers_mut().insert(name, value.clone());
+            }
+        }
+
+        let scan = self.client.request(scan_req);
+        tokio::pin!(scan);
+
+        let read = tee_body(body, sender, self.max_body_size, self.timeout);
+        tokio::pin!(read);
+
+        // scanner may answer before the whole body is sent
+        let mut answered = None;
+        let buffered = loop {
+            tokio::select! {
+                buffered = &mut read => break buffered,
+                resp = &mut scan, if answered.is_none() => answered = Some(resp),
+            }
+        };
+
+        let resp = match answered {
+            Some(resp) => resp,
+            None => match tokio::time::timeout(self.timeout, scan).await {
+                Ok(resp) => resp,
+                Err(_) => return (buffered, Verdict::Failed("timeout".to_string())),
+            },
+        };
+
+        let verdict = match resp {
+            Ok(resp) if resp.status().is_success() => Verdict::Clean,
+            Ok(resp) if self.infected_status.contains(&resp.status()) => Verdict::Infected,
+            Ok(resp) => Verdict::Failed(format!("status {}", resp.status())),
+            Err(err) => Verdict::Failed(err.to_string()),
+        };
+
+        (buffered, verdict)
+    }
+}
+
+#[async_trait::async_trait]
+impl Plugin for VirusScanPlugin {
+    fn name(&self) -> &str {
+        "virus_scan"
+    }
+
+    fn priority(&self) -> u32 {
+        1600
+    }
+
+    async fn on_access(
+        &self,
+        ctx: &mut GatewayContext,
+        req: HyperRequest,
+    ) -> Result<HyperRequest, HyperResponse> {
+        if req.body().is_end_stream() {
+            return Ok(req);
+        }
+
+        let content_length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+
+        if content_length.unwrap_or_default() > self.max_body_size {
+            return Err(json_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "upload too large to scan",
+            ));
+        }
+
+        let mut permit =
+            match memory_budget().try_reserve(content_length.unwrap_or(self.max_body_size)) {
+                Some(permit) => permit,
+                None => return Err(service_unavailable(Duration::from_secs(1))),
+            };
+
+        let (parts, body) = req.into_parts();
+        let req = HyperRequest::from_parts(parts, Body::empty());
+
+        let (buffered, verdict) = self.scan(&req, body).await;
+
+        let buffered = match buffered {
+            Ok(buffered) => buffered,
+            Err(ReadError::TooLarge) => {
+                return Err(json_error(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "upload too large to scan",
+                ))
+            }
+            Err(ReadError::Body(err)) => {
+                tracing::debug!(route_id = ?ctx.route_id, %err, "read upload failed");
+                return Err(json_error(StatusCode::BAD_REQUEST, "read upload failed"));
+            }
+        };
+
+        match verdict {
+            Verdict::Clean => {}
+            Verdict::Infected => {
+                tracing::warn!(route_id = ?ctx.route_id, "upload rejected by virus scan");
+                return Err(json_error(
+                    StatusCode::FORBIDDEN,
+                    "upload rejected by virus scan",
+                ));
+            }
+            Verdict::Failed(err) => {
+                tracing::error!(route_id = ?ctx.route_id, %err, "virus scan failed");
+
+                if !self.fail_open {
+                    return Err(json_error(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "virus scan unavailable",
+                    ));
+                }
+            }
+        }
+
+        // forwarded body holds the memory as long as the request
+        permit.shrink(buffered.len());
+        ctx.keep_permit(permit);
+
+        let (parts, _) = req.into_parts();
+        Ok(HyperRequest::from_parts(parts, Body::from(buffered)))
+    }
+}
+
+/// Read the whole body, chunks are copied to `sender` as long as the scanner receives,
+/// a scanner stalled longer than `timeout` is skipped.
+async fn tee_body(
+    mut body: Body,
+    mut sender: hyper::body::Sender,
+    limit: usize,
+    timeout: Duration,
+) -> Result<Bytes, ReadError> {
+    let mut buf = Vec::new();
+    let mut sending = true;
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(ReadError::Body)?;
+
+        if buf.len() + chunk.len() > limit {
+            sender.abort();
+            return Err(ReadError::TooLarge);
+        }
+        buf.extend_from_slice(&chunk);
+
+        if sending {
+            sending = matches!(
+                tokio::time::timeout(timeout, sender.send_data(chunk)).await,
+                Ok(Ok(()))
+            );
+        }
+    }
+
+    Ok(buf.into())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use hyper::{
+        http::uri::Scheme,
+        service::{make_service_fn, service_fn},
+    };
+
+    use super::*;
+
+    /// Scanner flags bodies containing `EICAR`.
+    fn start_scanner() -> SocketAddr {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: HyperRequest| async move {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let infected = body.windows(5).any(|w| w == b"EICAR");
+
+                let status = if infected {
+                    StatusCode::NOT_ACCEPTABLE
+                } else {
+                    StatusCode::OK
+                };
+
+                Ok::<_, Infallible>(
+                    hyper::Response::builder()
+                        .status(status)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }))
+        });
+
+        let srv = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = srv.local_addr();
+        tokio::spawn(srv);
+
+        addr
+    }
+
+    fn new_plugin(endpoint: String, fail_open: bool) -> VirusScanPlugin {
+        VirusScanPlugin::new(VirusScanConfig {
+            endpoint,
+            infected_status: default_infected_status(),
+            max_body_size: 1024,
+            timeout: 1000,
+            fail_open,
+        })
+        .unwrap()
+    }
+
+    async fn upload(plugin: &VirusScanPlugin, body: Vec<u8>) -> Result<Bytes, StatusCode> {
+        let req = hyper::Request::builder()
+            .method(Method::POST)
+            .body(Body::wrap_stream(futures::stream::iter(
+                body.chunks(100)
+                    .map(|c| Ok::<_, Infallible>(c.to_vec()))
+                    .collect::<Vec<_>>(),
+            )))
+            .unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        match plugin.on_access(&mut ctx, req).await {
+            Ok(req) => Ok(hyper::body::to_bytes(req.into_body()).await.unwrap()),
+            Err(resp) => Err(resp.status()),
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_upload() {
+        let addr = start_scanner();
+        let plugin = new_plugin(format!("http://{}/scan", addr), false);
+
+        let clean = vec![b'a'; 800];
+        assert_eq!(upload(&plugin, clean.clone()).await, Ok(clean.into()));
+
+        let mut infected = vec![b'a'; 500];
+        infected.extend_from_slice(b"EICAR");
+        assert_eq!(upload(&plugin, infected).await, Err(StatusCode::FORBIDDEN));
+
+        assert_eq!(
+            upload(&plugin, vec![b'a'; 2000]).await,
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        );
+    }
+
+    #[tokio::test]
+    async fn scanner_unavailable() {
+        // nothing listens on the port once the listener dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let endpoint = format!("http://{}/scan", addr);
+
+        let plugin = new_plugin(endpoint.clone(), false);
+        assert_eq!(
+            upload(&plugin, b"hello".to_vec()).await,
+            Err(StatusCode::SERVICE_UNAVAILABLE)
+        );
+
+        let plugin = new_plugin(endpoint, true);
+        assert_eq!(
+            upload(&plugin, b"hello".to_vec()).await,
+            Ok(Bytes::from_static(b"hello"))
+        );
+    }
+}
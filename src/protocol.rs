@@ -0,0 +1,254 @@
+//! Translation between client and upstream protocols.
+//!
+//! Client and upstream connections speak http/1.1 or h2 independently, so requests are
+//! forwarded as http/1.1 messages without connection-specific headers, and hyper frames
+//! them for whichever protocol the upstream connection negotiated. Interim 1xx responses
+//! of upstream other than `101` are not relayed, the client gets `100 Continue` from
+//! gateway once its body is read.
+
+use std::time::Instant;
+
+use hyper::{
+    header::{HeaderName, HeaderValue, CONNECTION, EXPECT, TE, TRANSFER_ENCODING, UPGRADE},
+    upgrade::OnUpgrade,
+    HeaderMap, StatusCode, Version,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::context::GatewayContext;
+use crate::http::{bad_gateway, HyperRequest, HyperResponse};
+
+/// Target of tunnel session events, one per session when it closed.
+pub const TUNNEL_LOG_TARGET: &str = "apireception::tunnel";
+
+/// Per route overrides of protocol translation.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ProtocolConfig {
+    /// pass `Upgrade` of http/1.1 clients, like websocket, and tunnel after `101`,
+    /// otherwise the request is forwarded as plain http
+    #[serde(default)]
+    pub upgrade: bool,
+    /// pass `Expect: 100-continue` to upstream instead of dropping it
+    #[serde(default)]
+    pub forward_expect: bool,
+}
+
+/// Client side of an upgrade, waiting for upstream to switch protocols.
+pub struct PendingUpgrade {
+    client: OnUpgrade,
+    protocol: HeaderValue,
+}
+
+/// Strip connection-specific headers, keep `Upgrade` when allowed.
+pub fn prepare_request(cfg: &ProtocolConfig, req: &mut HyperRequest) -> Option<PendingUpgrade> {
+    // h2 has no `Upgrade`, extended CONNECT is not supported
+    let protocol = if cfg.upgrade
+        && req.version() == Version::HTTP_11
+        && connection_has(req.headers(), "upgrade")
+    {
+        req.headers().get(UPGRADE).cloned()
+    } else {
+        None
+    };
+
+    strip_connection_headers(req.headers_mut());
+
+    if !cfg.forward_expect {
+        req.headers_mut().remove(EXPECT);
+    }
+
+    // hyper sends it over h2 when negotiated, but refuses h2 requests on http/1.1
+    *req.version_mut() = Version::HTTP_11;
+
+    let protocol = protocol?;
+    req.headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    req.headers_mut().insert(UPGRADE, protocol.clone());
+
+    Some(PendingUpgrade {
+        client: hyper::upgrade::on(req),
+        protocol,
+    })
+}
+
+/// Strip connection-specific headers of upstream response, tunnel when protocol switched.
+pub fn finish_response(
+    ctx: &GatewayContext,
+    upgrade: Option<PendingUpgrade>,
+    mut resp: HyperResponse,
+) -> HyperResponse {
+    if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        strip_connection_headers(resp.headers_mut());
+        return resp;
+    }
+
+    let upgrade = match upgrade {
+        Some(upgrade) => upgrade,
+        None => {
+            tracing::warn!("upstream switched protocols without upgrade requested");
+            return bad_gateway();
+        }
+    };
+
+    let upstream = hyper::upgrade::on(&mut resp);
+
+    strip_connection_headers(resp.headers_mut());
+    resp.headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("upgrade"));
+    resp.headers_mut().insert(UPGRADE, upgrade.protocol);
+
+    let session = TunnelSession {
+        route_id: ctx.route_id.clone(),
+        upstream_id: ctx.upstream_id.clone(),
+        remote_addr: ctx.remote_addr().map(|addr| addr.to_string()),
+        protocol: String::from_utf8_lossy(upgrade.protocol.as_bytes()).to_string(),
+    };
+
+    tokio::spawn(async move {
+        let start = Instant::now();
+
+        let (mut client, mut upstream) = match tokio::try_join!(upgrade.client, upstream) {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                session.log(start, 0, 0, &format!("upgrade failed: {}", err));
+                return;
+            }
+        };
+
+        match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+            Ok((bytes_in, bytes_out)) => session.log(start, bytes_in, bytes_out, "closed"),
+            // bytes copied before the error are not reported by tokio
+            Err(err) => session.log(start, 0, 0, &err.to_string()),
+        }
+    });
+
+    resp
+}
+
+/// Long-lived session after `101`, logged once closed instead of as an http request.
+struct TunnelSession {
+    route_id: Option<String>,
+    upstream_id: Option<String>,
+    remote_addr: Option<String>,
+    protocol: String,
+}
+
+impl TunnelSession {
+    /// `bytes_in` are sent by client, `bytes_out` by upstream.
+    fn log(&self, start: Instant, bytes_in: u64, bytes_out: u64, close: &str) {
+        tracing::info!(
+            target: TUNNEL_LOG_TARGET,
+            route_id = ?self.route_id,
+            upstream_id = ?self.upstream_id,
+            remote_addr = ?self.remote_addr,
+            protocol = %self.protocol,
+            duration_ms = start.elapsed().as_millis() as u64,
+            bytes_in,
+            bytes_out,
+            close,
+            "tunnel session"
+        );
+    }
+}
+
+/// Remove hop-by-hop headers and the ones listed in `Connection`, except `TE: trailers`
+/// which h2 allows and grpc needs.
+pub fn strip_connection_headers(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    let trailers = headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("trailers"));
+
+    for name in listed {
+        headers.remove(name);
+    }
+
+    for name in [CONNECTION, TE, TRANSFER_ENCODING, UPGRADE] {
+        headers.remove(name);
+    }
+    for name in ["keep-alive", "proxy-connection"] {
+        headers.remove(name);
+    }
+
+    if trailers {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+}
+
+fn connection_has(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case(token))
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Body;
+
+    use super::*;
+
+    #[test]
+    fn translate_request() {
+        let request = || {
+            hyper::Request::builder()
+                .version(Version::HTTP_11)
+                .header("connection", "keep-alive, Upgrade, x-hop")
+                .header("upgrade", "websocket")
+                .header("keep-alive", "timeout=5")
+                .header("x-hop", "1")
+                .header("te", "gzip, trailers")
+                .header("expect", "100-continue")
+                .header("x-end", "1")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let mut req = request();
+        let upgrade = prepare_request(&ProtocolConfig::default(), &mut req);
+        assert!(upgrade.is_none());
+
+        let headers = req.headers();
+        for name in ["connection", "upgrade", "keep-alive", "x-hop", "expect"] {
+            assert!(!headers.contains_key(name), "{}", name);
+        }
+        assert_eq!(headers["te"], "trailers");
+        assert_eq!(headers["x-end"], "1");
+
+        let cfg = ProtocolConfig {
+            upgrade: true,
+            forward_expect: true,
+        };
+        let mut req = request();
+        let upgrade = prepare_request(&cfg, &mut req);
+        assert_eq!(
+            upgrade.map(|u| u.protocol),
+            Some("websocket".parse().unwrap())
+        );
+
+        let headers = req.headers();
+        assert_eq!(headers["connection"], "upgrade");
+        assert_eq!(headers["upgrade"], "websocket");
+        assert_eq!(headers["expect"], "100-continue");
+        assert!(!headers.contains_key("x-hop"));
+
+        // h2 clients can not upgrade
+        let mut req = request();
+        *req.version_mut() = Version::HTTP_2;
+        assert!(prepare_request(&cfg, &mut req).is_none());
+        assert_eq!(req.version(), Version::HTTP_11);
+        assert!(!req.headers().contains_key("upgrade"));
+    }
+}
@@ -15,7 +15,10 @@ use tokio::sync::Notify;
 use crate::{
     config::{RegistryProvider, RouteConfig, UpstreamConfig},
     error::{upstream_not_found, ConfigError},
-    router::{PathRouter, Route},
+    fixture::RouteTest,
+    migration::{migrate, LEGACY_VERSION, REGISTRY_VERSION},
+    plugins::Plugin,
+    router::{HostRouter, Route, RoutePlugin},
     upstream::{Upstream, UpstreamMap},
 };
 
@@ -31,16 +34,40 @@ impl Endpoint {
     }
 }
 
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct RegistryConfig {
+    /// schema version, older documents are migrated on load
+    #[serde(default = "legacy_version")]
+    pub version: u32,
     #[serde(default)]
     pub routes: Vec<RouteConfig>,
     #[serde(default)]
     pub upstreams: Vec<UpstreamConfig>,
+    /// requests with the route they should hit, see `crate::fixture`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tests: Vec<RouteTest>,
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        RegistryConfig {
+            version: REGISTRY_VERSION,
+            routes: Vec::new(),
+            upstreams: Vec::new(),
+            tests: Vec::new(),
+        }
+    }
+}
+
+fn legacy_version() -> u32 {
+    LEGACY_VERSION
 }
 
 impl RegistryConfig {
     pub fn load(provider: &RegistryProvider) -> Result<Self, ConfigError> {
+        #[cfg(feature = "chaos")]
+        crate::chaos::check_load()?;
+
         match provider {
             RegistryProvider::Etcd(cfg) => {
                 unimplemented!()
@@ -87,8 +114,24 @@ impl RegistryConfig {
     //     Ok(())
     // }
 
+    /// Load and migrate to current version, the upgraded form is written back.
     pub fn load_file(path: impl AsRef<Path>) -> Result<RegistryConfig, ConfigError> {
-        crate::config::load_file(path)
+        let path = path.as_ref();
+
+        let mut doc: serde_json::Value = crate::config::load_file(path)?;
+        let from = migrate(&mut doc)?;
+        let cfg: RegistryConfig = serde_json::from_value(doc)?;
+
+        if from != REGISTRY_VERSION {
+            // keep the original, in case of rollback
+            let backup = format!("{}.v{}.bak", path.display(), from);
+            std::fs::copy(path, &backup)?;
+            cfg.dump_file(path)?;
+
+            tracing::info!(?path, from, to = REGISTRY_VERSION, %backup, "registry migrated");
+        }
+
+        Ok(cfg)
     }
 
     pub fn dump_file(&self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
@@ -99,37 +142,63 @@ impl RegistryConfig {
 #[derive(Clone, Default)]
 pub struct Registry {
     pub config: RegistryConfig,
-    pub router: PathRouter,
-    pub upstreams: UpstreamMap,
+    /// shared by requests in flight, copied on write by route changes
+    pub router: Arc<HostRouter>,
+    /// shared, cloned for every request
+    pub upstreams: Arc<UpstreamMap>,
+    /// plugins of enabled routes by route id, started and stopped by `RegistryWriter`
+    pub plugins: RoutePlugins,
 }
 
+pub type RoutePlugins = HashMap<String, Vec<RoutePlugin>>;
+
 impl Registry {
     pub fn new(provider: &RegistryProvider) -> Result<Self, ConfigError> {
         let config = RegistryConfig::load(provider)?;
 
-        let router = Self::build_router(&config)?;
+        let (router, plugins) = Self::build_routes(&config)?;
         let upstreams = Self::build_upstream_map(&config)?;
 
         Ok(Registry {
             config,
-            router,
-            upstreams,
+            router: Arc::new(router),
+            upstreams: Arc::new(upstreams),
+            plugins,
         })
     }
 
     pub(crate) fn new_reader_writer() -> (RegistryReader, RegistryWriter) {
         let (write, read) = left_right::new::<Registry, RegistryOp>();
 
-        (RegistryReader(read), RegistryWriter(write))
+        let writer = RegistryWriter {
+            handle: write,
+            running: Vec::new(),
+        };
+
+        (RegistryReader(read), writer)
+    }
+
+    /// Check config can be built, without touching any registry.
+    pub fn validate(cfg: &RegistryConfig) -> Result<(), ConfigError> {
+        Self::build_router(cfg)?;
+        Self::build_upstream_map(cfg)?;
+
+        Ok(())
     }
 
     pub fn reload(&mut self, cfg: RegistryConfig) -> Result<(), ConfigError> {
-        let router = Self::build_router(&cfg)?;
+        let (router, plugins) = Self::build_routes(&cfg)?;
         let upstreams = Self::build_upstream_map(&cfg)?;
 
+        for (name, upstream) in &upstreams {
+            let previous = self.upstreams.get(name).map(|prev| prev.read().unwrap());
+            upstream.write().unwrap().warmup(previous.as_deref());
+        }
+
         self.config = cfg;
-        self.router = router;
-        self.upstreams = upstreams;
+        self.router = Arc::new(router);
+        self.upstreams = Arc::new(upstreams);
+        self.plugins = plugins;
 
         Ok(())
     }
@@ -137,68 +206,127 @@ impl Registry {
     pub fn add_route(&mut self, cfg: &RouteConfig) -> Result<(), ConfigError> {
         let route = Route::new(cfg)?;
 
+        if self.config.routes.iter().any(|r| r.id == route.id) {
+            return Err(ConfigError::DuplicateRouteId(route.id));
+        }
+
+        if !route.enabled {
+            self.config.routes.push(cfg.clone());
+            return Ok(());
+        }
+
         // check upstream
-        self.upstreams
-            .values()
-            .find(|item| item.read().unwrap().id == route.upstream_id)
-            .ok_or(ConfigError::UpstreamNotFound(route.upstream_id.clone()))?;
-
-        for uri in &cfg.uris {
-            let endpoint = self.router.at_or_default(uri);
-            endpoint.push(route.clone());
-            endpoint.sort_unstable_by_key(|r| Reverse(r.priority))
+        for upstream_id in route.upstream_ids() {
+            self.upstreams
+                .values()
+                .find(|item| item.read().unwrap().id == upstream_id)
+                .ok_or_else(|| upstream_not_found(upstream_id))?;
+        }
+
+        for host in route_hosts(cfg) {
+            for uri in &cfg.uris {
+                let router = Arc::make_mut(&mut self.router);
+                check_conflict(router.for_host(host).at_or_default(uri), &route, uri)?;
+            }
+        }
+
+        for host in route_hosts(cfg) {
+            for uri in &cfg.uris {
+                let endpoint = Arc::make_mut(&mut self.router)
+                    .for_host(host)
+                    .at_or_default(uri);
+                endpoint.push(route.clone());
+                endpoint.sort_unstable_by_key(|r| Reverse(r.priority))
+            }
         }
 
+        self.plugins.insert(route.id.clone(), route.plugins);
+        self.config.routes.push(cfg.clone());
+
         Ok(())
     }
 
     pub fn delete_route(&mut self, cfg: &RouteConfig) -> Result<(), ConfigError> {
         let route = Route::new(cfg)?;
 
-        for uri in &cfg.uris {
-            let endpoint = self.router.at_or_default(uri);
+        for host in route_hosts(cfg) {
+            for uri in &cfg.uris {
+                let endpoint = Arc::make_mut(&mut self.router)
+                    .for_host(host)
+                    .at_or_default(uri);
 
-            endpoint.retain(|item| item.id != route.id);
-            endpoint.sort_unstable_by_key(|r| Reverse(r.priority))
+                endpoint.retain(|item| item.id != route.id);
+                endpoint.sort_unstable_by_key(|r| Reverse(r.priority))
+            }
         }
 
+        self.plugins.remove(&route.id);
+        self.config.routes.retain(|r| r.id != route.id);
+
         Ok(())
     }
 
     pub fn add_upstream(&mut self, cfg: &UpstreamConfig) -> Result<(), ConfigError> {
-        let upstream = Upstream::new(cfg)?;
+        let mut upstream = Upstream::new(cfg)?;
 
-        self.upstreams
+        {
+            let previous = self.upstreams.get(&upstream.id).map(|prev| prev.read().unwrap());
+            upstream.warmup(previous.as_deref());
+        }
+
+        Arc::make_mut(&mut self.upstreams)
             .insert(upstream.id.clone(), Arc::new(RwLock::new(upstream)));
         Ok(())
     }
 
     pub fn delete_upstream(&mut self, upstream: &UpstreamConfig) -> Result<(), ConfigError> {
-        self.upstreams.remove(&upstream.id);
+        Arc::make_mut(&mut self.upstreams).remove(&upstream.id);
         Ok(())
     }
 
-    fn build_router(cfg: &RegistryConfig) -> Result<PathRouter, ConfigError> {
-        let mut router = PathRouter::new();
+    pub(crate) fn build_router(cfg: &RegistryConfig) -> Result<HostRouter, ConfigError> {
+        Self::build_routes(cfg).map(|(router, _)| router)
+    }
+
+    fn build_routes(cfg: &RegistryConfig) -> Result<(HostRouter, RoutePlugins), ConfigError> {
+        let mut router = HostRouter::new();
+        let mut plugins = RoutePlugins::new();
 
         let upstream_set: HashSet<&str> =
             HashSet::from_iter(cfg.upstreams.iter().map(|up| up.id.as_str()));
 
+        let mut route_ids = HashSet::new();
+
         for r in &cfg.routes {
-            upstream_set
-                .get(r.upstream_id.as_str())
-                .ok_or_else(|| upstream_not_found(&r.upstream_id))?;
+            if !route_ids.insert(r.id.as_str()) {
+                return Err(ConfigError::DuplicateRouteId(r.id.clone()));
+            }
 
             let route = Route::new(r)?;
 
-            for uri in &r.uris {
-                let endpoint = router.at_or_default(uri);
-                endpoint.push(route.clone());
-                endpoint.sort_unstable_by_key(|r| Reverse(r.priority))
+            for upstream_id in route.upstream_ids() {
+                upstream_set
+                    .get(upstream_id)
+                    .ok_or_else(|| upstream_not_found(upstream_id))?;
+            }
+
+            if !route.enabled {
+                continue;
             }
+
+            for host in route_hosts(r) {
+                for uri in &r.uris {
+                    let endpoint = router.for_host(host).at_or_default(uri);
+                    check_conflict(endpoint, &route, uri)?;
+                    endpoint.push(route.clone());
+                    endpoint.sort_unstable_by_key(|r| Reverse(r.priority))
+                }
+            }
+
+            plugins.insert(route.id.clone(), route.plugins);
         }
 
-        Ok(router)
+        Ok((router, plugins))
     }
 
     fn build_upstream_map(cfg: &RegistryConfig) -> Result<UpstreamMap, ConfigError> {
@@ -250,6 +378,40 @@ impl Registry {
     }
 }
 
+/// Hosts of route, `None` stands for the default router.
+fn route_hosts(cfg: &RouteConfig) -> Vec<Option<&str>> {
+    if cfg.hosts.is_empty() {
+        vec![None]
+    } else {
+        cfg.hosts.iter().map(|h| Some(h.as_str())).collect()
+    }
+}
+
+/// Same uri and priority with identical matcher is an error, overlapping matchers only warned.
+fn check_conflict(routes: &[Route], route: &Route, uri: &str) -> Result<(), ConfigError> {
+    for exist in routes.iter().filter(|r| r.priority == route.priority) {
+        if exist.matcher == route.matcher {
+            return Err(ConfigError::RouteConflict(
+                exist.id.clone(),
+                route.id.clone(),
+                uri.to_string(),
+            ));
+        }
+
+        if !exist.matcher.is_disjoint(&route.matcher) {
+            tracing::warn!(
+                route = %route.id,
+                other = %exist.id,
+                %uri,
+                priority = route.priority,
+                "routes may overlap on same uri and priority"
+            );
+        }
+    }
+
+    Ok(())
+}
+
 #[derive(Debug)]
 pub enum RegistryOp {
     Reload(RegistryConfig),
@@ -280,22 +442,77 @@ impl Absorb<RegistryOp> for Registry {
         }
     }
 
+    /// Take over what the first copy built, so both copies share plugin instances.
+    fn absorb_second(&mut self, _operation: RegistryOp, other: &Self) {
+        *self = other.clone();
+    }
+
     fn sync_with(&mut self, first: &Self) {
         *self = first.clone();
     }
 }
 
+type SharedPlugin = Arc<Box<dyn Plugin + Send + Sync>>;
 
-pub struct RegistryWriter(WriteHandle<Registry, RegistryOp>);
+pub struct RegistryWriter {
+    handle: WriteHandle<Registry, RegistryOp>,
+    /// plugin instances started, of the published registry
+    running: Vec<SharedPlugin>,
+}
 
 impl RegistryWriter {
     pub fn load_config(&mut self, conf: RegistryConfig) {
-        self.0.append(RegistryOp::Reload(conf));
+        self.handle.append(RegistryOp::Reload(conf));
     }
 
 
     pub fn publish(&mut self) {
-        self.0.publish();
+        #[cfg(feature = "chaos")]
+        {
+            crate::chaos::before_publish();
+            if let Err(err) = crate::chaos::check_publish() {
+                tracing::warn!(%err, "publish registry failed");
+                return;
+            }
+        }
+
+        self.handle.publish();
+        self.cycle_plugins();
+    }
+
+    /// Start plugins new to the published registry, stop those left behind.
+    fn cycle_plugins(&mut self) {
+        let published: Vec<SharedPlugin> = match self.handle.enter() {
+            Some(registry) => registry
+                .plugins
+                .values()
+                .flatten()
+                .map(|p| p.plugin.clone())
+                .collect(),
+            None => return,
+        };
+
+        for plugin in &self.running {
+            if !published.iter().any(|p| Arc::ptr_eq(p, plugin)) {
+                plugin.on_stop();
+            }
+        }
+
+        for plugin in &published {
+            if !self.running.iter().any(|p| Arc::ptr_eq(p, plugin)) {
+                plugin.on_start();
+            }
+        }
+
+        self.running = published;
+    }
+}
+
+impl Drop for RegistryWriter {
+    fn drop(&mut self) {
+        for plugin in self.running.drain(..) {
+            plugin.on_stop();
+        }
     }
 }
 
@@ -313,3 +530,475 @@ impl RegistryReader {
 }
 
 
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::http::uri::Scheme;
+
+    use super::*;
+    use crate::config::PluginConfig;
+    use crate::context::GatewayContext;
+    use crate::plugins::register_plugin;
+    use crate::services::GatewayService;
+
+    fn registry_config(routes: Vec<RouteConfig>) -> RegistryConfig {
+        RegistryConfig {
+            routes,
+            upstreams: vec![UpstreamConfig {
+                id: "upstream-001".to_string(),
+                name: "upstream-001".to_string(),
+                strategy: "random".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn route(id: &str, uri: &str, matcher: &str) -> RouteConfig {
+        RouteConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            uris: vec![uri.to_string()],
+            upstream_id: "upstream-001".to_string(),
+            matcher: matcher.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn duplicate_route_id() {
+        let cfg = registry_config(vec![
+            route("hello", "/hello", ""),
+            route("hello", "/world", ""),
+        ]);
+
+        let err = Registry::default().reload(cfg).unwrap_err();
+        assert!(matches!(err, ConfigError::DuplicateRouteId(ref id) if id == "hello"));
+    }
+
+    #[test]
+    fn conflict_route() {
+        let cfg = registry_config(vec![
+            route("hello", "/hello", "Method('GET')"),
+            route("hello-2", "/hello", "Method('GET')"),
+        ]);
+
+        let err = Registry::default().reload(cfg).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "route<hello> conflicts with route<hello-2> on uri</hello>"
+        );
+
+        // disjoint or overlapping matchers are allowed
+        let cfg = registry_config(vec![
+            route("hello", "/hello", "Method('GET')"),
+            route("hello-2", "/hello", "Method('POST')"),
+            route("hello-3", "/hello", "Host('a.com')"),
+        ]);
+
+        assert!(Registry::default().reload(cfg).is_ok());
+    }
+
+    #[tokio::test]
+    async fn mock_route_without_upstream() {
+        let mock = |when: Option<&str>| {
+            let mut r = route("mock", "/mock", "");
+            r.upstream_id = String::new();
+            r.plugins.insert(
+                "mock".to_string(),
+                PluginConfig {
+                    enable: true,
+                    when: when.map(String::from),
+                    order: None,
+                    config: serde_json::json!({ "body": "stub" }),
+                },
+            );
+            r
+        };
+
+        let mut registry = Registry::default();
+        registry.reload(registry_config(vec![mock(None)])).unwrap();
+
+        let req = hyper::Request::builder()
+            .uri("/mock")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        let resp = GatewayService::serve(&registry.router, &registry.upstreams, ctx, req).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"stub");
+
+        let mut other = mock(None);
+        other.id = "mock-2".to_string();
+        other.uris = vec!["/mock-2".to_string()];
+        assert!(registry.add_route(&other).is_ok());
+
+        // requests not matching `when` would need an upstream
+        let err = Registry::default()
+            .reload(registry_config(vec![mock(Some("Method('GET')"))]))
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::UpstreamNotFound(_)));
+
+        let mut r = route("hello", "/hello", "");
+        r.upstream_id = String::new();
+        assert!(Registry::default().reload(registry_config(vec![r])).is_err());
+    }
+
+    #[test]
+    fn expr_matcher_route() {
+        let cfg = registry_config(vec![
+            route("v1", "/hello", "Expr('header.x-api-version < 2')"),
+            route("v2", "/hello", "Expr('header.x-api-version >= 2')"),
+        ]);
+
+        let mut registry = Registry::default();
+        registry.reload(cfg).unwrap();
+
+        let find = |version: &str| {
+            let req = hyper::Request::builder()
+                .uri("/hello")
+                .header("x-api-version", version)
+                .body(hyper::Body::empty())
+                .unwrap();
+            let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+            GatewayService::find_route(&registry.router, &ctx, &req).map(|(r, _)| r.id.clone())
+        };
+        assert_eq!(find("3"), Some("v2".to_string()));
+        assert_eq!(find("1"), Some("v1".to_string()));
+        assert_eq!(find("beta"), None);
+
+        let invalid = route("v2", "/hello", "Expr('header.x-api-version >')");
+        let cfg = registry_config(vec![invalid]);
+        assert!(matches!(
+            Registry::default().reload(cfg),
+            Err(ConfigError::MatcherParse(_))
+        ));
+    }
+
+    #[test]
+    fn add_conflict_route() {
+        let mut registry = Registry::default();
+        registry
+            .reload(registry_config(vec![route("hello", "/hello", "")]))
+            .unwrap();
+
+        assert!(registry.add_route(&route("hello", "/world", "")).is_err());
+        assert!(registry.add_route(&route("hello-2", "/hello", "")).is_err());
+        assert!(registry.add_route(&route("world", "/world", "")).is_ok());
+        assert_eq!(registry.config.routes.len(), 2);
+    }
+
+    #[test]
+    fn host_routes() {
+        let req = |host: &str| {
+            hyper::Request::builder()
+                .uri("/hello")
+                .header("host", host)
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+        let find = |registry: &Registry, host: &str| {
+            let req = req(host);
+            let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+            GatewayService::find_route(&registry.router, &ctx, &req).map(|(r, _)| r.id.clone())
+        };
+
+        let mut tenant_a = route("tenant-a", "/hello", "");
+        tenant_a.hosts = vec!["a.example.com".to_string()];
+        let mut tenant_any = route("tenant-any", "/hello", "");
+        tenant_any.hosts = vec!["*.example.com".to_string()];
+
+        let mut registry = Registry::default();
+        registry
+            .reload(registry_config(vec![
+                route("hello", "/hello", ""),
+                tenant_a.clone(),
+                tenant_any,
+            ]))
+            .unwrap();
+
+        assert_eq!(find(&registry, "a.example.com"), Some("tenant-a".to_string()));
+        assert_eq!(find(&registry, "b.example.com"), Some("tenant-any".to_string()));
+        assert_eq!(find(&registry, "other.com"), Some("hello".to_string()));
+
+        registry.delete_route(&tenant_a).unwrap();
+        assert_eq!(find(&registry, "a.example.com"), Some("tenant-any".to_string()));
+
+        registry.add_route(&tenant_a).unwrap();
+        assert_eq!(find(&registry, "a.example.com"), Some("tenant-a".to_string()));
+    }
+
+    #[test]
+    fn migrate_file() {
+        let dir = std::env::temp_dir()
+            .join(format!("apireception-migrate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("registry.json");
+
+        let legacy = serde_json::json!({
+            "routes": [],
+            "upstreams": [{
+                "id": "upstream-001",
+                "name": "upstream-001",
+                "desc": "",
+                "endpoints": [{"addr": "127.0.0.1:5000", "weight": 1}],
+                "strategy": "random",
+                "is_https": true,
+                "health_check": {
+                    "timeout": 0,
+                    "interval": 0,
+                    "path": "",
+                    "status_regex": "200",
+                    "rise": 1,
+                    "fall": 3,
+                    "default_down": false
+                }
+            }]
+        });
+        std::fs::write(&path, legacy.to_string()).unwrap();
+
+        let cfg = RegistryConfig::load_file(&path).unwrap();
+        assert_eq!(cfg.version, REGISTRY_VERSION);
+        assert_eq!(cfg.upstreams[0].endpoints[0].addr, "https://127.0.0.1:5000");
+
+        // written back, original kept
+        let saved: serde_json::Value = crate::config::load_file(&path).unwrap();
+        assert_eq!(saved["version"], REGISTRY_VERSION);
+        let backup = dir.join("registry.json.v1.bak");
+        let original = std::fs::read(backup).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&original).unwrap(),
+            legacy
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn mock_config(body: &str) -> RegistryConfig {
+        let mut r = route("mock", "/mock", "");
+        r.plugins.insert(
+            "mock".to_string(),
+            PluginConfig {
+                enable: true,
+                when: None,
+                order: None,
+                config: serde_json::json!({ "body": body }),
+            },
+        );
+        registry_config(vec![r])
+    }
+
+    /// Body served by the registry readers currently see.
+    async fn served(reader: &RegistryReader) -> String {
+        let req = hyper::Request::builder()
+            .uri("/mock")
+            .body(hyper::Body::empty())
+            .unwrap();
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let resp = {
+            let registry = reader.get();
+            let registry = &registry;
+            GatewayService::serve(&registry.router, &registry.upstreams, ctx, req).await
+        };
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    fn maintenance_config(enable: bool, order: Option<i32>) -> RegistryConfig {
+        let mut cfg = mock_config("open");
+        cfg.routes[0].plugins.insert(
+            "maintenance".to_string(),
+            PluginConfig {
+                enable,
+                when: None,
+                order,
+                config: serde_json::json!({ "body": "closed" }),
+            },
+        );
+        cfg
+    }
+
+    #[tokio::test]
+    async fn maintenance_toggle() {
+        let (reader, mut writer) = Registry::new_reader_writer();
+        writer.load_config(maintenance_config(true, None));
+        writer.publish();
+        assert_eq!(served(&reader).await, "closed");
+
+        writer.load_config(maintenance_config(false, None));
+        writer.publish();
+        assert_eq!(served(&reader).await, "open");
+
+        writer.load_config(maintenance_config(true, None));
+        writer.publish();
+        assert_eq!(served(&reader).await, "closed");
+    }
+
+    #[tokio::test]
+    async fn plugin_order_reload() {
+        let (reader, mut writer) = Registry::new_reader_writer();
+        writer.load_config(maintenance_config(true, None));
+        writer.publish();
+        assert_eq!(served(&reader).await, "closed");
+
+        // maintenance moved after mock, which answers first
+        writer.load_config(maintenance_config(true, Some(0)));
+        writer.publish();
+        assert_eq!(served(&reader).await, "open");
+
+        writer.load_config(maintenance_config(true, None));
+        writer.publish();
+        assert_eq!(served(&reader).await, "closed");
+    }
+
+    static STARTS: AtomicUsize = AtomicUsize::new(0);
+    static STOPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct LifecycleCounter;
+
+    #[async_trait::async_trait]
+    impl Plugin for LifecycleCounter {
+        fn priority(&self) -> u32 {
+            0
+        }
+
+        fn on_start(&self) {
+            STARTS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn on_stop(&self) {
+            STOPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn counter_config(body: &str) -> RegistryConfig {
+        let mut cfg = mock_config(body);
+        cfg.routes[0].plugins.insert(
+            "lifecycle_counter".to_string(),
+            PluginConfig {
+                enable: true,
+                when: None,
+                order: None,
+                config: serde_json::Value::Null,
+            },
+        );
+        cfg
+    }
+
+    #[tokio::test]
+    async fn plugin_lifecycle() {
+        register_plugin("lifecycle_counter", |_| Ok(Box::new(LifecycleCounter))).unwrap();
+        let counts = || (STARTS.load(Ordering::SeqCst), STOPS.load(Ordering::SeqCst));
+
+        let (reader, mut writer) = Registry::new_reader_writer();
+        writer.load_config(counter_config("v1"));
+        writer.publish();
+        assert_eq!(counts(), (1, 0));
+
+        // second copy catches up with the same instance
+        writer.publish();
+        assert_eq!(served(&reader).await, "v1");
+        assert_eq!(counts(), (1, 0));
+
+        writer.load_config(counter_config("v2"));
+        writer.publish();
+        assert_eq!(served(&reader).await, "v2");
+        assert_eq!(counts(), (2, 1));
+
+        writer.load_config(mock_config("v3"));
+        writer.publish();
+        writer.publish();
+        assert_eq!(counts(), (2, 2));
+
+        writer.load_config(counter_config("v4"));
+        writer.publish();
+        drop(writer);
+        assert_eq!(counts(), (3, 3));
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn chaos_failed_publish() {
+        crate::chaos::reset();
+
+        let (reader, mut writer) = Registry::new_reader_writer();
+        writer.load_config(mock_config("v1"));
+        writer.publish();
+        assert_eq!(served(&reader).await, "v1");
+
+        // config failing to build is dropped
+        writer.load_config(registry_config(vec![
+            route("hello", "/hello", ""),
+            route("hello", "/world", ""),
+        ]));
+        writer.publish();
+        assert_eq!(served(&reader).await, "v1");
+
+        crate::chaos::fail_publishes(2);
+        writer.load_config(mock_config("v2"));
+        for _ in 0..2 {
+            writer.publish();
+            assert_eq!(served(&reader).await, "v1");
+        }
+
+        writer.publish();
+        assert_eq!(served(&reader).await, "v2");
+
+        // both copies caught up
+        writer.publish();
+        assert_eq!(served(&reader).await, "v2");
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn chaos_delayed_publish() {
+        crate::chaos::reset();
+
+        let (reader, mut writer) = Registry::new_reader_writer();
+        writer.load_config(mock_config("v1"));
+        writer.publish();
+
+        let publisher = std::thread::spawn(move || {
+            crate::chaos::delay_publishes(std::time::Duration::from_millis(300));
+            writer.load_config(mock_config("v2"));
+            writer.publish();
+        });
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(served(&reader).await, "v1");
+
+        publisher.join().unwrap();
+        assert_eq!(served(&reader).await, "v2");
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn chaos_provider_outage() {
+        crate::chaos::reset();
+
+        let path = std::env::temp_dir()
+            .join(format!("apireception-chaos-{}.json", std::process::id()));
+        mock_config("v2").dump_file(&path).unwrap();
+        let provider = RegistryProvider::File(crate::config::FileProvider { path: path.clone() });
+
+        let (reader, mut writer) = Registry::new_reader_writer();
+        writer.load_config(mock_config("v1"));
+        writer.publish();
+
+        crate::chaos::fail_loads(2);
+        for _ in 0..2 {
+            assert!(RegistryConfig::load(&provider).is_err());
+            assert_eq!(served(&reader).await, "v1");
+        }
+
+        writer.load_config(RegistryConfig::load(&provider).unwrap());
+        writer.publish();
+        assert_eq!(served(&reader).await, "v2");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,48 +1,402 @@
+use std::borrow::Cow;
 use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
+use hyper::header::{HeaderName, HeaderValue};
+
+use crate::aggregate::Aggregate;
 use crate::config::RouteConfig;
+use crate::context::GatewayContext;
 use crate::error::ConfigError;
-use crate::matcher::RouteMatcher;
-use crate::plugins::{init_plugin, Plugin};
+use crate::http::HyperRequest;
+use crate::limiter::PriorityClass;
+use crate::matcher::{split_host_port, RouteMatcher};
+use crate::plugins::{init_plugins, plugin_load_mode, Plugin, TERMINATING_PLUGINS};
+use crate::protocol::ProtocolConfig;
+use crate::selector::EndpointSelector;
+use crate::slo::SloConfig;
 
 pub type PathRouter = pathrouter::Router<Vec<Route>>;
 
+/// Path routers selected by host, exact host beats wildcard beats default.
+#[derive(Clone, Default)]
+pub struct HostRouter {
+    exact: HashMap<String, PathRouter>,
+    /// `*.example.com` kept as `.example.com`, longest first
+    wildcard: Vec<(String, PathRouter)>,
+    default: PathRouter,
+}
+
+impl HostRouter {
+    pub fn new() -> Self {
+        HostRouter::default()
+    }
+
+    /// Path router of host pattern, `None` for the default router.
+    pub fn for_host(&mut self, host: Option<&str>) -> &mut PathRouter {
+        let host = match host {
+            Some(host) => normalize_host(host),
+            None => return &mut self.default,
+        };
+
+        match host.strip_prefix('*') {
+            Some(suffix) => {
+                let pos = match self.wildcard.iter().position(|(s, _)| s == suffix) {
+                    Some(pos) => pos,
+                    None => {
+                        self.wildcard.push((suffix.to_string(), PathRouter::new()));
+                        self.wildcard.sort_by_key(|(s, _)| Reverse(s.len()));
+                        self.wildcard
+                            .iter()
+                            .position(|(s, _)| s == suffix)
+                            .unwrap()
+                    }
+                };
+                &mut self.wildcard[pos].1
+            }
+            None => self
+                .exact
+                .entry(host.into_owned())
+                .or_insert_with(PathRouter::new),
+        }
+    }
+
+    /// Candidate path routers of request host, in precedence order.
+    pub fn routers(&self, host: Option<&str>) -> impl Iterator<Item = &PathRouter> {
+        let (exact, wildcard) = match host.map(normalize_host) {
+            Some(host) => (
+                self.exact.get(host.as_ref()),
+                self.wildcard
+                    .iter()
+                    .find(|(suffix, _)| {
+                        host.ends_with(suffix.as_str()) && host.len() > suffix.len()
+                    })
+                    .map(|(_, router)| router),
+            ),
+            None => (None, None),
+        };
+
+        exact
+            .into_iter()
+            .chain(wildcard)
+            .chain(std::iter::once(&self.default))
+    }
+}
+
+fn normalize_host(host: &str) -> Cow<'_, str> {
+    let host = split_host_port(host).0;
+
+    if host.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(host.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(host)
+    }
+}
+
 #[derive(Clone)]
 pub struct Route {
     pub id: String,
+    pub enabled: bool,
     pub matcher: RouteMatcher,
     pub upstream_id: String,
     pub overwrite_host: bool,
+    pub upstream_host: Option<HeaderValue>,
     pub priority: u32,
-    pub plugins: Vec<Arc<Box<dyn Plugin + Send + Sync>>>,
+    pub plugins: Vec<RoutePlugin>,
+    pub timeout: Option<Duration>,
+    pub timeout_header: Option<HeaderName>,
+    pub max_response_size: Option<u64>,
+    pub slo: Option<SloConfig>,
+    pub trace_match: bool,
+    pub aggregate: Option<Aggregate>,
+    pub priority_class: PriorityClass,
+    pub endpoint_selector: Option<EndpointSelector>,
+    pub auto_options: bool,
+    pub protocol: ProtocolConfig,
+}
+
+#[derive(Clone)]
+pub struct RoutePlugin {
+    /// key of plugin in route config
+    pub name: String,
+    /// `order` of config if set, or `priority()` of plugin
+    pub order: i64,
+    /// run plugin only when matched
+    pub when: Option<RouteMatcher>,
+    pub plugin: Arc<Box<dyn Plugin + Send + Sync>>,
+}
+
+impl RoutePlugin {
+    pub fn should_run(&self, ctx: &GatewayContext, req: &HyperRequest) -> bool {
+        self.when
+            .as_ref()
+            .map(|m| m.matchs(ctx, req))
+            .unwrap_or(true)
+    }
 }
 
 impl Route {
+    /// Upstreams used by route, including aggregate branches.
+    pub fn upstream_ids(&self) -> Vec<&str> {
+        let mut ids = Vec::new();
+
+        // empty for routes answered by terminating plugins
+        if !self.upstream_id.is_empty() {
+            ids.push(self.upstream_id.as_str());
+        }
+
+        if let Some(ref aggregate) = self.aggregate {
+            ids.extend(aggregate.upstream_ids());
+        }
+
+        ids
+    }
+
     pub fn new(cfg: &RouteConfig) -> Result<Route, ConfigError> {
-        if cfg.upstream_id.is_empty() {
+        // plugins with `when` may let requests through
+        let terminated = cfg
+            .plugins
+            .iter()
+            .any(|(name, p)| {
+                TERMINATING_PLUGINS.contains(&name.as_str()) && p.enable && p.when.is_none()
+            });
+
+        if cfg.upstream_id.is_empty() && !terminated {
             return Err(ConfigError::UpstreamNotFound("UpstreamId missing".to_string()));
         }
 
         let matcher = RouteMatcher::parse(&cfg.matcher)?;
 
-        let mut plugins = Vec::new();
+        let mut plugins = init_plugins(&cfg.id, &cfg.plugins, plugin_load_mode())?;
 
-        for (name, config) in &cfg.plugins {
-            let p = init_plugin(name, config.config.clone())?;
-            plugins.push(p);
-        }
+        let timeout_header = match cfg.timeout_header {
+            Some(ref name) => Some(
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| ConfigError::Message(format!("invalid timeout header: {}", e)))?,
+            ),
+            None => None,
+        };
 
-        // sort plugin by priority
-        plugins.sort_unstable_by_key(|p| Reverse(p.priority()));
+        let upstream_host = match cfg.upstream_host {
+            Some(ref host) => Some(
+                HeaderValue::from_str(host)
+                    .map_err(|_| ConfigError::Message(format!("invalid upstream_host<{}>", host)))?,
+            ),
+            None => None,
+        };
+
+        let aggregate = match cfg.aggregate {
+            Some(ref aggregate) => Some(Aggregate::new(aggregate, &cfg.upstream_id)?),
+            None => None,
+        };
+
+        let endpoint_selector = match cfg.endpoint_selector {
+            Some(ref script) => Some(EndpointSelector::new(script)?),
+            None => None,
+        };
+
+        // sort plugin by order, ties are deterministic
+        plugins.sort_unstable_by(|a, b| {
+            b.order
+                .cmp(&a.order)
+                .then_with(|| b.plugin.priority().cmp(&a.plugin.priority()))
+                .then_with(|| a.name.cmp(&b.name))
+        });
 
         Ok(Route {
             id: cfg.id.clone(),
+            enabled: cfg.enabled,
             matcher,
             overwrite_host: cfg.overwrite_host,
+            upstream_host,
             upstream_id: cfg.upstream_id.to_string(),
             priority: cfg.priority,
             plugins,
+            timeout: Some(Duration::from_millis(cfg.timeout)).filter(|t| !t.is_zero()),
+            timeout_header,
+            max_response_size: Some(cfg.max_response_size).filter(|s| *s > 0),
+            slo: cfg.slo.clone(),
+            trace_match: cfg.trace_match,
+            aggregate,
+            priority_class: cfg.priority_class,
+            endpoint_selector,
+            auto_options: cfg.auto_options,
+            protocol: cfg.protocol.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+    use crate::config::PluginConfig;
+    use crate::http::HyperResponse;
+    use crate::plugins::register_plugin;
+
+    fn add_route(router: &mut HostRouter, host: Option<&str>, id: &str) {
+        let route = Route::new(&RouteConfig {
+            id: id.to_string(),
+            upstream_id: "upstream-001".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        router.for_host(host).at_or_default("/api").push(route);
+    }
+
+    fn candidates<'a>(router: &'a HostRouter, host: &str) -> Vec<&'a str> {
+        router
+            .routers(Some(host))
+            .filter_map(|r| r.route("/api"))
+            .map(|(endpoint, _)| endpoint[0].id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn host_router_precedence() {
+        let mut router = HostRouter::new();
+
+        add_route(&mut router, None, "default");
+        add_route(&mut router, Some("*.example.com"), "wildcard");
+        add_route(&mut router, Some("*.b.example.com"), "wildcard-b");
+        add_route(&mut router, Some("A.example.com"), "exact");
+
+        assert_eq!(
+            candidates(&router, "a.Example.com:8080"),
+            vec!["exact", "wildcard", "default"]
+        );
+        assert_eq!(
+            candidates(&router, "c.example.com"),
+            vec!["wildcard", "default"]
+        );
+        assert_eq!(
+            candidates(&router, "x.b.example.com"),
+            vec!["wildcard-b", "default"]
+        );
+        assert_eq!(candidates(&router, "example.com"), vec!["default"]);
+        assert_eq!(router.routers(None).count(), 1);
+    }
+
+    struct StampPlugin {
+        value: HeaderValue,
+    }
+
+    #[async_trait::async_trait]
+    impl Plugin for StampPlugin {
+        fn priority(&self) -> u32 {
+            0
+        }
+
+        async fn on_access(
+            &self,
+            _ctx: &mut GatewayContext,
+            mut req: HyperRequest,
+        ) -> Result<HyperRequest, HyperResponse> {
+            req.headers_mut().insert("x-stamp", self.value.clone());
+            Ok(req)
+        }
+    }
+
+    fn new_stamp(cfg: serde_json::Value) -> Result<Box<dyn Plugin + Send + Sync>, ConfigError> {
+        let value = cfg["value"]
+            .as_str()
+            .and_then(|v| HeaderValue::from_str(v).ok())
+            .ok_or_else(|| ConfigError::Message("invalid stamp value".to_string()))?;
+
+        Ok(Box::new(StampPlugin { value }))
+    }
+
+    #[test]
+    fn custom_plugin() {
+        register_plugin("stamp", new_stamp).unwrap();
+        assert!(register_plugin("stamp", new_stamp).is_err());
+        assert!(register_plugin("headers", new_stamp).is_err());
+
+        let plugin = |value: serde_json::Value| PluginConfig {
+            enable: true,
+            when: None,
+            order: None,
+            config: serde_json::json!({ "value": value }),
+        };
+        let mut cfg = RouteConfig {
+            id: "stamped".to_string(),
+            upstream_id: "upstream-001".to_string(),
+            ..Default::default()
+        };
+
+        cfg.plugins.insert("stamp".to_string(), plugin(1.into()));
+        assert!(Route::new(&cfg).is_err());
+
+        cfg.plugins
+            .insert("stamp".to_string(), plugin("gateway".into()));
+        let route = Route::new(&cfg).unwrap();
+        assert_eq!(route.plugins.len(), 1);
+
+        let req = hyper::Request::builder().body(Body::empty()).unwrap();
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        let req = futures::executor::block_on(route.plugins[0].plugin.on_access(&mut ctx, req));
+        assert_eq!(req.unwrap().headers()["x-stamp"], "gateway");
+
+        cfg.plugins
+            .insert("no_such_plugin".to_string(), plugin("gateway".into()));
+        assert!(Route::new(&cfg).is_err());
+    }
+
+    fn plugin_names(plugins: serde_json::Value) -> Vec<String> {
+        let cfg: RouteConfig = serde_json::from_value(serde_json::json!({
+            "name": "ordered",
+            "desc": "",
+            "uris": ["/ordered"],
+            "upstream_id": "upstream-001",
+            "plugins": plugins,
+        }))
+        .unwrap();
+
+        Route::new(&cfg)
+            .unwrap()
+            .plugins
+            .iter()
+            .map(|p| p.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn plugin_order_override() {
+        let names = plugin_names(serde_json::json!({
+            "headers": { "enable": true },
+            "security_headers": { "enable": true },
+            "normalize_path": { "enable": true },
+            "request_id": { "enable": true },
+        }));
+        assert_eq!(
+            names,
+            vec![
+                "request_id",
+                "normalize_path",
+                "headers",
+                "security_headers"
+            ]
+        );
+
+        // ties on order break on priority, then name
+        let names = plugin_names(serde_json::json!({
+            "headers": { "enable": true, "order": 5000 },
+            "security_headers": { "enable": true, "order": 3200 },
+            "normalize_path": { "enable": true },
+            "request_id": { "enable": true, "order": -1 },
+        }));
+        assert_eq!(
+            names,
+            vec![
+                "headers",
+                "normalize_path",
+                "security_headers",
+                "request_id"
+            ]
+        );
+    }
+}
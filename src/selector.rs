@@ -0,0 +1,218 @@
+//! Route level script choosing endpoints before load balance, for custom affinity rules.
+//!
+//! The script defines `select(req, endpoints)`, `endpoints` are `host:port` of available
+//! endpoints, and the returned ones are kept, in returned order.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use hyper::HeaderMap;
+use rune::{
+    runtime::{RuntimeContext, VmError},
+    ContextError, FromValue, Module, Unit, Vm,
+};
+
+use crate::context::GatewayContext;
+use crate::error::ConfigError;
+use crate::http::HyperRequest;
+use crate::registry::Endpoint;
+
+#[derive(Clone)]
+pub struct EndpointSelector {
+    unit: Arc<Unit>,
+    runtime: Arc<RuntimeContext>,
+}
+
+impl EndpointSelector {
+    pub fn new(script: &str) -> Result<Self, ConfigError> {
+        let mut context = rune::Context::with_default_modules()
+            .map_err(|e| ConfigError::Message(format!("{:?}", e)))?;
+        context
+            .install(&build_module().map_err(|e| ConfigError::Message(format!("{:?}", e)))?)
+            .map_err(|e| ConfigError::Message(format!("{:?}", e)))?;
+
+        let mut sources = rune::Sources::new();
+        sources.insert(rune::Source::new("endpoint_selector", script));
+
+        let mut diagnostics = rune::Diagnostics::new();
+
+        let unit = rune::prepare(&mut sources)
+            .with_context(&context)
+            .with_diagnostics(&mut diagnostics)
+            .build()
+            .map_err(|_| {
+                ConfigError::Message(format!(
+                    "endpoint selector compile err: {:?}",
+                    diagnostics.diagnostics()
+                ))
+            })?;
+
+        Ok(EndpointSelector {
+            unit: Arc::new(unit),
+            runtime: Arc::new(context.runtime()),
+        })
+    }
+
+    /// Filter and reorder `ctx.available_endpoints`, kept as they are when script failed.
+    pub fn select(&self, ctx: &mut GatewayContext, req: &HyperRequest) {
+        let candidates: Vec<String> = ctx.available_endpoints.iter().map(endpoint_addr).collect();
+
+        let selected = match self.call(ctx, req, candidates) {
+            Ok(selected) => selected,
+            Err(err) => {
+                tracing::error!(route_id = ?ctx.route_id, %err, "endpoint selector failed");
+                return;
+            }
+        };
+
+        let mut available = std::mem::take(&mut ctx.available_endpoints);
+        ctx.available_endpoints = selected
+            .iter()
+            .filter_map(|addr| {
+                let pos = available.iter().position(|ep| endpoint_addr(ep) == *addr)?;
+                Some(available.remove(pos))
+            })
+            .collect();
+    }
+
+    fn call(
+        &self,
+        ctx: &GatewayContext,
+        req: &HyperRequest,
+        candidates: Vec<String>,
+    ) -> Result<Vec<String>, VmError> {
+        let mut vm = Vm::new(self.runtime.clone(), self.unit.clone());
+
+        let req = SelectRequest {
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            headers: req.headers().clone(),
+            params: ctx.path_params.clone(),
+            vars: ctx.vars.clone(),
+            remote_addr: ctx.remote_addr(),
+        };
+
+        let output = vm.call(&["select"], (req, candidates))?;
+
+        Vec::<String>::from_value(output)
+    }
+}
+
+fn endpoint_addr(ep: &Endpoint) -> String {
+    ep.target
+        .authority()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| ep.target.to_string())
+}
+
+fn build_module() -> Result<Module, ContextError> {
+    let mut module = Module::new();
+
+    module.ty::<SelectRequest>()?;
+
+    module.inst_fn("method", SelectRequest::method)?;
+    module.inst_fn("path", SelectRequest::path)?;
+    module.inst_fn("header", SelectRequest::header)?;
+    module.inst_fn("param", SelectRequest::param)?;
+    module.inst_fn("var", SelectRequest::var)?;
+    module.inst_fn("remote_addr", SelectRequest::remote_addr)?;
+
+    Ok(module)
+}
+
+/// Request seen by script, read only.
+#[derive(Debug, rune::Any)]
+struct SelectRequest {
+    method: String,
+    path: String,
+    headers: HeaderMap,
+    params: HashMap<String, String>,
+    vars: HashMap<String, String>,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl SelectRequest {
+    fn method(&self) -> String {
+        self.method.clone()
+    }
+
+    fn path(&self) -> String {
+        self.path.clone()
+    }
+
+    fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    }
+
+    /// path param captured by route uri
+    fn param(&self, name: &str) -> Option<String> {
+        self.params.get(name).cloned()
+    }
+
+    /// variable set by plugins
+    fn var(&self, name: &str) -> Option<String> {
+        self.vars.get(name).cloned()
+    }
+
+    fn remote_addr(&self) -> Option<String> {
+        self.remote_addr.map(|addr| addr.ip().to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    const SCRIPT: &str = r#"
+        pub fn select(req, endpoints) {
+            let premium = match req.header("x-tier") {
+                Some(tier) => tier == "premium",
+                None => false,
+            };
+
+            let selected = [];
+            for ep in endpoints {
+                if premium == (ep == "10.0.0.9:80") {
+                    selected.push(ep);
+                }
+            }
+            selected
+        }
+    "#;
+
+    fn select(selector: &EndpointSelector, tier: Option<&str>) -> Vec<String> {
+        let mut builder = hyper::Request::builder();
+        if let Some(tier) = tier {
+            builder = builder.header("x-tier", tier);
+        }
+        let req = builder.body(Body::empty()).unwrap();
+
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        ctx.available_endpoints = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.9:80"]
+            .iter()
+            .map(|addr| Endpoint::new(format!("http://{}", addr).parse().unwrap(), 1))
+            .collect();
+
+        selector.select(&mut ctx, &req);
+
+        ctx.available_endpoints.iter().map(endpoint_addr).collect()
+    }
+
+    #[test]
+    fn select_endpoints() {
+        let selector = EndpointSelector::new(SCRIPT).unwrap();
+
+        assert_eq!(select(&selector, Some("premium")), vec!["10.0.0.9:80"]);
+        assert_eq!(select(&selector, None), vec!["10.0.0.1:80", "10.0.0.2:80"]);
+
+        // failed script keeps endpoints
+        let selector = EndpointSelector::new("pub fn select(req, endpoints) { 1 }").unwrap();
+        assert_eq!(select(&selector, None).len(), 3);
+
+        assert!(EndpointSelector::new("pub fn select(").is_err());
+    }
+}
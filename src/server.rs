@@ -1,20 +1,19 @@
-use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
 use drain::Watch;
 use hyper::http::uri::Scheme;
 use hyper::server::conn::Http;
-use tokio::net::TcpListener;
-use tokio::sync::Notify;
-use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{mpsc, Notify};
 use tower::Service;
 use tracing::Instrument;
 
-use crate::config::Config;
+use crate::config::{Config, ServerConfig};
 use crate::error::ConfigError;
 use crate::registry::{Registry, RegistryReader, RegistryWriter, RegistryConfig};
 use crate::services::ConnService;
+use crate::tls::{Certificates, TlsAcceptor};
 use crate::trace::TraceExecutor;
 
 #[derive(Clone)]
@@ -22,7 +21,8 @@ pub struct ServerContext {
     pub http_addr: SocketAddr,
     pub https_addr: SocketAddr,
     pub adminapi_addr: Option<SocketAddr>,
-    pub certificates: Arc<HashMap<String, CertifiedKey>>,
+    pub certificates: Arc<Certificates>,
+    pub tls_acceptor: Option<TlsAcceptor>,
     pub registry: Registry,
     pub registry_writer: Arc<Mutex<RegistryWriter>>,
     pub registry_reader: RegistryReader,
@@ -43,14 +43,30 @@ impl ServerContext {
         };
 
         // load registry
+        crate::plugins::set_plugin_load_mode(cfg.server.plugin_load_mode);
+        // upstream clients of registry are built by provider too
+        crate::tls::set_tls_provider(cfg.server.tls_provider);
         let registry = Registry::new(&cfg.registry_provider)?; // check registry conf
         let (registry_reader, mut registry_writer) = Registry::new_reader_writer();
         let registry_config = RegistryConfig::load(&cfg.registry_provider)?;
         registry_writer.load_config(registry_config);
         registry_writer.publish();
 
-        let certificates = Arc::new(HashMap::new());
+        let (tls_acceptor, certificates) = if cfg.server.tls_config.is_empty() {
+            (None, Arc::default())
+        } else {
+            let (tls_acceptor, certificates) = crate::tls::build_acceptor(&cfg.server)?;
+            (Some(tls_acceptor), certificates)
+        };
         let registry_notify = Arc::new(Notify::new());
+        crate::budget::memory_budget().set_limit(cfg.server.memory_budget);
+        crate::store::init_store(&cfg.server.store).await?;
+        crate::diagnostics::set_match_trace(&cfg.server.match_trace);
+        crate::diagnostics::set_plugin_metrics(&cfg.server.plugin_metrics);
+        crate::access_log::set_access_log(cfg.server.access_log.as_deref())?;
+        crate::statsd::set_statsd(cfg.server.statsd.as_ref())?;
+        crate::alert::alert_manager().set_config(&cfg.server.alerts)?;
+
         let config = Arc::new(cfg);
 
 
@@ -61,6 +77,7 @@ impl ServerContext {
             adminapi_addr,
             registry,
             certificates,
+            tls_acceptor,
             config,
             registry_reader,
             registry_writer: Arc::new(Mutex::new(registry_writer)),
@@ -78,30 +95,131 @@ impl ServerContext {
 pub struct Server {
     scheme: Scheme,
     registry_reader: RegistryReader,
+    config: ServerConfig,
+    tls: Option<TlsAcceptor>,
+    ready: Option<mpsc::Sender<SocketAddr>>,
 }
 
 impl Server {
-    pub fn new(scheme: Scheme, registry_reader: RegistryReader) -> Self {
+    pub fn new(scheme: Scheme, registry_reader: RegistryReader, config: ServerConfig) -> Self {
         Server {
             scheme,
             registry_reader,
+            config,
+            tls: None,
+            ready: None,
         }
     }
 
+    pub fn with_tls(mut self, tls_acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(tls_acceptor);
+        self
+    }
+
+    /// Send the listen address when listeners are up.
+    pub fn with_ready(mut self, ready: mpsc::Sender<SocketAddr>) -> Self {
+        self.ready = Some(ready);
+        self
+    }
+
     pub async fn run(self, addr: SocketAddr, watch: Watch) -> crate::Result<()> {
         let Server {
             scheme,
             registry_reader,
+            config,
+            tls,
+            ready,
         } = self;
 
-        let http = Http::new().with_executor(TraceExecutor::new());
+        let mut http = Http::new().with_executor(TraceExecutor::new());
 
-        let listener = TcpListener::bind(addr).await?;
-
-        tracing::info!("server listen on {:?}", addr);
+        if let Some(size) = config.buffer.http1_max_buf_size {
+            http.max_buf_size(size);
+        }
+        http.http2_initial_stream_window_size(config.buffer.http2_stream_window_size)
+            .http2_initial_connection_window_size(config.buffer.http2_connection_window_size);
 
         let conn_svc = ConnService::new(registry_reader, scheme, http, watch.clone());
 
+        // listener passed by systemd socket activation
+        if let Some(listener) = crate::systemd::take_listener(scheme.as_str()) {
+            let listener = TcpListener::from_std(listener)?;
+            let addr = listener.local_addr()?;
+
+            tracing::info!("server listen on inherited {:?}", addr);
+            if let Some(ready) = ready {
+                let _ = ready.send(addr).await;
+            }
+
+            Self::accept_loop(listener, conn_svc, tls, watch).await;
+
+            return Ok(());
+        }
+
+        if config.acceptors <= 1 {
+            let listener = TcpListener::bind(addr).await?;
+
+            tracing::info!("server listen on {:?}", addr);
+            if let Some(ready) = ready {
+                let _ = ready.send(addr).await;
+            }
+
+            Self::accept_loop(listener, conn_svc, tls, watch).await;
+
+            return Ok(());
+        }
+
+        // one SO_REUSEPORT socket per acceptor, let kernel balance incoming connections
+        let mut acceptors = Vec::with_capacity(config.acceptors);
+        for _ in 0..config.acceptors {
+            let listener = Self::bind_reuseport(addr)?;
+            acceptors.push(tokio::spawn(Self::accept_loop(
+                listener,
+                conn_svc.clone(),
+                tls.clone(),
+                watch.clone(),
+            )));
+        }
+
+        tracing::info!(acceptors = config.acceptors, "server listen on {:?}", addr);
+        if let Some(ready) = ready {
+            let _ = ready.send(addr).await;
+        }
+
+        futures::future::join_all(acceptors).await;
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn bind_reuseport(addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+
+        socket.listen(1024)
+    }
+
+    #[cfg(not(unix))]
+    fn bind_reuseport(_addr: SocketAddr) -> std::io::Result<TcpListener> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "SO_REUSEPORT not support on this platform",
+        ))
+    }
+
+    async fn accept_loop(
+        listener: TcpListener,
+        conn_svc: ConnService,
+        tls: Option<TlsAcceptor>,
+        watch: Watch,
+    ) {
         loop {
             tokio::select! {
                 ret = listener.accept() => {
@@ -109,13 +227,9 @@ impl Server {
 
                     match ret {
                         Ok((stream, remote_addr)) => {
-                            let mut conn_svc = conn_svc.clone();
                             let span = tracing::debug_span!("connection", %remote_addr);
                             let _enter = span.enter();
-                            let fut = async move {
-                                let ret = Service::call(&mut conn_svc, stream).await;
-                                tracing::debug!(?ret, "handle connection done");
-                            };
+                            let fut = Self::serve_connection(conn_svc.clone(), stream, tls.clone());
                             tokio::spawn(fut.in_current_span());
                         }
                         Err(e) => {
@@ -129,7 +243,38 @@ impl Server {
                 }
             }
         }
+    }
 
-        Ok(())
+    /// Serve `stream` after tls handshake if any, failed handshakes are only logged.
+    async fn serve_connection(
+        mut conn_svc: ConnService,
+        stream: TcpStream,
+        tls: Option<TlsAcceptor>,
+    ) {
+        let ret = match tls {
+            Some(TlsAcceptor::Rustls(acceptor)) => match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    crate::tls::tls_stats().on_handshake();
+                    Service::call(&mut conn_svc, stream).await
+                }
+                Err(err) => {
+                    tracing::debug!(?err, "tls handshake failed");
+                    return;
+                }
+            },
+            #[cfg(feature = "native-tls")]
+            Some(TlsAcceptor::NativeTls(acceptor)) => match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    crate::tls::tls_stats().on_handshake();
+                    Service::call(&mut conn_svc, stream).await
+                }
+                Err(err) => {
+                    tracing::debug!(?err, "tls handshake failed");
+                    return;
+                }
+            },
+            None => Service::call(&mut conn_svc, stream).await,
+        };
+        tracing::debug!(?ret, "handle connection done");
     }
 }
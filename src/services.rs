@@ -2,31 +2,55 @@ use std::{
     collections::HashMap,
     net::SocketAddr,
     pin::Pin,
-    sync::{Arc, RwLock},
+    sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
-use futures::Future;
-use hyper::http::uri::Scheme;
+use futures::{Future, StreamExt};
+use hyper::{
+    header::{ALLOW, CONTENT_LENGTH, HOST},
+    http::uri::Scheme,
+    Body, HeaderMap, Method, StatusCode,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tower::Service;
-use tracing::{debug, error};
+use tracing::{debug, error, field::Empty, Instrument};
 
 use crate::{
     context::GatewayContext,
+    diagnostics::{match_trace_sampled, plugin_metrics_enabled, record_plugin},
     http::{
-        not_found, upstream_unavailable, HttpServer, HyperRequest, HyperResponse, ResponseFuture,
+        finish_response_body, gateway_timeout, json_error, loop_detected, not_found,
+        response_too_large, service_unavailable, upstream_unavailable, HttpServer, HyperRequest,
+        HyperResponse, ResponseFuture,
     },
-    registry::{Endpoint, RegistryReader},
+    registry::RegistryReader,
 };
 use crate::{
-    forwarder::Fowarder,
+    access_log::AccessLine,
+    alert::{alert_manager, Sample},
     http::bad_gateway,
+    identity::{forward_identity, strip_identity_headers},
+    journal::{journal, PendingEntry},
+    matcher::AllowedMethods,
     peer_addr::PeerAddr,
-    router::{PathRouter, Route},
-    upstream::Upstream,
+    plugins::Plugin,
+    router::{HostRouter, Route},
+    slo::slo_tracker,
+    statsd::record_request,
+    upstream::{UpstreamMap, UpstreamResolver},
 };
 
+/// max times a request can be routed again by internal redirect
+const MAX_INTERNAL_REDIRECTS: u32 = 8;
+
+pub enum Dispatched {
+    Response(HyperResponse),
+    /// route the rewritten request again
+    Redirect(GatewayContext, HyperRequest),
+}
+
 #[derive(Clone)]
 pub struct GatewayService {
     registry_reader: RegistryReader,
@@ -47,83 +71,455 @@ impl GatewayService {
         }
     }
 
-    pub fn find_route<'a>(router: &'a PathRouter, req: &HyperRequest) -> Option<&'a Route> {
-        match router.route(req.uri().path()) {
-            Some((endpoint, _params)) => {
-                let routes: Vec<&Route> =
-                    endpoint.iter().filter(|r| r.matcher.matchs(req)).collect();
+    pub fn find_route<'a>(
+        router: &'a HostRouter,
+        ctx: &GatewayContext,
+        req: &HyperRequest,
+    ) -> Option<(&'a Route, HashMap<String, String>)> {
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri().host());
 
-                routes.first().cloned()
+        // fall through to less specific host router when nothing matched
+        for path_router in router.routers(host) {
+            if let Some((endpoint, params)) = path_router.route(req.uri().path()) {
+                let route = endpoint
+                    .iter()
+                    .find(|r| r.enabled && r.matcher.matchs(ctx, req));
+
+                if let Some(route) = route {
+                    let params = params
+                        .into_iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect();
+
+                    return Some((route, params));
+                }
+
+                Self::trace_near_miss(endpoint, ctx, req);
             }
-            None => {
-                debug!("route not found");
-                None
+        }
+
+        debug!("route not found");
+        None
+    }
+
+    /// Answer for routes with `auto_options` whose uri matched, 204 to `OPTIONS` and 405 to
+    /// methods not allowed, both with `Allow` computed from their matchers.
+    fn auto_options(
+        router: &HostRouter,
+        ctx: &GatewayContext,
+        req: &HyperRequest,
+    ) -> Option<HyperResponse> {
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri().host());
+
+        let allowed = router
+            .routers(host)
+            .filter_map(|path_router| path_router.route(req.uri().path()))
+            .flat_map(|(routes, _)| routes.iter())
+            .filter(|r| r.enabled && r.auto_options)
+            .map(|r| r.matcher.allowed_methods(ctx, req))
+            .reduce(AllowedMethods::union)?;
+
+        let methods = match allowed {
+            AllowedMethods::Any => vec![
+                Method::GET,
+                Method::HEAD,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ],
+            AllowedMethods::Only(methods) if methods.is_empty() => return None,
+            AllowedMethods::Only(methods) => methods,
+        };
+
+        let status = if req.method() == Method::OPTIONS {
+            StatusCode::NO_CONTENT
+        } else if !methods.contains(req.method()) {
+            StatusCode::METHOD_NOT_ALLOWED
+        } else {
+            return None;
+        };
+
+        let allow = methods
+            .iter()
+            .filter(|m| **m != Method::OPTIONS)
+            .chain(Some(&Method::OPTIONS))
+            .map(Method::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let resp = hyper::Response::builder()
+            .status(status)
+            .header(ALLOW, allow)
+            .body(Body::empty())
+            .unwrap();
+
+        Some(resp)
+    }
+
+    /// Log which sub-expression failed for routes whose uri matched.
+    fn trace_near_miss(routes: &[Route], ctx: &GatewayContext, req: &HyperRequest) {
+        for route in routes.iter().filter(|r| r.enabled) {
+            if !match_trace_sampled(route.trace_match) {
+                continue;
+            }
+
+            if let Some(failed) = route.matcher.explain(ctx, req) {
+                debug!(
+                    route_id = %route.id,
+                    method = %req.method(),
+                    uri = %req.uri(),
+                    %failed,
+                    "route uri matched but matcher failed"
+                );
+            }
+        }
+    }
+
+    /// Route and dispatch request, following internal redirects.
+    pub async fn serve(
+        router: &HostRouter,
+        upstreams: &Arc<UpstreamMap>,
+        ctx: GatewayContext,
+        req: HyperRequest,
+    ) -> HyperResponse {
+        let mut matched = None;
+        let start_time = ctx.start_time();
+        // for requests answered before forwarding, variables as received
+        let access_line = AccessLine::render(&ctx, &req);
+
+        let mut resp = if !journal().is_active() {
+            Self::serve_routes(router, upstreams, ctx, req, &mut matched).await
+        } else {
+            let pending = PendingEntry::new(&ctx, &req);
+            let resp = Self::serve_routes(router, upstreams, ctx, req, &mut matched).await;
+            journal().record(pending, matched, &resp);
+            resp
+        };
+
+        let latency = start_time.elapsed().unwrap_or_default();
+        record_request(matched, resp.status(), latency);
+
+        let access_line = resp.extensions_mut().remove::<AccessLine>().or(access_line);
+        if let Some(access_line) = access_line {
+            access_line.log(&resp, latency);
+        }
+
+        resp
+    }
+
+    /// `matched` is set to the route last dispatched to.
+    async fn serve_routes<'a>(
+        router: &'a HostRouter,
+        upstreams: &Arc<UpstreamMap>,
+        mut ctx: GatewayContext,
+        mut req: HyperRequest,
+        matched: &mut Option<&'a str>,
+    ) -> HyperResponse {
+        loop {
+            // routes with `auto_options` never see `OPTIONS`
+            let found = Self::find_route(router, &ctx, &req)
+                .filter(|(route, _)| !(route.auto_options && req.method() == Method::OPTIONS));
+
+            let (route, params) = match found {
+                Some(found) => found,
+                None => return Self::auto_options(router, &ctx, &req).unwrap_or_else(not_found),
+            };
+            *matched = Some(route.id.as_str());
+
+            match Self::dispatch(ctx, route, params, upstreams, req).await {
+                Dispatched::Response(resp) => return resp,
+                Dispatched::Redirect(c, r) => {
+                    if c.redirects >= MAX_INTERNAL_REDIRECTS {
+                        error!(route_id = %route.id, uri = %r.uri(), "too many internal redirects");
+                        return loop_detected();
+                    }
+
+                    debug!(route_id = %route.id, uri = %r.uri(), "internal redirect");
+
+                    ctx = c;
+                    ctx.internal_redirect = false;
+                    ctx.redirects += 1;
+                    req = r;
+                }
             }
         }
     }
 
+    /// Run plugins of route and forward, plugins may ask for an internal redirect,
+    /// then their `after_forward` are skipped.
     pub async fn dispatch(
         mut ctx: GatewayContext,
         route: &Route,
-        upstreams: &HashMap<String, Arc<RwLock<Upstream>>>,
+        path_params: HashMap<String, String>,
+        upstreams: &Arc<UpstreamMap>,
         mut req: HyperRequest,
-    ) -> HyperResponse {
+    ) -> Dispatched {
         ctx.overwrite_host = route.overwrite_host;
+        ctx.upstream_host = route.upstream_host.clone();
+        ctx.protocol = route.protocol.clone();
         ctx.route_id = Some(route.id.clone());
         ctx.upstream_id = Some(route.upstream_id.clone());
+        tracing::Span::current().record("route_id", route.id.as_str());
+        ctx.path_params = path_params;
+        ctx.upstreams = Some(UpstreamResolver::new(upstreams.clone()));
+
+        strip_identity_headers(req.headers_mut());
+
+        let plugin_metrics = plugin_metrics_enabled();
+        let request_size = Self::content_length(req.headers());
+
+        // before forward, remember plugins executed, only them run after forward
+        let mut executed = Vec::with_capacity(route.plugins.len());
+        for p in &route.plugins {
+            if !p.should_run(&ctx, &req) {
+                continue;
+            }
+            executed.push(&p.plugin);
+
+            let started = plugin_metrics.then(Instant::now);
+            let accessed = p.plugin.on_access(&mut ctx, req).await;
+            if let Some(started) = started {
+                record_plugin(
+                    &mut ctx,
+                    p.plugin.name(),
+                    "on_access",
+                    started.elapsed(),
+                    accessed.is_err(),
+                );
+            }
 
-        // before forward
-        for plugin in &route.plugins {
-            match plugin.on_access(&mut ctx, req) {
+            match accessed {
                 Ok(r) => {
                     req = r;
                 }
                 Err(resp) => {
-                    return resp;
+                    // answered by this plugin, the ones before it see the response,
+                    // the latest first
+                    executed.pop();
+                    let resp = Self::after_forward(
+                        &mut ctx,
+                        executed.into_iter().rev(),
+                        resp,
+                        plugin_metrics,
+                    )
+                    .await;
+
+                    let upstream_id = ctx.upstream_id.clone();
+                    let upstream_id = upstream_id.as_deref().unwrap_or(&route.upstream_id);
+                    let resp = Self::complete(&ctx, route, upstream_id, request_size, None, resp);
+                    return Dispatched::Response(resp);
                 }
             }
+
+            if ctx.internal_redirect {
+                return Dispatched::Redirect(ctx, req);
+            }
         }
 
+        // with variables set by plugins
+        let access_line = AccessLine::render(&ctx, &req);
+
+        forward_identity(&ctx, req.headers_mut());
+
         // fallback to route.upstream_id
         let upstream_id = ctx.upstream_id.clone().unwrap_or(route.upstream_id.clone());
         ctx.upstream_id = Some(upstream_id.clone());
+        tracing::Span::current().record("upstream_id", upstream_id.as_str());
 
-        let mut forwarder = match upstreams.get(&upstream_id) {
-            Some(upstream) => {
-                let upstream = upstream.read().unwrap();
-                let healthy_endpoints = upstream.healthy_endpoints();
-                let available_endpoints = if healthy_endpoints.is_empty() {
-                    upstream.all_endpoints()
-                } else {
-                    healthy_endpoints
+        // do forward, within the remaining time budget of route and deadline of request
+        let budget = route
+            .timeout
+            .map(|t| t.saturating_sub(ctx.start_time().elapsed().unwrap_or_default()));
+        let budget = match ctx.deadline.map(|d| d.saturating_duration_since(Instant::now())) {
+            Some(remaining) => Some(budget.map_or(remaining, |b| b.min(remaining))),
+            None => budget,
+        };
+
+        let forwarded = match route.aggregate {
+            Some(ref aggregate) => {
+                let aggregated = aggregate.run(&ctx, upstreams, &req);
+                Self::within_budget(budget, async { Ok::<_, crate::Error>(aggregated.await) }).await
+            }
+            None => {
+                let (mut forwarder, _permit) = match upstreams.get(&upstream_id) {
+                    Some(upstream) => {
+                        let upstream = upstream.read().unwrap();
+
+                        match upstream.limiter.try_acquire(route.priority_class) {
+                            Some(permit) => (upstream.forwarder(&mut ctx), permit),
+                            None => {
+                                debug!(
+                                    route_id = %route.id,
+                                    %upstream_id,
+                                    class = ?route.priority_class,
+                                    "upstream concurrency limit reached, request shed"
+                                );
+                                return Dispatched::Response(service_unavailable(
+                                    Duration::from_secs(1),
+                                ));
+                            }
+                        }
+                    }
+                    None => {
+                        return Dispatched::Response(upstream_unavailable());
+                    }
                 };
 
-                let available_endpoints = available_endpoints
-                    .into_iter()
-                    .cloned()
-                    .collect::<Vec<Endpoint>>();
+                if let Some(ref selector) = route.endpoint_selector {
+                    selector.select(&mut ctx, &req);
+                }
 
-                ctx.available_endpoints = available_endpoints;
+                // all endpoints warming up, or vetoed by selector
+                if ctx.available_endpoints.is_empty() {
+                    debug!(route_id = %route.id, %upstream_id, "no endpoint available");
+                    return Dispatched::Response(upstream_unavailable());
+                }
 
-                Fowarder::new(upstream.client.clone(), upstream.strategy.clone())
-            }
-            None => {
-                return upstream_unavailable();
+                Self::within_budget(budget, forwarder.forward(&mut ctx, req)).await
             }
         };
 
-        // do forward
-        let mut resp = match forwarder.forward(&mut ctx, req).await {
-            Ok(resp) => resp,
-            Err(err) => {
+        let mut resp = match forwarded {
+            Some(Ok(resp)) => resp,
+            Some(Err(err)) => {
                 error!(?err, "forward request failed");
                 bad_gateway()
             }
+            None => {
+                error!(route_id = %route.id, "forward request timeout");
+                ctx.timed_out = true;
+                Self::timeout_response(&ctx, route)
+            }
         };
 
+        if let Some(limit) = route.max_response_size {
+            resp = Self::limit_response(route, limit, resp);
+        }
+
         // after forward
-        for plugin in &route.plugins {
-            resp = plugin.after_forward(&mut ctx, resp);
+        let resp = Self::after_forward(&mut ctx, executed.into_iter(), resp, plugin_metrics).await;
+        let resp = Self::complete(&ctx, route, &upstream_id, request_size, access_line, resp);
+
+        Dispatched::Response(resp)
+    }
+
+    /// Run `after_forward` of `plugins` in turn.
+    async fn after_forward<'a>(
+        ctx: &mut GatewayContext,
+        plugins: impl Iterator<Item = &'a Arc<Box<dyn Plugin + Send + Sync>>>,
+        mut resp: HyperResponse,
+        plugin_metrics: bool,
+    ) -> HyperResponse {
+        for plugin in plugins {
+            let started = plugin_metrics.then(Instant::now);
+            resp = plugin.after_forward(ctx, resp).await;
+            if let Some(started) = started {
+                record_plugin(
+                    ctx,
+                    plugin.name(),
+                    "after_forward",
+                    started.elapsed(),
+                    false,
+                );
+            }
+        }
+
+        resp
+    }
+
+    /// Finish response, forwarded or answered by a plugin, and record it for slo and alerts.
+    fn complete(
+        ctx: &GatewayContext,
+        route: &Route,
+        upstream_id: &str,
+        request_size: Option<u64>,
+        access_line: Option<AccessLine>,
+        resp: HyperResponse,
+    ) -> HyperResponse {
+        let mut resp = finish_response_body(resp);
+        if let Some(access_line) = access_line {
+            resp.extensions_mut().insert(access_line);
+        }
+
+        let latency = ctx.start_time().elapsed().unwrap_or_default();
+        if let Some(ref slo) = route.slo {
+            slo_tracker().record(&route.id, slo, resp.status(), latency);
+        }
+
+        let sample = Sample {
+            status: resp.status(),
+            latency,
+            request_size,
+            response_size: Self::content_length(resp.headers()),
+        };
+        alert_manager().record(&route.id, upstream_id, sample);
+
+        resp
+    }
+
+    fn content_length(headers: &HeaderMap) -> Option<u64> {
+        headers.get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+    }
+
+    /// `None` when the budget run out.
+    async fn within_budget<F: Future>(budget: Option<Duration>, fut: F) -> Option<F::Output> {
+        match budget {
+            Some(budget) => tokio::time::timeout(budget, fut).await.ok(),
+            None => Some(fut.await),
+        }
+    }
+
+    /// Refuse response declared larger than `limit`, otherwise abort the body stream once
+    /// `limit` exceeded, trailers are dropped.
+    fn limit_response(route: &Route, limit: u64, resp: HyperResponse) -> HyperResponse {
+        let declared = resp
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+
+        if declared.map_or(false, |len| len > limit) {
+            error!(route_id = %route.id, limit, "upstream response too large");
+            return response_too_large();
+        }
+
+        let route_id = route.id.clone();
+        let mut size = 0;
+
+        let (parts, body) = resp.into_parts();
+        let body = body.map(move |chunk| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+
+            if size > limit {
+                error!(%route_id, limit, "upstream response too large, stream aborted");
+                return Err(format!("response larger than {} bytes", limit).into());
+            }
+
+            Ok(chunk)
+        });
+
+        HyperResponse::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    fn timeout_response(ctx: &GatewayContext, route: &Route) -> HyperResponse {
+        let mut resp = match ctx.deadline {
+            Some(_) => json_error(StatusCode::GATEWAY_TIMEOUT, "request timeout"),
+            None => gateway_timeout(),
+        };
+
+        if let (Some(name), Some(timeout)) = (&route.timeout_header, route.timeout) {
+            let elapsed = ctx.start_time().elapsed().unwrap_or_default();
+            let remaining = timeout.saturating_sub(elapsed).as_millis() as u64;
+            resp.headers_mut().insert(name.clone(), remaining.into());
         }
 
         resp
@@ -147,15 +543,29 @@ impl Service<HyperRequest> for GatewayService {
         let router = self.registry_reader.get().router.clone();
         let upstreams = self.registry_reader.get().upstreams.clone();
 
-        Box::pin(async move {
-            let found = Self::find_route(&router, &req);
-            let resp = match found {
-                Some(route) => Self::dispatch(ctx, route, &upstreams, req).await,
-                None => not_found(),
-            };
+        // `request_id` and `variant` are recorded by plugins `request_id` and `traffic_split`
+        let span = tracing::info_span!(
+            "request",
+            otel.kind = "server",
+            method = %req.method(),
+            path = req.uri().path(),
+            route_id = Empty,
+            upstream_id = Empty,
+            endpoint = Empty,
+            status = Empty,
+            request_id = Empty,
+            variant = Empty,
+        );
+        crate::telemetry::set_parent(&span, req.headers());
 
-            Ok(resp)
-        })
+        Box::pin(
+            async move {
+                let resp = Self::serve(&router, &upstreams, ctx, req).await;
+                tracing::Span::current().record("status", resp.status().as_u16());
+                Ok(resp)
+            }
+            .instrument(span),
+        )
     }
 }
 
@@ -208,7 +618,7 @@ where
         let svc = GatewayService::new(registry_reader, remote_addr, scheme);
 
         Box::pin(async move {
-            let mut conn = server.serve_connection(io, svc);
+            let mut conn = server.serve_connection(io, svc).with_upgrades();
             tokio::select! {
                 res = &mut conn => {
                     debug!(?res, "The client is shutting down the connection");
@@ -224,3 +634,1362 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+
+    use hyper::{
+        server::conn::AddrIncoming,
+        service::{make_service_fn, service_fn},
+        Body,
+    };
+
+    use super::*;
+    use crate::config::{
+        EndpointConfig, PluginConfig, PluginMetricsConfig, RouteConfig, UpstreamConfig,
+    };
+    use crate::context::{Consumer, PluginTimings, SelectedEndpoint};
+    use crate::plugins::{register_plugin, PathRewriteConfig, Plugin};
+    use crate::protocol::ProtocolConfig;
+    use crate::registry::{Registry, RegistryConfig};
+
+    /// Serve registry on a local port like `ConnService`, with upgrades.
+    fn spawn_gateway(registry: Registry) -> SocketAddr {
+        let registry = Arc::new(registry);
+
+        let make_svc = make_service_fn(move |_| {
+            let registry = registry.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: HyperRequest| {
+                    let registry = registry.clone();
+                    async move {
+                        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+                        let resp =
+                            GatewayService::serve(&registry.router, &registry.upstreams, ctx, req)
+                                .await;
+                        Ok::<_, Infallible>(resp)
+                    }
+                }))
+            }
+        });
+        let srv = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = srv.local_addr();
+        tokio::spawn(srv);
+
+        addr
+    }
+
+    /// Stub upstream on a local port, answering every request with `handler`.
+    fn spawn_upstream<F, Fut>(handler: F) -> SocketAddr
+    where
+        F: Fn(HyperRequest) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = HyperResponse> + Send + 'static,
+    {
+        spawn_upstream_on(
+            hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()),
+            handler,
+        )
+    }
+
+    /// Like `spawn_upstream`, served by `builder` with its protocol options.
+    fn spawn_upstream_on<F, Fut>(
+        builder: hyper::server::Builder<AddrIncoming>,
+        handler: F,
+    ) -> SocketAddr
+    where
+        F: Fn(HyperRequest) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = HyperResponse> + Send + 'static,
+    {
+        let make_svc = make_service_fn(move |_| {
+            let handler = handler.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: HyperRequest| {
+                    let resp = handler(req);
+                    async move { Ok::<_, Infallible>(resp.await) }
+                }))
+            }
+        });
+        let srv = builder.serve(make_svc);
+        let addr = srv.local_addr();
+        tokio::spawn(srv);
+
+        addr
+    }
+
+    fn upstream_config(id: &str, addr: SocketAddr) -> UpstreamConfig {
+        UpstreamConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            endpoints: vec![EndpointConfig {
+                addr: format!("http://{}", addr),
+                weight: 1,
+            }],
+            strategy: "random".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Route named after its only uri, running `plugins` with their configs.
+    fn route_config(
+        uri: &str,
+        upstream_id: &str,
+        plugins: &[(&str, serde_json::Value)],
+    ) -> RouteConfig {
+        let plugins = plugins
+            .iter()
+            .map(|(name, config)| {
+                let plugin = PluginConfig {
+                    enable: true,
+                    when: None,
+                    order: None,
+                    config: config.clone(),
+                };
+                (name.to_string(), plugin)
+            })
+            .collect();
+
+        RouteConfig {
+            id: uri.to_string(),
+            name: uri.to_string(),
+            uris: vec![uri.to_string()],
+            upstream_id: upstream_id.to_string(),
+            plugins,
+            ..Default::default()
+        }
+    }
+
+    fn load_registry(routes: Vec<RouteConfig>, upstreams: Vec<UpstreamConfig>) -> Registry {
+        let mut registry = Registry::default();
+        registry
+            .reload(RegistryConfig {
+                routes,
+                upstreams,
+                ..Default::default()
+            })
+            .unwrap();
+
+        registry
+    }
+
+    /// Single route on `uri` to the stub upstream at `addr`, running `plugins`.
+    fn registry_with_plugins(
+        uri: &str,
+        addr: SocketAddr,
+        plugins: &[(&str, serde_json::Value)],
+    ) -> Registry {
+        load_registry(
+            vec![route_config(uri, "stub", plugins)],
+            vec![upstream_config("stub", addr)],
+        )
+    }
+
+    fn get(uri: &str) -> HyperRequest {
+        hyper::Request::builder()
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Serve `req` through `registry` without a connection, like `GatewayService`.
+    async fn serve(registry: &Registry, req: HyperRequest) -> HyperResponse {
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        GatewayService::serve(&registry.router, &registry.upstreams, ctx, req).await
+    }
+
+    #[tokio::test]
+    async fn rewrite_with_path_params() {
+        // upstream echoes the uri it received
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            hyper::Response::new(Body::from(req.uri().to_string()))
+        });
+
+        let rewrite = PathRewriteConfig::Static("/v2/users/{id}".to_string());
+        let registry = registry_with_plugins(
+            "/users/:id/orders",
+            upstream_addr,
+            &[("path_rewrite", serde_json::to_value(rewrite).unwrap())],
+        );
+
+        let req = get("/users/42/orders?page=2");
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        let (_, params) = GatewayService::find_route(&registry.router, &ctx, &req).unwrap();
+        assert_eq!(params.get("id").map(String::as_str), Some("42"));
+
+        let resp = serve(&registry, req).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+
+        assert_eq!(&body[..], b"/v2/users/42?page=2");
+    }
+
+    #[tokio::test]
+    async fn auto_options() {
+        // upstream echoes the method it received
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            hyper::Response::new(Body::from(req.method().to_string()))
+        });
+
+        let route = |uri: &str, matcher: &str, auto_options: bool| RouteConfig {
+            matcher: matcher.to_string(),
+            auto_options,
+            ..route_config(uri, "echo", &[])
+        };
+        let registry = load_registry(
+            vec![
+                RouteConfig {
+                    id: "list-items".to_string(),
+                    ..route("/items", "Method('GET')", true)
+                },
+                RouteConfig {
+                    id: "add-item".to_string(),
+                    ..route("/items", "Method('POST')", true)
+                },
+                route("/any", "", true),
+                route("/backend", "", false),
+            ],
+            vec![upstream_config("echo", upstream_addr)],
+        );
+
+        let options = |method: &str, uri: &str| {
+            let req = hyper::Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            let registry = &registry;
+
+            async move {
+                let (parts, body) = serve(registry, req).await.into_parts();
+                (parts, hyper::body::to_bytes(body).await.unwrap())
+            }
+        };
+
+        let (parts, _) = options("OPTIONS", "/items").await;
+        assert_eq!(parts.status, StatusCode::NO_CONTENT);
+        assert_eq!(parts.headers[ALLOW], "GET, POST, OPTIONS");
+
+        let (parts, _) = options("DELETE", "/items").await;
+        assert_eq!(parts.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(parts.headers[ALLOW], "GET, POST, OPTIONS");
+
+        let (parts, body) = options("POST", "/items").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(&body[..], b"POST");
+
+        // route matching any method
+        let (parts, _) = options("OPTIONS", "/any").await;
+        assert_eq!(parts.status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            parts.headers[ALLOW],
+            "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS"
+        );
+
+        // left to backend
+        let (parts, body) = options("OPTIONS", "/backend").await;
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(&body[..], b"OPTIONS");
+
+        let (parts, _) = options("OPTIONS", "/missing").await;
+        assert_eq!(parts.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn protocol_translation() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // http/1.1 only upstream, echoes 4 bytes after upgrade
+        let h1_only = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).http1_only(true);
+        let upstream_addr = spawn_upstream_on(h1_only, |mut req: HyperRequest| async move {
+            if req.headers().contains_key("upgrade") {
+                let upgrade = hyper::upgrade::on(&mut req);
+                tokio::spawn(async move {
+                    let mut io = upgrade.await.unwrap();
+                    let mut buf = [0u8; 4];
+                    io.read_exact(&mut buf).await.unwrap();
+                    io.write_all(&buf).await.unwrap();
+                });
+
+                return hyper::Response::builder()
+                    .status(StatusCode::SWITCHING_PROTOCOLS)
+                    .header("connection", "upgrade")
+                    .header("upgrade", "echo")
+                    .body(Body::empty())
+                    .unwrap();
+            }
+
+            let te = req
+                .headers()
+                .get("te")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("-")
+                .to_string();
+            let body = format!("{:?} te={}", req.version(), te);
+            hyper::Response::new(Body::from(body))
+        });
+
+        let route = |uri: &str, upgrade: bool| RouteConfig {
+            protocol: ProtocolConfig {
+                upgrade,
+                ..Default::default()
+            },
+            ..route_config(uri, "h1", &[])
+        };
+        let registry = load_registry(
+            vec![route("/ws", true), route("/plain", false)],
+            vec![upstream_config("h1", upstream_addr)],
+        );
+        let gateway = spawn_gateway(registry);
+
+        // h2 client to http/1.1 upstream
+        let client = hyper::Client::builder()
+            .http2_only(true)
+            .build_http::<Body>();
+        let req = hyper::Request::builder()
+            .uri(format!("http://{}/plain", gateway))
+            .header("te", "trailers")
+            .body(Body::empty())
+            .unwrap();
+        let resp = client.request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"HTTP/1.1 te=trailers");
+
+        let upgrade = |uri: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(gateway).await.unwrap();
+            let head = format!(
+                "GET {} HTTP/1.1\r\nhost: gateway\r\nconnection: upgrade\r\nupgrade: echo\r\n\r\n",
+                uri
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8; 1];
+                stream.read_exact(&mut byte).await.unwrap();
+                head.push(byte[0]);
+            }
+
+            (stream, String::from_utf8(head).unwrap())
+        };
+
+        let (mut stream, head) = upgrade("/ws").await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head.contains("upgrade: echo"), "{}", head);
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // upgrade not allowed, forwarded as plain http
+        let (_, head) = upgrade("/plain").await;
+        assert!(head.starts_with("HTTP/1.1 200"), "{}", head);
+    }
+
+    #[test]
+    fn disabled_route_not_matched() {
+        let mut route = route_config("/hello", "stub", &[]);
+        let upstreams = vec![upstream_config("stub", "127.0.0.1:1".parse().unwrap())];
+
+        let req = get("/hello");
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+
+        let registry = load_registry(vec![route.clone()], upstreams.clone());
+        assert!(GatewayService::find_route(&registry.router, &ctx, &req).is_some());
+
+        route.enabled = false;
+        let registry = load_registry(vec![route], upstreams);
+        assert!(GatewayService::find_route(&registry.router, &ctx, &req).is_none());
+    }
+
+    #[tokio::test]
+    async fn route_timeout_budget() {
+        let upstream_addr = spawn_upstream(|_| async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            hyper::Response::new(Body::empty())
+        });
+
+        let registry = load_registry(
+            vec![RouteConfig {
+                timeout: 50,
+                timeout_header: Some("X-Timeout-Remaining".to_string()),
+                ..route_config("/slow", "slow", &[])
+            }],
+            vec![upstream_config("slow", upstream_addr)],
+        );
+
+        let resp = serve(&registry, get("/slow")).await;
+
+        assert_eq!(resp.status(), hyper::StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(resp.headers()["x-timeout-remaining"], "0");
+    }
+
+    #[tokio::test]
+    async fn timeout_plugin() {
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            if req.uri().path() == "/slow" {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+            hyper::Response::new(Body::from("upstream"))
+        });
+
+        let timeout = ("timeout", serde_json::json!({ "timeout_ms": 100 }));
+        let delay = (
+            "fault_injection",
+            serde_json::json!({ "delay": { "percentage": 100, "duration_ms": 200 } }),
+        );
+        let registry = load_registry(
+            vec![
+                route_config("/slow", "stub", &[timeout.clone()]),
+                route_config("/fast", "stub", &[timeout.clone()]),
+                route_config("/slow-plugin", "stub", &[timeout, delay]),
+            ],
+            vec![upstream_config("stub", upstream_addr)],
+        );
+
+        let timed = |uri: &str| {
+            let req = get(uri);
+            let registry = &registry;
+
+            async move {
+                let start = std::time::Instant::now();
+                let resp = serve(registry, req).await;
+                let status = resp.status();
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                (status, body, start.elapsed())
+            }
+        };
+
+        let (status, body, elapsed) = timed("/slow").await;
+        assert_eq!(status, hyper::StatusCode::GATEWAY_TIMEOUT);
+        assert!(elapsed < Duration::from_millis(400), "elapsed {:?}", elapsed);
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["message"], "request timeout");
+
+        let (status, body, _) = timed("/fast").await;
+        assert_eq!(status, hyper::StatusCode::OK);
+        assert_eq!(&body[..], b"upstream");
+
+        // plugin time counts
+        let (status, _, _) = timed("/slow-plugin").await;
+        assert_eq!(status, hyper::StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn internal_redirect() {
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            hyper::Response::new(Body::from(req.uri().to_string()))
+        });
+
+        let redirect = |uri: &str, to: &str| {
+            route_config(
+                uri,
+                "echo",
+                &[("internal_redirect", serde_json::json!({ "uri": to }))],
+            )
+        };
+        let registry = load_registry(
+            vec![
+                redirect("/login/:user", "/internal/auth/{user}"),
+                route_config("/internal/auth/:user", "echo", &[]),
+                redirect("/loop", "/loop"),
+            ],
+            vec![upstream_config("echo", upstream_addr)],
+        );
+
+        let resp = serve(&registry, get("/login/tom?next=home")).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"/internal/auth/tom?next=home");
+
+        let resp = serve(&registry, get("/loop")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::LOOP_DETECTED);
+    }
+
+    #[tokio::test]
+    async fn aggregate_branches() {
+        // upstream answers json with the uri it received
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            let body = serde_json::json!({ "uri": req.uri().to_string() }).to_string();
+            hyper::Response::new(Body::from(body))
+        });
+
+        let aggregate = serde_json::json!({
+            "branches": [
+                { "name": "user", "uri": "/users/{id}" },
+                { "name": "orders", "uri": "/orders?user={id}" },
+                { "name": "down", "upstream_id": "down", "timeout": 200 },
+            ],
+            "template": { "user": "$.user.uri", "orders": "$.orders.uri", "down": "$.down" },
+            "on_failure": "partial",
+        });
+        let registry = load_registry(
+            vec![RouteConfig {
+                aggregate: Some(serde_json::from_value(aggregate).unwrap()),
+                ..route_config("/profile/:id", "echo", &[])
+            }],
+            vec![
+                upstream_config("echo", upstream_addr),
+                upstream_config("down", "127.0.0.1:1".parse().unwrap()),
+            ],
+        );
+
+        let resp = serve(&registry, get("/profile/42")).await;
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(body["user"], "/users/42");
+        assert_eq!(body["orders"], "/orders?user=42");
+        assert_eq!(body["down"], serde_json::Value::Null);
+        assert!(body["_errors"]["down"].is_string());
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_in_flight() {
+        // upstream holds responses open until the gate is opened
+        let arrived = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+
+        let (a, g) = (arrived.clone(), gate.clone());
+        let upstream_addr = spawn_upstream(move |_| {
+            let (arrived, gate) = (a.clone(), g.clone());
+            async move {
+                arrived.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                gate.acquire().await.unwrap().forget();
+                hyper::Response::new(Body::from("done"))
+            }
+        });
+
+        let registry = registry_with_plugins(
+            "/slow",
+            upstream_addr,
+            &[(
+                "concurrency_limit",
+                serde_json::json!({ "max_in_flight": 2 }),
+            )],
+        );
+        let slow = || serve(&registry, get("/slow"));
+
+        let (first, second, rejected) = tokio::join!(slow(), slow(), async {
+            while arrived.load(std::sync::atomic::Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+
+            let rejected = slow().await;
+            gate.add_permits(2);
+            rejected
+        });
+
+        assert_eq!(first.status(), hyper::StatusCode::OK);
+        assert_eq!(second.status(), hyper::StatusCode::OK);
+        assert_eq!(rejected.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+        assert!(rejected.headers().contains_key(hyper::header::RETRY_AFTER));
+
+        // slots released after responses
+        gate.add_permits(1);
+        assert_eq!(slow().await.status(), hyper::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn fault_injection() {
+        let upstream_addr =
+            spawn_upstream(|_| async { hyper::Response::new(Body::from("upstream")) });
+
+        let fault = |uri: &str, config: serde_json::Value| {
+            route_config(uri, "stub", &[("fault_injection", config)])
+        };
+        let registry = load_registry(
+            vec![
+                fault(
+                    "/abort",
+                    serde_json::json!({
+                        "abort": { "percentage": 100, "status_code": 418, "body": "injected" },
+                    }),
+                ),
+                fault(
+                    "/delay",
+                    serde_json::json!({ "delay": { "percentage": 100, "duration_ms": 200 } }),
+                ),
+            ],
+            vec![upstream_config("stub", upstream_addr)],
+        );
+
+        let resp = serve(&registry, get("/abort")).await;
+        assert_eq!(resp.status(), hyper::StatusCode::IM_A_TEAPOT);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"injected");
+
+        let start = std::time::Instant::now();
+        let resp = serve(&registry, get("/delay")).await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(resp.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"upstream");
+    }
+
+    #[tokio::test]
+    async fn mirror_requests() {
+        // upstream answers with its name, and reports requests it received
+        let start_upstream = |name: &'static str| {
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<(String, hyper::body::Bytes)>();
+            let addr = spawn_upstream(move |req: HyperRequest| {
+                let tx = tx.clone();
+                async move {
+                    let uri = req.uri().to_string();
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let _ = tx.send((uri, body));
+                    hyper::Response::new(Body::from(name))
+                }
+            });
+
+            (addr, rx)
+        };
+
+        let (primary_addr, _primary_rx) = start_upstream("primary");
+        let (shadow_addr, mut shadow_rx) = start_upstream("shadow");
+
+        let mirror = |uri: &str, mirror_upstream: &str| {
+            let config = serde_json::json!({
+                "upstream_id": mirror_upstream,
+                "include_body": true,
+            });
+            route_config(uri, "primary", &[("mirror", config)])
+        };
+        let registry = load_registry(
+            vec![mirror("/orders", "shadow"), mirror("/users", "down")],
+            vec![
+                upstream_config("primary", primary_addr),
+                upstream_config("shadow", shadow_addr),
+                upstream_config("down", "127.0.0.1:1".parse().unwrap()),
+            ],
+        );
+
+        let post = |uri: &str| {
+            let req = hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(uri)
+                .body(Body::from("hello"))
+                .unwrap();
+            let registry = &registry;
+
+            async move {
+                let resp = serve(registry, req).await;
+                hyper::body::to_bytes(resp.into_body()).await.unwrap()
+            }
+        };
+
+        assert_eq!(&post("/orders?id=1").await[..], b"primary");
+
+        let (uri, body) = tokio::time::timeout(Duration::from_secs(1), shadow_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(uri, "/orders?id=1");
+        assert_eq!(&body[..], b"hello");
+
+        // failed mirror leaves primary alone
+        assert_eq!(&post("/users").await[..], b"primary");
+    }
+
+    #[tokio::test]
+    async fn shared_request_body() {
+        // upstream reports body and content-length it received
+        let start_upstream = || {
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<(String, hyper::body::Bytes)>();
+            let addr = spawn_upstream(move |req: HyperRequest| {
+                let tx = tx.clone();
+                async move {
+                    let length = req
+                        .headers()
+                        .get(CONTENT_LENGTH)
+                        .map(|v| v.to_str().unwrap().to_string())
+                        .unwrap_or_default();
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let _ = tx.send((length, body));
+                    hyper::Response::new(Body::empty())
+                }
+            });
+
+            (addr, rx)
+        };
+
+        let (primary_addr, mut primary_rx) = start_upstream();
+        let (shadow_addr, mut shadow_rx) = start_upstream();
+
+        // both plugins read the body
+        let plugins = [
+            (
+                "request_validation",
+                serde_json::json!({ "schema": { "required": ["name"] } }),
+            ),
+            (
+                "mirror",
+                serde_json::json!({ "upstream_id": "shadow", "include_body": true }),
+            ),
+        ];
+        let registry = load_registry(
+            vec![route_config("/orders", "primary", &plugins)],
+            vec![
+                upstream_config("primary", primary_addr),
+                upstream_config("shadow", shadow_addr),
+            ],
+        );
+
+        // chunked, without content-length
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri("/orders")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::wrap_stream(futures::stream::iter(vec![
+                Ok::<_, std::io::Error>(r#"{"name": "#),
+                Ok(r#""hello"}"#),
+            ])))
+            .unwrap();
+        let resp = serve(&registry, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let expected = r#"{"name": "hello"}"#;
+        for rx in [&mut primary_rx, &mut shadow_rx] {
+            let (length, body) = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(length, expected.len().to_string());
+            assert_eq!(&body[..], expected.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn range_passthrough() {
+        const DOCUMENT: &str = r#"{"data":"0123456789"}"#;
+        const ETAG: &str = "\"v1\"";
+
+        // upstream serves single byte ranges, honoring `If-Range` by etag
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+
+            let range: Option<(usize, usize)> = header("range")
+                .filter(|_| header("if-range").map(|tag| tag == ETAG).unwrap_or(true))
+                .and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.split_once('-'))
+                .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)));
+
+            let builder = hyper::Response::builder()
+                .header("content-type", "application/json")
+                .header("accept-ranges", "bytes")
+                .header("etag", ETAG);
+
+            let resp = match range {
+                Some((start, end)) => builder
+                    .status(hyper::StatusCode::PARTIAL_CONTENT)
+                    .header(
+                        "content-range",
+                        format!("bytes {}-{}/{}", start, end, DOCUMENT.len()),
+                    )
+                    .body(Body::from(&DOCUMENT[start..=end])),
+                None => builder.body(Body::from(DOCUMENT)),
+            };
+
+            resp.unwrap()
+        });
+
+        // response plugins should leave partial bodies alone
+        let registry = registry_with_plugins(
+            "/download",
+            upstream_addr,
+            &[
+                (
+                    "response_template",
+                    serde_json::json!({ "template": { "digits": "$.data" } }),
+                ),
+                (
+                    "headers",
+                    serde_json::json!({ "response": { "set": { "x-gateway": "on" } } }),
+                ),
+            ],
+        );
+
+        let download = |headers: &[(&str, &str)]| {
+            let mut builder = hyper::Request::builder().uri("/download");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            let req = builder.body(Body::empty()).unwrap();
+            let registry = &registry;
+
+            async move {
+                let (parts, body) = serve(registry, req).await.into_parts();
+                (parts, hyper::body::to_bytes(body).await.unwrap())
+            }
+        };
+
+        let (parts, body) = download(&[("range", "bytes=9-12")]).await;
+        assert_eq!(parts.status, hyper::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(parts.headers["content-range"], "bytes 9-12/21");
+        assert_eq!(parts.headers["accept-ranges"], "bytes");
+        assert_eq!(parts.headers["x-gateway"], "on");
+        assert_eq!(&body[..], b"0123");
+
+        let (parts, body) = download(&[("range", "bytes=9-12"), ("if-range", ETAG)]).await;
+        assert_eq!(parts.status, hyper::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&body[..], b"0123");
+
+        // stale validator gets the full document, transformed as usual
+        let (parts, body) = download(&[("range", "bytes=9-12"), ("if-range", "\"v0\"")]).await;
+        assert_eq!(parts.status, hyper::StatusCode::OK);
+        assert!(!parts.headers.contains_key("content-range"));
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({ "digits": "0123456789" }));
+    }
+
+    #[tokio::test]
+    async fn upstream_host_header() {
+        // upstream echoes the host header it received
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            let host = req.headers().get("host").cloned();
+            let host = host.map(|h| h.as_bytes().to_vec()).unwrap_or_default();
+            hyper::Response::new(Body::from(host))
+        });
+
+        let route = |uri: &str, overwrite_host: bool, upstream_host: Option<&str>| RouteConfig {
+            overwrite_host,
+            upstream_host: upstream_host.map(String::from),
+            ..route_config(uri, "stub", &[])
+        };
+        let upstreams = vec![upstream_config("stub", upstream_addr)];
+
+        let registry = load_registry(
+            vec![
+                route("/keep", false, None),
+                route("/endpoint", true, None),
+                route("/custom", false, Some("assets.internal.example.com")),
+                route("/custom-first", true, Some("assets.internal.example.com")),
+            ],
+            upstreams.clone(),
+        );
+
+        let host = |path: &str| {
+            let req = hyper::Request::builder()
+                .uri(path)
+                .header("host", "gw.example.com")
+                .body(Body::empty())
+                .unwrap();
+            let registry = &registry;
+
+            async move {
+                let resp = serve(registry, req).await;
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        assert_eq!(host("/keep").await, "gw.example.com");
+        assert_eq!(host("/endpoint").await, upstream_addr.to_string());
+        assert_eq!(host("/custom").await, "assets.internal.example.com");
+        assert_eq!(host("/custom-first").await, "assets.internal.example.com");
+
+        let invalid = RegistryConfig {
+            routes: vec![route("/bad", false, Some("bad\nhost"))],
+            upstreams,
+            ..Default::default()
+        };
+        assert!(Registry::default().reload(invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn max_response_size() {
+        // upstream answers 40 bytes, or 120 bytes with or without content-length
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            let body = match req.uri().path() {
+                "/small" => Body::from(vec![b'a'; 40]),
+                "/declared" => Body::from(vec![b'a'; 120]),
+                _ => Body::wrap_stream(futures::stream::iter(
+                    (0..3).map(|_| Ok::<_, Infallible>(vec![b'a'; 40])),
+                )),
+            };
+            hyper::Response::new(body)
+        });
+
+        let registry = load_registry(
+            vec![RouteConfig {
+                max_response_size: 50,
+                ..route_config("/:size", "stub", &[])
+            }],
+            vec![upstream_config("stub", upstream_addr)],
+        );
+
+        let resp = serve(&registry, get("/small")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body.len(), 40);
+
+        let resp = serve(&registry, get("/declared")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            resp.headers()[crate::http::X_GATEWAY_ERROR],
+            "response_too_large"
+        );
+
+        // headers already sent, the body is cut
+        let resp = serve(&registry, get("/chunked")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(hyper::body::to_bytes(resp.into_body()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn short_circuit_response() {
+        let upstream_addr =
+            spawn_upstream(|_| async { hyper::Response::new(Body::from("upstream")) });
+
+        // headers runs before maintenance answers, and sees its response
+        let mut route = route_config(
+            "/closed",
+            "stub",
+            &[
+                (
+                    "headers",
+                    serde_json::json!({ "response": { "set": { "x-gateway": "on" } } }),
+                ),
+                ("maintenance", serde_json::json!({})),
+            ],
+        );
+        route.plugins.get_mut("headers").unwrap().order = Some(5000);
+        let registry = load_registry(vec![route], vec![upstream_config("stub", upstream_addr)]);
+
+        let resp = serve(&registry, get("/closed")).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()["x-gateway"], "on");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], br#"{"message":"service under maintenance"}"#);
+    }
+
+    #[tokio::test]
+    async fn normalize_path_after_routing() {
+        // upstream echoes the uri it received
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            hyper::Response::new(Body::from(req.uri().to_string()))
+        });
+
+        let registry = registry_with_plugins(
+            "/files/:name",
+            upstream_addr,
+            &[("normalize_path", serde_json::json!({}))],
+        );
+
+        let fetch = |path: &str| {
+            let req = get(path);
+            let registry = &registry;
+
+            async move {
+                let resp = serve(registry, req).await;
+                let status = resp.status();
+                let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                (status, String::from_utf8(body.to_vec()).unwrap())
+            }
+        };
+
+        assert_eq!(
+            fetch("/files/%7Ereport?v=1").await,
+            (StatusCode::OK, "/files/~report?v=1".to_string())
+        );
+
+        // routed by the raw path, the upstream gets the normalized one
+        assert_eq!(fetch("/files/%2e%2e").await, (StatusCode::OK, "/".to_string()));
+
+        // normalized path would match, but routing already happened on the raw one
+        assert_eq!(fetch("/files/x/../report").await.0, StatusCode::NOT_FOUND);
+    }
+
+    /// Fields recorded on `request` spans.
+    #[derive(Clone, Default)]
+    struct SpanFields(Arc<std::sync::Mutex<HashMap<String, String>>>);
+
+    impl tracing::field::Visit for SpanFields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            let value = format!("{:?}", value);
+            self.0.lock().unwrap().insert(field.name().to_string(), value);
+        }
+
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            let value = value.to_string();
+            self.0.lock().unwrap().insert(field.name().to_string(), value);
+        }
+    }
+
+    impl<S> tracing_subscriber::Layer<S> for SpanFields
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if attrs.metadata().name() == "request" {
+                attrs.record(&mut self.clone());
+            }
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            if ctx.span(id).map_or(false, |s| s.name() == "request") {
+                values.record(&mut self.clone());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn request_span() {
+        use opentelemetry::trace::TracerProvider as _;
+        use tracing_subscriber::prelude::*;
+
+        // upstream echoes the traceparent it received
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            let traceparent = req.headers().get("traceparent").cloned();
+            hyper::Response::new(
+                traceparent.map_or(Body::empty(), |v| Body::from(v.as_bytes().to_vec())),
+            )
+        });
+
+        let (reader, mut writer) = Registry::new_reader_writer();
+        writer.load_config(RegistryConfig {
+            routes: vec![RouteConfig {
+                id: "users".to_string(),
+                ..route_config("/users/:id", "echo", &[])
+            }],
+            upstreams: vec![upstream_config("echo", upstream_addr)],
+            ..Default::default()
+        });
+        writer.publish();
+
+        opentelemetry::global::set_text_map_propagator(
+            opentelemetry::sdk::propagation::TraceContextPropagator::new(),
+        );
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder().build();
+        let fields = SpanFields::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(fields.clone())
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let req = hyper::Request::builder()
+            .uri("/users/1?v=1")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .body(Body::empty())
+            .unwrap();
+        let mut svc = GatewayService::new(reader, None, Scheme::HTTP);
+        let resp = svc.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // upstream continues the trace of caller
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let traceparent = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            traceparent.starts_with("00-0af7651916cd43dd8448eb211c80319c-"),
+            "{}",
+            traceparent
+        );
+        assert_ne!(traceparent, "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01");
+
+        let fields = fields.0.lock().unwrap();
+        let expected = [
+            ("method", "GET".to_string()),
+            ("path", "/users/1".to_string()),
+            ("route_id", "users".to_string()),
+            ("upstream_id", "echo".to_string()),
+            ("status", "200".to_string()),
+        ];
+        for (name, value) in expected {
+            assert_eq!(fields.get(name), Some(&value), "{}", name);
+        }
+        assert!(fields["endpoint"].starts_with(&format!("http://{}", upstream_addr)));
+    }
+
+    /// Shares consumer named by `x-user`.
+    struct ConsumerStamp;
+
+    #[async_trait::async_trait]
+    impl Plugin for ConsumerStamp {
+        fn priority(&self) -> u32 {
+            2500
+        }
+
+        async fn on_access(
+            &self,
+            ctx: &mut GatewayContext,
+            req: HyperRequest,
+        ) -> Result<HyperRequest, HyperResponse> {
+            if let Some(user) = req.headers().get("x-user").and_then(|v| v.to_str().ok()) {
+                ctx.set(Consumer(user.to_string()));
+            }
+            Ok(req)
+        }
+    }
+
+    /// Reports values shared by other plugins and forwarder in response headers.
+    struct ConsumerEcho;
+
+    #[async_trait::async_trait]
+    impl Plugin for ConsumerEcho {
+        fn priority(&self) -> u32 {
+            100
+        }
+
+        async fn after_forward(
+            &self,
+            ctx: &mut GatewayContext,
+            mut resp: HyperResponse,
+        ) -> HyperResponse {
+            let consumer = ctx
+                .get::<Consumer>()
+                .map(|c| c.0.clone())
+                .unwrap_or_default();
+            let endpoint = ctx
+                .get::<SelectedEndpoint>()
+                .map(|e| e.0.to_string())
+                .unwrap_or_default();
+
+            let headers = resp.headers_mut();
+            headers.insert("x-consumer", consumer.parse().unwrap());
+            headers.insert("x-endpoint", endpoint.parse().unwrap());
+            resp
+        }
+    }
+
+    #[tokio::test]
+    async fn typed_context_values() {
+        register_plugin("consumer_stamp", |_| Ok(Box::new(ConsumerStamp))).unwrap();
+        register_plugin("consumer_echo", |_| Ok(Box::new(ConsumerEcho))).unwrap();
+
+        let upstream_addr = spawn_upstream(|_| async { hyper::Response::new(Body::empty()) });
+        let registry = registry_with_plugins(
+            "/users",
+            upstream_addr,
+            &[
+                ("consumer_stamp", serde_json::json!({})),
+                ("consumer_echo", serde_json::json!({})),
+            ],
+        );
+
+        let req = hyper::Request::builder()
+            .uri("/users")
+            .header("x-user", "alice")
+            .body(Body::empty())
+            .unwrap();
+        let resp = serve(&registry, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-consumer"], "alice");
+        let endpoint = resp.headers()["x-endpoint"].to_str().unwrap();
+        assert!(
+            endpoint.starts_with(&format!("http://{}", upstream_addr)),
+            "{}",
+            endpoint
+        );
+    }
+
+    struct SlowPlugin;
+
+    #[async_trait::async_trait]
+    impl Plugin for SlowPlugin {
+        fn name(&self) -> &str {
+            "slow"
+        }
+
+        fn priority(&self) -> u32 {
+            2000
+        }
+
+        async fn on_access(
+            &self,
+            _ctx: &mut GatewayContext,
+            req: HyperRequest,
+        ) -> Result<HyperRequest, HyperResponse> {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            Ok(req)
+        }
+
+        async fn after_forward(
+            &self,
+            _ctx: &mut GatewayContext,
+            resp: HyperResponse,
+        ) -> HyperResponse {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            resp
+        }
+    }
+
+    /// Reports timings recorded so far as `plugin.phase=ms;...` in `x-timings`.
+    struct TimingProbe;
+
+    #[async_trait::async_trait]
+    impl Plugin for TimingProbe {
+        fn name(&self) -> &str {
+            "timing_probe"
+        }
+
+        fn priority(&self) -> u32 {
+            0
+        }
+
+        async fn after_forward(
+            &self,
+            ctx: &mut GatewayContext,
+            mut resp: HyperResponse,
+        ) -> HyperResponse {
+            let timings = ctx.get::<PluginTimings>().map_or(Vec::new(), |t| {
+                t.0.iter()
+                    .map(|t| format!("{}.{}={}", t.plugin, t.phase, t.elapsed.as_millis()))
+                    .collect()
+            });
+
+            let timings = timings.join(";").parse().unwrap();
+            resp.headers_mut().insert("x-timings", timings);
+            resp
+        }
+    }
+
+    #[tokio::test]
+    async fn plugin_timings() {
+        register_plugin("slow", |_| Ok(Box::new(SlowPlugin))).unwrap();
+        register_plugin("timing_probe", |_| Ok(Box::new(TimingProbe))).unwrap();
+
+        let upstream_addr = spawn_upstream(|_| async { hyper::Response::new(Body::empty()) });
+        let registry = registry_with_plugins(
+            "/timed",
+            upstream_addr,
+            &[
+                ("slow", serde_json::json!({})),
+                ("timing_probe", serde_json::json!({})),
+            ],
+        );
+
+        let timed = || async {
+            let resp = serve(&registry, get("/timed")).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            resp.headers()
+                .get("x-timings")
+                .map(|v| v.to_str().unwrap().to_string())
+        };
+
+        crate::diagnostics::set_plugin_metrics(&PluginMetricsConfig {
+            enable: true,
+            slow_threshold: 10,
+        });
+        let timings = timed().await.unwrap();
+        crate::diagnostics::set_plugin_metrics(&PluginMetricsConfig::default());
+
+        // probe runs last, its own `after_forward` is not finished yet
+        let timings: HashMap<_, u128> = timings
+            .split(';')
+            .map(|t| {
+                let (name, ms) = t.split_once('=').unwrap();
+                (name.to_string(), ms.parse().unwrap())
+            })
+            .collect();
+        assert_eq!(timings.len(), 3, "{:?}", timings);
+        assert!(timings["slow.on_access"] >= 30, "{:?}", timings);
+        assert!(timings["slow.after_forward"] >= 30, "{:?}", timings);
+        assert!(timings["timing_probe.on_access"] < 30, "{:?}", timings);
+
+        // nothing recorded when disabled
+        assert_eq!(timed().await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn script_rewrites_request_body() {
+        // upstream answers the body it received, with its length
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            let length = req.headers()[CONTENT_LENGTH].clone();
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let mut resp = hyper::Response::new(Body::from(body));
+            resp.headers_mut().insert("x-length", length);
+            resp
+        });
+
+        let script = r#"
+            pub fn on_access(req) {
+                let body = req.json().unwrap();
+                body["via"] = "gateway";
+                req.set_body(body).unwrap();
+                Ok(req)
+            }
+        "#;
+        let registry = registry_with_plugins(
+            "/users",
+            upstream_addr,
+            &[(
+                "script",
+                serde_json::json!({ "script": script, "buffer_body": true }),
+            )],
+        );
+
+        let req = hyper::Request::builder()
+            .method("POST")
+            .uri("/users")
+            .header(CONTENT_LENGTH, "16")
+            .body(Body::from(r#"{"name":"alice"}"#))
+            .unwrap();
+        let resp = serve(&registry, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let length = resp.headers()["x-length"].clone();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(length, body.len().to_string().as_str());
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({ "name": "alice", "via": "gateway" })
+        );
+    }
+
+    #[tokio::test]
+    async fn script_rewrites_uri() {
+        // upstream answers the uri it received, and whether `x-internal` came along
+        let upstream_addr = spawn_upstream(|req: HyperRequest| async move {
+            let internal = req.headers().contains_key("x-internal").to_string();
+            let mut resp = hyper::Response::new(Body::from(req.uri().to_string()));
+            resp.headers_mut()
+                .insert("x-internal", internal.parse().unwrap());
+            resp
+        });
+
+        let script = r#"
+            pub fn on_access(req) {
+                if req.method() == "GET" && req.path() == "/users" {
+                    if req.query()["sort"] == "asc" {
+                        req.set_query_param("page", "2").unwrap();
+                    }
+                    req.set_path("/v2/users").unwrap();
+                    req.set_query_param("from", "script gw").unwrap();
+                    req.remove_header("x-internal");
+                }
+                Ok(req)
+            }
+        "#;
+        let registry = registry_with_plugins(
+            "/users",
+            upstream_addr,
+            &[("script", serde_json::json!({ "script": script }))],
+        );
+
+        let req = hyper::Request::builder()
+            .uri("/users?page=1&sort=asc")
+            .header("x-internal", "1")
+            .body(Body::empty())
+            .unwrap();
+        let resp = serve(&registry, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["x-internal"], "false");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"/v2/users?page=2&sort=asc&from=script+gw");
+    }
+}
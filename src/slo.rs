@@ -0,0 +1,277 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use hyper::{header::CONTENT_TYPE, Body, Client, Method, Request, StatusCode};
+use serde::{Deserialize, Serialize};
+
+/// buckets in a rolling window
+const BUCKETS: u64 = 60;
+
+lazy_static::lazy_static! {
+    static ref G_SLO_TRACKER: SloTracker = SloTracker::default();
+}
+
+pub fn slo_tracker() -> &'static SloTracker {
+    &G_SLO_TRACKER
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SloConfig {
+    /// availability objective, like 0.999
+    pub availability: f64,
+    /// requests slower than this in milliseconds count as bad, 0 means disabled
+    #[serde(default)]
+    pub latency: u64,
+    /// rolling window in seconds
+    #[serde(default = "default_window")]
+    pub window: u64,
+    /// alert when error budget burns faster than this rate, 0 means disabled
+    #[serde(default)]
+    pub burn_rate_alert: f64,
+    /// post the report to webhook when alerting
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+fn default_window() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SloReport {
+    pub route_id: String,
+    pub objective: f64,
+    pub total: u64,
+    pub bad: u64,
+    pub availability: f64,
+    /// ratio of error budget left, negative when exhausted
+    pub error_budget_remaining: f64,
+    /// 1.0 means burning exactly the whole budget in a window
+    pub burn_rate: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct SloTracker {
+    routes: RwLock<HashMap<String, Arc<Mutex<RouteSlo>>>>,
+}
+
+impl SloTracker {
+    pub fn record(&self, route_id: &str, cfg: &SloConfig, status: StatusCode, latency: Duration) {
+        let bad = status.is_server_error()
+            || (cfg.latency > 0 && latency > Duration::from_millis(cfg.latency));
+
+        let slo = self.routes.read().unwrap().get(route_id).cloned();
+        let slo = match slo {
+            Some(slo) => slo,
+            None => self
+                .routes
+                .write()
+                .unwrap()
+                .entry(route_id.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(RouteSlo::new(cfg.clone()))))
+                .clone(),
+        };
+
+        let mut slo = slo.lock().unwrap();
+        if &slo.cfg != cfg {
+            *slo = RouteSlo::new(cfg.clone());
+        }
+        slo.record(bad);
+
+        if let Some(report) = slo.check_alert(route_id) {
+            tracing::warn!(?report, "slo error budget burning too fast");
+
+            if let Some(ref webhook) = slo.cfg.webhook {
+                spawn_webhook(webhook.clone(), &report);
+            }
+        }
+    }
+
+    pub fn reports(&self) -> Vec<SloReport> {
+        let routes = self.routes.read().unwrap();
+
+        let mut reports = routes
+            .iter()
+            .map(|(route_id, slo)| slo.lock().unwrap().report(route_id))
+            .collect::<Vec<_>>();
+
+        reports.sort_unstable_by(|a, b| a.route_id.cmp(&b.route_id));
+        reports
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// bucket index since unix epoch
+    index: u64,
+    total: u64,
+    bad: u64,
+}
+
+#[derive(Debug)]
+struct RouteSlo {
+    cfg: SloConfig,
+    bucket_secs: u64,
+    buckets: VecDeque<Bucket>,
+    last_alert: Option<Instant>,
+}
+
+impl RouteSlo {
+    fn new(cfg: SloConfig) -> Self {
+        let bucket_secs = (cfg.window / BUCKETS).max(1);
+
+        RouteSlo {
+            cfg,
+            bucket_secs,
+            buckets: VecDeque::with_capacity(BUCKETS as usize),
+            last_alert: None,
+        }
+    }
+
+    fn current_index(&self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        now / self.bucket_secs
+    }
+
+    fn expire(&mut self, index: u64) {
+        while let Some(front) = self.buckets.front() {
+            if front.index + BUCKETS <= index {
+                self.buckets.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn record(&mut self, bad: bool) {
+        let index = self.current_index();
+        self.expire(index);
+
+        match self.buckets.back_mut() {
+            Some(bucket) if bucket.index == index => {
+                bucket.total += 1;
+                bucket.bad += bad as u64;
+            }
+            _ => self.buckets.push_back(Bucket {
+                index,
+                total: 1,
+                bad: bad as u64,
+            }),
+        }
+    }
+
+    fn report(&mut self, route_id: &str) -> SloReport {
+        self.expire(self.current_index());
+
+        let (total, bad) = self
+            .buckets
+            .iter()
+            .fold((0, 0), |(t, b), bucket| (t + bucket.total, b + bucket.bad));
+
+        let error_rate = if total == 0 {
+            0.0
+        } else {
+            bad as f64 / total as f64
+        };
+        let budget = (1.0 - self.cfg.availability).max(f64::EPSILON);
+        let burn_rate = error_rate / budget;
+
+        SloReport {
+            route_id: route_id.to_string(),
+            objective: self.cfg.availability,
+            total,
+            bad,
+            availability: 1.0 - error_rate,
+            error_budget_remaining: 1.0 - burn_rate,
+            burn_rate,
+        }
+    }
+
+    /// Report when burn rate exceeds the alert threshold, at most once per bucket.
+    fn check_alert(&mut self, route_id: &str) -> Option<SloReport> {
+        if self.cfg.burn_rate_alert <= 0.0 {
+            return None;
+        }
+
+        let cooldown = Duration::from_secs(self.bucket_secs);
+        if matches!(self.last_alert, Some(t) if t.elapsed() < cooldown) {
+            return None;
+        }
+
+        let report = self.report(route_id);
+        if report.burn_rate < self.cfg.burn_rate_alert {
+            return None;
+        }
+
+        self.last_alert = Some(Instant::now());
+        Some(report)
+    }
+}
+
+/// Post `payload` as json to `url` in background, failures are only logged.
+pub(crate) fn spawn_webhook<T: Serialize>(url: String, payload: &T) {
+    let body = serde_json::to_vec(payload).unwrap_or_default();
+
+    tokio::spawn(async move {
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client: Client<_, Body> = Client::builder().build(https);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body));
+
+        let ret = match req {
+            Ok(req) => client.request(req).await.map(|_| ()).map_err(|e| e.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+
+        if let Err(err) = ret {
+            tracing::error!(%err, %url, "post webhook failed");
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_budget() {
+        let cfg = SloConfig {
+            availability: 0.9,
+            latency: 100,
+            window: 3600,
+            burn_rate_alert: 0.0,
+            webhook: None,
+        };
+
+        let tracker = SloTracker::default();
+        for _ in 0..18 {
+            tracker.record("r1", &cfg, StatusCode::OK, Duration::from_millis(10));
+        }
+        tracker.record("r1", &cfg, StatusCode::BAD_GATEWAY, Duration::from_millis(10));
+        tracker.record("r1", &cfg, StatusCode::OK, Duration::from_millis(200));
+
+        let reports = tracker.reports();
+        assert_eq!(reports.len(), 1);
+
+        let report = &reports[0];
+        assert_eq!((report.total, report.bad), (20, 2));
+        assert!((report.availability - 0.9).abs() < 1e-9);
+        assert!((report.burn_rate - 1.0).abs() < 1e-9);
+        assert!(report.error_budget_remaining.abs() < 1e-9);
+    }
+}
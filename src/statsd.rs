@@ -0,0 +1,447 @@
+//! Request metrics pushed to StatsD, or DogStatsD with tags.
+//!
+//! Metrics are queued to a sender thread and batched into packets, dropped when
+//! the queue is full or sending fails, requests never wait on them.
+
+use std::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender},
+        RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::budget::{memory_budget, MemoryBudget};
+use crate::error::ConfigError;
+
+/// ethernet MTU minus IP and UDP headers
+const MAX_PACKET_SIZE: usize = 1432;
+const QUEUE_SIZE: usize = 4096;
+/// memory budget sampled this often, sent when changed
+const GAUGE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StatsdConfig {
+    /// like `127.0.0.1:8125`
+    pub addr: String,
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// ratio of requests reported
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// send route and status as DogStatsD tags, otherwise as parts of metric name
+    #[serde(default)]
+    pub dogstatsd: bool,
+    /// max milliseconds a metric waits for its packet to fill
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: u64,
+}
+
+fn default_prefix() -> String {
+    "gateway".to_string()
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_flush_interval() -> u64 {
+    1000
+}
+
+lazy_static::lazy_static! {
+    static ref G_STATSD: RwLock<Option<StatsdSink>> = RwLock::new(None);
+}
+
+/// Start sending metrics to `cfg`, or stop when `None`.
+pub fn set_statsd(cfg: Option<&StatsdConfig>) -> Result<(), ConfigError> {
+    let sink = cfg.map(|cfg| StatsdSink::new(cfg.clone())).transpose()?;
+
+    *G_STATSD.write().unwrap() = sink;
+
+    Ok(())
+}
+
+/// Count request and its latency, `route_id` is `None` when no route matched.
+pub fn record_request(route_id: Option<&str>, status: StatusCode, latency: Duration) {
+    if let Some(sink) = G_STATSD.read().unwrap().as_ref() {
+        sink.record(route_id, status, latency);
+    }
+}
+
+/// Time spent by plugin in `phase`, and rejection when it answered the request itself.
+pub fn record_plugin(
+    route_id: &str,
+    plugin: &str,
+    phase: &str,
+    elapsed: Duration,
+    short_circuited: bool,
+) {
+    if let Some(sink) = G_STATSD.read().unwrap().as_ref() {
+        sink.record_plugin(route_id, plugin, phase, elapsed, short_circuited);
+    }
+}
+
+/// Reservation refused by exhausted memory budget.
+pub fn record_budget_rejection() {
+    if let Some(sink) = G_STATSD.read().unwrap().as_ref() {
+        let _ = sink
+            .tx
+            .try_send(format!("{}.memory_budget.rejections:1|c", sink.cfg.prefix));
+    }
+}
+
+struct StatsdSink {
+    cfg: StatsdConfig,
+    tx: SyncSender<String>,
+}
+
+impl StatsdSink {
+    fn new(cfg: StatsdConfig) -> Result<Self, ConfigError> {
+        if !(cfg.sample_rate > 0.0 && cfg.sample_rate <= 1.0) {
+            return Err(ConfigError::Message(format!(
+                "invalid statsd sample_rate<{}>",
+                cfg.sample_rate
+            )));
+        }
+
+        let addr =
+            cfg.addr.to_socket_addrs()?.next().ok_or_else(|| {
+                ConfigError::Message(format!("invalid statsd addr<{}>", cfg.addr))
+            })?;
+
+        let local = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
+        let interval = Duration::from_millis(cfg.flush_interval);
+        let prefix = cfg.prefix.clone();
+        std::thread::Builder::new()
+            .name("statsd".to_string())
+            .spawn(move || flush_loop(socket, rx, interval, &prefix))?;
+
+        Ok(StatsdSink { cfg, tx })
+    }
+
+    fn record(&self, route_id: Option<&str>, status: StatusCode, latency: Duration) {
+        if self.cfg.sample_rate < 1.0 && rand::random::<f64>() >= self.cfg.sample_rate {
+            return;
+        }
+
+        for line in self.lines(route_id, status, latency) {
+            // queue full, drop the metric rather than block
+            let _ = self.tx.try_send(line);
+        }
+    }
+
+    fn record_plugin(
+        &self,
+        route_id: &str,
+        plugin: &str,
+        phase: &str,
+        elapsed: Duration,
+        short_circuited: bool,
+    ) {
+        if self.cfg.sample_rate < 1.0 && rand::random::<f64>() >= self.cfg.sample_rate {
+            return;
+        }
+
+        for line in self.plugin_lines(route_id, plugin, phase, elapsed, short_circuited) {
+            let _ = self.tx.try_send(line);
+        }
+    }
+
+    fn plugin_lines(
+        &self,
+        route_id: &str,
+        plugin: &str,
+        phase: &str,
+        elapsed: Duration,
+        short_circuited: bool,
+    ) -> Vec<String> {
+        let prefix = &self.cfg.prefix;
+        let route = sanitize(route_id);
+        let plugin = sanitize(plugin);
+        let us = elapsed.as_micros();
+
+        let rate = if self.cfg.sample_rate < 1.0 {
+            format!("|@{}", self.cfg.sample_rate)
+        } else {
+            String::new()
+        };
+
+        let mut lines = Vec::with_capacity(2);
+        if self.cfg.dogstatsd {
+            let tags = format!("|#route:{},plugin:{},phase:{}", route, plugin, phase);
+            lines.push(format!("{}.plugin_us:{}|ms{}{}", prefix, us, rate, tags));
+            if short_circuited {
+                lines.push(format!("{}.plugin_rejections:1|c{}{}", prefix, rate, tags));
+            }
+        } else {
+            let name = format!("{}.{}.{}", route, plugin, phase);
+            lines.push(format!("{}.plugin_us.{}:{}|ms{}", prefix, name, us, rate));
+            if short_circuited {
+                lines.push(format!("{}.plugin_rejections.{}:1|c{}", prefix, name, rate));
+            }
+        }
+
+        lines
+    }
+
+    fn lines(&self, route_id: Option<&str>, status: StatusCode, latency: Duration) -> [String; 2] {
+        let prefix = &self.cfg.prefix;
+        let route = sanitize(route_id.unwrap_or("unmatched"));
+        let class = format!("{}xx", status.as_u16() / 100);
+        let ms = latency.as_millis();
+
+        let rate = if self.cfg.sample_rate < 1.0 {
+            format!("|@{}", self.cfg.sample_rate)
+        } else {
+            String::new()
+        };
+
+        if self.cfg.dogstatsd {
+            let tags = format!("|#route:{},status:{}", route, class);
+            [
+                format!("{}.requests:1|c{}{}", prefix, rate, tags),
+                format!("{}.latency_ms:{}|ms{}{}", prefix, ms, rate, tags),
+            ]
+        } else {
+            [
+                format!("{}.requests.{}.{}:1|c{}", prefix, route, class, rate),
+                format!("{}.latency_ms.{}:{}|ms{}", prefix, route, ms, rate),
+            ]
+        }
+    }
+}
+
+/// Keep route ids from breaking the line format, like `.`, `:` or `|`.
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Batch lines into packets, sent when full or `interval` after their first line.
+fn flush_loop(socket: UdpSocket, rx: Receiver<String>, interval: Duration, prefix: &str) {
+    let mut packet = String::with_capacity(MAX_PACKET_SIZE);
+    let mut deadline: Option<Instant> = None;
+    let mut budget_used = None;
+    let mut next_gauges = Instant::now();
+
+    loop {
+        let now = Instant::now();
+        if now >= next_gauges {
+            let budget = memory_budget();
+            if budget_used != Some(budget.used()) {
+                budget_used = Some(budget.used());
+                for line in budget_lines(prefix, budget) {
+                    push(&socket, &mut packet, &mut deadline, interval, line);
+                }
+            }
+            next_gauges = now + GAUGE_INTERVAL;
+        }
+
+        if deadline.map_or(false, |d| now >= d) {
+            send(&socket, &mut packet);
+            deadline = None;
+        }
+
+        let wake = deadline.map_or(next_gauges, |d| d.min(next_gauges));
+        match rx.recv_timeout(wake.saturating_duration_since(Instant::now())) {
+            Ok(line) => push(&socket, &mut packet, &mut deadline, interval, line),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                send(&socket, &mut packet);
+                return;
+            }
+        }
+    }
+}
+
+/// Bytes used of memory budget and its pressure, as gauges.
+fn budget_lines(prefix: &str, budget: &MemoryBudget) -> [String; 2] {
+    [
+        format!("{}.memory_budget.used:{}|g", prefix, budget.used()),
+        format!(
+            "{}.memory_budget.pressure:{:.3}|g",
+            prefix,
+            budget.pressure()
+        ),
+    ]
+}
+
+/// Add `line` to packet, sending it first when full.
+fn push(
+    socket: &UdpSocket,
+    packet: &mut String,
+    deadline: &mut Option<Instant>,
+    interval: Duration,
+    line: String,
+) {
+    if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+        send(socket, packet);
+        *deadline = None;
+    }
+    if !packet.is_empty() {
+        packet.push('\n');
+    }
+    packet.push_str(&line);
+    deadline.get_or_insert_with(|| Instant::now() + interval);
+}
+
+fn send(socket: &UdpSocket, packet: &mut String) {
+    if packet.is_empty() {
+        return;
+    }
+
+    // no statsd listening or buffer full, metrics are best effort
+    if let Err(err) = socket.send(packet.as_bytes()) {
+        tracing::trace!(?err, "send statsd packet failed");
+    }
+    packet.clear();
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+    use crate::config::{PluginConfig, RouteConfig, UpstreamConfig};
+    use crate::context::GatewayContext;
+    use crate::registry::{Registry, RegistryConfig};
+    use crate::services::GatewayService;
+
+    #[tokio::test]
+    async fn statsd_metrics() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut route = RouteConfig {
+            id: "statsd.test".to_string(),
+            name: "statsd".to_string(),
+            uris: vec!["/statsd-test".to_string()],
+            upstream_id: "upstream-001".to_string(),
+            ..Default::default()
+        };
+        route.plugins.insert(
+            "mock".to_string(),
+            PluginConfig {
+                enable: true,
+                when: None,
+                order: None,
+                config: serde_json::json!({ "body": "ok" }),
+            },
+        );
+        let cfg = RegistryConfig {
+            routes: vec![route],
+            upstreams: vec![UpstreamConfig {
+                id: "upstream-001".to_string(),
+                name: "upstream-001".to_string(),
+                strategy: "random".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let mut registry = Registry::default();
+        registry.reload(cfg).unwrap();
+        let registry = &registry;
+
+        set_statsd(Some(&StatsdConfig {
+            addr: listener.local_addr().unwrap().to_string(),
+            prefix: "gw".to_string(),
+            sample_rate: 1.0,
+            dogstatsd: true,
+            flush_interval: 10,
+        }))
+        .unwrap();
+
+        let req = hyper::Request::builder()
+            .uri("/statsd-test")
+            .body(Body::empty())
+            .unwrap();
+        let ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        let resp = GatewayService::serve(&registry.router, &registry.upstreams, ctx, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // other tests may serve requests meanwhile, look for lines of this one
+        let mut lines = Vec::new();
+        let mut buf = [0; MAX_PACKET_SIZE];
+        while lines.len() < 2 {
+            let n = listener.recv(&mut buf).unwrap();
+            let packet = String::from_utf8(buf[..n].to_vec()).unwrap();
+            lines.extend(
+                packet
+                    .lines()
+                    .filter(|l| l.ends_with("|#route:statsd_test,status:2xx"))
+                    .map(str::to_string),
+            );
+        }
+        set_statsd(None).unwrap();
+
+        assert_eq!(lines[0], "gw.requests:1|c|#route:statsd_test,status:2xx");
+        let latency = lines[1]
+            .strip_prefix("gw.latency_ms:")
+            .and_then(|l| l.strip_suffix("|ms|#route:statsd_test,status:2xx"))
+            .unwrap();
+        assert!(latency.parse::<u64>().is_ok(), "{}", lines[1]);
+
+        let sink = StatsdSink::new(StatsdConfig {
+            addr: listener.local_addr().unwrap().to_string(),
+            prefix: "gw".to_string(),
+            sample_rate: 0.5,
+            dogstatsd: false,
+            flush_interval: 10,
+        })
+        .unwrap();
+        assert_eq!(
+            sink.lines(None, StatusCode::NOT_FOUND, Duration::from_millis(12)),
+            [
+                "gw.requests.unmatched.4xx:1|c|@0.5",
+                "gw.latency_ms.unmatched:12|ms|@0.5"
+            ]
+        );
+        assert_eq!(
+            sink.plugin_lines(
+                "users",
+                "key_auth",
+                "on_access",
+                Duration::from_micros(42),
+                true
+            ),
+            [
+                "gw.plugin_us.users.key_auth.on_access:42|ms|@0.5",
+                "gw.plugin_rejections.users.key_auth.on_access:1|c|@0.5"
+            ]
+        );
+
+        let budget = MemoryBudget::new(100);
+        let _permit = budget.try_reserve(25).unwrap();
+        assert_eq!(
+            budget_lines("gw", &budget),
+            [
+                "gw.memory_budget.used:25|g",
+                "gw.memory_budget.pressure:0.250|g"
+            ]
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::{memory::Entry, MemoryStore, Store};
+use crate::error::StoreError;
+
+/// Memory store persisted to a json file after every change.
+pub struct FileStore {
+    path: PathBuf,
+    memory: MemoryStore,
+    /// keep file writes in order
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl FileStore {
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref().to_path_buf();
+
+        let entries: HashMap<String, Entry> = if path.is_file() {
+            serde_json::from_slice(&tokio::fs::read(&path).await?)?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(FileStore {
+            path,
+            memory: MemoryStore::with_entries(entries),
+            write_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    async fn persist(&self) -> Result<(), StoreError> {
+        let _guard = self.write_lock.lock().await;
+
+        let data = serde_json::to_vec(&self.memory.snapshot())?;
+        tokio::fs::write(&self.path, data).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for FileStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.memory.get_sync(key))
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), StoreError> {
+        self.memory.set_sync(key, value, ttl);
+        self.persist().await
+    }
+
+    async fn incr(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64, StoreError> {
+        let count = self.memory.incr_sync(key, delta, ttl)?;
+        self.persist().await?;
+
+        Ok(count)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        self.memory.delete_sync(key);
+        self.persist().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_store_reopen() {
+        let path = std::env::temp_dir().join(format!("apireception-store-{}.json", rand::random::<u32>()));
+
+        let store = FileStore::open(&path).await.unwrap();
+        store.set("k", b"v".to_vec(), None).await.unwrap();
+        store.incr("c", 3, None).await.unwrap();
+
+        let store = FileStore::open(&path).await.unwrap();
+        assert_eq!(store.get("k").await.unwrap(), Some(b"v".to_vec()));
+        assert_eq!(store.incr("c", 1, None).await.unwrap(), 4);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::Store;
+use crate::error::StoreError;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(super) struct Entry {
+    pub value: Vec<u8>,
+    pub expire_at: Option<SystemTime>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        matches!(self.expire_at, Some(t) if t <= now)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    pub(super) fn with_entries(entries: HashMap<String, Entry>) -> Self {
+        MemoryStore {
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Live entries, expired ones are purged.
+    pub(super) fn snapshot(&self) -> HashMap<String, Entry> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let now = SystemTime::now();
+        entries.retain(|_, e| !e.is_expired(now));
+
+        entries.clone()
+    }
+
+    pub(super) fn get_sync(&self, key: &str) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(key) {
+            Some(e) if e.is_expired(SystemTime::now()) => {
+                entries.remove(key);
+                None
+            }
+            Some(e) => Some(e.value.clone()),
+            None => None,
+        }
+    }
+
+    pub(super) fn set_sync(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) {
+        let entry = Entry {
+            value,
+            expire_at: ttl.map(|ttl| SystemTime::now() + ttl),
+        };
+
+        self.entries.lock().unwrap().insert(key.to_string(), entry);
+    }
+
+    pub(super) fn incr_sync(
+        &self,
+        key: &str,
+        delta: i64,
+        ttl: Option<Duration>,
+    ) -> Result<i64, StoreError> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = SystemTime::now();
+
+        let entry = match entries.get_mut(key) {
+            Some(e) if !e.is_expired(now) => e,
+            _ => {
+                entries.insert(
+                    key.to_string(),
+                    Entry {
+                        value: b"0".to_vec(),
+                        expire_at: ttl.map(|ttl| now + ttl),
+                    },
+                );
+                entries.get_mut(key).unwrap()
+            }
+        };
+
+        let count = std::str::from_utf8(&entry.value)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or_else(|| StoreError::Message(format!("value of {} is not a counter", key)))?
+            + delta;
+
+        entry.value = count.to_string().into_bytes();
+
+        Ok(count)
+    }
+
+    pub(super) fn delete_sync(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        Ok(self.get_sync(key))
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), StoreError> {
+        self.set_sync(key, value, ttl);
+        Ok(())
+    }
+
+    async fn incr(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64, StoreError> {
+        self.incr_sync(key, delta, ttl)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        self.delete_sync(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_store() {
+        let store = MemoryStore::new();
+
+        store.set("k", b"v".to_vec(), None).await.unwrap();
+        assert_eq!(store.get("k").await.unwrap(), Some(b"v".to_vec()));
+
+        assert_eq!(store.incr("c", 2, None).await.unwrap(), 2);
+        assert_eq!(store.incr("c", -1, None).await.unwrap(), 1);
+        assert!(store.incr("k", 1, None).await.is_err());
+
+        store
+            .set("t", b"v".to_vec(), Some(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(store.get("t").await.unwrap(), None);
+
+        store.delete("k").await.unwrap();
+        assert_eq!(store.get("k").await.unwrap(), None);
+    }
+}
@@ -0,0 +1,75 @@
+mod file;
+mod memory;
+#[cfg(feature = "redis-store")]
+mod redis_store;
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::StoreError;
+
+pub use self::file::FileStore;
+pub use self::memory::MemoryStore;
+#[cfg(feature = "redis-store")]
+pub use self::redis_store::RedisStore;
+
+lazy_static::lazy_static! {
+    static ref G_STORE: RwLock<Arc<dyn Store>> = RwLock::new(Arc::new(MemoryStore::new()));
+}
+
+/// Shared state store for stateful plugins.
+#[async_trait::async_trait]
+pub trait Store: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError>;
+
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>)
+        -> Result<(), StoreError>;
+
+    /// Add `delta` to counter, `ttl` only applied when the counter is created.
+    async fn incr(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64, StoreError>;
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError>;
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreConfig {
+    Memory,
+    File { path: PathBuf },
+    Redis { url: String },
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig::Memory
+    }
+}
+
+/// Init global store from config.
+pub async fn init_store(cfg: &StoreConfig) -> Result<(), StoreError> {
+    let store: Arc<dyn Store> = match cfg {
+        StoreConfig::Memory => Arc::new(MemoryStore::new()),
+        StoreConfig::File { path } => Arc::new(FileStore::open(path).await?),
+        #[cfg(feature = "redis-store")]
+        StoreConfig::Redis { url } => Arc::new(RedisStore::connect(url).await?),
+        #[cfg(not(feature = "redis-store"))]
+        StoreConfig::Redis { .. } => {
+            return Err(StoreError::Message(
+                "build without `redis-store` feature".to_string(),
+            ))
+        }
+    };
+
+    *G_STORE.write().unwrap() = store;
+
+    Ok(())
+}
+
+pub fn store() -> Arc<dyn Store> {
+    G_STORE.read().unwrap().clone()
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use redis::{aio::ConnectionManager, AsyncCommands};
+
+use super::Store;
+use crate::error::StoreError;
+
+pub struct RedisStore {
+    conn: ConnectionManager,
+}
+
+impl RedisStore {
+    pub async fn connect(url: &str) -> Result<Self, StoreError> {
+        let client = redis::Client::open(url)?;
+        let conn = ConnectionManager::new(client).await?;
+
+        Ok(RedisStore { conn })
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for RedisStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StoreError> {
+        let mut conn = self.conn.clone();
+
+        Ok(conn.get(key).await?)
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.conn.clone();
+
+        match ttl {
+            Some(ttl) => conn.pset_ex(key, value, ttl.as_millis() as usize).await?,
+            None => conn.set(key, value).await?,
+        }
+
+        Ok(())
+    }
+
+    async fn incr(&self, key: &str, delta: i64, ttl: Option<Duration>) -> Result<i64, StoreError> {
+        let mut conn = self.conn.clone();
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        if let Some(ttl) = ttl {
+            // create counter with ttl, keep it when exists
+            pipe.cmd("SET")
+                .arg(key)
+                .arg(0)
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .arg("NX")
+                .ignore();
+        }
+        pipe.incr(key, delta);
+
+        let (count,): (i64,) = pipe.query_async(&mut conn).await?;
+
+        Ok(count)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn.clone();
+
+        conn.del(key).await?;
+
+        Ok(())
+    }
+}
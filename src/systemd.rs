@@ -0,0 +1,199 @@
+//! Systemd integration, socket activation and `sd_notify` readiness.
+//!
+//! Both are driven by environment variables set by systemd, and are no-op when absent.
+
+use std::time::Duration;
+
+/// First file descriptor passed by socket activation.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+lazy_static::lazy_static! {
+    static ref G_LISTEN_FDS: std::sync::Mutex<Vec<(String, std::net::TcpListener)>> =
+        std::sync::Mutex::new(take_listen_fds());
+}
+
+/// Take the listener passed by socket activation for `name`, like `http` or `https`.
+///
+/// Sockets are matched by `FileDescriptorName=` of socket unit, or in order of
+/// `http`, `https` when unnamed.
+pub fn take_listener(name: &str) -> Option<std::net::TcpListener> {
+    let mut fds = G_LISTEN_FDS.lock().unwrap();
+    let index = fds.iter().position(|(n, _)| n == name)?;
+
+    Some(fds.remove(index).1)
+}
+
+#[cfg(unix)]
+fn take_listen_fds() -> Vec<(String, std::net::TcpListener)> {
+    use std::os::unix::io::FromRawFd;
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let count = std::env::var("LISTEN_FDS").ok();
+    let names = std::env::var("LISTEN_FDNAMES").ok();
+
+    // not inherited by child processes
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    match pid.and_then(|pid| pid.parse::<u32>().ok()) {
+        Some(pid) if pid == std::process::id() => {}
+        _ => return Vec::new(),
+    }
+
+    let count = count.and_then(|c| c.parse::<i32>().ok()).unwrap_or(0);
+
+    fd_names(names.as_deref(), count)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, name)| {
+            let fd = LISTEN_FDS_START + i as i32;
+            // SAFETY: fds from `LISTEN_FDS_START` are passed to this process by systemd
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+
+            match listener.set_nonblocking(true) {
+                Ok(_) => {
+                    tracing::info!(fd, %name, addr = ?listener.local_addr(), "inherit listener");
+                    Some((name, listener))
+                }
+                Err(err) => {
+                    tracing::warn!(fd, %name, %err, "invalid inherited listener");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn take_listen_fds() -> Vec<(String, std::net::TcpListener)> {
+    Vec::new()
+}
+
+/// Names of passed fds, `LISTEN_FDNAMES` is colon separated, `unknown` for unnamed ones.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn fd_names(names: Option<&str>, count: i32) -> Vec<String> {
+    let names: Vec<&str> = names.map(|n| n.split(':').collect()).unwrap_or_default();
+    let default_names = ["http", "https"];
+
+    (0..count.max(0) as usize)
+        .map(|i| match names.get(i) {
+            Some(name) if !name.is_empty() && *name != "unknown" => name.to_string(),
+            _ => default_names.get(i).unwrap_or(&"unknown").to_string(),
+        })
+        .collect()
+}
+
+/// Send state to service manager, like `READY=1`.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        let path = match std::env::var("NOTIFY_SOCKET") {
+            Ok(path) if !path.is_empty() => path,
+            _ => return,
+        };
+
+        if let Err(err) = notify_to(&path, state) {
+            tracing::warn!(%path, %err, state, "sd_notify failed");
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn notify_to(path: &str, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "abstract socket not support on this platform",
+            ));
+        }
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Send `WATCHDOG=1` at half of `WATCHDOG_USEC` when watchdog enabled.
+pub fn spawn_watchdog() {
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+
+    tracing::info!(?interval, "systemd watchdog enabled");
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn listen_fd_names() {
+        assert_eq!(fd_names(None, 2), vec!["http", "https"]);
+        assert_eq!(fd_names(Some("https:http"), 2), vec!["https", "http"]);
+        assert_eq!(
+            fd_names(Some("unknown:admin"), 3),
+            vec!["http", "admin", "unknown"]
+        );
+        assert!(fd_names(None, 0).is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notify_socket() {
+        let path = std::env::temp_dir().join(format!("apireception-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,183 @@
+//! OpenTelemetry export of request spans, and W3C trace context propagation.
+//!
+//! Without `telemetry` config no layer nor propagator is installed, trace headers
+//! of client pass to upstream untouched.
+
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    HeaderMap,
+};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    sdk::{
+        propagation::TraceContextPropagator,
+        trace::{self, Sampler},
+        Resource,
+    },
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use serde::{Deserialize, Serialize};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{filter::LevelFilter, registry::LookupSpan, Layer};
+
+use crate::error::ConfigError;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TelemetryConfig {
+    /// OTLP grpc endpoint, like `http://127.0.0.1:4317`
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    /// ratio of traces started by gateway sampled, sampled traces of callers are followed
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+fn default_service_name() -> String {
+    "apireception".to_string()
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+/// Layer exporting spans to `cfg.endpoint`, must be called within tokio runtime.
+pub fn layer<S>(cfg: &TelemetryConfig) -> Result<impl Layer<S>, ConfigError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if !(0.0..=1.0).contains(&cfg.sample_rate) {
+        return Err(ConfigError::Message(format!(
+            "invalid telemetry sample_rate<{}>",
+            cfg.sample_rate
+        )));
+    }
+
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(cfg.sample_rate)));
+    let resource = Resource::new(vec![KeyValue::new(
+        "service.name",
+        cfg.service_name.clone(),
+    )]);
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&cfg.endpoint),
+        )
+        .with_trace_config(
+            trace::config()
+                .with_sampler(sampler)
+                .with_resource(resource),
+        )
+        .install_batch(opentelemetry::sdk::runtime::Tokio)
+        .map_err(|err| ConfigError::Message(format!("init telemetry error: {}", err)))?;
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(LevelFilter::INFO))
+}
+
+/// Export spans not sent yet, blocks until done.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl<'a> Injector for HeaderInjector<'a> {
+    fn set(&mut self, key: &str, value: String) {
+        let name = HeaderName::from_bytes(key.as_bytes());
+        let value = HeaderValue::from_str(&value);
+
+        if let (Ok(name), Ok(value)) = (name, value) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Continue trace of caller in `span`, from `traceparent` and `tracestate`.
+pub fn set_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let parent = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+
+    span.set_parent(parent);
+}
+
+/// Pass trace of current span to upstream, replacing the one of caller.
+pub fn inject_context(headers: &mut HeaderMap) {
+    let cx = tracing::Span::current().context();
+
+    global::get_text_map_propagator(|p| p.inject_context(&cx, &mut HeaderInjector(headers)));
+}
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::trace::TracerProvider as _;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    #[test]
+    fn trace_propagation() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let provider = trace::TracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut incoming = HeaderMap::new();
+            incoming.insert("traceparent", HeaderValue::from_static(TRACEPARENT));
+            incoming.insert("tracestate", HeaderValue::from_static("congo=t61rcWkgMzE"));
+
+            let span = tracing::info_span!("request");
+            set_parent(&span, &incoming);
+            let enter = span.enter();
+
+            // upstream continues the trace of caller, as child of gateway span
+            let mut outgoing = incoming.clone();
+            inject_context(&mut outgoing);
+            let traceparent = outgoing["traceparent"].to_str().unwrap();
+            assert!(
+                traceparent.starts_with(&format!("00-{}-", TRACE_ID)),
+                "{}",
+                traceparent
+            );
+            assert!(traceparent.ends_with("-01"), "{}", traceparent);
+            assert_ne!(traceparent, TRACEPARENT);
+            assert_eq!(outgoing["tracestate"], "congo=t61rcWkgMzE");
+            drop(enter);
+
+            // a new trace without caller
+            let span = tracing::info_span!("request");
+            set_parent(&span, &HeaderMap::new());
+            let _enter = span.enter();
+
+            let mut outgoing = HeaderMap::new();
+            inject_context(&mut outgoing);
+            let traceparent = outgoing["traceparent"].to_str().unwrap();
+            assert!(!traceparent.contains(TRACE_ID), "{}", traceparent);
+        });
+    }
+}
@@ -0,0 +1,774 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use base64::Engine;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::{
+    self,
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    server::{
+        ClientHello, ProducesTickets, ResolvesServerCert, ResolvesServerCertUsingSni,
+        ServerSessionMemoryCache, StoresServerSessions,
+    },
+    sign::CertifiedKey,
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
+
+use x509_parser::{
+    der_parser::asn1_rs::{Any, Class, FromDer, Tag},
+    time::ASN1Time,
+};
+
+use crate::config::{ServerConfig, TlsConfig, TlsProvider, TlsSessionConfig};
+use crate::error::ConfigError;
+
+lazy_static::lazy_static! {
+    static ref G_TLS_STATS: TlsStats = TlsStats::default();
+    static ref G_TLS_PROVIDER: RwLock<TlsProvider> = RwLock::new(TlsProvider::default());
+}
+
+pub fn set_tls_provider(provider: TlsProvider) {
+    *G_TLS_PROVIDER.write().unwrap() = provider;
+}
+
+pub fn tls_provider() -> TlsProvider {
+    *G_TLS_PROVIDER.read().unwrap()
+}
+
+pub fn tls_stats() -> &'static TlsStats {
+    &G_TLS_STATS
+}
+
+#[derive(Debug, Default)]
+pub struct TlsStats {
+    handshakes: AtomicU64,
+    tickets_issued: AtomicU64,
+    ticket_resumptions: AtomicU64,
+    session_id_resumptions: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TlsStatsSnapshot {
+    pub handshakes: u64,
+    pub tickets_issued: u64,
+    pub ticket_resumptions: u64,
+    pub session_id_resumptions: u64,
+    pub resumption_rate: f64,
+}
+
+impl TlsStats {
+    pub fn on_handshake(&self) {
+        self.handshakes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> TlsStatsSnapshot {
+        let handshakes = self.handshakes.load(Ordering::Relaxed);
+        let ticket_resumptions = self.ticket_resumptions.load(Ordering::Relaxed);
+        let session_id_resumptions = self.session_id_resumptions.load(Ordering::Relaxed);
+
+        let resumption_rate = if handshakes == 0 {
+            0.0
+        } else {
+            (ticket_resumptions + session_id_resumptions) as f64 / handshakes as f64
+        };
+
+        TlsStatsSnapshot {
+            handshakes,
+            tickets_issued: self.tickets_issued.load(Ordering::Relaxed),
+            ticket_resumptions,
+            session_id_resumptions,
+            resumption_rate,
+        }
+    }
+}
+
+/// Certificates selected by SNI, OCSP responses stapled are reloaded by `reload_ocsp`.
+#[derive(Default)]
+pub struct Certificates {
+    keys: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    ocsp_paths: HashMap<String, PathBuf>,
+}
+
+impl Certificates {
+    pub fn is_empty(&self) -> bool {
+        self.keys.read().unwrap().is_empty()
+    }
+
+    pub fn keys(&self) -> Vec<(String, Arc<CertifiedKey>)> {
+        let keys = self.keys.read().unwrap();
+        keys.iter()
+            .map(|(name, key)| (name.clone(), key.clone()))
+            .collect()
+    }
+
+    /// Read OCSP response files again, a staple failing to read is kept as it was.
+    pub fn reload_ocsp(&self) {
+        for (name, path) in &self.ocsp_paths {
+            let ocsp = match std::fs::read(path) {
+                Ok(ocsp) => ocsp,
+                Err(err) => {
+                    tracing::error!(%name, ?path, %err, "read ocsp response failed");
+                    continue;
+                }
+            };
+
+            let mut keys = self.keys.write().unwrap();
+            if let Some(key) = keys.get_mut(name) {
+                if key.ocsp.as_ref() != Some(&ocsp) {
+                    tracing::info!(%name, ?path, "ocsp response reloaded");
+                    let mut reloaded = CertifiedKey::clone(key);
+                    reloaded.ocsp = Some(ocsp);
+                    *key = Arc::new(reloaded);
+                }
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name()?;
+        self.keys.read().unwrap().get(name).cloned()
+    }
+}
+
+/// Handshakes of https listener, by `tls_provider`.
+#[derive(Clone)]
+pub enum TlsAcceptor {
+    Rustls(tokio_rustls::TlsAcceptor),
+    #[cfg(feature = "native-tls")]
+    NativeTls(tokio_native_tls::TlsAcceptor),
+}
+
+/// Build acceptor of `tls_provider`, certificates are loaded by rustls either way for monitoring.
+pub fn build_acceptor(cfg: &ServerConfig) -> Result<(TlsAcceptor, Arc<Certificates>), ConfigError> {
+    let (config, certificates) = build_server_config(cfg)?;
+
+    let acceptor = match cfg.tls_provider {
+        TlsProvider::Rustls => TlsAcceptor::Rustls(config.into()),
+        TlsProvider::NativeTls => native_acceptor(cfg)?,
+    };
+
+    Ok((acceptor, certificates))
+}
+
+/// Identity of the only certificate, see `TlsProvider::validate`, key should be PKCS#8.
+#[cfg(feature = "native-tls")]
+fn native_acceptor(cfg: &ServerConfig) -> Result<TlsAcceptor, ConfigError> {
+    let tls = cfg
+        .tls_config
+        .values()
+        .next()
+        .ok_or_else(|| ConfigError::Message("no certificate".to_string()))?;
+
+    let cert = std::fs::read(&tls.cert_path)?;
+    let key = std::fs::read(&tls.key_path)?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key).map_err(|e| {
+        ConfigError::Message(format!("invalid PKCS#8 key {:?}: {}", tls.key_path, e))
+    })?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)
+        .map_err(|e| ConfigError::Message(format!("native tls acceptor: {}", e)))?;
+
+    Ok(TlsAcceptor::NativeTls(acceptor.into()))
+}
+
+#[cfg(not(feature = "native-tls"))]
+fn native_acceptor(_cfg: &ServerConfig) -> Result<TlsAcceptor, ConfigError> {
+    Err(ConfigError::Message(
+        "build without `native-tls` feature".to_string(),
+    ))
+}
+
+/// Build rustls server config, certificates selected by SNI.
+fn build_server_config(
+    cfg: &ServerConfig,
+) -> Result<(Arc<rustls::ServerConfig>, Arc<Certificates>), ConfigError> {
+    let mut certificates = Certificates::default();
+
+    for (name, tls) in &cfg.tls_config {
+        let key = load_certified_key(tls)?;
+
+        // checks the certificate is valid for `name`
+        ResolvesServerCertUsingSni::new()
+            .add(name, key.clone())
+            .map_err(|e| ConfigError::Message(format!("add certificate for {}: {}", name, e)))?;
+
+        certificates
+            .keys
+            .get_mut()
+            .unwrap()
+            .insert(name.clone(), Arc::new(key));
+        if let Some(ref ocsp_path) = tls.ocsp_path {
+            certificates
+                .ocsp_paths
+                .insert(name.clone(), ocsp_path.clone());
+        }
+    }
+
+    let certificates = Arc::new(certificates);
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(certificates.clone());
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let session = &cfg.tls_session;
+    config.session_storage = Arc::new(CountingSessionCache {
+        inner: ServerSessionMemoryCache::new(session.cache_size),
+    });
+    if session.tickets {
+        config.ticketer = Arc::new(RotatingTicketer::new(session)?);
+    }
+
+    Ok((Arc::new(config), certificates))
+}
+
+fn load_certified_key(cfg: &TlsConfig) -> Result<CertifiedKey, ConfigError> {
+    let certs = rustls_pemfile::certs(&mut open_pem(&cfg.cert_path)?)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    let key = rustls_pemfile::read_all(&mut open_pem(&cfg.key_path)?)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            ConfigError::Message(format!("no private key found in {:?}", cfg.key_path))
+        })?;
+
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|e| ConfigError::Message(format!("invalid private key: {}", e)))?;
+
+    let mut certified_key = CertifiedKey::new(certs, key);
+
+    if let Some(ref ocsp_path) = cfg.ocsp_path {
+        certified_key.ocsp = Some(std::fs::read(ocsp_path)?);
+    }
+
+    Ok(certified_key)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateInfo {
+    pub name: String,
+    pub subject: String,
+    pub issuer: String,
+    /// unix timestamp in seconds
+    pub not_after: i64,
+    pub days_remaining: i64,
+    pub ocsp_stapled: bool,
+    /// `nextUpdate` of OCSP response stapled, unix timestamp in seconds
+    pub ocsp_next_update: Option<i64>,
+}
+
+impl CertificateInfo {
+    pub fn new(name: &str, key: &CertifiedKey) -> Result<Self, ConfigError> {
+        let der = key
+            .cert
+            .first()
+            .ok_or_else(|| ConfigError::Message(format!("empty certificate chain for {}", name)))?;
+
+        let (_, cert) = x509_parser::parse_x509_certificate(&der.0)
+            .map_err(|e| ConfigError::Message(format!("parse certificate for {}: {}", name, e)))?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let not_after = cert.validity().not_after.timestamp();
+
+        Ok(CertificateInfo {
+            name: name.to_string(),
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            not_after,
+            days_remaining: (not_after - now) / 86400,
+            ocsp_stapled: key.ocsp.is_some(),
+            ocsp_next_update: key.ocsp.as_deref().and_then(ocsp_next_update),
+        })
+    }
+}
+
+pub fn certificate_infos(certificates: &Certificates) -> Vec<CertificateInfo> {
+    let mut infos = certificates
+        .keys()
+        .into_iter()
+        .filter_map(|(name, key)| match CertificateInfo::new(&name, &key) {
+            Ok(info) => Some(info),
+            Err(err) => {
+                tracing::error!(%err, "inspect certificate failed");
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    infos.sort_unstable_by_key(|info| info.not_after);
+    infos
+}
+
+/// Check certificates expiry periodically, warn when expire within `warn_days`.
+/// OCSP responses are reloaded at the same time, warn when stale.
+pub fn spawn_expiry_monitor(certificates: Arc<Certificates>, warn_days: u64) {
+    if certificates.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(60 * 60));
+
+        loop {
+            ticker.tick().await;
+
+            certificates.reload_ocsp();
+
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64;
+
+            for info in certificate_infos(&certificates) {
+                if let Some(next_update) = info.ocsp_next_update.filter(|t| *t < now) {
+                    tracing::warn!(name = %info.name, next_update, "ocsp response stale");
+                }
+
+                if info.days_remaining < 0 {
+                    tracing::error!(name = %info.name, not_after = info.not_after, "certificate expired");
+                } else if info.days_remaining <= warn_days as i64 {
+                    tracing::warn!(
+                        name = %info.name,
+                        days_remaining = info.days_remaining,
+                        "certificate expiring soon"
+                    );
+                }
+            }
+        }
+    });
+}
+
+/// Client config for upstreams, a certificate in verified chain must match one of `pins`.
+pub fn pinned_client_config(pins: &[String]) -> Result<ClientConfig, ConfigError> {
+    let pins = pins
+        .iter()
+        .map(|pin| parse_pin(pin))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut roots = RootCertStore::empty();
+    let certs = rustls_native_certs::load_native_certs()?
+        .into_iter()
+        .map(|cert| cert.0)
+        .collect::<Vec<_>>();
+    roots.add_parsable_certificates(&certs);
+
+    let verifier = PinnedCertVerifier {
+        inner: WebPkiVerifier::new(roots, None),
+        pins,
+    };
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// Pin like `sha256/<base64>`, the prefix is optional.
+fn parse_pin(pin: &str) -> Result<[u8; 32], ConfigError> {
+    let encoded = pin
+        .strip_prefix("sha256/")
+        .map(|p| p.trim_start_matches('/'))
+        .unwrap_or(pin);
+
+    base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .ok()
+        .and_then(|digest| digest.try_into().ok())
+        .ok_or_else(|| ConfigError::Message(format!("invalid tls pin<{}>", pin)))
+}
+
+fn spki_sha256(cert: &Certificate) -> Option<[u8; 32]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+
+    Some(Sha256::digest(cert.public_key().raw).into())
+}
+
+/// Verify as usual, then check pins against the chain.
+struct PinnedCertVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(spki_sha256)
+            .any(|digest| self.pins.contains(&digest));
+
+        if !pinned {
+            tracing::error!(?server_name, "upstream certificate not matching pins");
+            return Err(rustls::Error::General(
+                "certificate not matching pins".to_string(),
+            ));
+        }
+
+        Ok(verified)
+    }
+}
+
+fn open_pem(path: &Path) -> Result<BufReader<File>, ConfigError> {
+    Ok(BufReader::new(File::open(path)?))
+}
+
+/// `nextUpdate` of the first single response in a DER encoded OCSP response, unix
+/// timestamp in seconds. `None` when absent or malformed.
+fn ocsp_next_update(der: &[u8]) -> Option<i64> {
+    // OCSPResponse { responseStatus, responseBytes [0] { responseType, response } }
+    let (resp, _) = der_expect(der, Class::Universal, Tag::Sequence)?;
+    let (_status, rest) = der_expect(resp, Class::Universal, Tag::Enumerated)?;
+    let (bytes, _) = der_expect(rest, Class::ContextSpecific, Tag(0))?;
+    let (bytes, _) = der_expect(bytes, Class::Universal, Tag::Sequence)?;
+    let (_type, rest) = der_expect(bytes, Class::Universal, Tag::Oid)?;
+    let (basic, _) = der_expect(rest, Class::Universal, Tag::OctetString)?;
+
+    // BasicOCSPResponse { tbsResponseData { version [0], responderID, producedAt, responses } }
+    let (basic, _) = der_expect(basic, Class::Universal, Tag::Sequence)?;
+    let (data, _) = der_expect(basic, Class::Universal, Tag::Sequence)?;
+    let rest = der_expect(data, Class::ContextSpecific, Tag(0)).map_or(data, |(_, rest)| rest);
+    let (rest, _responder) = <Any as FromDer>::from_der(rest).ok()?;
+    let (_produced_at, rest) = der_expect(rest, Class::Universal, Tag::GeneralizedTime)?;
+    let (responses, _) = der_expect(rest, Class::Universal, Tag::Sequence)?;
+
+    // SingleResponse { certID, certStatus, thisUpdate, nextUpdate [0] }
+    let (single, _) = der_expect(responses, Class::Universal, Tag::Sequence)?;
+    let (_cert_id, rest) = der_expect(single, Class::Universal, Tag::Sequence)?;
+    let (rest, _status) = <Any as FromDer>::from_der(rest).ok()?;
+    let (_this_update, rest) = der_expect(rest, Class::Universal, Tag::GeneralizedTime)?;
+    let (next_update, _) = der_expect(rest, Class::ContextSpecific, Tag(0))?;
+    let (_, next_update) = ASN1Time::from_der(next_update).ok()?;
+
+    Some(next_update.timestamp())
+}
+
+/// Next DER element of `class` and `tag`, gives its content and what follows.
+fn der_expect(i: &[u8], class: Class, tag: Tag) -> Option<(&[u8], &[u8])> {
+    let (rest, any) = <Any as FromDer>::from_der(i).ok()?;
+    (any.header.class() == class && any.header.tag() == tag).then(|| (any.data, rest))
+}
+
+/// Session ID cache counting resumptions.
+struct CountingSessionCache {
+    inner: Arc<ServerSessionMemoryCache>,
+}
+
+impl StoresServerSessions for CountingSessionCache {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.inner.get(key);
+        if value.is_some() {
+            tls_stats()
+                .session_id_resumptions
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        value
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.inner.take(key);
+        if value.is_some() {
+            tls_stats()
+                .session_id_resumptions
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        value
+    }
+
+    fn can_cache(&self) -> bool {
+        self.inner.can_cache()
+    }
+}
+
+/// Ticketer rotating keys every `rotation`, tickets from the previous key still accepted.
+struct RotatingTicketer {
+    rotation: Duration,
+    keys: RwLock<TicketKeys>,
+}
+
+struct TicketKeys {
+    current: TicketKey,
+    previous: Option<TicketKey>,
+    rotated_at: Instant,
+}
+
+impl RotatingTicketer {
+    fn new(cfg: &TlsSessionConfig) -> Result<Self, ConfigError> {
+        let current = TicketKey::generate()
+            .ok_or_else(|| ConfigError::Message("create tls ticket key failed".to_string()))?;
+
+        Ok(RotatingTicketer {
+            rotation: Duration::from_secs(cfg.ticket_rotation),
+            keys: RwLock::new(TicketKeys {
+                current,
+                previous: None,
+                rotated_at: Instant::now(),
+            }),
+        })
+    }
+
+    fn rotate_if_needed(&self) {
+        if self.keys.read().unwrap().rotated_at.elapsed() < self.rotation {
+            return;
+        }
+
+        let mut keys = self.keys.write().unwrap();
+        if keys.rotated_at.elapsed() < self.rotation {
+            return;
+        }
+
+        match TicketKey::generate() {
+            Some(key) => {
+                let previous = std::mem::replace(&mut keys.current, key);
+                keys.previous = Some(previous);
+                keys.rotated_at = Instant::now();
+                tracing::info!("tls ticket key rotated");
+            }
+            None => {
+                tracing::error!("rotate tls ticket key failed");
+            }
+        }
+    }
+}
+
+/// Single ticket key, ChaCha20-Poly1305 with random nonce like the ticketer of rustls,
+/// which rotates on its own schedule and so is not used.
+struct TicketKey(LessSafeKey);
+
+impl TicketKey {
+    fn generate() -> Option<Self> {
+        let mut key = [0u8; 32];
+        SystemRandom::new().fill(&mut key).ok()?;
+        let key = UnboundKey::new(&aead::CHACHA20_POLY1305, &key).ok()?;
+
+        Some(TicketKey(LessSafeKey::new(key)))
+    }
+
+    /// Nonce followed by sealed `plain`.
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+
+        let mut sealed = plain.to_vec();
+        self.0
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut sealed,
+            )
+            .ok()?;
+
+        Some([&nonce[..], &sealed].concat())
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        if ticket.len() < NONCE_LEN {
+            return None;
+        }
+
+        let (nonce, sealed) = ticket.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut plain = sealed.to_vec();
+        let len = self
+            .0
+            .open_in_place(nonce, Aad::empty(), &mut plain)
+            .ok()?
+            .len();
+        plain.truncate(len);
+
+        Some(plain)
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        // tickets stay decryptable for two rotation periods
+        (self.rotation.as_secs() * 2).min(u32::MAX as u64) as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.rotate_if_needed();
+
+        let ticket = self.keys.read().unwrap().current.encrypt(plain);
+        if ticket.is_some() {
+            tls_stats().tickets_issued.fetch_add(1, Ordering::Relaxed);
+        }
+        ticket
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.keys.read().unwrap();
+
+        let plain = keys.current.decrypt(cipher).or_else(|| {
+            keys.previous
+                .as_ref()
+                .and_then(|previous| previous.decrypt(cipher))
+        });
+        if plain.is_some() {
+            tls_stats()
+                .ticket_resumptions
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        plain
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        if content.len() < 0x80 {
+            out.push(content.len() as u8);
+        } else {
+            out.extend_from_slice(&[0x82, (content.len() >> 8) as u8, content.len() as u8]);
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    fn ocsp_response(next_update: Option<&str>) -> Vec<u8> {
+        let time = |t: &str| der(0x18, t.as_bytes());
+
+        let mut single = [
+            der(0x30, &[0u8; 200]),
+            der(0x80, &[]),
+            time("20240101000000Z"),
+        ]
+        .concat();
+        if let Some(next_update) = next_update {
+            single.extend(der(0xa0, &time(next_update)));
+        }
+
+        let data = [
+            der(0xa1, &der(0x30, &[])),
+            time("20240101000000Z"),
+            der(0x30, &der(0x30, &single)),
+        ]
+        .concat();
+        let basic = der(
+            0x30,
+            &[der(0x30, &data), der(0x30, &[]), der(0x03, &[0])].concat(),
+        );
+        let bytes = der(0x30, &[der(0x06, &[1, 2, 3]), der(0x04, &basic)].concat());
+
+        der(0x30, &[der(0x0a, &[0]), der(0xa0, &bytes)].concat())
+    }
+
+    #[test]
+    fn parse_ocsp_next_update() {
+        assert_eq!(
+            ocsp_next_update(&ocsp_response(Some("19700101000000Z"))),
+            Some(0)
+        );
+        assert_eq!(
+            ocsp_next_update(&ocsp_response(Some("20240229120000Z"))),
+            Some(1709208000)
+        );
+        assert_eq!(ocsp_next_update(&ocsp_response(None)), None);
+    }
+
+    #[test]
+    fn parse_malformed_ocsp() {
+        assert_eq!(ocsp_next_update(&[]), None);
+        assert_eq!(ocsp_next_update(b"\x30\x03\x0a\x01\x01"), None);
+
+        // bad times
+        for time in ["2024022912Z", "20241301000000Z", "2024010100000aZ", ""] {
+            assert_eq!(ocsp_next_update(&ocsp_response(Some(time))), None, "{time}");
+        }
+
+        // truncated at every length
+        let resp = ocsp_response(Some("20240108000000Z"));
+        for len in 0..resp.len() {
+            assert_eq!(ocsp_next_update(&resp[..len]), None, "truncated to {len}");
+        }
+
+        // wrong outer tag
+        let mut wrong_tag = resp;
+        wrong_tag[0] = 0x31;
+        assert_eq!(ocsp_next_update(&wrong_tag), None);
+
+        // length beyond input, indefinite length
+        assert_eq!(
+            ocsp_next_update(b"\x30\x84\xff\xff\xff\xff\x0a\x01\x00"),
+            None
+        );
+        assert_eq!(ocsp_next_update(b"\x30\x80\x0a\x01\x00\x00\x00"), None);
+    }
+
+    #[test]
+    fn rotate_ticket_keys() {
+        let mut ticketer = RotatingTicketer::new(&TlsSessionConfig::default()).unwrap();
+        // rotated before every ticket
+        ticketer.rotation = Duration::ZERO;
+
+        let first = ticketer.encrypt(b"session").unwrap();
+        assert_eq!(ticketer.decrypt(&first).as_deref(), Some(&b"session"[..]));
+
+        // previous key still accepted
+        let second = ticketer.encrypt(b"session").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(ticketer.decrypt(&first).as_deref(), Some(&b"session"[..]));
+        assert_eq!(ticketer.decrypt(&second).as_deref(), Some(&b"session"[..]));
+
+        let mut third = ticketer.encrypt(b"session").unwrap();
+        assert_eq!(ticketer.decrypt(&first), None);
+
+        let last = third.len() - 1;
+        third[last] ^= 1;
+        assert_eq!(ticketer.decrypt(&third), None);
+        assert_eq!(ticketer.decrypt(&third[..4]), None);
+    }
+}
@@ -7,13 +7,35 @@ use hyper::Uri;
 use crate::config::UpstreamConfig;
 
 use crate::error::ConfigError;
-use crate::forwarder::HttpClient;
-use crate::health::{HealthConfig, Healthiness};
+use crate::context::GatewayContext;
+use crate::forwarder::{Fowarder, HttpClient};
+use crate::health::{spawn_warmup, HealthConfig, Healthiness, WarmupConfig};
+use crate::limiter::ConcurrencyLimiter;
 use crate::load_balance::*;
 use crate::registry::Endpoint;
 
 pub type UpstreamMap = HashMap<String, Arc<RwLock<Upstream>>>;
 
+/// Resolve upstreams by id, for plugins sending requests to other upstreams.
+#[derive(Clone)]
+pub struct UpstreamResolver(Arc<UpstreamMap>);
+
+impl UpstreamResolver {
+    pub fn new(upstreams: Arc<UpstreamMap>) -> Self {
+        UpstreamResolver(upstreams)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<RwLock<Upstream>>> {
+        self.0.get(id).cloned()
+    }
+}
+
+impl std::fmt::Debug for UpstreamResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.0.keys()).finish()
+    }
+}
+
 pub struct Upstream {
     pub id: String,
     pub name: String,
@@ -21,6 +43,8 @@ pub struct Upstream {
     pub strategy: Arc<Box<dyn LoadBalanceStrategy>>,
     pub endpoints: Vec<(Endpoint, Arc<RwLock<Healthiness>>)>,
     pub health_config: HealthConfig,
+    pub warmup: Option<WarmupConfig>,
+    pub limiter: Arc<ConcurrencyLimiter>,
 }
 
 impl Upstream {
@@ -38,12 +62,17 @@ impl Upstream {
             "random" => Arc::new(Box::new(Random::new())),
             "weighted" => Arc::new(Box::new(WeightedRandom::new())),
             "least_request" => Arc::new(Box::new(LeastRequest::new())),
+            "roundrobin" => Arc::new(Box::new(RoundRobin::new())),
+            "p2c" => Arc::new(Box::new(P2C::new(
+                endpoints.iter().map(|(ep, _)| ep.target.clone()),
+            ))),
             s => {
                 return Err(ConfigError::UnknownLBStrategy(s.to_string()));
             }
         };
 
-        let client = HttpClient::new();
+        cfg.buffer.validate()?;
+        let client = HttpClient::with_tls_pins(&cfg.buffer, &cfg.tls_pins)?;
 
         Ok(Upstream {
             id: cfg.id.clone(),
@@ -52,9 +81,44 @@ impl Upstream {
             client,
             strategy,
             health_config: cfg.health_check.clone(),
+            warmup: cfg.warmup.clone(),
+            limiter: Arc::new(ConcurrencyLimiter::new(cfg.max_concurrency)),
         })
     }
 
+    /// Keep healthiness of endpoints existed in `previous`, warm up the new joined.
+    pub fn warmup(&mut self, previous: Option<&Upstream>) {
+        // keep counting in-flight requests across reload
+        if let Some(prev) = previous.filter(|p| p.limiter.limit() == self.limiter.limit()) {
+            self.limiter = prev.limiter.clone();
+        }
+
+        for (endpoint, healthiness) in self.endpoints.iter_mut() {
+            let existed = previous.and_then(|prev| {
+                prev.endpoints
+                    .iter()
+                    .find(|(ep, _)| ep.target == endpoint.target)
+            });
+
+            match (existed, &self.warmup) {
+                (Some((_, prev_healthiness)), _) => {
+                    *healthiness = prev_healthiness.clone();
+                }
+                (None, Some(cfg)) => {
+                    if tokio::runtime::Handle::try_current().is_ok() {
+                        spawn_warmup(
+                            cfg.clone(),
+                            self.id.clone(),
+                            endpoint.target.clone(),
+                            healthiness.clone(),
+                        );
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+    }
+
     pub fn healthy_endpoints(&self) -> Vec<&Endpoint> {
         self.endpoints
             .iter()
@@ -65,10 +129,26 @@ impl Upstream {
             .collect::<Vec<_>>()
     }
 
+    /// Forwarder of upstream, available endpoints are kept in `ctx`.
+    pub fn forwarder(&self, ctx: &mut GatewayContext) -> Fowarder {
+        let healthy_endpoints = self.healthy_endpoints();
+        let available_endpoints = if healthy_endpoints.is_empty() {
+            self.all_endpoints()
+        } else {
+            healthy_endpoints
+        };
+
+        ctx.available_endpoints = available_endpoints.into_iter().cloned().collect();
+
+        Fowarder::new(self.client.clone(), self.strategy.clone())
+    }
+
     pub fn all_endpoints(&self) -> Vec<&Endpoint> {
         self.endpoints
             .iter()
-            .filter(|(endpoint, _healthiness)| endpoint.weight != 0)
+            .filter(|(endpoint, healthiness)| {
+                (endpoint.weight != 0) && (*healthiness.read().unwrap() != Healthiness::Warming)
+            })
             .map(|(endpoint, _)| endpoint)
             .collect::<Vec<_>>()
     }
@@ -86,3 +166,95 @@ impl Upstream {
     //     Some(endpoint)
     // }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{convert::Infallible, time::Duration};
+
+    use hyper::{
+        http::uri::Scheme,
+        service::{make_service_fn, service_fn},
+        Body,
+    };
+
+    use super::*;
+    use crate::config::EndpointConfig;
+
+    #[tokio::test]
+    async fn warmup_new_endpoint() {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_req: hyper::Request<Body>| async move {
+                Ok::<_, Infallible>(hyper::Response::new(Body::empty()))
+            }))
+        });
+        let srv = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = srv.local_addr();
+        tokio::spawn(srv);
+
+        let cfg = UpstreamConfig {
+            id: "upstream-001".to_string(),
+            name: "upstream-001".to_string(),
+            endpoints: vec![EndpointConfig {
+                addr: format!("http://{}", addr),
+                weight: 1,
+            }],
+            strategy: "random".to_string(),
+            warmup: Some(WarmupConfig {
+                path: "/warmup".to_string(),
+                timeout: 1000,
+                attempts: 3,
+                interval: 100,
+            }),
+            ..Default::default()
+        };
+
+        let mut upstream = Upstream::new(&cfg).unwrap();
+        upstream.warmup(None);
+
+        assert!(upstream.all_endpoints().is_empty());
+
+        for _ in 0..50 {
+            if !upstream.healthy_endpoints().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(upstream.healthy_endpoints().len(), 1);
+
+        // existed endpoint is admitted directly on reload
+        let mut reloaded = Upstream::new(&cfg).unwrap();
+        reloaded.warmup(Some(&upstream));
+        assert_eq!(reloaded.healthy_endpoints().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn all_endpoints_warming() {
+        let cfg = UpstreamConfig {
+            id: "upstream-001".to_string(),
+            name: "upstream-001".to_string(),
+            endpoints: vec![EndpointConfig {
+                addr: "http://127.0.0.1:1".to_string(),
+                weight: 1,
+            }],
+            strategy: "p2c".to_string(),
+            warmup: Some(WarmupConfig {
+                path: "/warmup".to_string(),
+                timeout: 1000,
+                attempts: 3,
+                interval: 100,
+            }),
+            ..Default::default()
+        };
+
+        let mut upstream = Upstream::new(&cfg).unwrap();
+        upstream.warmup(None);
+
+        let req = hyper::Request::new(Body::empty());
+        let mut ctx = GatewayContext::new(None, Scheme::HTTP, &req);
+        let mut forwarder = upstream.forwarder(&mut ctx);
+        assert!(ctx.available_endpoints.is_empty());
+
+        // refused instead of picking from nothing
+        assert!(forwarder.forward(&mut ctx, req).await.is_err());
+    }
+}
@@ -0,0 +1,338 @@
+use std::fmt;
+
+use headers::{Cookie, HeaderMapExt};
+use hyper::header::HOST;
+
+use crate::context::{Consumer, GatewayContext};
+use crate::error::ConfigError;
+use crate::http::HyperRequest;
+
+/// Request scoped variable, referenced as `$name` or `${name}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Variable {
+    RemoteAddr,
+    Scheme,
+    Host,
+    Method,
+    /// request path
+    Uri,
+    /// request path with query
+    RequestUri,
+    Args,
+    RouteId,
+    UpstreamId,
+    /// identity set by auth plugins
+    Consumer,
+    /// `$arg_name`, query parameter
+    Arg(String),
+    /// `$http_name`, request header, `_` in name stands for `-`
+    Http(String),
+    /// `$cookie_name`
+    Cookie(String),
+    /// `$param_name`, path param captured by route uri
+    Param(String),
+    /// `$var_name`, variable set by plugins
+    Custom(String),
+}
+
+impl Variable {
+    /// Parse variable name without `$`, unknown names are rejected.
+    pub fn parse(name: &str) -> Result<Variable, String> {
+        let var = match name {
+            "remote_addr" => Variable::RemoteAddr,
+            "scheme" => Variable::Scheme,
+            "host" => Variable::Host,
+            "method" => Variable::Method,
+            "uri" => Variable::Uri,
+            "request_uri" => Variable::RequestUri,
+            "args" => Variable::Args,
+            "route_id" => Variable::RouteId,
+            "upstream_id" => Variable::UpstreamId,
+            "consumer" => Variable::Consumer,
+            _ => {
+                let suffix = |prefix: &str| name.strip_prefix(prefix).filter(|s| !s.is_empty());
+
+                if let Some(arg) = suffix("arg_") {
+                    Variable::Arg(arg.to_string())
+                } else if let Some(header) = suffix("http_") {
+                    Variable::Http(header.replace('_', "-").to_ascii_lowercase())
+                } else if let Some(cookie) = suffix("cookie_") {
+                    Variable::Cookie(cookie.to_string())
+                } else if let Some(param) = suffix("param_") {
+                    Variable::Param(param.to_string())
+                } else if let Some(custom) = suffix("var_") {
+                    Variable::Custom(custom.to_string())
+                } else {
+                    return Err(format!("unknown variable `${}`", name));
+                }
+            }
+        };
+
+        Ok(var)
+    }
+
+    pub fn resolve(&self, ctx: &GatewayContext, req: &HyperRequest) -> Option<String> {
+        match self {
+            Variable::RemoteAddr => ctx.remote_addr().map(|addr| addr.ip().to_string()),
+            Variable::Scheme => Some(ctx.orig_scheme().to_string()),
+            Variable::Host => req
+                .headers()
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string())
+                .or_else(|| ctx.orig_host().map(String::from)),
+            Variable::Method => Some(req.method().to_string()),
+            Variable::Uri => Some(req.uri().path().to_string()),
+            Variable::RequestUri => req.uri().path_and_query().map(|pq| pq.to_string()),
+            Variable::Args => req.uri().query().map(|q| q.to_string()),
+            Variable::RouteId => ctx.route_id.clone(),
+            Variable::UpstreamId => ctx.upstream_id.clone(),
+            Variable::Consumer => ctx.get::<Consumer>().map(|c| c.0.clone()),
+            Variable::Arg(name) => req.uri().query().and_then(|q| {
+                url::form_urlencoded::parse(q.as_bytes())
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.into_owned())
+            }),
+            Variable::Http(name) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_string()),
+            Variable::Cookie(name) => req
+                .headers()
+                .typed_get::<Cookie>()
+                .and_then(|cookie| cookie.get(name).map(|v| v.to_string())),
+            Variable::Param(name) => ctx.path_params.get(name).cloned(),
+            Variable::Custom(name) => ctx.vars.get(name).cloned(),
+        }
+    }
+}
+
+/// Name accepted by `Variable::parse`.
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Variable::RemoteAddr => write!(f, "remote_addr"),
+            Variable::Scheme => write!(f, "scheme"),
+            Variable::Host => write!(f, "host"),
+            Variable::Method => write!(f, "method"),
+            Variable::Uri => write!(f, "uri"),
+            Variable::RequestUri => write!(f, "request_uri"),
+            Variable::Args => write!(f, "args"),
+            Variable::RouteId => write!(f, "route_id"),
+            Variable::UpstreamId => write!(f, "upstream_id"),
+            Variable::Consumer => write!(f, "consumer"),
+            Variable::Arg(name) => write!(f, "arg_{}", name),
+            Variable::Http(name) => write!(f, "http_{}", name.replace('-', "_")),
+            Variable::Cookie(name) => write!(f, "cookie_{}", name),
+            Variable::Param(name) => write!(f, "param_{}", name),
+            Variable::Custom(name) => write!(f, "var_{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Var(Variable),
+}
+
+/// String with embedded variables, like `/api/$arg_version/${uri}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Template, ConfigError> {
+        Self::parse_with(s, false)
+    }
+
+    /// Parse path template, `{name}` is short for path param `${param_name}`.
+    pub fn parse_path(s: &str) -> Result<Template, ConfigError> {
+        Self::parse_with(s, true)
+    }
+
+    fn parse_with(s: &str, path_params: bool) -> Result<Template, ConfigError> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = s;
+
+        let unclosed = || ConfigError::Message(format!("unclosed variable in template `{}`", s));
+
+        while let Some(pos) = rest.find(|c: char| c == '$' || (path_params && c == '{')) {
+            literal.push_str(&rest[..pos]);
+
+            let (var, remain) = if let Some(braced) = rest[pos..].strip_prefix('{') {
+                let end = braced.find('}').ok_or_else(unclosed)?;
+                let name = &braced[..end];
+                (
+                    Some(Variable::Param(name.to_string())).filter(|_| !name.is_empty()),
+                    &braced[end + 1..],
+                )
+            } else {
+                let after = &rest[pos + 1..];
+                let (name, remain) = if let Some(braced) = after.strip_prefix('{') {
+                    let end = braced.find('}').ok_or_else(unclosed)?;
+                    (&braced[..end], &braced[end + 1..])
+                } else {
+                    let end = after
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                };
+
+                if name.is_empty() {
+                    // lone `$` is kept as it is
+                    literal.push('$');
+                    rest = after;
+                    continue;
+                }
+
+                let var = Variable::parse(name)
+                    .map_err(|e| ConfigError::Message(format!("{} in template `{}`", e, s)))?;
+                (Some(var), remain)
+            };
+
+            match var {
+                Some(var) => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Var(var));
+                }
+                None => literal.push_str("{}"),
+            }
+            rest = remain;
+        }
+
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Template { segments })
+    }
+
+    /// Render template, missing variables are rendered as empty string.
+    pub fn render(&self, ctx: &GatewayContext, req: &HyperRequest) -> String {
+        let mut ret = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => ret.push_str(s),
+                Segment::Var(var) => {
+                    if let Some(value) = var.resolve(ctx, req) {
+                        ret.push_str(&value);
+                    }
+                }
+            }
+        }
+
+        ret
+    }
+
+    /// Render template, `None` when any variable is missing.
+    pub fn render_all(&self, ctx: &GatewayContext, req: &HyperRequest) -> Option<String> {
+        let mut ret = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => ret.push_str(s),
+                Segment::Var(var) => ret.push_str(&var.resolve(ctx, req)?),
+            }
+        }
+
+        Some(ret)
+    }
+}
+
+/// Source accepted by `Template::parse`, variables are braced.
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => write!(f, "{}", s)?,
+                Segment::Var(var) => write!(f, "${{{}}}", var)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{http::uri::Scheme, Body};
+
+    use super::*;
+
+    #[test]
+    fn parse_template() {
+        let tpl = Template::parse("/v$arg_version${uri}-$$").unwrap();
+
+        assert_eq!(
+            tpl.segments,
+            vec![
+                Segment::Literal("/v".to_string()),
+                Segment::Var(Variable::Arg("version".to_string())),
+                Segment::Var(Variable::Uri),
+                Segment::Literal("-$$".to_string()),
+            ]
+        );
+
+        assert!(Template::parse("/${uri").is_err());
+        assert!(Template::parse("/$tenant").is_err());
+        assert!(Template::parse("/${http_}").is_err());
+
+        let tpl = Template::parse("$remote_addr:$http_x_user_id-${var_tenant}$").unwrap();
+        assert_eq!(
+            tpl.to_string(),
+            "${remote_addr}:${http_x_user_id}-${var_tenant}$"
+        );
+        assert_eq!(Template::parse(&tpl.to_string()).unwrap(), tpl);
+
+        let tpl = Template::parse_path("/v2/users/{id}/$param_page").unwrap();
+
+        assert_eq!(
+            tpl.segments,
+            vec![
+                Segment::Literal("/v2/users/".to_string()),
+                Segment::Var(Variable::Param("id".to_string())),
+                Segment::Literal("/".to_string()),
+                Segment::Var(Variable::Param("page".to_string())),
+            ]
+        );
+
+        assert!(Template::parse_path("/users/{id").is_err());
+    }
+
+    #[test]
+    fn render_template() {
+        let req = hyper::Request::builder()
+            .uri("/api/user?version=2")
+            .header("host", "example.com")
+            .header("x-user-id", "42")
+            .header("cookie", "env=dev")
+            .body(Body::empty())
+            .unwrap();
+
+        let mut ctx = GatewayContext::new(None, Scheme::HTTPS, &req);
+        ctx.route_id = Some("r1".to_string());
+        ctx.vars.insert("tenant".to_string(), "acme".to_string());
+
+        let tpl = Template::parse(
+            "$scheme://$host$request_uri $arg_version $http_x_user_id $cookie_env $route_id $var_tenant $var_missing.",
+        )
+        .unwrap();
+
+        assert_eq!(
+            tpl.render(&ctx, &req),
+            "https://example.com/api/user?version=2 2 42 dev r1 acme ."
+        );
+        assert_eq!(tpl.render_all(&ctx, &req), None);
+
+        let tpl = Template::parse("$route_id:$var_tenant").unwrap();
+        assert_eq!(tpl.render_all(&ctx, &req).as_deref(), Some("r1:acme"));
+    }
+}
@@ -0,0 +1,94 @@
+"""Write geoip-country.mmdb, a tiny GeoLite2-Country database for geoip plugin tests."""
+
+import ipaddress
+import os
+
+def ctrl(t, size):
+    assert size < 29
+    if t <= 7:
+        return bytes([(t << 5) | size])
+    return bytes([size, t - 7])
+
+def enc(v):
+    if isinstance(v, str):
+        b = v.encode(); return ctrl(2, len(b)) + b
+    if isinstance(v, bool):
+        return ctrl(14, int(v))
+    if isinstance(v, tuple):  # (type, int)
+        t, n = v
+        b = n.to_bytes((n.bit_length() + 7) // 8, 'big') if n else b''
+        return ctrl(t, len(b)) + b
+    if isinstance(v, dict):
+        out = ctrl(7, len(v))
+        for k, x in v.items():
+            out += enc(k) + enc(x)
+        return out
+    if isinstance(v, list):
+        out = ctrl(11, len(v))
+        for x in v: out += enc(x)
+        return out
+    raise TypeError(v)
+
+U16, U32, U64 = 5, 6, 9
+
+nets = {
+    '1.2.3.0/24': ('CN', 'China', 1814991),
+    '8.8.8.0/24': ('US', 'United States', 6252001),
+    '81.2.69.0/24': ('GB', 'United Kingdom', 2635167),
+}
+
+data = b''
+offsets = {}
+for net, (iso, name, gid) in nets.items():
+    offsets[net] = len(data)
+    data += enc({'country': {'geoname_id': (U32, gid), 'iso_code': iso, 'names': {'en': name}}})
+
+# trie: node = [left, right], child is node or ('data', off)
+root = [None, None]
+for net in nets:
+    n = ipaddress.ip_network(net)
+    bits = int(n.network_address)
+    node = root
+    for i in range(n.prefixlen):
+        b = (bits >> (31 - i)) & 1
+        if i == n.prefixlen - 1:
+            node[b] = ('data', offsets[net])
+        else:
+            if node[b] is None:
+                node[b] = [None, None]
+            node = node[b]
+
+order = []
+queue = [root]
+while queue:
+    n = queue.pop(0)
+    order.append(n)
+    for c in n:
+        if isinstance(c, list): queue.append(c)
+index = {id(n): i for i, n in enumerate(order)}
+count = len(order)
+
+tree = b''
+for n in order:
+    for c in n:
+        if c is None: v = count
+        elif isinstance(c, list): v = index[id(c)]
+        else: v = count + 16 + c[1]
+        tree += v.to_bytes(3, 'big')
+
+meta = enc({
+    'binary_format_major_version': (U16, 2),
+    'binary_format_minor_version': (U16, 0),
+    'build_epoch': (U64, 1700000000),
+    'database_type': 'GeoLite2-Country',
+    'description': {'en': 'geoip test database'},
+    'ip_version': (U16, 4),
+    'languages': ['en'],
+    'node_count': (U32, count),
+    'record_size': (U16, 24),
+})
+
+out = tree + b'\x00' * 16 + data + b'\xab\xcd\xefMaxMind.com' + meta
+path = os.path.join(os.path.dirname(os.path.abspath(__file__)), 'geoip-country.mmdb')
+with open(path, 'wb') as f:
+    f.write(out)